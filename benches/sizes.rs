@@ -0,0 +1,44 @@
+//! Compare the methods for calculating directory sizes
+//!
+//! By default a generated store is measured. Set `NIX_SWEEP_BENCH_PATH` to measure a real
+//! directory instead, e.g. `/nix/store`.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use nix_sweep::nix::fixture::Fixture;
+use nix_sweep::nix::store::NIX_STORE;
+use nix_sweep::utils::files;
+
+
+const PATH_ENV_VAR: &str = "NIX_SWEEP_BENCH_PATH";
+const FIXTURE_PATHS: usize = 200;
+const FIXTURE_PATH_SIZE: usize = 16 * 1024;
+
+
+fn fixture_store(dir: &Path) -> PathBuf {
+    let mut fixture = Fixture::create(dir).unwrap();
+    for i in 0..FIXTURE_PATHS {
+        fixture.add_store_path(&format!("package-{i}"), FIXTURE_PATH_SIZE, &[]).unwrap();
+    }
+    fixture.path(Path::new(NIX_STORE))
+}
+
+fn sizes(c: &mut Criterion) {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = match env::var_os(PATH_ENV_VAR) {
+        Some(path) => PathBuf::from(path),
+        None => fixture_store(tmp.path()),
+    };
+
+    let mut group = c.benchmark_group("dir-size");
+    group.sample_size(10);
+    group.bench_function("naive", |b| b.iter(|| files::dir_size_naive(&path)));
+    group.bench_function("hardlink-aware", |b| b.iter(|| files::dir_size_considering_hardlinks_uncached(&path)));
+    group.finish();
+}
+
+criterion_group!(benches, sizes);
+criterion_main!(benches);
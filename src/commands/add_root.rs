@@ -1,61 +1,139 @@
 use std::os::unix;
-use std::{env, fs};
-use std::path::PathBuf;
+use std::path::{self, Path, PathBuf};
+use std::fs;
+use std::str::FromStr;
 
-use crate::nix::store::Store;
+use crate::nix::backend::backend;
+use crate::nix::profiles::Profile;
+use crate::nix::roots::GCRoot;
+use crate::nix::sandbox;
+use crate::nix::store::{Store, StorePath};
 use crate::utils::fmt::FmtWithEllipsis;
-use crate::utils::interaction::conclusion;
+use crate::utils::interaction::{conclusion, warn};
 
 use super::Command;
 
 
 #[derive(clap::Args)]
 pub struct AddRootCommand {
-    /// Where to point the gc root to
-    target: PathBuf,
+    /// Where to point the gc roots to
+    #[clap(required_unless_present = "profile")]
+    targets: Vec<PathBuf>,
 
-    /// The preferred name for the gc root
+    /// The preferred name for the gc root (only for a single target)
+    ///
+    /// With --indirect this is the location of the symlink to the store path (e.g. ./result).
+    /// Without a name, roots are named after the target (e.g. `result`), with a numeric suffix if
+    /// another root of that name already exists.
+    #[clap(short, long)]
     name: Option<PathBuf>,
 
+    /// Add a root for the current generation of this profile
+    ///
+    /// You can pass the option multiple times to protect multiple profiles.
+    #[clap(short, long)]
+    profile: Vec<String>,
+
     /// Point the gc root directly to the corresponding store path
     #[clap(short, long)]
     direct: bool,
+
+    /// Register the root indirectly through /nix/var/nix/gcroots/auto, under the same name as `nix-store --add-root --indirect`
+    ///
+    /// If NAME is given, a symlink to the store path is created there and registered. Otherwise the
+    /// target itself must be a symlink to the store, which is then registered.
+    #[clap(short, long, conflicts_with = "direct")]
+    indirect: bool,
+
+    /// Realise the target derivation first and add a root for its output
+    #[clap(long)]
+    from_derivation: bool,
 }
 
 
 impl Command for AddRootCommand {
-    fn run(self) -> Result<(), String> {
-        if !self.target.exists() {
-            return Err("Target does not exist".to_owned());
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        if self.name.is_some() && self.targets.len() + self.profile.len() > 1 {
+            return Err("A name can only be given for a single target".to_owned());
         }
 
-        let canonic = fs::canonicalize(&self.target)
-            .map_err(|e| e.to_string())?;
-        if !Store::is_valid_path(&canonic) {
-            return Err("Target does not point to a store path".to_owned());
+        let mut roots = Vec::with_capacity(self.targets.len() + self.profile.len());
+        for target in &self.targets {
+            if !sandbox::exists(target)? {
+                return Err(format!("Target {} does not exist", target.to_string_lossy()));
+            }
+
+            let mut canonic = sandbox::canonicalize(target)?;
+            if self.from_derivation {
+                canonic = realise(&canonic)?;
+            }
+            if !Store::is_valid_path(&canonic) {
+                return Err(format!("Target {} does not point to a store path", target.to_string_lossy()));
+            }
+
+            // name the root after what the user passed (e.g. ./result), not the store path behind it
+            let named_after = if self.from_derivation { &canonic } else { target };
+            let default_name = path::absolute(named_after).ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+                .unwrap_or_default();
+            roots.push((target.clone(), canonic, default_name));
+        }
+
+        for profile_str in &self.profile {
+            let profile = Profile::from_str(profile_str)?;
+            let generation = profile.active_generation()?;
+            let canonic = generation.store_path()?.path().clone();
+            let profile_name = profile.path().file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            roots.push((canonic.clone(), canonic, format!("{}-{}", profile_name, generation.number())));
         }
 
-        let root_target = if self.direct {
-            canonic
+        for (target, canonic, default_name) in roots {
+            self.add_root(&target, canonic, default_name)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AddRootCommand {
+    fn add_root(&self, target: &Path, canonic: PathBuf, default_name: String) -> Result<(), String> {
+        let (root_target, full_gc_path) = if self.indirect {
+            let link = match &self.name {
+                Some(name) => {
+                    unix::fs::symlink(&canonic, name)
+                        .map_err(|e| format!("Unable to create {}: {}", name.to_string_lossy(), e))?;
+                    name.clone()
+                },
+                None if !self.from_derivation && !Store::is_valid_path(target) => target.to_path_buf(),
+                None => return Err("Please specify where to create the link to the store path".to_owned()),
+            };
+            let link = path::absolute(&link)
+                .map_err(|e| e.to_string())?;
+            let gc_path = GCRoot::auto_dir().join(GCRoot::auto_name(&link));
+            (link, gc_path)
         } else {
-            self.target.clone()
-        };
+            let root_target = if self.direct || self.from_derivation || Store::is_valid_path(target) {
+                canonic
+            } else {
+                path::absolute(target)
+                    .map_err(|e| e.to_string())?
+            };
 
-        let gc_parent = match env::var("USER") {
-            Ok(user) => PathBuf::from(format!("/nix/var/nix/gcroots/per-user/{}", user)),
-            Err(_) => PathBuf::from("/nix/var/nix/gcroots"),
+            let gc_parent = GCRoot::user_dir();
+            let full_gc_path = match &self.name {
+                Some(n) => gc_parent.join(n),
+                None => free_root_name(&gc_parent, &default_name, &root_target),
+            };
+            (root_target, full_gc_path)
         };
 
-        let full_gc_path = match self.name {
-            Some(n) => gc_parent.join(n),
-            None => {
-                let mut count = 0;
-                while gc_parent.join(format!("gcroot-{}", count)).is_symlink() {
-                    count += 1;
-                }
-                gc_parent.join(format!("gcroot-{}", count))
-            },
-        };
+        // registering the same root again just replaces the existing link
+        if (self.indirect || self.name.is_none()) && full_gc_path.is_symlink() {
+            fs::remove_file(&full_gc_path)
+                .map_err(|e| e.to_string())?;
+        }
 
         unix::fs::symlink(&root_target, &full_gc_path)
             .map_err(|e| e.to_string())?;
@@ -71,3 +149,31 @@ impl Command for AddRootCommand {
         Ok(())
     }
 }
+
+/// Realise a derivation, returning its first output
+fn realise(drv: &Path) -> Result<PathBuf, String> {
+    if !StorePath::new(drv.to_path_buf())?.is_drv() {
+        return Err("Target is not a derivation".to_owned());
+    }
+
+    let outputs = backend().realise(drv)?;
+    if outputs.len() > 1 {
+        warn(&format!("Derivation has {} outputs, only adding a root for the first one", outputs.len()));
+    }
+    outputs.into_iter()
+        .next()
+        .ok_or("Derivation has no outputs".to_owned())
+}
+
+/// First path for a root named `name` in `dir` that is not taken by a root pointing elsewhere
+fn free_root_name(dir: &Path, name: &str, target: &Path) -> PathBuf {
+    let taken = |p: &Path| p.symlink_metadata().is_ok() && fs::read_link(p).ok().as_deref() != Some(target);
+
+    let mut candidate = dir.join(name);
+    let mut i = 1;
+    while taken(&candidate) {
+        candidate = dir.join(format!("{name}-{i}"));
+        i += 1;
+    }
+    candidate
+}
@@ -1,19 +1,38 @@
 use std::cmp::{self, Reverse};
 use std::io;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 use colored::Colorize;
 use rayon::slice::ParallelSliceMut;
+use serde::Serialize;
 
 use crate::utils::terminal::terminal_width;
-use crate::utils::{files, journal};
+use crate::utils::{files, journal, progress};
+use crate::utils::progress::Event;
 use crate::utils::fmt::*;
-use crate::utils::interaction::{announce, resolve};
+use crate::config::{self, ConfigPreset};
+use crate::state::State;
+use crate::utils::interaction::{announce, ask, exit_threshold_exceeded, resolve, warn};
 use crate::utils::journal::*;
-use crate::nix::profiles::Profile;
+use crate::nix::backend::backend;
+use crate::nix::builds::Leftover;
+use crate::nix::database;
+use crate::nix::offline;
+use crate::nix::sandbox;
+use crate::nix::profiles::{OrphanedUserDir, Profile};
 use crate::nix::roots::GCRoot;
+use crate::nix::settings::{self, AutoGC};
 use crate::nix::store::{Store, StorePath, NIX_STORE};
 
+use super::gc::GIB;
+
+
+const FORECAST_WINDOW: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+const FORECAST_MIN_SPAN: Duration = Duration::from_secs(60 * 60);
+const MEASUREMENT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const BUILD_LEFTOVER_MIN_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
 
 #[derive(clap::Args)]
 pub struct AnalyzeCommand {
@@ -42,9 +61,58 @@ pub struct AnalyzeCommand {
     drv_closures: bool,
 
 
+    /// Don't record the store size and skip the usage forecast
+    #[clap(long)]
+    no_forecast: bool,
+
+    /// Only print the profiles and gc roots as tsv
+    #[clap(long, conflicts_with = "json")]
+    tsv: bool,
+
+    /// Only print the profiles and gc roots as json
+    #[clap(long)]
+    json: bool,
+
     /// Show n gc-roots and profiles
     #[clap(long, default_value_t = 5)]
     show: usize,
+
+    /// Offer to delete build directories left behind in /tmp (nix-build-*)
+    ///
+    /// Only directories in which nothing has been modified for a day are removed, as younger ones may
+    /// still belong to a running build. Home directories of the build users are only reported.
+    #[clap(long, conflicts_with_all = ["tsv", "json"])]
+    remove_build_leftovers: bool,
+
+    /// Leave out profiles matching this name or path pattern (can be given multiple times)
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Exit with code 2 if the store is larger than this (e.g. "50GiB" or "80%" of its device)
+    ///
+    /// The store size is measured the same way as for the thresholds of garbage collection, so
+    /// this can be used as a disk pressure check for monitoring.
+    #[clap(long, value_name = "SIZE|PERCENT", value_parser = StoreThreshold::parse)]
+    fail_if_store_above: Option<StoreThreshold>,
+
+    /// Nix database to read paths and references from (usually /nix/var/nix/db/db.sqlite)
+    ///
+    /// With --offline, only a copied store is analyzed using a copy of its Nix database, which works
+    /// without a running Nix daemon, e.g. for disk images, backups or broken systems. Profiles and
+    /// gc roots are not analyzed in this mode.
+    #[clap(long, requires = "store", conflicts_with_all = ["dead", "tsv", "json"])]
+    db: Option<PathBuf>,
+
+    /// Directory containing the store paths referenced by the database
+    #[clap(long, requires = "db")]
+    store: Option<PathBuf>,
+}
+
+/// Limit for the size of the store, either absolute or relative to its device
+#[derive(Clone, Copy, Debug)]
+enum StoreThreshold {
+    Size(u64),
+    Percent(u64),
 }
 
 struct StoreAnalysis {
@@ -55,13 +123,21 @@ struct StoreAnalysis {
     drv_size: u64,
     journal_size: Option<u64>,
     blkdev_info: Option<(String, u64)>,
+    mount_info: Option<files::MountInfo>,
+    fs_usage: Option<(u64, u64, u64)>,
+    last_gc: Option<(SystemTime, usize, u64)>,
+    last_cleanout: Option<SystemTime>,
+    auto_gc: Option<AutoGC>,
+    offline: bool,
     dead_info: Option<(usize, u64)>,
+    build_leftovers: Vec<Leftover>,
     drv_closure_info: Option<(usize, u64)>,
 }
 
 struct ProfileAnalysis {
     profiles: Vec<(PathBuf, Option<Profile>, Option<u64>)>,
     drained: usize,
+    orphaned: Vec<(OrphanedUserDir, Option<u64>)>,
 }
 
 struct GCRootsAnalysis {
@@ -69,10 +145,42 @@ struct GCRootsAnalysis {
     drained: usize,
 }
 
+struct ForecastAnalysis {
+    nmeasurements: usize,
+    span: Duration,
+    growth_per_day: Option<f64>,
+    store_size: u64,
+    fs_size: u64,
+    available: u64,
+    quota: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct JsonReport {
+    store_size: u64,
+    profiles: Vec<JsonProfile>,
+    gc_roots: Vec<JsonGCRoot>,
+}
+
+#[derive(Serialize)]
+struct JsonProfile {
+    path: String,
+    size: Option<u64>,
+    percentage: Option<f64>,
+    generations: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct JsonGCRoot {
+    path: String,
+    size: Option<u64>,
+    percentage: Option<f64>,
+}
+
 
 
 impl StoreAnalysis {
-    fn create(journal: bool, dead: bool, drv_closures: bool) -> Result<Self, String> {
+    fn create(journal: bool, dead: bool, drv_closures: bool, offline: bool) -> Result<Self, String> {
         let store_paths = Store::all_paths()?;
         let nstore_paths = store_paths.len();
         let drv_paths: Vec<_> = store_paths.into_iter().filter(StorePath::is_drv).collect();
@@ -83,6 +191,7 @@ impl StoreAnalysis {
         let mut drv_size = 0;
         let mut journal_size = None;
         let mut dead_info = None;
+        let mut build_leftovers = Vec::new();
         let mut drv_closure_info = None;
 
         rayon::scope(|s| {
@@ -116,6 +225,13 @@ impl StoreAnalysis {
                 });
             }
 
+            // leftovers of a copied store are not on this system
+            if !offline {
+                s.spawn(|_| {
+                    build_leftovers = Leftover::find();
+                });
+            }
+
             if dead {
                 s.spawn(|_| {
                     let dead_paths = resolve(Store::paths_dead());
@@ -125,15 +241,21 @@ impl StoreAnalysis {
             }
         });
 
-        let blkdev_info = Store::blkdev()
-            .and_then(|d| files::get_blkdev_size(&d).map(|s| (d, s)))
-            .ok();
+        let blkdev_info = Store::device().ok();
+        let mount_info = files::mount_of_path(Store::dir()).ok();
+        let fs_usage = files::fs_usage(Store::dir()).ok();
+        // the local state says nothing about a copied store
+        let state = if offline { None } else { State::load().ok() };
+        let last_gc = state.as_ref().and_then(|s| s.last_gc());
+        let last_cleanout = state.as_ref().and_then(|s| s.last_cleanout());
+        let auto_gc = if offline { None } else { settings::auto_gc() };
 
         Ok(StoreAnalysis {
             nstore_paths, store_size_naive, store_size_hl,
             ndrv_paths, drv_size,
-            blkdev_info, drv_closure_info, dead_info,
-            journal_size,
+            blkdev_info, mount_info, fs_usage, last_gc, last_cleanout, drv_closure_info, dead_info,
+            build_leftovers,
+            journal_size, auto_gc, offline,
         })
     }
 
@@ -145,19 +267,40 @@ impl StoreAnalysis {
         self.store_size_naive - self.store_size_hl
     }
 
+    /// Warn if the thresholds of the default preset are preempted by the daemon's auto gc
+    fn check_auto_gc(&self, auto_gc: &AutoGC) {
+        let preset = match ConfigPreset::load(config::DEFAULT_PRESET, None) {
+            Ok(preset) => preset,
+            Err(_) => return,
+        };
+        let available = match self.fs_usage {
+            Some((_, _, available)) => available,
+            None => return,
+        };
+
+        let mut thresholds = Vec::new();
+        if let Some(bigger) = preset.gc_bigger {
+            thresholds.push(bigger * GIB);
+        }
+        if let (Some(quota), Some((_, dev_size))) = (preset.gc_quota, &self.blkdev_info) {
+            thresholds.push(quota * dev_size / 100);
+        }
+        auto_gc.warn_conflicts(&thresholds, self.store_size(), available);
+    }
+
     fn report(&self) -> Result<(), String> {
         announce("System:");
 
         print!("{:<20} {}", format!("{}:", NIX_STORE), FmtSize::new(self.store_size()).left_pad().yellow());
         if let Some((dev, dev_size)) = &self.blkdev_info {
             let percent_str = FmtPercentage::new(self.store_size(), *dev_size).left_pad();
-            println!("\t({} of {} [{}])", percent_str, dev, size::Size::from_bytes(*dev_size));
+            println!("\t({} of {} [{}])", percent_str, dev, FmtSize::new(*dev_size));
         } else {
             println!();
         }
 
         if let Some(journal_size) = self.journal_size {
-            print!("{:<20} {:>11}", format!("{}:", JOURNAL_PATH), FmtSize::new(journal_size).left_pad().yellow());
+            print!("{:<20} {}", format!("{}:", JOURNAL_PATH), FmtSize::new(journal_size).left_pad().yellow());
 
             if let Some((dev, size)) = &self.blkdev_info {
                 let percent_str = FmtPercentage::new(journal_size, *size).left_pad();
@@ -167,14 +310,59 @@ impl StoreAnalysis {
             }
         }
 
+        if let Some(mount) = &self.mount_info {
+            let dedicated = if mount.mount_point.starts_with("/nix") {
+                "dedicated".green()
+            } else {
+                "shared".yellow()
+            };
+            println!("{:<20} {} ({} on {}, {})", "Mount point:",
+                mount.mount_point.to_string_lossy(), mount.fs_type, mount.device, dedicated);
+        }
+        if let Some((total, used, available)) = self.fs_usage {
+            println!("{:<20} {} total, {} used, {} free", "File system:",
+                FmtSize::new(total).to_string().yellow(),
+                FmtSize::new(used).to_string().yellow(),
+                FmtSize::new(available).to_string().green());
+        }
+
+        let since = |time: SystemTime| SystemTime::now().duration_since(time).unwrap_or_default();
+        if self.offline {
+            println!("{:<20} {}", "Store:", Store::dir().to_string_lossy());
+        } else {
+            match self.last_gc {
+                Some((time, deleted, freed)) => println!("{:<20} {} ago, {} paths deleted, freed {}", "Last GC:",
+                    FmtAge::new(since(time)).to_string().bright_blue(), deleted, FmtSize::new(freed).to_string().green()),
+                None => println!("{:<20} {}", "Last GC:", "never recorded".bright_black()),
+            }
+            match self.last_cleanout {
+                Some(time) => println!("{:<20} {} ago", "Last cleanout:", FmtAge::new(since(time)).to_string().bright_blue()),
+                None => println!("{:<20} {}", "Last cleanout:", "never recorded".bright_black()),
+            }
+        }
+
+        if let Some(auto_gc) = self.auto_gc {
+            if !auto_gc.enabled() {
+                println!("{:<20} {}", "Daemon auto GC:", "disabled".bright_black());
+            } else if auto_gc.max_free == u64::MAX {
+                println!("{:<20} below {} free, collecting all garbage", "Daemon auto GC:",
+                    FmtSize::new(auto_gc.min_free).to_string().yellow());
+            } else {
+                println!("{:<20} below {} free, up to {} free", "Daemon auto GC:",
+                    FmtSize::new(auto_gc.min_free).to_string().yellow(),
+                    FmtSize::new(auto_gc.max_free).to_string().green());
+            }
+            self.check_auto_gc(&auto_gc);
+        }
+
         let mut max_metric_len = 0;
-        max_metric_len = cmp::max(max_metric_len, self.nstore_paths.to_string().len());
-        max_metric_len = cmp::max(max_metric_len, self.ndrv_paths.to_string().len());
+        max_metric_len = cmp::max(max_metric_len, FmtNumber::new(self.nstore_paths).to_string().chars().count());
+        max_metric_len = cmp::max(max_metric_len, FmtNumber::new(self.ndrv_paths).to_string().chars().count());
         if let Some((ndrv_closure, _)) = self.drv_closure_info {
-            max_metric_len = cmp::max(max_metric_len, ndrv_closure.to_string().len());
+            max_metric_len = cmp::max(max_metric_len, FmtNumber::new(ndrv_closure).to_string().chars().count());
         }
         if let Some((ndead, _)) = self.drv_closure_info {
-            max_metric_len = cmp::max(max_metric_len, ndead.to_string().len());
+            max_metric_len = cmp::max(max_metric_len, FmtNumber::new(ndead).to_string().chars().count());
         }
         if self.store_size_naive > self.store_size_hl {
             max_metric_len = cmp::max(max_metric_len, FmtSize::new(self.hardlinking_savings()).to_string().len());
@@ -185,13 +373,13 @@ impl StoreAnalysis {
         println!();
         println!("{:<desc_width$}  {:>metric_width$}",
             "Number of store paths:",
-            self.nstore_paths.to_string().bright_blue(),
+            FmtNumber::new(self.nstore_paths).to_string().bright_blue(),
             desc_width = max_desc_len,
             metric_width = max_metric_len,
         );
         println!("{:<desc_width$}  {:>metric_width$}\t{} {}",
             "Derivation files (*.drv) in store:",
-            self.ndrv_paths.to_string().cyan(),
+            FmtNumber::new(self.ndrv_paths).to_string().cyan(),
             FmtSize::new(self.drv_size).left_pad().cyan(),
             FmtPercentage::new(self.drv_size, self.store_size_hl).bracketed().left_pad().cyan(),
             desc_width = max_desc_len,
@@ -200,7 +388,7 @@ impl StoreAnalysis {
         if let Some((ndrv_closure, drv_closure_size)) = self.drv_closure_info {
             println!("{:<desc_width$}  {:>metric_width$}\t{} {}",
                 "Closure of *.drv files in store:",
-                ndrv_closure.to_string().bright_cyan(),
+                FmtNumber::new(ndrv_closure).to_string().bright_cyan(),
                 FmtSize::new(drv_closure_size).left_pad().bright_cyan(),
                 FmtPercentage::new(drv_closure_size, self.store_size_hl).bracketed().left_pad().bright_cyan(),
                 desc_width = max_desc_len,
@@ -210,7 +398,7 @@ impl StoreAnalysis {
         if let Some((ndead, dead_size)) = self.dead_info {
             println!("{:<desc_width$}  {:>metric_width$}\t{} {}",
                 "Dead paths (collectable garbage):",
-                ndead.to_string().magenta(),
+                FmtNumber::new(ndead).to_string().magenta(),
                 FmtSize::new(dead_size).left_pad().magenta(),
                 FmtPercentage::new(dead_size, self.store_size_hl).bracketed().left_pad().magenta(),
                 desc_width = max_desc_len,
@@ -218,6 +406,17 @@ impl StoreAnalysis {
             );
         }
 
+        if !self.build_leftovers.is_empty() {
+            let size: u64 = self.build_leftovers.iter().map(|l| l.size()).sum();
+            println!("{:<desc_width$}  {:>metric_width$}\t{}",
+                "Build leftovers (outside store):",
+                self.build_leftovers.len().to_string().red(),
+                FmtSize::new(size).left_pad().red(),
+                desc_width = max_desc_len,
+                metric_width = max_metric_len,
+            );
+        }
+
         println!();
         if self.store_size_naive > self.store_size_hl {
             println!("{:<desc_width$}  {:>metric_width$}",
@@ -241,8 +440,8 @@ impl StoreAnalysis {
 }
 
 impl ProfileAnalysis {
-    fn create(all: bool, show: usize) -> Result<Self, String> {
-        let profile_paths = GCRoot::profile_paths()?;
+    fn create(all: bool, show: usize, exclude: &[String]) -> Result<Self, String> {
+        let profile_paths = Profile::discover(exclude)?;
 
         let mut profiles = Vec::with_capacity(profile_paths.len());
         for path in profile_paths {
@@ -261,7 +460,15 @@ impl ProfileAnalysis {
             0
         };
 
-        Ok(ProfileAnalysis { profiles, drained })
+        let orphaned = OrphanedUserDir::find()?
+            .into_iter()
+            .map(|o| {
+                let size = o.closure_size().ok();
+                (o, size)
+            })
+            .collect();
+
+        Ok(ProfileAnalysis { profiles, drained, orphaned })
     }
 
     fn report(&self, full_paths: bool, store_size: u64) -> Result<(), String> {
@@ -300,8 +507,41 @@ impl ProfileAnalysis {
             println!("...and {} more", self.drained);
         }
 
+        if !self.orphaned.is_empty() {
+            let size: u64 = self.orphaned.iter().flat_map(|(_, s)| s).sum();
+            let users: Vec<_> = self.orphaned.iter().map(|(o, _)| o.user()).collect();
+            println!("{} the profiles of {} removed users ({}) keep {} alive (see `nix-sweep tidyup-profiles`)",
+                "Note:".yellow(),
+                self.orphaned.len(),
+                users.join(", "),
+                FmtSize::new(size).to_string().yellow());
+        }
+
         Ok(())
     }
+
+    fn report_tsv(&self, store_size: u64) {
+        for (path, profile, size) in &self.profiles {
+            let size_str = size.map(|s| s.to_string()).unwrap_or(String::from("n/a"));
+            let percentage_str = size.map(|s| format!("{:.2}", percentage(s, store_size)))
+                .unwrap_or(String::from("n/a"));
+            let generations_str = profile.as_ref()
+                .map(|p| p.generations().len().to_string())
+                .unwrap_or(String::from("n/a"));
+            println!("profile\t{}\t{}\t{}\t{}", path.to_string_lossy(), size_str, percentage_str, generations_str);
+        }
+    }
+
+    fn to_json(&self, store_size: u64) -> Vec<JsonProfile> {
+        self.profiles.iter()
+            .map(|(path, profile, size)| JsonProfile {
+                path: path.to_string_lossy().to_string(),
+                size: *size,
+                percentage: size.map(|s| rounded_percentage(s, store_size)),
+                generations: profile.as_ref().map(|p| p.generations().len()),
+            })
+            .collect()
+    }
 }
 
 impl GCRootsAnalysis {
@@ -310,7 +550,7 @@ impl GCRootsAnalysis {
             .into_iter()
             .filter(|r| r.is_independent())
             .map(|r| match r.store_path().cloned() {
-                Ok(path) => (r, Some(path.closure_size())),
+                Ok(path) => (r, path.closure_size().ok()),
                 Err(_) => (r, None),
             })
             .collect();
@@ -360,20 +600,244 @@ impl GCRootsAnalysis {
         let roots: Vec<_> = self.gc_roots.iter()
             .map(|tup| tup.0.clone())
             .collect();
-        let total_size = GCRoot::full_closure_size(&roots)?;
-        let size_str = FmtSize::new(total_size).to_string();
-        let percentage_str = FmtPercentage::new(total_size, store_size)
-            .bracketed()
+        let total_size = match backend().is_offline() {
+            true => None,
+            false => Some(GCRoot::full_closure_size(&roots)?),
+        };
+        let size_str = FmtOrNA::mapped(total_size, FmtSize::new).to_string();
+        let percentage_str = FmtOrNA::mapped(total_size, |s| FmtPercentage::new(s, store_size).bracketed())
+            .or_empty()
             .left_pad();
         println!("Total closure size of independent gc roots:\t{} {}", size_str.yellow(), percentage_str);
 
         Ok(())
     }
+
+    fn report_tsv(&self, store_size: u64) {
+        for (root, size) in &self.gc_roots {
+            let size_str = size.map(|s| s.to_string()).unwrap_or(String::from("n/a"));
+            let percentage_str = size.map(|s| format!("{:.2}", percentage(s, store_size)))
+                .unwrap_or(String::from("n/a"));
+            println!("gc-root\t{}\t{}\t{}", root.link().to_string_lossy(), size_str, percentage_str);
+        }
+    }
+
+    fn to_json(&self, store_size: u64) -> Vec<JsonGCRoot> {
+        self.gc_roots.iter()
+            .map(|(root, size)| JsonGCRoot {
+                path: root.link().to_string_lossy().to_string(),
+                size: *size,
+                percentage: size.map(|s| rounded_percentage(s, store_size)),
+            })
+            .collect()
+    }
 }
 
+impl ForecastAnalysis {
+    fn create(store_size: u64) -> Result<Self, String> {
+        let (fs_size, available) = files::fs_space(Store::dir())?;
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // measurements taken shortly after each other add nothing to the forecast, so they are not kept
+        let mut state = State::load()?;
+        let due = state.store_sizes().last()
+            .is_none_or(|m| now.saturating_sub(m.time) >= MEASUREMENT_INTERVAL.as_secs());
+        state.record_store_size(store_size, available);
+        if due && let Err(e) = state.save() {
+            warn(&format!("Unable to record store size: {e}"));
+        }
+
+        let mut measurements: Vec<_> = state.store_sizes().iter()
+            .filter(|m| m.time + FORECAST_WINDOW.as_secs() >= now)
+            .collect();
+        measurements.sort_by_key(|m| m.time);
+        let nmeasurements = measurements.len();
+        let span = match (measurements.first(), measurements.last()) {
+            (Some(first), Some(last)) => Duration::from_secs(last.time.saturating_sub(first.time)),
+            _ => Duration::ZERO,
+        };
+
+        // least squares fit of store size over time
+        let growth_per_day = if nmeasurements >= 2 && span >= FORECAST_MIN_SPAN {
+            let n = nmeasurements as f64;
+            let t0 = measurements[0].time;
+            let mean_x = measurements.iter().map(|m| m.time.saturating_sub(t0) as f64).sum::<f64>() / n;
+            let mean_y = measurements.iter().map(|m| m.size as f64).sum::<f64>() / n;
+            let (cov, var) = measurements.iter()
+                .map(|m| (m.time.saturating_sub(t0) as f64 - mean_x, m.size as f64 - mean_y))
+                .fold((0.0, 0.0), |(cov, var), (dx, dy)| (cov + dx * dy, var + dx * dx));
+            Some(cov / var * 86400.0)
+        } else {
+            None
+        };
+
+        let quota = ConfigPreset::load(config::DEFAULT_PRESET, None).ok()
+            .and_then(|p| p.gc_quota);
+
+        Ok(ForecastAnalysis { nmeasurements, span, growth_per_day, store_size, fs_size, available, quota })
+    }
+
+    fn report(&self) -> Result<(), String> {
+        announce("Forecast:");
+
+        let growth = match self.growth_per_day {
+            Some(growth) => growth,
+            None => {
+                println!("Not enough measurements for a forecast yet ({} recorded, run analyze again later)", self.nmeasurements);
+                return Ok(());
+            },
+        };
+
+        let basis = format!("(based on {} measurements over {})", self.nmeasurements, FmtAge::new(self.span));
+        if growth <= 0.0 {
+            println!("{:<24} {} {}", "Store growth:", "none".green(), basis.bright_black());
+            return Ok(());
+        }
+
+        println!("{:<24} {} {}", "Store growth:", format!("+{}/day", FmtSize::new(growth as u64)).yellow(), basis.bright_black());
+
+        let days_until_full = self.available as f64 / growth;
+        println!("{:<24} {}", "Device full in:", format!("~{} days", days_until_full.round() as u64).red());
+
+        if let Some(quota) = self.quota {
+            let threshold = self.fs_size * quota / 100;
+            if self.store_size >= threshold {
+                println!("{:<24} {}", format!("Quota of {quota}%:"), "already exceeded".red());
+            } else {
+                let days = (threshold - self.store_size) as f64 / growth;
+                let date = Duration::try_from_secs_f64(days * 86400.0).ok()
+                    .and_then(|d| SystemTime::now().checked_add(d));
+                match date {
+                    Some(date) => println!("{:<24} {} ({})",
+                        format!("Quota of {quota}% in:"),
+                        format!("~{} days", days.round() as u64).yellow(),
+                        FmtDate::new(date)),
+                    None => println!("{:<24} {}", format!("Quota of {quota}% in:"), "no forecast".bright_black()),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl AnalyzeCommand {
+    /// Analyze the store and, unless a copy of the Nix database is given, the profiles and gc roots
+    /// as far as the file system tells
+    fn run_offline(&self) -> Result<(), String> {
+        match (&self.db, &self.store) {
+            (Some(db), Some(store)) => database::init(db, store)?,
+            _ if self.drv_closures => return Err("Closures of derivations can only be analyzed offline with --db and --store".to_owned()),
+            _ => (),
+        }
+        if self.remove_build_leftovers {
+            return Err("Build leftovers cannot be removed offline".to_owned());
+        }
+
+        eprintln!("Indexing store...");
+        progress::emit(Event::PhaseStart("index-store"));
+        let store_analysis = StoreAnalysis::create(false, false, self.drv_closures, true)?;
+        progress::emit(Event::PhaseEnd("index-store"));
+
+        // the profiles and gc roots on this system have nothing to do with a copied database
+        if self.db.is_some() {
+            store_analysis.report()?;
+            println!();
+            return self.check_store_size(&store_analysis);
+        }
+
+        eprintln!("Indexing profiles and gc roots...");
+        let profile_analysis = ProfileAnalysis::create(self.all, self.show, &self.exclude)?;
+        let gc_roots_analysis = GCRootsAnalysis::create(self.all, self.show)?;
+        self.report(&store_analysis, &profile_analysis, &gc_roots_analysis)
+    }
+
+    fn report(&self, store_analysis: &StoreAnalysis, profile_analysis: &ProfileAnalysis,
+            gc_roots_analysis: &GCRootsAnalysis) -> Result<(), String> {
+        let store_size = store_analysis.store_size();
+        if self.tsv {
+            profile_analysis.report_tsv(store_size);
+            gc_roots_analysis.report_tsv(store_size);
+            return self.check_store_size(store_analysis);
+        } else if self.json {
+            let report = JsonReport {
+                store_size,
+                profiles: profile_analysis.to_json(store_size),
+                gc_roots: gc_roots_analysis.to_json(store_size),
+            };
+            println!("{}", serde_json::to_string(&report).map_err(|e| e.to_string())?);
+            return self.check_store_size(store_analysis);
+        }
+
+        store_analysis.report()?;
+        profile_analysis.report(self.full_paths, store_size)?;
+        gc_roots_analysis.report(self.full_paths, store_size)?;
+
+        // the recorded sizes belong to the local store, not to the one below --store-root, and a
+        // recovery environment is no place to record them either
+        if !self.no_forecast && sandbox::root().is_none() && !offline::enabled() {
+            ForecastAnalysis::create(store_size)?.report()?;
+        }
+
+        if self.remove_build_leftovers {
+            remove_build_leftovers(&store_analysis.build_leftovers);
+        }
+
+        println!();
+        self.check_store_size(store_analysis)
+    }
+
+    /// Exit with [THRESHOLD_EXIT_CODE](crate::utils::interaction::THRESHOLD_EXIT_CODE) if the store
+    /// exceeds --fail-if-store-above
+    fn check_store_size(&self, store_analysis: &StoreAnalysis) -> Result<(), String> {
+        let threshold = match self.fail_if_store_above {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+
+        let size = store_analysis.store_size();
+        match threshold {
+            StoreThreshold::Size(limit) if size > limit => {
+                exit_threshold_exceeded(&format!("Store has a size of {} (limit: {})", FmtSize::new(size), FmtSize::new(limit)));
+            },
+            StoreThreshold::Percent(quota) => {
+                let (_, dev_size) = store_analysis.blkdev_info.as_ref()
+                    .ok_or("Unable to determine the size of the device containing the store")?;
+                if size > quota * dev_size / 100 {
+                    exit_threshold_exceeded(&format!("Store uses {} of its device (limit: {quota}%)",
+                        FmtPercentage::new(size, *dev_size)));
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl StoreThreshold {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s.trim().strip_suffix('%') {
+            Some(percent) => match percent.trim().parse() {
+                Ok(percent) if (1..100).contains(&percent) => Ok(StoreThreshold::Percent(percent)),
+                _ => Err(format!("Invalid percentage '{s}' - must be between 1% and 99%")),
+            },
+            None => config::parse_size(s).map(StoreThreshold::Size),
+        }
+    }
+}
 
 impl super::Command for AnalyzeCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        if offline::enabled() {
+            return self.run_offline();
+        } else if self.db.is_some() {
+            return Err("Analyzing a copy of the Nix database (--db) requires --offline".to_owned());
+        }
+
         let mut store_analysis = Err("Store indexing not completed yet".to_owned());
         let mut profile_analysis = Err("Profile indexing not completed yet".to_owned());
         let mut gc_roots_analysis = Err("Gc roots indexing not completed yet".to_owned());
@@ -381,31 +845,77 @@ impl super::Command for AnalyzeCommand {
         eprintln!("Indexing store, profiles and gc roots...");
         rayon::scope(|s| {
             s.spawn(|_| {
-                store_analysis = StoreAnalysis::create(!self.no_journal, self.dead, self.drv_closures);
+                progress::emit(Event::PhaseStart("index-store"));
+                store_analysis = StoreAnalysis::create(!self.no_journal, self.dead, self.drv_closures, false);
                 eprintln!("Finished store indexing");
+                progress::emit(Event::PhaseEnd("index-store"));
             });
 
             s.spawn(|_| {
-                profile_analysis = ProfileAnalysis::create(self.all, self.show);
+                progress::emit(Event::PhaseStart("index-profiles"));
+                profile_analysis = ProfileAnalysis::create(self.all, self.show, &self.exclude);
                 eprintln!("Finished profile indexing");
+                progress::emit(Event::PhaseEnd("index-profiles"));
             });
 
             s.spawn(|_| {
+                progress::emit(Event::PhaseStart("index-gc-roots"));
                 gc_roots_analysis = GCRootsAnalysis::create(self.all, self.show);
                 eprintln!("Finished gc roots indexing");
+                progress::emit(Event::PhaseEnd("index-gc-roots"));
             });
         });
 
-        let store_analysis = store_analysis?;
-        let profile_analysis = profile_analysis?;
-        let gc_roots_analysis = gc_roots_analysis?;
+        self.report(&store_analysis?, &profile_analysis?, &gc_roots_analysis?)
+    }
+}
 
+/// Offer to delete the build directories that are old enough not to belong to a running build
+fn remove_build_leftovers(leftovers: &[Leftover]) {
+    println!();
+    announce("Build leftovers:");
+    if leftovers.is_empty() {
+        println!("{}", "none found".bright_black());
+        return;
+    }
 
-        store_analysis.report()?;
-        profile_analysis.report(self.full_paths, store_analysis.store_size())?;
-        gc_roots_analysis.report(self.full_paths, store_analysis.store_size())?;
+    let mut removable = Vec::new();
+    for leftover in leftovers {
+        let note = if !leftover.is_build_dir() {
+            "home directory of a build user, not removed"
+        } else if leftover.age() < BUILD_LEFTOVER_MIN_AGE {
+            "recent, might belong to a running build"
+        } else {
+            removable.push(leftover);
+            ""
+        };
+        println!("{}\t{}\t{} old\t{}", leftover.path().to_string_lossy(),
+            FmtSize::new(leftover.size()).left_pad().yellow(),
+            FmtAge::new(leftover.age()),
+            note.bright_black());
+    }
 
-        println!();
-        Ok(())
+    let size: u64 = removable.iter().map(|l| l.size()).sum();
+    if removable.is_empty() || !ask(&format!("\nRemove {} build directories ({})?", removable.len(), FmtSize::new(size)), false) {
+        return;
     }
+    for leftover in removable {
+        match leftover.remove() {
+            Ok(()) => println!("-> Removed {}", leftover.path().to_string_lossy()),
+            Err(e) => warn(&e),
+        }
+    }
+}
+
+fn percentage(amount: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        amount as f64 * 100.0 / total as f64
+    }
+}
+
+/// Percentage rounded to two decimal places, as in the tsv output
+fn rounded_percentage(amount: u64, total: u64) -> f64 {
+    (percentage(amount, total) * 100.0).round() / 100.0
 }
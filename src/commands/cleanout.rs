@@ -1,12 +1,22 @@
 use std::path;
-use std::str::FromStr;
+use std::time::Instant;
 
 use colored::Colorize;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::config::{self, ConfigPreset};
+use crate::job::{self, Job};
 use crate::utils::interaction::*;
-use crate::utils::fmt::FmtAge;
-use crate::nix::profiles::Profile;
+use crate::utils::fmt::*;
+use crate::nix::profiles::{Profile, RemovalBackend};
+use crate::nix::backend::GCResult;
+use crate::nix::planner;
+use crate::state::State;
+use crate::utils::{privileges, progress, signals};
+use crate::utils::hooks::{self, Hook};
+use crate::utils::snapshots;
+use crate::utils::progress::Event;
+use crate::utils::users::User;
 
 use super::gc::GCCommand;
 
@@ -32,60 +42,658 @@ pub struct CleanoutCommand {
     #[clap(long)]
     no_size: bool,
 
-    /// Profiles to clean out; valid values: system, user, home, <path_to_profile>
+    /// How to remove generations
+    ///
+    /// The unlink backend deletes the generation symlinks directly, which may help if nix-env refuses
+    /// to operate on the profile. In either case the store paths are only freed by the next garbage collection.
+    #[clap(long, value_enum, default_value_t)]
+    removal_backend: RemovalBackend,
+
+    /// Show a single list of marked generations for all profiles and ask only once
+    #[clap(long)]
+    combined: bool,
+
+    /// Show the packages that changed between the newest marked and the oldest kept generation
+    ///
+    /// This is shown before asking for confirmation, so you can see what the history that is about
+    /// to be removed contains.
+    #[clap(long)]
+    diff: bool,
+
+    /// Adjust the marked generations of a dry run interactively and optionally remove them afterwards
+    ///
+    /// Once generations are removed, the rest of the run is no longer a dry run either, so garbage
+    /// collection is performed as configured.
+    #[clap(long, requires = "dry_run", conflicts_with = "combined")]
+    browse: bool,
+
+    /// Resolve the user and home profiles of this user instead of the current one
+    ///
+    /// Only root may operate on the profiles of other users.
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Continue with the remaining profiles if some of them cannot be found
+    #[clap(long)]
+    skip_missing: bool,
+
+    /// Leave out profiles matching this name or path pattern (can be given multiple times)
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Profiles to clean out; valid values: system, user, home, <path_to_profile>, <pattern>
     #[clap(required = true)]
     profiles: Vec<String>,
 }
 
+impl CleanoutCommand {
+    /// Non-interactive clean out based on a preset, without running garbage collection
+    pub fn new(preset: String, config: Option<path::PathBuf>, profiles: Vec<String>) -> Self {
+        let cleanout_config = ConfigPreset {
+            keep_min: None,
+            interactive: Some(false),
+            gc: Some(false),
+            ..ConfigPreset::default()
+        };
+        CleanoutCommand { preset, config, cleanout_config, profiles, dry_run: false, no_size: true, user: None, combined: false,
+            skip_missing: false, removal_backend: RemovalBackend::default(), diff: false, browse: false, exclude: Vec::new() }
+    }
+
+    /// Clean out based on a preset, asking for confirmation as configured there
+    ///
+    /// Profiles that do not exist are skipped.
+    pub fn with_preset(preset: String, config: Option<path::PathBuf>, profiles: Vec<String>) -> Self {
+        let cleanout_config = ConfigPreset {
+            keep_min: None,
+            ..ConfigPreset::default()
+        };
+        CleanoutCommand { preset, config, cleanout_config, profiles, dry_run: false, no_size: false, user: None, combined: false,
+            skip_missing: true, removal_backend: RemovalBackend::default(), diff: false, browse: false, exclude: Vec::new() }
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Run garbage collection afterwards, regardless of the preset
+    pub fn gc(mut self, gc: bool) -> Self {
+        self.cleanout_config.gc = Some(gc);
+        self
+    }
+}
+
 impl super::Command for CleanoutCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(mut self, ctx: &super::Context) -> Result<(), String> {
+        let start = Instant::now();
+        self.no_size = ctx.skip_sizes(self.no_size);
         self.cleanout_config.validate()?;
         let config = ConfigPreset::load(&self.preset, self.config.as_ref())?
             .override_with(&self.cleanout_config);
+        config.validate_gc()?;
         let interactive = config.interactive.is_none() || config.interactive == Some(true);
+        if self.browse && config.defer.is_some() {
+            return Err("--browse cannot be combined with deferred removal".to_owned());
+        }
+        set_age_thresholds(config.age_fresh, config.age_stale);
+        let mut state = State::load()?;
+
+        let user = self.user.as_deref().map(User::select).transpose()?;
+
+        let mut profiles = Profile::resolve_args(&self.profiles, user.as_ref(), self.skip_missing, &self.exclude)?;
+        if !self.no_size || config.remove_larger_than.is_some() || config.free_at_least.is_some() {
+            ctx.prefetch_profiles(&profiles);
+        }
+        for profile in profiles.iter_mut() {
+            profile.apply_markers(&config)?;
+            profile.apply_pending(&state);
+            if let Some(defer) = config.defer {
+                profile.apply_deferral(defer, &mut state);
+            }
+        }
+
+        // remove the generations freeing the most first, but only as many as needed
+        if let Some(target) = config.free_at_least {
+            let plan = planner::free_at_least(&mut profiles, target)?;
+            if plan.expected < target {
+                warn(&format!("Removing all {} marked generations is expected to free only {} (less than {})",
+                    plan.candidates, FmtSize::new(plan.expected), FmtSize::new(target)));
+            } else {
+                info(&format!("Removing {} of {} marked generations is expected to free {} (at least {})",
+                    plan.selected, plan.candidates, FmtSize::new(plan.expected), FmtSize::new(target)));
+            }
+        }
 
-        for profile_str in self.profiles {
-            let mut profile = Profile::from_str(&profile_str)?;
-            profile.apply_markers(&config);
+        // continue an interrupted run without asking for what has been decided already
+        let mut job = (!self.dry_run || (self.browse && job::resuming())).then(Job::begin);
+        if let Some(job) = &job {
+            profiles.retain(|p| {
+                let done = job.is_done(&p.path());
+                if done {
+                    info(&format!("-> Skipping profile {} (done before the interruption)", p.path().to_string_lossy()));
+                }
+                !done
+            });
+            for profile in profiles.iter_mut() {
+                if let Some(numbers) = job.confirmed(&profile.path()) {
+                    profile.clear_markers();
+                    for number in numbers {
+                        // generations removed before the interruption are gone
+                        let _ = profile.toggle_marker(*number);
+                    }
+                }
+            }
+        }
+        let is_confirmed = |job: &Option<Job>, profile: &Profile| job.as_ref()
+            .is_some_and(|j| j.confirmed(&profile.path()).is_some());
+
+        // find out what requires root before removing anything
+        if !self.dry_run {
+            check_privileges(&profiles, config.gc == Some(true))?;
+        }
 
-            profile.list_generations(!self.no_size, true);
+        progress::emit(Event::PhaseStart("cleanout"));
+        let mut summary = RunSummary {
+            deadline: config.timeout.map(|t| start + t),
+            ..RunSummary::default()
+        };
+        if self.combined {
+            list_combined(&profiles, !self.no_size);
+            if self.diff {
+                for profile in profiles.iter().filter(|p| p.count_marked() > 0) {
+                    list_changes(profile, true);
+                }
+            }
+            let nmarked: usize = profiles.iter().map(|p| p.count_marked()).sum();
 
             if self.dry_run {
                 conclusion("Skipping generation removal (dry run)");
-            } else if profile.count_marked() == 0 {
+            } else if nmarked == 0 {
                 conclusion("Nothing to do");
-            } else if interactive {
-                let confirmation = ask("Do you want to delete the marked generations?", false);
-                if confirmation {
-                    remove_generations(&profile);
-                } else {
+            } else if interactive
+                    && !profiles.iter().filter(|p| p.count_marked() > 0).all(|p| is_confirmed(&job, p))
+                    && !ask("Do you want to delete the marked generations?", false) {
+                conclusion("Not touching profiles\n");
+            } else {
+                if let Some(job) = &mut job {
+                    for profile in profiles.iter().filter(|p| p.count_marked() > 0) {
+                        job.confirm(&profile.path(), profile.marked_numbers())?;
+                    }
+                }
+                for profile in profiles.iter().filter(|p| p.count_marked() > 0) {
+                    self.remove(profile, &config, &mut summary)?;
+                    if let Some(job) = &mut job && summary.is_complete(profile) {
+                        job.done(&profile.path())?;
+                    }
+                }
+            }
+        } else {
+            for profile in profiles.iter_mut() {
+                if signals::interrupted() {
+                    break;
+                }
+                profile.list_generations(!self.no_size, false, true, false);
+                if self.diff {
+                    list_changes(profile, false);
+                }
+
+                if self.browse {
+                    self.browse(profile, &config, &mut job, &mut summary)?;
+                } else if self.dry_run {
+                    if !self.no_size {
+                        summary.expected_garbage += profile.marked_only_size();
+                    }
+                    conclusion("Skipping generation removal (dry run)");
+                } else if profile.count_marked() == 0 {
+                    conclusion("Nothing to do");
+                } else if interactive && !is_confirmed(&job, profile)
+                        && !ask("Do you want to delete the marked generations?", false) {
                     conclusion("Not touching profile\n");
+                } else {
+                    if let Some(job) = &mut job {
+                        job.confirm(&profile.path(), profile.marked_numbers())?;
+                    }
+                    self.remove(profile, &config, &mut summary)?;
+                }
+
+                if let Some(job) = &mut job && summary.is_complete(profile) {
+                    job.done(&profile.path())?;
                 }
-            } else {
-                remove_generations(&profile);
             }
         }
 
-        if config.gc == Some(true) {
-            let gc_cmd = GCCommand::new(interactive, self.dry_run, config.gc_bigger, config.gc_quota, config.gc_modest);
-            gc_cmd.run()?;
+        progress::emit(Event::PhaseEnd("cleanout"));
+
+        // removing generations while browsing turns the rest of a dry run into a real one
+        let dry_run = self.dry_run && !summary.went_live;
+        let removed_any = !summary.removed.is_empty();
+        let nremoved: usize = summary.removed.iter().map(|(_, count)| count).sum();
+        if nremoved > 0 {
+            state.record_cleanout(nremoved);
+        }
+        // the state also tracks deferred generations, so it is saved even if nothing was removed
+        if !dry_run && let Err(e) = state.save() {
+            warn(&format!("Unable to record clean out: {e}"));
+        }
+
+        if config.gc == Some(true) && signals::interrupted() {
+            info("\n-> Skipping garbage collection (cancelled)");
+        } else if config.gc == Some(true) && !dry_run && summary.timed_out() {
+            info("\n-> Skipping garbage collection (time budget used up)");
+        } else if config.gc == Some(true) {
+            if !dry_run {
+                summary.ensure_snapshot(&config)?;
+            }
+            let timeout = summary.deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let gc_cmd = GCCommand::new(interactive, dry_run, config.gc_bigger, config.gc_quota, config.gc_modest, timeout)
+                .with_pending_garbage(if dry_run { summary.expected_garbage } else { 0 });
+            summary.gc = gc_cmd.collect()?;
+        }
+
+        if !dry_run || removed_any {
+            summary.report(!self.no_size);
+        }
+
+        // keep the job around for `nix-sweep resume` if not everything was done
+        if let Some(job) = job
+                && summary.remaining.is_empty() && !signals::interrupted() {
+            job.finish()?;
         }
 
         Ok(())
     }
 }
 
-fn remove_generations(profile: &Profile) {
+/// Make sure everything that requires root can be done, before anything is removed
+fn check_privileges(profiles: &[Profile], gc: bool) -> Result<(), String> {
+    let mut privileged: Vec<_> = profiles.iter()
+        .filter(|p| p.count_marked() > 0)
+        .filter(|p| !p.path().parent().is_some_and(privileges::can_write))
+        .map(|p| format!("Removing generations of {}", p.path().to_string_lossy()))
+        .collect();
+    if gc && privileges::store_needs_root() {
+        privileged.push("Garbage collection".to_owned());
+    }
+    privileges::check(&privileged)
+}
+
+impl CleanoutCommand {
+    /// Remove the marked generations of a profile, running the configured hooks around it
+    fn remove(&self, profile: &Profile, config: &ConfigPreset, summary: &mut RunSummary) -> Result<(), String> {
+        let reason = match (signals::interrupted(), summary.timed_out()) {
+            (true, _) => "cancelled",
+            (_, true) => "time budget used up",
+            _ => "",
+        };
+        if !reason.is_empty() {
+            warn(&format!("Not removing generations of {} ({reason})", profile.path().to_string_lossy()));
+            summary.remaining.push((profile.path().to_string_lossy().to_string(), profile.count_marked()));
+            return Ok(());
+        }
+
+        // an interruption only takes effect once the generation being removed is gone
+        signals::deferred(|| {
+            summary.ensure_snapshot(config)?;
+            hooks::run(Hook::PreCleanout, config, profile)?;
+            summary.record(profile, !self.no_size);
+            let remaining = remove_generations(profile, self.removal_backend, summary.deadline);
+            summary.record_remaining(remaining);
+            hooks::run(Hook::PostCleanout, config, profile)
+        })
+    }
+}
+
+impl CleanoutCommand {
+    /// Let the user adjust the markers of a profile until they remove the marked generations or quit
+    fn browse(&self, profile: &mut Profile, config: &ConfigPreset, job: &mut Option<Job>,
+            summary: &mut RunSummary) -> Result<(), String> {
+        let mut config = config.clone();
+        // generations the user explicitly marked (true) or unmarked (false)
+        let mut overrides: Vec<(usize, bool)> = Vec::new();
+
+        loop {
+            let input = prompt("Adjust markers ([+/-] keep-max, [t <generation>] toggle, [r]emove, [q]uit):");
+            let tokens: Vec<_> = input.split_whitespace().collect();
+            match tokens.as_slice() {
+                ["+"] | ["-"] => {
+                    let current = config.keep_max.unwrap_or(profile.generations().len());
+                    let keep_max = if tokens[0] == "+" { current + 1 } else { current.saturating_sub(1).max(1) };
+                    config.keep_max = Some(keep_max);
+                    info(&format!("Keeping at most {keep_max} generations"));
+                },
+                ["t", number] => match number.parse::<usize>() {
+                    Ok(number) => match profile.toggle_marker(number) {
+                        Ok(()) => {
+                            let marked = profile.marked_numbers().contains(&number);
+                            overrides.retain(|(n, _)| *n != number);
+                            overrides.push((number, marked));
+                        },
+                        Err(e) => { warn(&e); continue; },
+                    },
+                    Err(_) => { warn(&format!("Invalid generation number '{number}'")); continue; },
+                },
+                ["r"] => {
+                    if profile.count_marked() == 0 {
+                        conclusion("Nothing to do");
+                    } else if ask("Do you want to delete the marked generations?", false) {
+                        // the checks of a real run were skipped, as this started out as a dry run
+                        let gc = config.gc == Some(true) && !summary.went_live;
+                        check_privileges(std::slice::from_ref(profile), gc)?;
+                        let job = job.get_or_insert_with(Job::begin);
+                        job.confirm(&profile.path(), profile.marked_numbers())?;
+                        summary.went_live = true;
+                        self.remove(profile, &config, summary)?;
+                    } else {
+                        conclusion("Not touching profile\n");
+                    }
+                    return Ok(());
+                },
+                [] | ["q"] => {
+                    conclusion("Skipping generation removal (dry run)");
+                    return Ok(());
+                },
+                _ => { warn(&format!("Unknown command '{input}'")); continue; },
+            }
+
+            // re-evaluate the criteria and apply the manual changes on top
+            profile.clear_markers();
+            profile.apply_markers(&config)?;
+            overrides.retain(|(number, marked)| match profile.set_marker(*number, *marked) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn(&format!("Dropping manual change: {e}"));
+                    false
+                },
+            });
+            profile.list_generations(!self.no_size, false, true, false);
+            if self.diff {
+                list_changes(profile, false);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct RunSummary {
+    /// Whether generations were removed while browsing a dry run
+    went_live: bool,
+    removed: Vec<(String, usize)>,
+    expected_garbage: u64,
+    last_expected: u64,
+    gc: Option<GCResult>,
+    snapshot: Option<String>,
+    deadline: Option<Instant>,
+    remaining: Vec<(String, usize)>,
+}
+
+impl RunSummary {
+    /// Create the configured snapshot, unless it was already taken during this run
+    fn ensure_snapshot(&mut self, config: &ConfigPreset) -> Result<(), String> {
+        if let (Some(kind), None) = (config.snapshot, &self.snapshot) {
+            let snapshot = snapshots::create(kind, config.snapshot_target.as_deref(), config.snapshot_keep)
+                .map_err(|e| format!("Unable to create snapshot: {e}"))?;
+            conclusion(&format!("Created snapshot {snapshot}"));
+            self.snapshot = Some(snapshot);
+        }
+        Ok(())
+    }
+
+    /// Whether the time budget of the run is used up
+    fn timed_out(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Whether all generations of the profile that were supposed to be removed are gone
+    fn is_complete(&self, profile: &Profile) -> bool {
+        let path = profile.path().to_string_lossy().to_string();
+        !self.remaining.iter().any(|(p, _)| *p == path)
+    }
+
+    /// Correct the record of the last profile, if some of its generations were not removed
+    fn record_remaining(&mut self, remaining: usize) {
+        if remaining == 0 {
+            return;
+        }
+        if let Some((path, count)) = self.removed.last_mut() {
+            *count -= remaining;
+            self.remaining.push((path.clone(), remaining));
+
+            // the estimate is an upper bound if only some of the generations were removed
+            if *count == 0 {
+                self.expected_garbage -= self.last_expected;
+            }
+        }
+    }
+
+    /// Record the marked generations of a profile before they are removed
+    fn record(&mut self, profile: &Profile, estimate_size: bool) {
+        self.removed.push((profile.path().to_string_lossy().to_string(), profile.count_marked()));
+        if estimate_size {
+            self.last_expected = profile.marked_only_size();
+            self.expected_garbage += self.last_expected;
+        }
+    }
+
+    fn report(&self, print_size: bool) {
+        announce("Summary");
+
+        let max_path_len = self.removed.iter()
+            .map(|(p, _)| p.len())
+            .max()
+            .unwrap_or(0);
+        for (path, count) in &self.removed {
+            println!("{:<width$}  {} generations removed", path, count.to_string().bright_blue(), width = max_path_len);
+        }
+        let total: usize = self.removed.iter().map(|(_, c)| c).sum();
+        println!("Generations removed: {}", total.to_string().bright_blue());
+
+        for (path, count) in &self.remaining {
+            println!("{:<width$}  {} generations remaining", path, count.to_string().yellow(), width = max_path_len);
+        }
+        if !self.remaining.is_empty() {
+            let total: usize = self.remaining.iter().map(|(_, c)| c).sum();
+            println!("Not removed:         {}", total.to_string().yellow());
+        }
+
+        if print_size {
+            println!("Expected garbage:    {}", FmtSize::new(self.expected_garbage).to_string().yellow());
+        }
+        if let Some(gc) = self.gc {
+            println!("Deleted by gc:       {}", format!("{} store paths", gc.deleted).bright_blue());
+            println!("Freed by gc:         {}", FmtSize::new(gc.freed).to_string().green());
+        }
+        if let Some(snapshot) = &self.snapshot {
+            println!("Snapshot:            {snapshot}");
+        }
+        println!();
+    }
+}
+
+/// Show the package changes from the newest marked generation to the oldest kept one after it
+fn list_changes(profile: &Profile, print_profile: bool) {
+    let generations = profile.generations();
+    let newest_marked = match generations.iter().filter(|g| g.marked()).max() {
+        Some(generation) => generation,
+        None => return,
+    };
+    let oldest_kept = match generations.iter().filter(|g| !g.marked() && *g > newest_marked).min() {
+        Some(generation) => generation,
+        None => return,
+    };
+
+    let profile_str = match print_profile {
+        true => format!(" of {}", profile.path().to_string_lossy()),
+        false => String::new(),
+    };
+    println!("\nPackage changes{} from generation {} (newest marked) to {} (oldest kept):",
+        profile_str, newest_marked.number(), oldest_kept.number());
+
+    let changes = newest_marked.store_path()
+        .and_then(|old| oldest_kept.store_path().and_then(|new| new.package_changes(&old)));
+    let changes = match changes {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn(&format!("Unable to compare the generations: {e}"));
+            return;
+        },
+    };
+    if changes.is_empty() {
+        println!("{}", "  no changes".bright_black());
+    }
+
+    // same notation as `nix store diff-closures`
+    let versions = |versions: &[String]| match versions.is_empty() {
+        true => "∅".to_owned(),
+        false => versions.iter()
+            .map(|v| if v.is_empty() { "ε" } else { v })
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    for change in changes {
+        let line = format!("  {}: {} → {}", change.name, versions(&change.old), versions(&change.new));
+        match (change.old.is_empty(), change.new.is_empty()) {
+            (true, _) => println!("{}", line.green()),
+            (_, true) => println!("{}", line.red()),
+            _ => println!("{}", line.yellow()),
+        }
+    }
+}
+
+fn list_combined(profiles: &[Profile], print_size: bool) {
+    announce("Generations marked for removal");
+
+    let marked: Vec<_> = profiles.iter()
+        .flat_map(|p| p.generations().iter().filter(|g| g.marked()).map(move |g| (p, g)))
+        .collect();
+    let sizes: Vec<_> = marked.par_iter()
+        .map(|(_, g)| if print_size { g.store_path().and_then(|sp| sp.closure_size()).ok() } else { None })
+        .collect();
+
+    let max_path_len = marked.iter()
+        .map(|(p, _)| p.path().to_string_lossy().len())
+        .max()
+        .unwrap_or(0);
+
+    for ((profile, generation), size) in marked.iter().zip(sizes) {
+        let path_str = FmtWithEllipsis::fitting_terminal(profile.path().to_string_lossy().to_string(), max_path_len, 48)
+            .right_pad();
+        let id_str = format!("[{}]", generation.number());
+        let age_str = FmtAge::new(generation.age())
+            .with_suffix::<4>(" old".to_owned())
+            .left_pad();
+        let size_str = if print_size {
+            FmtOrNA::mapped(size, FmtSize::new).left_pad()
+        } else {
+            String::new()
+        };
+
+        println!("{}  {:>6}  {}  {}", path_str, id_str.bright_blue(), age_colored(generation.age(), &age_str), size_str.yellow());
+    }
+
+    let nprofiles = profiles.iter().filter(|p| p.count_marked() > 0).count();
+    println!("\n{} generations marked for removal in {} profiles", marked.len(), nprofiles);
+}
+
+/// Remove the marked generations of a profile, returning how many were left over when the deadline passed
+/// or the run was cancelled
+fn remove_generations(profile: &Profile, backend: RemovalBackend, deadline: Option<Instant>) -> usize {
     announce(&format!("Removing old generations for profile {}", profile.path().to_string_lossy()));
+    let mut remaining = 0;
     for generation in profile.generations() {
         let age_str = FmtAge::new(generation.age()).to_string();
-        if generation.marked() {
+        if generation.marked() && (signals::interrupted() || deadline.is_some_and(|d| Instant::now() >= d)) {
+            remaining += 1;
+        } else if generation.marked() {
             println!("{}", format!("-> Removing generation {} ({} old)", generation.number(), age_str).bright_blue());
-            resolve(generation.remove());
-        } else {
+            let result = generation.remove(backend);
+            // children (e.g. nix-env) share the process group and are interrupted as well
+            if result.is_err() && signals::interrupted() && generation.path().is_symlink() {
+                remaining += 1;
+                continue;
+            }
+            resolve(result);
+            progress::emit(Event::Item("cleanout", &generation.path().to_string_lossy()));
+        } else if verbosity() >= 1 {
             println!("{}", format!("-> Keeping generation {} ({} old)", generation.number(), age_str).bright_black());
         }
     }
+
+    if remaining > 0 {
+        warn(&format!("Stopped early, {remaining} marked generations were not removed"));
+    }
+    if backend == RemovalBackend::Unlink {
+        println!("{}", "Note: The store paths of removed generations are only freed by the next garbage collection".yellow());
+    }
     println!();
+    remaining
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::nix::fixture::Fixture;
+    use crate::nix::mock::{self, Call};
+
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn profile_with_generations(root: &Path, count: usize) -> Profile {
+        let mut fixture = Fixture::create(root).unwrap();
+        let profile = Path::new("/nix/var/nix/profiles/test");
+        for number in 1..=count {
+            let target = fixture.missing_store_path("test-profile");
+            fixture.add_generation(profile, number, &target, DAY * (count - number) as u32).unwrap();
+        }
+        Profile::from_path(fixture.path(profile)).unwrap()
+    }
+
+    #[test]
+    fn removes_marked_generations() {
+        let mock = mock::install();
+        let root = tempfile::tempdir().unwrap();
+        let mut profile = profile_with_generations(root.path(), 4);
+        profile.toggle_marker(1).unwrap();
+        profile.toggle_marker(3).unwrap();
+
+        assert_eq!(remove_generations(&profile, RemovalBackend::NixEnv, None), 0);
+        assert_eq!(mock.calls(), vec![
+            Call::DeleteGeneration(profile.path(), 1),
+            Call::DeleteGeneration(profile.path(), 3),
+        ]);
+        let numbers: Vec<_> = Profile::from_path(profile.path()).unwrap()
+            .generations().iter()
+            .map(|g| g.number())
+            .collect();
+        assert_eq!(numbers, vec![2, 4]);
+    }
+
+    #[test]
+    fn keeps_generations_after_deadline() {
+        let mock = mock::install();
+        let root = tempfile::tempdir().unwrap();
+        let mut profile = profile_with_generations(root.path(), 3);
+        profile.toggle_marker(1).unwrap();
+        profile.toggle_marker(2).unwrap();
+
+        assert_eq!(remove_generations(&profile, RemovalBackend::NixEnv, Some(Instant::now())), 2);
+        assert_eq!(mock.calls(), Vec::new());
+        assert_eq!(Profile::from_path(profile.path()).unwrap().generations().len(), 3);
+    }
+
+    #[test]
+    fn unlink_bypasses_backend() {
+        let mock = mock::install();
+        let root = tempfile::tempdir().unwrap();
+        let mut profile = profile_with_generations(root.path(), 2);
+        profile.toggle_marker(1).unwrap();
+
+        assert_eq!(remove_generations(&profile, RemovalBackend::Unlink, None), 0);
+        assert_eq!(mock.calls(), Vec::new());
+        assert_eq!(Profile::from_path(profile.path()).unwrap().generations().len(), 1);
+    }
+}
@@ -1,4 +1,5 @@
 use std::fs;
+use std::io;
 use std::path;
 
 use clap::CommandFactory;
@@ -7,12 +8,25 @@ use clap_complete::Shell;
 
 #[derive(clap::Args)]
 pub struct CompletionsCommand {
-    directory: path::PathBuf,
+    /// Only generate completions for this shell and write them to stdout
+    #[clap(long, value_enum)]
+    shell: Option<Shell>,
+
+    /// Directory to write the completions for all supported shells to
+    #[clap(required_unless_present = "shell", conflicts_with = "shell")]
+    directory: Option<path::PathBuf>,
 }
 
 impl super::Command for CompletionsCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
         let mut command = crate::Args::command();
+
+        if let Some(shell) = self.shell {
+            clap_complete::aot::generate(shell, &mut command, "nix-sweep", &mut io::stdout());
+            return Ok(());
+        }
+
+        let directory = self.directory.unwrap();
         let shells = &[
             (Shell::Bash, "bash"),
             (Shell::Zsh, "zsh"),
@@ -22,7 +36,7 @@ impl super::Command for CompletionsCommand {
         ];
 
         for (shell, ending) in shells {
-            let mut file = fs::File::create(self.directory.join(format!("nix-sweep.{}", ending)))
+            let mut file = fs::File::create(directory.join(format!("nix-sweep.{}", ending)))
                 .map_err(|e| e.to_string())?;
             clap_complete::aot::generate(*shell, &mut command, "nix-sweep", &mut file);
         }
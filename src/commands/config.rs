@@ -0,0 +1,165 @@
+use std::any::TypeId;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{ArgAction, CommandFactory};
+use serde_json::{json, Map, Value};
+
+use crate::config::{self, ConfigFile, ConfigPreset, RENAMED_KEYS};
+use crate::utils::calendar::LocalDate;
+use crate::utils::interaction::conclusion;
+
+
+/// Options of [ConfigPreset] that can only be passed on the command line
+const CLI_ONLY_OPTIONS: &[&str] = &["GENERATION", "_non_interactive"];
+
+/// Keys whose option negates them (e.g. --non-interactive), with the option documenting the key itself
+const NEGATED_OPTIONS: &[(&str, &str)] = &[("interactive", "_non_interactive")];
+
+
+#[derive(clap::Args)]
+pub struct ConfigCommand {
+    #[clap(subcommand)]
+    subcommand: ConfigSubcommand,
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigSubcommand {
+    /// Print the keys available in presets as JSON
+    ///
+    /// For each key the type, the default value (if any) and the documentation are given. The
+    /// schema is generated from the same definitions as the command line options of `cleanout`, so
+    /// it can be used to generate options and validation for the NixOS module or other tools.
+    DumpSchema,
+
+    /// Rename keys that are still used with their old name in a preset file
+    ///
+    /// Comments and formatting of the file are preserved. Without --config, the preset file of the
+    /// current user is migrated.
+    Migrate(MigrateArgs),
+}
+
+#[derive(clap::Args)]
+struct MigrateArgs {
+    /// Preset file to migrate instead of that of the current user
+    #[clap(short('C'), long)]
+    config: Option<PathBuf>,
+
+    /// Only show which keys would be renamed
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl super::Command for ConfigCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        match self.subcommand {
+            ConfigSubcommand::DumpSchema => dump_schema(),
+            ConfigSubcommand::Migrate(args) => args.run(),
+        }
+    }
+}
+
+fn dump_schema() -> Result<(), String> {
+    let defaults = serde_json::to_value(ConfigPreset::default())
+        .map_err(|e| format!("Unable to serialize the default preset: {e}"))?;
+    let command = ConfigPreset::command();
+
+    let keys: Map<_, _> = command.get_arguments()
+        .filter(|a| !CLI_ONLY_OPTIONS.contains(&a.get_id().as_str()) && a.get_long().is_some())
+        .map(|arg| {
+            let key = arg.get_id().as_str().replace('_', "-");
+            let value_type = value_type(arg);
+            let mut fields = Map::new();
+            fields.insert("type".to_owned(), json!(value_type));
+
+            if value_type == "enum" {
+                let values: Vec<_> = arg.get_possible_values().iter()
+                    .filter(|v| !v.is_hide_set())
+                    .map(|v| v.get_name().to_owned())
+                    .collect();
+                fields.insert("values".to_owned(), json!(values));
+            }
+            if let Some(default) = defaults.get(&key).filter(|d| !d.is_null()) {
+                fields.insert("default".to_owned(), default.clone());
+            }
+
+            let documenting = NEGATED_OPTIONS.iter()
+                .find(|(k, _)| arg.get_id() == *k)
+                .and_then(|(_, option)| command.get_arguments().find(|a| a.get_id() == *option))
+                .unwrap_or(arg);
+            if let Some(help) = documenting.get_long_help().or(documenting.get_help()) {
+                fields.insert("description".to_owned(), json!(help.to_string()));
+            }
+
+            (key, Value::Object(fields))
+        })
+        .collect();
+
+    let renamed: Map<_, _> = RENAMED_KEYS.iter()
+        .map(|(old, new)| (old.to_string(), json!(new)))
+        .collect();
+
+    let schema = json!({ "preset": keys, "renamed": renamed });
+    println!("{}", serde_json::to_string_pretty(&schema).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+impl MigrateArgs {
+    fn run(self) -> Result<(), String> {
+        let path = match self.config {
+            Some(path) => path,
+            None => ConfigFile::user_config_file()?
+                .ok_or("No preset file found (use --config to migrate another file)")?,
+        };
+        let path_str = path.to_string_lossy();
+        if path.extension().is_some_and(|e| e == "json") {
+            return Err(format!("{path_str} is a JSON file - please rename the keys where it is generated"));
+        }
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read {path_str}: {e}"))?;
+        let (migrated, renamed) = config::migrate_toml(&content, RENAMED_KEYS)
+            .map_err(|e| format!("Unable to migrate {path_str}: {e}"))?;
+
+        for key in &renamed {
+            match &key.preset {
+                Some(preset) => println!("{path_str}\t{preset}.{} -> {preset}.{}", key.old, key.new),
+                None => println!("{path_str}\t{} -> {}", key.old, key.new),
+            }
+        }
+
+        if renamed.is_empty() {
+            conclusion(&format!("No keys to rename in {path_str}"));
+        } else if self.dry_run {
+            conclusion(&format!("Would rename {} keys in {path_str}", renamed.len()));
+        } else {
+            fs::write(&path, migrated)
+                .map_err(|e| format!("Unable to write {path_str}: {e}"))?;
+            conclusion(&format!("Renamed {} keys in {path_str}", renamed.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Type of the value of a preset key, as named in the schema
+fn value_type(arg: &clap::Arg) -> &'static str {
+    let type_id = arg.get_value_parser().type_id();
+    if matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse) {
+        "boolean"
+    } else if !arg.get_possible_values().is_empty() {
+        "enum"
+    } else if arg.get_value_names().is_some_and(|n| n.iter().any(|n| n == "SIZE")) {
+        "size"
+    } else if type_id == TypeId::of::<Duration>() {
+        "duration"
+    } else if type_id == TypeId::of::<LocalDate>() {
+        "date"
+    } else if type_id == TypeId::of::<PathBuf>() {
+        "path"
+    } else if type_id == TypeId::of::<usize>() || type_id == TypeId::of::<u64>() {
+        "integer"
+    } else {
+        "string"
+    }
+}
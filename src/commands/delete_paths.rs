@@ -0,0 +1,110 @@
+use colored::Colorize;
+
+use crate::nix::store::{Store, StorePath};
+use crate::utils::{files, glob, privileges};
+use crate::utils::fmt::{FmtSize, Formattable};
+use crate::utils::interaction::{announce, ask, conclusion, warn};
+use crate::HashSet;
+
+
+#[derive(clap::Args)]
+pub struct DeletePathsCommand {
+    /// Do not ask before deleting
+    #[clap(short('n'), long)]
+    non_interactive: bool,
+
+    /// Only show what would be deleted
+    #[clap(short, long)]
+    dry_run: bool,
+
+    /// Store paths, symlinks to store paths or wildcard patterns (e.g. '/nix/store/*-firefox-*')
+    #[clap(required = true)]
+    paths: Vec<String>,
+}
+
+impl super::Command for DeletePathsCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        if !self.dry_run && privileges::store_needs_root() {
+            privileges::check(&["Deleting store paths".to_owned()])?;
+        }
+
+        let mut targets: Vec<_> = glob::expand_args(&self.paths)?
+            .iter()
+            .map(|p| StorePath::from_symlink(p.as_ref()))
+            .collect::<Result<_, _>>()?;
+        targets.sort_by(|a, b| a.path().cmp(b.path()));
+        targets.dedup();
+
+        let dead = Store::paths_dead()?;
+        let mut deletable = Vec::new();
+        let mut refused = 0;
+        for target in targets {
+            announce(&target.path().to_string_lossy());
+
+            // paths still reachable from a gc root cannot be deleted without breaking that root
+            if !dead.contains(&target) {
+                println!("{}", "still alive, refusing to delete".red());
+                let roots = target.roots()?;
+                if !roots.is_empty() {
+                    println!("Referenced by the following gc roots:");
+                    for root in &roots {
+                        println!("  {}", root.to_string_lossy().bright_black());
+                    }
+                }
+                refused += 1;
+                continue;
+            }
+
+            println!("size: {}", FmtSize::new(target.size()).left_pad().yellow());
+            let mut referrers: Vec<_> = target.referrers()?
+                .into_iter()
+                .filter(|sp| *sp != target)
+                .collect();
+            referrers.sort_by(|a, b| a.path().cmp(b.path()));
+            if !referrers.is_empty() {
+                println!("Referenced by the following dead paths, which are deleted as well:");
+                for referrer in &referrers {
+                    println!("  {}", referrer.path().to_string_lossy().bright_black());
+                }
+            }
+
+            deletable.push(target);
+            deletable.extend(referrers);
+        }
+
+        let mut seen = HashSet::default();
+        deletable.retain(|sp| seen.insert(sp.clone()));
+        if deletable.is_empty() {
+            return Err(format!("None of the given paths can be deleted ({refused} still alive)"));
+        }
+
+        let paths: Vec<_> = deletable.iter().map(|sp| sp.path().clone()).collect();
+        let size = files::dir_size_considering_hardlinks_all(&paths);
+        println!();
+        println!("Deleting {} store paths would free {}", paths.len(), FmtSize::new(size).to_string().yellow());
+
+        if self.dry_run {
+            conclusion("Not deleting paths (dry run)");
+            return Ok(());
+        } else if !self.non_interactive && !ask(&format!("Delete {} store paths?", paths.len()), false) {
+            conclusion("Not touching paths");
+            return Ok(());
+        }
+
+        // a single call lets nix delete the referrers before the paths they reference
+        if let Err(e) = Store::delete(&deletable) {
+            warn(&e);
+        }
+
+        let remaining: Vec<_> = paths.iter().filter(|p| p.symlink_metadata().is_ok()).cloned().collect();
+        conclusion(&format!("Deleted {} store paths, freeing about {}",
+            paths.len() - remaining.len(),
+            FmtSize::new(size.saturating_sub(files::dir_size_considering_hardlinks_all(&remaining)))));
+
+        match (remaining.len(), refused) {
+            (0, 0) => Ok(()),
+            (0, _) => Err(format!("Refused to delete {refused} paths that are still alive")),
+            (n, _) => Err(format!("Unable to delete {n} store paths")),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::parse_size;
+use crate::nix::fixture::Fixture;
+use crate::utils::duration;
+use crate::utils::interaction::conclusion;
+
+
+const FIXTURE_HOME: &str = "/home/user";
+
+
+#[derive(clap::Args)]
+pub struct DevCommand {
+    #[clap(subcommand)]
+    subcommand: DevSubcommand,
+}
+
+#[derive(clap::Subcommand)]
+enum DevSubcommand {
+    /// Create a fake Nix installation with profiles and gc roots for use with --store-root
+    ///
+    /// This is meant for reproducing bugs without exposing the real store. Store paths are plain
+    /// directories of the given size, which reference each other like real closures: every
+    /// generation references a package of its own and a base shared by all generations.
+    MakeFixture(MakeFixtureArgs),
+}
+
+#[derive(clap::Args)]
+struct MakeFixtureArgs {
+    /// Profiles to create, relative to /nix/var/nix/profiles (can be given multiple times)
+    #[clap(long = "profile", value_name = "NAME", default_values_t = ["system".to_owned()])]
+    profiles: Vec<String>,
+
+    /// Number of generations per profile
+    #[clap(long, default_value_t = 5)]
+    generations: usize,
+
+    /// Time between two generations (the newest one is created right away)
+    #[clap(long, default_value = "7d", value_parser = duration::parse)]
+    interval: Duration,
+
+    /// Let this many of the oldest generations of each profile point to missing store paths
+    #[clap(long, default_value_t = 0)]
+    broken: usize,
+
+    /// Number of gc roots in the home directory of the fixture, in addition to the profiles
+    #[clap(long, default_value_t = 2)]
+    gc_roots: usize,
+
+    /// Number of gc roots whose link has been removed
+    #[clap(long, default_value_t = 1)]
+    dangling_roots: usize,
+
+    /// Number of store paths not referenced by any gc root
+    #[clap(long, default_value_t = 3)]
+    garbage: usize,
+
+    /// Size of each package (e.g. "1MiB")
+    #[clap(long, default_value = "4KiB", value_parser = parse_size)]
+    size: u64,
+
+    /// Create the fixture even if the directory is not empty
+    #[clap(long)]
+    force: bool,
+
+    /// Directory to create the fixture in
+    dir: PathBuf,
+}
+
+impl super::Command for DevCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        match self.subcommand {
+            DevSubcommand::MakeFixture(args) => args.run(),
+        }
+    }
+}
+
+impl MakeFixtureArgs {
+    fn run(self) -> Result<(), String> {
+        let nonempty = fs::read_dir(&self.dir).is_ok_and(|mut entries| entries.next().is_some());
+        if nonempty && !self.force {
+            return Err(format!("{} is not empty (use --force to create the fixture anyway)", self.dir.to_string_lossy()));
+        }
+        if self.broken > self.generations {
+            return Err("Cannot have more broken generations than generations".to_owned());
+        }
+        let size = self.size as usize;
+
+        let mut fixture = Fixture::create(&self.dir)?;
+        let base = fixture.add_store_path("base", size, &[])?;
+
+        for profile in &self.profiles {
+            let profile_path = Path::new("/nix/var/nix/profiles").join(profile);
+            let name = profile_path.file_name()
+                .ok_or(format!("Invalid profile name '{profile}'"))?
+                .to_string_lossy()
+                .to_string();
+
+            for number in 1..=self.generations {
+                let age = self.interval * (self.generations - number) as u32;
+                let target = if number <= self.broken {
+                    fixture.missing_store_path(&format!("{name}-{number}"))
+                } else {
+                    let package = fixture.add_store_path(&format!("{name}-package-{number}"), size, &[])?;
+                    fixture.add_store_path(&format!("{name}-{number}"), 0, &[base.clone(), package])?
+                };
+                fixture.add_generation(&profile_path, number, &target, age)?;
+            }
+        }
+
+        for i in 1..=self.gc_roots {
+            let target = fixture.add_store_path(&format!("result-{i}"), size, std::slice::from_ref(&base))?;
+            fixture.add_gc_root(&Path::new(FIXTURE_HOME).join(format!("result-{i}")), &target, self.interval * i as u32)?;
+        }
+        for i in 1..=self.dangling_roots {
+            fixture.add_auto_root(&Path::new(FIXTURE_HOME).join(format!("removed-result-{i}")))?;
+        }
+        for i in 1..=self.garbage {
+            fixture.add_store_path(&format!("garbage-{i}"), size, &[])?;
+        }
+
+        conclusion(&format!("Created fixture in {} (use it with --store-root {})",
+            fixture.root().to_string_lossy(), fixture.root().to_string_lossy()));
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use colored::Colorize;
+
+use crate::nix::store::{Store, StorePath};
+use crate::utils::files;
+use crate::utils::fmt::{FmtSize, Formattable};
+use crate::utils::interaction::{announce, ask, conclusion, info, warn};
+use crate::utils::privileges;
+
+
+#[derive(clap::Args)]
+pub struct DrvsCommand {
+    /// Only list derivations that are not referenced by any gc root
+    #[clap(short, long)]
+    orphaned: bool,
+
+    /// Delete orphaned derivations without running a full garbage collection
+    #[clap(long, requires = "orphaned")]
+    delete: bool,
+
+    /// Do not ask before deleting orphaned derivations
+    #[clap(short('n'), long)]
+    non_interactive: bool,
+}
+
+impl super::Command for DrvsCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        if self.delete && privileges::store_needs_root() {
+            privileges::check(&["Deleting store paths".to_owned()])?;
+        }
+
+        let dead = match self.orphaned {
+            true => Some(Store::paths_dead()?),
+            false => None,
+        };
+        let mut drvs: Vec<_> = match &dead {
+            Some(dead) => dead.iter().filter(|sp| sp.is_drv()).cloned().collect(),
+            None => Store::all_paths()?.into_iter().filter(StorePath::is_drv).collect(),
+        };
+        drvs.sort_by(|a, b| a.path().cmp(b.path()));
+
+        match self.orphaned {
+            true => announce(&format!("Orphaned derivations ({}):", drvs.len())),
+            false => announce(&format!("Derivations ({}):", drvs.len())),
+        }
+        for drv in &drvs {
+            println!("{}\t{}", FmtSize::new(drv.size()).left_pad().yellow(), drv.path().to_string_lossy());
+        }
+
+        let paths: Vec<_> = drvs.iter().map(|sp| sp.path().clone()).collect();
+        let drv_size = files::dir_size_considering_hardlinks_all(&paths);
+        println!();
+        println!("Total size of derivations:     {}", FmtSize::new(drv_size).left_pad().cyan());
+
+        let dead = match dead {
+            Some(dead) => dead,
+            None => return Ok(()),
+        };
+
+        // sources and other derivations that are only kept around by the orphaned derivations
+        let refs: Vec<_> = drvs.iter().collect();
+        let closure: Vec<_> = StorePath::full_closure(&refs).into_iter()
+            .filter(|sp| dead.contains(sp))
+            .map(|sp| sp.path().clone())
+            .collect();
+        println!("Dead closure of derivations:   {} {}",
+            FmtSize::new(files::dir_size_considering_hardlinks_all(&closure)).left_pad().bright_cyan(),
+            format!("({} paths)", closure.len()).bright_black());
+
+        if drvs.is_empty() {
+            conclusion("No orphaned derivations found");
+            return Ok(());
+        } else if !self.delete {
+            return Ok(());
+        } else if !self.non_interactive && !ask(&format!("\nDelete {} orphaned derivations?", drvs.len()), false) {
+            conclusion("Not touching derivations");
+            return Ok(());
+        }
+
+        announce("Deleting orphaned derivations");
+        if let Err(e) = Store::delete(&drvs) {
+            warn(&e);
+        }
+
+        let remaining: Vec<_> = paths.into_iter().filter(|p| p.symlink_metadata().is_ok()).collect();
+        if !remaining.is_empty() {
+            info(&format!("{} derivations could not be deleted", remaining.len()));
+        }
+        conclusion(&format!("Deleted {} orphaned derivations, freeing about {}",
+            drvs.len() - remaining.len(),
+            FmtSize::new(drv_size.saturating_sub(files::dir_size_considering_hardlinks_all(&remaining)))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::env;
+use std::path;
+
+use crate::config;
+use crate::nix::profiles::Profile;
+use crate::utils::interaction::{announce, info};
+use crate::utils::privileges;
+use crate::utils::users::User;
+
+use super::cleanout::CleanoutCommand;
+
+
+#[derive(clap::Args)]
+pub struct FullCleanCommand {
+    /// Settings for clean out criteria
+    #[clap(short, long, default_value_t = config::DEFAULT_PRESET.to_owned())]
+    preset: String,
+
+    /// Alternative config file
+    #[clap(short('C'), long)]
+    config: Option<path::PathBuf>,
+
+    /// List, but do not actually delete old generations or collect garbage
+    #[clap(short, long)]
+    dry_run: bool,
+
+    /// Only clean out the profiles of the current user (used when running as the invoking user of sudo)
+    #[clap(long, hide(true))]
+    user_only: bool,
+}
+
+impl super::Command for FullCleanCommand {
+    fn run(self, ctx: &super::Context) -> Result<(), String> {
+        // when running via sudo, the user profiles are cleaned out by the invoking user, so root
+        // does not create or modify files in their profile directories
+        let invoking_user = match !self.user_only && User::current()?.is_root() {
+            true => env::var("SUDO_USER").ok().filter(|u| !u.is_empty() && u != "root"),
+            false => None,
+        };
+
+        let system_allowed = !self.user_only && (privileges::can_escalate() || Profile::system()
+            .is_ok_and(|p| p.path().parent().is_some_and(privileges::can_write)));
+        let gc_allowed = !self.user_only && (privileges::can_escalate() || !privileges::store_needs_root());
+
+        if let Some(user) = &invoking_user {
+            announce(&format!("Cleaning user profiles of {user}"));
+            run_as_user(user)?;
+        } else {
+            // not every user has both a home-manager and a nix-env profile
+            let (user_part, mut profiles): (Vec<_>, Vec<_>) = ["home", "user"].into_iter()
+                .flat_map(|p| Some((p.to_owned(), Profile::from_str_for_user(p, None).ok()?)))
+                .unzip();
+
+            // query the closures of both clean outs at once, they usually share most of their store paths
+            if system_allowed && let Ok(system) = Profile::system() {
+                profiles.push(system);
+            }
+            ctx.prefetch_profiles(&profiles);
+
+            if user_part.is_empty() {
+                info("-> No user profiles found");
+            } else {
+                announce("Cleaning user profiles");
+                CleanoutCommand::with_preset(self.preset.clone(), self.config.clone(), user_part)
+                    .dry_run(self.dry_run)
+                    .gc(false)
+                    .run(ctx)?;
+            }
+        }
+
+        if self.user_only {
+            return Ok(());
+        }
+
+        let mut system_part = Vec::new();
+        if system_allowed {
+            system_part.push("system".to_owned());
+        } else {
+            info("\n-> Skipping the system profile, as it requires root (pass --sudo to include it)");
+        }
+        if !gc_allowed {
+            info("\n-> Skipping garbage collection, as it requires root (pass --sudo to include it)");
+        }
+
+        if !system_part.is_empty() || gc_allowed {
+            announce("Cleaning system profile");
+            CleanoutCommand::with_preset(self.preset, self.config, system_part)
+                .dry_run(self.dry_run)
+                .gc(gc_allowed)
+                .run(ctx)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the user part of this command again as `user`, with the same arguments
+fn run_as_user(user: &str) -> Result<(), String> {
+    let program = env::current_exe()
+        .map_err(|e| format!("Unable to locate nix-sweep: {e}"))?;
+    let status = privileges::command_as_user(user, &program)
+        .args(env::args_os().skip(1))
+        .arg("--user-only")
+        .status()
+        .map_err(|e| format!("Unable to run nix-sweep as {user}: {e}"))?;
+
+    match status.code() {
+        _ if status.success() => Ok(()),
+        Some(code) => Err(format!("Cleaning the profiles of {user} failed (exit code {code})")),
+        None => Err(format!("Cleaning the profiles of {user} failed")),
+    }
+}
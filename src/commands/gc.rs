@@ -1,10 +1,21 @@
-use crate::utils::files;
-use crate::utils::fmt::{FmtPercentage, FmtSize};
-use crate::utils::interaction::{announce, ask};
+use std::io;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config::parse_size;
+use crate::utils::{duration, files, privileges, progress, signals, terminal};
+use crate::utils::progress::Event;
+use crate::utils::fmt::{FmtAge, FmtSize};
+use crate::utils::interaction::{announce, ask, conclusion, info, verbosity, warn};
+use crate::nix::backend::GCResult;
+use crate::nix::settings;
 use crate::nix::store::Store;
+use crate::state::State;
 
 
-const GIB: u64 = 1024 * 1024 * 1024;
+pub const GIB: u64 = 1024 * 1024 * 1024;
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_millis(200);
 
 
 #[derive(clap::Args)]
@@ -18,6 +29,8 @@ pub struct GCCommand {
     _non_interactive: bool,
 
     /// Only perform gc if the store is bigger than BIGGER Gibibytes.
+    ///
+    /// If combined with --quota, gc is performed as soon as either threshold is exceeded.
     #[clap(short, long)]
     bigger: Option<u64>,
 
@@ -34,72 +47,355 @@ pub struct GCCommand {
     /// The desired target size of the store is calculated based on --bigger or --quota and then
     /// rewritten to match the --max-freed option of nix-store(1). Garbage collection is then
     /// performed stopping, as soon as the desired target size is met.
+    /// If both --bigger and --quota are given, the larger of the two target sizes is used.
     #[clap(short, long)]
     modest: bool,
+
+    /// Only perform gc if less than this is available on the file system of the store (e.g. "20GiB")
+    ///
+    /// The missing space is calculated from the file system statistics and passed as --max-freed to
+    /// nix-store(1), so garbage collection stops as soon as the desired amount of space is free.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size, conflicts_with_all = ["bigger", "quota", "modest"])]
+    ensure_free: Option<u64>,
+
+    /// Stop deleting further paths once this time budget is used up
+    #[clap(short, long, value_parser = duration::parse)]
+    timeout: Option<Duration>,
+
+    /// Garbage expected from generations a dry run did not actually remove
+    #[clap(skip)]
+    pending_garbage: u64,
 }
 
 impl GCCommand {
-    pub fn new(interactive: bool, dry_run: bool, bigger: Option<u64>, quota: Option<u64>, modest: bool) -> Self {
-        GCCommand { interactive, dry_run, bigger, quota, _non_interactive: !interactive, modest }
+    pub fn new(interactive: bool, dry_run: bool, bigger: Option<u64>, quota: Option<u64>, modest: bool,
+            timeout: Option<Duration>) -> Self {
+        GCCommand { interactive, dry_run, bigger, quota, _non_interactive: !interactive, modest, ensure_free: None, timeout, pending_garbage: 0 }
+    }
+
+    /// Include the garbage of generations that were only marked for removal in a dry run estimate
+    pub fn with_pending_garbage(mut self, bytes: u64) -> Self {
+        self.pending_garbage = bytes;
+        self
+    }
+
+    /// Space that has to be freed to reach --ensure-free, `None` if it is not given
+    fn missing_free_space(&self) -> Result<Option<u64>, String> {
+        let target = match self.ensure_free {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+
+        let (_, available) = files::fs_space(Store::dir())?;
+        info(&format!("{} available on the file system of the store (target: {})",
+            FmtSize::new(available), FmtSize::new(target)));
+        Ok(Some(target.saturating_sub(available)))
+    }
+}
+
+/// Live progress line for a running garbage collection
+struct GCProgress {
+    start: Instant,
+    last_update: Option<Instant>,
+    deleted: usize,
+    max_freed: Option<u64>,
+    available_before: Option<u64>,
+    deadline: Option<Instant>,
+    live: bool,
+}
+
+impl GCProgress {
+    fn new(max_freed: Option<u64>, deadline: Option<Instant>) -> Self {
+        GCProgress {
+            start: Instant::now(),
+            last_update: None,
+            deleted: 0,
+            max_freed,
+            available_before: files::fs_space(Store::dir()).ok().map(|(_, a)| a),
+            deadline,
+            live: verbosity() >= 0 && terminal::is_fancy(io::stderr()),
+        }
+    }
+
+    /// Space freed since the start of the garbage collection
+    fn freed(&self) -> Option<u64> {
+        let available = files::fs_space(Store::dir()).ok().map(|(_, a)| a);
+        self.available_before.zip(available)
+            .map(|(before, after)| after.saturating_sub(before))
+    }
+
+    /// Whether the time budget is used up
+    fn timed_out(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    fn path_deleted(&mut self, path: &Path) -> ControlFlow<()> {
+        self.deleted += 1;
+        progress::emit(Event::Item("gc", &path.to_string_lossy()));
+
+        if !self.live {
+            if verbosity() >= 1 {
+                eprintln!("deleting '{}'", path.to_string_lossy());
+            }
+        } else if self.last_update.is_none_or(|t| t.elapsed() >= PROGRESS_UPDATE_INTERVAL) {
+            self.draw();
+            self.last_update = Some(Instant::now());
+        }
+
+        match self.timed_out() || signals::interrupted() {
+            true => ControlFlow::Break(()),
+            false => ControlFlow::Continue(()),
+        }
+    }
+
+    fn draw(&self) {
+        let freed = self.freed();
+        let progress = match (self.max_freed, freed) {
+            (Some(max_freed), Some(freed)) if max_freed > 0 => {
+                let done = (freed as f64 / max_freed as f64).min(1.0);
+                let eta = match done > 0.0 {
+                    true => FmtAge::new(self.start.elapsed().mul_f64((1.0 - done) / done)).to_string(),
+                    false => "unknown".to_owned(),
+                };
+                format!(", {:.0}% (ETA: {eta})", done * 100.0)
+            },
+            _ => String::new(),
+        };
+
+        let freed = freed.map(|f| FmtSize::new(f).to_string()).unwrap_or("n/a".to_owned());
+        eprint!("\r\x1b[2K{} paths deleted, {freed} freed{progress}", self.deleted);
+    }
+
+    fn finish(&self) {
+        if self.live && self.deleted > 0 {
+            self.draw();
+            eprintln!();
+        }
+    }
+}
+
+impl GCCommand {
+    fn measure_store_size(&self) -> Result<Option<u64>, String> {
+        if self.bigger.is_none() && self.quota.is_none() {
+            return Ok(None);
+        }
+
+        info("Calculating store size...");
+        let start = Instant::now();
+        let size = Store::size()?;
+        info(&format!("Store has a size of {} (measured in {:.1}s)", FmtSize::new(size), start.elapsed().as_secs_f64()));
+        Ok(Some(size))
     }
 }
 
 impl super::Command for GCCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        self.collect().map(|_| ())
+    }
+}
+
+impl GCCommand {
+    /// Run the garbage collection, returning its result if it was actually performed
+    pub fn collect(self) -> Result<Option<GCResult>, String> {
+        if !self.dry_run && privileges::store_needs_root() {
+            privileges::check(&["Garbage collection".to_owned()])?;
+        }
+
+        let mut gc_result = None;
         announce("Starting garbage collection");
+        progress::emit(Event::PhaseStart("gc"));
+        let store_size = self.measure_store_size()?;
+        let blkdev_size = match self.quota {
+            Some(_) => Some(Store::device()?.1),
+            None => None,
+        };
+
         if let Some(bigger) = self.bigger {
-            eprintln!("Calculating store size...");
-            let size = Store::size()?;
-            eprintln!("Store has a size of {} (threshold: {})", FmtSize::new(size), FmtSize::new(bigger * GIB));
-            if size <= bigger * GIB {
-                let msg = format!("Nothing to do: Store size is at {} ({} below the threshold of {})",
-                    FmtSize::new(size),
-                    FmtSize::new(bigger * GIB - size),
-                    FmtSize::new(bigger * GIB));
-                eprintln!("\n-> {msg}");
-                return Ok(());
-            }
+            info(&format!("Size threshold is {}", FmtSize::new(bigger * GIB)));
         }
+        if let (Some(quota), Some(size), Some(blkdev_size)) = (self.quota, store_size, blkdev_size) {
+            info(&format!("Store uses {}% (quota: {quota}%)", size * 100 / blkdev_size));
+        }
+        let targets = size_targets(self.bigger, self.quota, blkdev_size);
 
-        if let Some(quota) = self.quota {
-            eprintln!("Calculating store size...");
-            let size = Store::size()?;
-            let blkdev_size = files::get_blkdev_size(&Store::blkdev()?)?;
-            let percentage = size * 100 / blkdev_size;
-            eprintln!("Store uses {percentage}% (quota: {quota}%)");
-            if percentage <= quota {
-                let msg = format!("Nothing to do: Device usage of store is at {} (below the threshold of {})",
-                    FmtPercentage::new(size, blkdev_size),
-                    FmtPercentage::new(quota, 100));
-                eprintln!("\n-> {msg}");
-                return Ok(());
-            }
+        // the daemon's own auto gc might kick in long before our thresholds are reached
+        if let (Some(size), Some(auto_gc)) = (store_size, settings::auto_gc())
+                && let Ok((_, available)) = files::fs_space(Store::dir()) {
+            auto_gc.warn_conflicts(&targets, size, available);
         }
 
-        let max_freed = if self.modest {
-            if let Some(bigger) = self.bigger {
-                Some(Store::size()? - bigger * GIB)
-            } else if let Some(quota) = self.quota {
-                let blkdev_size = files::get_blkdev_size(&Store::blkdev()?)?;
-                Some(Store::size()? - quota * blkdev_size / 100)
-            } else {
-                return Err("Cannot use --modest without --bigger or --quota being".to_owned());
-            }
-        } else {
-            None
+        if let Some(size) = store_size
+                && !exceeds_any(size, &targets) {
+            let msg = format!("Nothing to do: Store size is at {} (below the threshold of {})",
+                FmtSize::new(size),
+                FmtSize::new(*targets.iter().min().unwrap_or(&0)));
+            info(&format!("\n-> {msg}"));
+            progress::emit(Event::PhaseEnd("gc"));
+            return Ok(None);
+        }
+
+        let missing = self.missing_free_space()?;
+        if missing == Some(0) {
+            info("\n-> Nothing to do: Enough space is available on the file system of the store");
+            progress::emit(Event::PhaseEnd("gc"));
+            return Ok(None);
+        }
+
+        let max_freed = match self.modest {
+            true => Some(modest_max_freed(store_size, &targets)?),
+            false => missing,
         };
 
+        if max_freed == Some(0) {
+            info("\n-> Nothing to do: Store size is already below the more lenient threshold (--modest)");
+            progress::emit(Event::PhaseEnd("gc"));
+            return Ok(None);
+        }
+
         if let Some(bytes) = max_freed {
-            eprintln!("Freeing up to {} (--modest)", FmtSize::new(bytes));
+            let option = if self.modest { "--modest" } else { "--ensure-free" };
+            info(&format!("Freeing up to {} ({option})", FmtSize::new(bytes)));
         }
 
         if self.dry_run {
-            eprintln!("\n-> Skipping garbage collection (dry run)");
+            info("Calculating size of dead paths...");
+            let dead_paths: Vec<_> = Store::paths_dead()?
+                .into_iter()
+                .map(|sp| sp.path().clone())
+                .collect();
+            let dead_size = files::dir_size_considering_hardlinks_all(&dead_paths) + self.pending_garbage;
+            let expected = match max_freed {
+                Some(bytes) => dead_size.min(bytes),
+                None => dead_size,
+            };
+            let pending = match self.pending_garbage {
+                0 => String::new(),
+                bytes => format!(" and {} of the generations to remove", FmtSize::new(bytes)),
+            };
+            conclusion(&format!("Garbage collection would free up to {} ({} dead paths{pending})",
+                FmtSize::new(expected), dead_paths.len()));
+            info("\n-> Skipping garbage collection (dry run)");
         } else if !self.interactive || ask("\nDo you want to perform garbage collection now?", false) {
-            eprintln!("Starting garbage collector");
-            Store::gc(max_freed)?
+            // finding all dead paths up front would take about as long as the collection itself,
+            // so progress is only measured against the amount to free, if there is one
+            info("Starting garbage collector");
+            let deadline = self.timeout.map(|t| Instant::now() + t);
+            let mut gc_progress = GCProgress::new(max_freed, deadline);
+            let reported = signals::deferred(|| Store::gc(max_freed, deadline, &mut |path| gc_progress.path_deleted(path)));
+            gc_progress.finish();
+
+            // fall back to our own observations if nix did not report a summary
+            let result = reported?.unwrap_or(GCResult {
+                deleted: gc_progress.deleted,
+                freed: gc_progress.freed().unwrap_or(0),
+            });
+            if signals::interrupted() {
+                warn("Garbage collection cancelled");
+            } else if gc_progress.timed_out() {
+                warn("Stopped garbage collection as the time budget was used up");
+            }
+            progress::emit(Event::BytesFreed("gc", result.freed));
+            conclusion(&format!("Garbage collection deleted {} store paths, freeing {}",
+                result.deleted, FmtSize::new(result.freed)));
+
+            let mut state = State::load()?;
+            state.record_gc(result.deleted, result.freed);
+            if let Err(e) = state.save() {
+                warn(&format!("Unable to record garbage collection: {e}"));
+            }
+            gc_result = Some(result);
         }
 
-        Ok(())
+        progress::emit(Event::PhaseEnd("gc"));
+
+        Ok(gc_result)
+    }
+}
+
+/// Store sizes above which garbage is collected, as implied by --bigger and --quota
+fn size_targets(bigger: Option<u64>, quota: Option<u64>, blkdev_size: Option<u64>) -> Vec<u64> {
+    let bigger = bigger.map(|b| b * GIB);
+    let quota = quota.zip(blkdev_size).map(|(q, size)| q * size / 100);
+    bigger.into_iter().chain(quota).collect()
+}
+
+/// Whether the store is collected, which is the case if it exceeds any of the targets
+fn exceeds_any(store_size: u64, targets: &[u64]) -> bool {
+    targets.iter().any(|target| store_size > *target)
+}
+
+/// Amount to free with --modest
+///
+/// With both thresholds only as much is collected as is needed to get below the more lenient one.
+fn modest_max_freed(store_size: Option<u64>, targets: &[u64]) -> Result<u64, String> {
+    match (store_size, targets.iter().max()) {
+        (Some(size), Some(target)) => Ok(size.saturating_sub(*target)),
+        _ => Err("Cannot use --modest without --bigger or --quota being set".to_owned()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::nix::mock::{self, Call};
+
+    use super::*;
+
+    fn dead_paths(count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| Store::dir().join(format!("{}-dead-{i}", "0".repeat(32))))
+            .collect()
+    }
+
+    #[test]
+    fn either_threshold_triggers() {
+        // 10 GiB threshold and 50% of a 40 GiB device
+        let targets = size_targets(Some(10), Some(50), Some(40 * GIB));
+        assert_eq!(targets, vec![10 * GIB, 20 * GIB]);
+        assert!(!exceeds_any(10 * GIB, &targets));
+        assert!(exceeds_any(15 * GIB, &targets));
+        assert!(exceeds_any(25 * GIB, &targets));
+        assert!(!exceeds_any(0, &[]));
+    }
+
+    #[test]
+    fn modest_uses_lenient_target() {
+        let targets = size_targets(Some(10), Some(50), Some(40 * GIB));
+        assert_eq!(modest_max_freed(Some(25 * GIB), &targets), Ok(5 * GIB));
+        assert_eq!(modest_max_freed(Some(15 * GIB), &targets), Ok(0));
+        assert_eq!(modest_max_freed(Some(15 * GIB), &size_targets(Some(10), None, None)), Ok(5 * GIB));
+        assert!(modest_max_freed(Some(15 * GIB), &[]).is_err());
+        assert!(modest_max_freed(None, &targets).is_err());
+    }
+
+    #[test]
+    fn deletes_all_dead_paths() {
+        let mock = mock::install();
+        let dead = dead_paths(3);
+        mock.add_dead(&dead);
+
+        let mut gc_progress = GCProgress::new(Some(GIB), None);
+        let result = Store::gc(Some(GIB), None, &mut |path| gc_progress.path_deleted(path)).unwrap();
+        assert_eq!(gc_progress.deleted, 3);
+        assert_eq!(result.map(|r| r.deleted), Some(3));
+        assert_eq!(mock.calls(), vec![Call::CollectGarbage(Some(GIB))]);
+        assert!(Store::paths_dead().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stops_when_time_budget_is_used_up() {
+        let mock = mock::install();
+        let dead = dead_paths(3);
+        mock.add_dead(&dead);
+
+        let deadline = Some(Instant::now());
+        let mut gc_progress = GCProgress::new(None, deadline);
+        let result = Store::gc(None, deadline, &mut |path| gc_progress.path_deleted(path)).unwrap();
+        assert!(gc_progress.timed_out());
+        assert_eq!(gc_progress.deleted, 1);
+        assert_eq!(result.map(|r| r.deleted), Some(1));
+        assert_eq!(Store::paths_dead().unwrap().len(), 2);
     }
 }
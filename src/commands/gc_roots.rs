@@ -1,14 +1,21 @@
 use std::cmp::Reverse;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use colored::Colorize;
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use rayon::slice::ParallelSliceMut;
 
+use crate::utils::duration;
 use crate::utils::fmt::*;
-use crate::utils::interaction::announce;
+use crate::utils::interaction::{announce, exit_threshold_exceeded, warn};
 use crate::utils::ordered_channel::OrderedChannel;
+use crate::utils::template::Template;
 use crate::nix::roots::GCRoot;
+use crate::nix::store::StorePath;
+use crate::HashMap;
+
+const FORMAT_FIELDS: &[&str] = &["link", "store_path", "size", "age", "type", "accessible"];
 
 #[derive(clap::Args)]
 pub struct GCRootsCommand {
@@ -21,9 +28,19 @@ pub struct GCRootsCommand {
     paths: bool,
 
     /// Present list as tsv
+    ///
+    /// Columns: link, store path, closure size (unless --no-size), age in seconds,
+    /// type (profile, current, proc or other) and accessibility (accessible or inaccessible).
     #[clap(long)]
     tsv: bool,
 
+    /// Print each gc root according to a template, e.g. '{link}\t{store_path}'
+    ///
+    /// Fields: {link}, {store_path}, {size} (in bytes), {age} (in seconds), {type} and {accessible}.
+    /// Literal braces are written as '{{' and '}}'.
+    #[clap(long, value_parser = Template::parse, conflicts_with_all = ["long", "paths", "tsv", "dedup_by_target"])]
+    format: Option<Template>,
+
     /// Include profiles
     #[clap(short('p'), long)]
     include_profiles: bool,
@@ -45,39 +62,222 @@ pub struct GCRootsCommand {
     exclude_inaccessible: bool,
 
     /// Only show gc roots older than OLDER
-    #[clap(long, value_parser = |s: &str| duration_str::parse_std(s))]
+    #[clap(long, value_parser = duration::parse)]
     older: Option<Duration>,
 
     /// Only show gc roots newer than NEWER
-    #[clap(long, value_parser = |s: &str| duration_str::parse_std(s))]
+    #[clap(long, value_parser = duration::parse)]
     newer: Option<Duration>,
 
+    /// Exit with code 2 if any listed gc root is older than WARN_OLDER
+    #[clap(long, value_parser = duration::parse)]
+    warn_older: Option<Duration>,
+
     /// Do not calculate the size of generations
     #[clap(long)]
     no_size: bool,
 
+    /// Show the size of the store paths only referenced by each gc root instead of its whole closure
+    ///
+    /// Unlike closure sizes, these do not overlap and add up to at most the estimated total size.
+    #[clap(long, conflicts_with = "no_size")]
+    unique_sizes: bool,
+
     /// Query Nix for gc roots instead of enumerating the directory
     #[clap(long)]
     query_nix: bool,
+
+    /// Also list links into the store below DIR, even if they are not registered as gc roots
+    ///
+    /// This finds e.g. `result` links of builds run with `--no-out-link` or of copied projects.
+    /// Can be given multiple times.
+    #[clap(long, value_name = "DIR")]
+    scan: Vec<PathBuf>,
+
+    /// How many levels of directories below the --scan directories to search
+    #[clap(long, default_value_t = 4, requires = "scan")]
+    depth: usize,
+
+    /// Order in which to list the gc roots
+    #[clap(long, value_enum, default_value_t)]
+    sort: RootOrder,
+
+    /// Reverse the order of the list
+    #[clap(long)]
+    reverse: bool,
+
+    /// Exit with an error if the size of any gc root cannot be calculated
+    ///
+    /// Otherwise the error is shown for the affected gc roots and the listing continues.
+    #[clap(long)]
+    strict: bool,
+
+    /// List each store path once together with all gc roots pointing to it
+    ///
+    /// With --tsv the columns are: store path, closure size (unless --no-size), age in seconds of the
+    /// newest gc root and the gc roots themselves (one column each).
+    #[clap(long, conflicts_with = "long")]
+    dedup_by_target: bool,
+}
+
+/// Closure size of a listed row, None if it is not calculated or the store path is not accessible
+type RowSize = Option<Result<u64, String>>;
+
+/// Gc roots pointing to the same store path
+struct Target<'a> {
+    /// None for gc roots whose store path is not accessible, which are listed on their own
+    store_path: Option<&'a StorePath>,
+    roots: Vec<&'a GCRoot>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+enum RootOrder {
+    /// Oldest first
+    #[default]
+    Age,
+
+    /// Biggest closure first
+    Size,
+
+    /// Alphabetically by link
+    Path,
+
+    /// Grouped by type (current, other, proc or profile), oldest first within each group
+    Type,
 }
 
 impl super::Command for GCRootsCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(mut self, ctx: &super::Context) -> Result<(), String> {
+        self.no_size = ctx.skip_sizes(self.no_size);
+        self.unique_sizes &= !self.no_size;
+        if self.sort == RootOrder::Size && self.no_size {
+            return Err("Sorting by size is not possible with --no-size".to_owned());
+        }
+        if self.depth == 0 {
+            return Err("Depth must not be zero".to_owned());
+        }
+        if let Some(format) = &self.format {
+            format.check_fields(FORMAT_FIELDS)?;
+            self.no_size |= !format.uses("size");
+            self.unique_sizes &= !self.no_size;
+        }
+
         let print_size = !(self.no_size || self.paths);
         let mut roots = GCRoot::all(self.query_nix, self.include_proc, self.include_missing)?;
+        roots.extend(GCRoot::scan(&self.scan, self.depth)?);
         let nroots_total = roots.len();
         roots.par_sort_by_key(|r| r.link().clone());
         roots.dedup_by_key(|r| r.link().clone());
-        roots.par_sort_by_key(|r| Reverse(r.age().cloned().unwrap_or(Duration::MAX)));
+        if self.sort != RootOrder::Path {
+            roots.par_sort_by_key(|r| Reverse(r.age().cloned().unwrap_or(Duration::MAX)));
+        }
+        if self.sort == RootOrder::Type {
+            roots.par_sort_by_key(|r| r.kind());
+        }
 
         roots = GCRoot::filter_roots(roots, self.include_profiles, self.include_current,
             !self.exclude_inaccessible, self.older, self.newer);
         let nroots_listed = roots.len();
 
-        if !self.tsv && !self.paths {
-            announce(&format!("Listing {nroots_listed} gc roots (out of {nroots_total} total)"));
+        if print_size || self.sort == RootOrder::Size {
+            ctx.prefetch_roots(&roots);
+        }
+
+        // sizes have to be known up front to sort by them or to tell which parts of the closures are
+        // shared, otherwise they are calculated while listing
+        let mut sizes = None;
+        if self.unique_sizes && !self.dedup_by_target {
+            let store_paths: Vec<_> = roots.iter()
+                .map(|r| r.store_path().ok())
+                .collect();
+            sizes = Some(StorePath::exclusive_closure_sizes(&store_paths));
+        }
+        if self.sort == RootOrder::Size {
+            let known_sizes = sizes.take();
+            let mut sized: Vec<_> = roots.into_par_iter()
+                .enumerate()
+                .map(|(i, r)| {
+                    let size = match &known_sizes {
+                        Some(sizes) => sizes[i].clone(),
+                        None => closure_size(&r),
+                    };
+                    (r, size)
+                })
+                .collect();
+            sized.sort_by_key(|(_, size)| Reverse(size.clone().and_then(Result::ok).unwrap_or(0)));
+            let (sorted, sorted_sizes): (Vec<_>, Vec<_>) = sized.into_iter().unzip();
+            roots = sorted;
+            sizes = Some(sorted_sizes);
+        }
+        if self.reverse {
+            roots.reverse();
+            if let Some(sizes) = sizes.as_mut() {
+                sizes.reverse();
+            }
+        }
+
+        let (rows_size, nfailed) = if self.dedup_by_target {
+            let targets = group_by_target(&roots);
+            if !self.machine_readable() {
+                announce(&format!("Listing {} store paths referenced by {nroots_listed} gc roots (out of {nroots_total} total)",
+                    targets.len()));
+            }
+            self.list_targets(&targets, print_size)
+        } else {
+            if !self.machine_readable() {
+                announce(&format!("Listing {nroots_listed} gc roots (out of {nroots_total} total)"));
+            }
+            self.list_roots(&roots, sizes, print_size)
+        };
+
+        if !self.machine_readable() && !self.no_size {
+            println!();
+            let full_closure = GCRoot::full_closure(&roots);
+            let total_size = GCRoot::full_closure_size(&roots)?;
+            println!("Estimated total size: {} ({} store paths)",
+                FmtSize::new(total_size).to_string().yellow(), full_closure.len());
+            if let Some(note) = size_overlap_note(rows_size, total_size, self.unique_sizes) {
+                println!("{}", note.bright_black());
+            }
+        }
+
+        if !self.machine_readable() {
+            println!();
+        }
+
+        if nfailed > 0 {
+            let msg = format!("Unable to calculate the size of {nfailed} gc roots");
+            match self.strict {
+                true => return Err(msg),
+                false => warn(&msg),
+            }
+        }
+
+        if let Some(warn_older) = self.warn_older {
+            let nexceeding = roots.iter()
+                .filter(|r| r.age().map(|a| *a > warn_older).unwrap_or(false))
+                .count();
+            if nexceeding > 0 {
+                exit_threshold_exceeded(&format!("{nexceeding} gc roots are older than {}", FmtAge::new(warn_older)));
+            }
         }
 
+        Ok(())
+    }
+}
+
+impl GCRootsCommand {
+    /// Whether only the rows are printed, without headings and totals
+    fn machine_readable(&self) -> bool {
+        self.paths || self.tsv || self.format.is_some()
+    }
+
+    /// List the gc roots, returning the sum of the listed sizes and the number of failed size calculations
+    fn list_roots(&self, roots: &[GCRoot], sizes: Option<Vec<RowSize>>, print_size: bool) -> (u64, usize) {
+        let nroots_listed = roots.len();
+        let mut rows_size = 0;
+        let mut nfailed = 0;
+
         let max_link_len = roots.iter()
             .map(|r| r.link().to_string_lossy().len())
             .max()
@@ -87,45 +287,192 @@ impl super::Command for GCRootsCommand {
         rayon::join( || {
             roots.par_iter()
                 .enumerate()
-                .map(|(i, root)| match print_size {
-                    true => (i, (root, root.closure_size().ok())),
-                    false => (i, (root, None)),
+                .map(|(i, root)| match (&sizes, print_size) {
+                    (Some(sizes), _) => (i, (root, sizes[i].clone())),
+                    (None, true) => (i, (root, closure_size(root))),
+                    (None, false) => (i, (root, None)),
                 })
                 .for_each(|(i, tup)| ordered_channel.put(i, tup));
         }, || {
             for (root, closure_size) in ordered_channel.iter(nroots_listed) {
+                let (closure_size, error) = match closure_size {
+                    Some(Ok(size)) => (Some(size), None),
+                    Some(Err(e)) => (None, Some(e)),
+                    None => (None, None),
+                };
+                rows_size += closure_size.unwrap_or(0);
+
                 if self.paths {
                     println!("{}", root.link().to_string_lossy());
                 } else if self.tsv {
                     let path = root.store_path().as_ref().map(|p| p.path().to_string_lossy().to_string())
                         .unwrap_or_default();
+                    let age = root.age().map(|a| a.as_secs().to_string())
+                        .unwrap_or(String::from("n/a"));
+                    let accessible = if root.is_accessible() { "accessible" } else { "inaccessible" };
                     if self.no_size {
-                        println!("{}\t{}", root.link().to_string_lossy(), path);
+                        println!("{}\t{}\t{}\t{}\t{}", root.link().to_string_lossy(), path, age, root.kind(), accessible);
                     } else {
                         let size = closure_size.as_ref().map(|s| s.to_string())
                             .unwrap_or(String::from("n/a"));
-                        println!("{}\t{}\t{}", root.link().to_string_lossy(), path, size);
+                        println!("{}\t{}\t{}\t{}\t{}\t{}", root.link().to_string_lossy(), path, size, age, root.kind(), accessible);
                     }
+                } else if let Some(format) = &self.format {
+                    let line = format.render(|field| match field {
+                        "link" => root.link().to_string_lossy().to_string(),
+                        "store_path" => root.store_path().as_ref().map(|p| p.path().to_string_lossy().to_string())
+                            .unwrap_or_default(),
+                        "size" => closure_size.as_ref().map(|s| s.to_string())
+                            .unwrap_or(String::from("n/a")),
+                        "age" => root.age().map(|a| a.as_secs().to_string())
+                            .unwrap_or(String::from("n/a")),
+                        "type" => root.kind().to_owned(),
+                        "accessible" => if root.is_accessible() { "accessible" } else { "inaccessible" }.to_owned(),
+                        _ => String::new(),
+                    });
+                    println!("{line}");
                 } else if self.long {
                     root.print_fancy(closure_size, !self.no_size);
                 } else {
                     root.print_concise(closure_size, !self.no_size, max_link_len);
                 }
+
+                if let Some(e) = error {
+                    nfailed += 1;
+                    self.report_size_error(root.link().to_string_lossy().as_ref(), &e);
+                }
             }
         });
 
-        if !self.paths && !self.tsv && !self.no_size {
-            println!();
-            let full_closure = GCRoot::full_closure(&roots);
-            let total_size = GCRoot::full_closure_size(&roots)?;
-            println!("Estimated total size: {} ({} store paths)",
-                FmtSize::new(total_size).to_string().yellow(), full_closure.len());
+        (rows_size, nfailed)
+    }
+
+    /// List the gc roots grouped by store path, returning the sum of the listed sizes and the
+    /// number of failed size calculations
+    fn list_targets(&self, targets: &[Target], print_size: bool) -> (u64, usize) {
+        let sizes: Vec<_> = if print_size && self.unique_sizes {
+            let store_paths: Vec<_> = targets.iter()
+                .map(|t| t.store_path)
+                .collect();
+            StorePath::exclusive_closure_sizes(&store_paths)
+        } else {
+            targets.par_iter()
+                .map(|t| match (print_size, t.store_path) {
+                    (true, Some(store_path)) => Some(store_path.closure_size()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let rows_size = sizes.iter().flatten().flatten().sum();
+        let mut nfailed = 0;
+
+        let max_path_len = targets.iter()
+            .map(|t| t.display_path().len())
+            .max()
+            .unwrap_or(0);
+
+        for (target, closure_size) in targets.iter().zip(sizes) {
+            let (closure_size, error) = match closure_size {
+                Some(Ok(size)) => (Some(size), None),
+                Some(Err(e)) => (None, Some(e)),
+                None => (None, None),
+            };
+            let age = target.age();
+            if self.paths {
+                println!("{}", target.display_path());
+            } else if self.tsv {
+                let mut columns = vec![target.store_path.map(|sp| sp.path().to_string_lossy().to_string())
+                    .unwrap_or_default()];
+                if !self.no_size {
+                    columns.push(closure_size.map(|s| s.to_string()).unwrap_or(String::from("n/a")));
+                }
+                columns.push(age.map(|a| a.as_secs().to_string()).unwrap_or(String::from("n/a")));
+                columns.extend(target.roots.iter().map(|r| r.link().to_string_lossy().to_string()));
+                println!("{}", columns.join("\t"));
+            } else {
+                let size_str = if !self.no_size {
+                    FmtOrNA::mapped(closure_size, FmtSize::new)
+                        .left_pad()
+                } else {
+                    String::new()
+                };
+                let age_str = FmtOrNA::mapped(age, |a| FmtAge::new(a).with_suffix::<4>(" old".to_owned()))
+                    .or_empty()
+                    .right_pad();
+                let age_str = match age {
+                    Some(age) => age_colored(age, &age_str),
+                    None => age_str.bright_blue(),
+                };
+                let path_str = FmtWithEllipsis::fitting_terminal(target.display_path(), max_path_len, 32)
+                    .right_pad();
+
+                println!("{}  {}    {}", path_str, size_str.yellow(), age_str);
+                for root in &target.roots {
+                    println!("{}", format!("  <- {}", root.link().to_string_lossy()).bright_black());
+                }
+            }
+
+            if let Some(e) = error {
+                nfailed += 1;
+                self.report_size_error(&target.display_path(), &e);
+            }
         }
 
-        if !self.paths && !self.tsv {
-            println!();
+        (rows_size, nfailed)
+    }
+
+    /// Show why the size of a row could not be calculated, right below the row
+    fn report_size_error(&self, row: &str, e: &str) {
+        if self.tsv || self.format.is_some() {
+            warn(&format!("Unable to calculate the closure size of {row}: {e}"));
+        } else if !self.paths {
+            println!("{}", format!("  -> unable to calculate closure size: {e}").red());
         }
+    }
+}
 
-        Ok(())
+/// Closure size of a gc root, None if its store path is not accessible
+fn closure_size(root: &GCRoot) -> RowSize {
+    root.store_path().ok()
+        .map(|sp| sp.closure_size())
+}
+
+impl Target<'_> {
+    /// Age of the newest gc root, as the store path is kept alive at least as long as that one
+    fn age(&self) -> Option<Duration> {
+        self.roots.iter()
+            .filter_map(|r| r.age().ok())
+            .min()
+            .cloned()
+    }
+
+    fn display_path(&self) -> String {
+        match self.store_path {
+            Some(store_path) => store_path.path().to_string_lossy().to_string(),
+            None => String::from("<not accessible>"),
+        }
+    }
+}
+
+/// Group gc roots by their store path, keeping the order in which the store paths first appear
+fn group_by_target(roots: &[GCRoot]) -> Vec<Target<'_>> {
+    let mut targets: Vec<Target> = Vec::new();
+    let mut indices: HashMap<&StorePath, usize> = HashMap::default();
+    for root in roots {
+        let store_path = match root.store_path() {
+            Ok(store_path) => store_path,
+            Err(_) => {
+                targets.push(Target { store_path: None, roots: vec![root] });
+                continue;
+            },
+        };
+        match indices.get(store_path) {
+            Some(i) => targets[*i].roots.push(root),
+            None => {
+                indices.insert(store_path, targets.len());
+                targets.push(Target { store_path: Some(store_path), roots: vec![root] });
+            },
+        }
     }
+    targets
 }
@@ -1,6 +1,17 @@
-use std::str::FromStr;
+use std::path;
+use std::time::Duration;
 
+use crate::config::ConfigPreset;
 use crate::nix::profiles::Profile;
+use crate::state::State;
+use crate::utils::duration;
+use crate::utils::fmt::{set_age_thresholds, FmtAge, FmtDate};
+use crate::utils::interaction::{exit_threshold_exceeded, warn};
+use crate::utils::template::Template;
+use crate::utils::users::User;
+
+
+const FORMAT_FIELDS: &[&str] = &["number", "path", "store_path", "name", "revision", "profile", "size", "age", "created", "marker"];
 
 
 #[derive(clap::Args)]
@@ -10,50 +21,204 @@ pub struct GenerationsCommand {
     paths: bool,
 
     /// Present list as tsv
+    ///
+    /// Columns: number, path, store path, closure size (unless --no-size), age in seconds and,
+    /// with --preset, whether the generation would be kept or removed (keep or remove).
     #[clap(long)]
     tsv: bool,
 
+    /// Print each generation according to a template, e.g. '{number}\t{path}'
+    ///
+    /// Fields: {number}, {path}, {store_path}, {name}, {revision}, {profile}, {size} (in bytes),
+    /// {age} (in seconds), {created} (date) and, with --preset, {marker} (keep or remove).
+    /// Literal braces are written as '{{' and '}}'.
+    #[clap(long, value_parser = Template::parse, conflicts_with_all = ["paths", "tsv"])]
+    format: Option<Template>,
+
+    /// Evaluate the clean out criteria of this preset for each generation
+    #[clap(short, long)]
+    preset: Option<String>,
+
+    /// Alternative config file
+    #[clap(short('C'), long)]
+    config: Option<path::PathBuf>,
+
+    /// Show which generations the preset would keep or remove, without removing anything
+    #[clap(long, requires = "preset")]
+    markers: bool,
+
     /// Do not calculate the size of generations
     #[clap(long)]
     no_size: bool,
 
-    /// Profiles to list; valid values: system, user, home, <path_to_profile>
+    /// Show the size of the store paths only referenced by each generation instead of its whole closure
+    ///
+    /// Unlike closure sizes, these do not overlap and add up to at most the estimated total size.
+    #[clap(long, conflicts_with = "no_size")]
+    unique_sizes: bool,
+
+    /// Summarize the packages added, removed and changed compared to the previous generation
+    ///
+    /// Packages are derived from the names of the store paths in the closure of each generation.
+    #[clap(long)]
+    show_changes: bool,
+
+    /// Exit with code 2 if any listed generation is older than WARN_OLDER
+    #[clap(long, value_parser = duration::parse)]
+    warn_older: Option<Duration>,
+
+    /// Exit with an error if the size of any generation cannot be calculated
+    ///
+    /// Otherwise the error is shown for the affected generations and the listing continues.
+    #[clap(long)]
+    strict: bool,
+
+    /// Resolve the user and home profiles of this user instead of the current one
+    ///
+    /// Only root may operate on the profiles of other users.
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Continue with the remaining profiles if some of them cannot be found
+    #[clap(long)]
+    skip_missing: bool,
+
+    /// Leave out profiles matching this name or path pattern (can be given multiple times)
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Profiles to list; valid values: system, user, home, <path_to_profile>, <pattern>
     #[clap(required = true)]
     profiles: Vec<String>,
 }
 
 impl super::Command for GenerationsCommand {
-    fn run(self) -> Result<(), String> {
-        for profile_str in self.profiles {
-            let profile = Profile::from_str(&profile_str)?;
+    fn run(mut self, ctx: &super::Context) -> Result<(), String> {
+        self.no_size = ctx.skip_sizes(self.no_size);
+        self.unique_sizes &= !self.no_size;
+        if let Some(format) = &self.format {
+            format.check_fields(FORMAT_FIELDS)?;
+            if format.uses("marker") && self.preset.is_none() {
+                return Err("The {marker} field requires --preset".to_owned());
+            }
+            self.no_size |= !format.uses("size");
+        }
+        let state = State::load()?;
+        let config = self.preset.as_deref()
+            .map(|p| ConfigPreset::load(p, self.config.as_ref()))
+            .transpose()?;
+        if let Some(config) = &config {
+            set_age_thresholds(config.age_fresh, config.age_stale);
+        }
+        let mut nexceeding = 0;
+        let mut nfailed = 0;
+
+        let user = self.user.as_deref().map(User::select).transpose()?;
+
+        let profiles = Profile::resolve_args(&self.profiles, user.as_ref(), self.skip_missing, &self.exclude)?;
+        let needs_closures = !self.paths && (!self.no_size || (self.show_changes && self.format.is_none()))
+            || config.as_ref().is_some_and(|c| c.remove_larger_than.is_some());
+        if needs_closures {
+            ctx.prefetch_profiles(&profiles);
+        }
+
+        for mut profile in profiles {
+            if let Some(config) = &config {
+                profile.apply_markers(config)?;
+            }
+            profile.apply_pending(&state);
+            if let Some(warn_older) = self.warn_older {
+                nexceeding += profile.generations().iter()
+                    .filter(|g| g.age() > warn_older)
+                    .count();
+            }
 
             if self.paths {
                 for generation in profile.generations() {
                     println!("{}", generation.path().to_string_lossy());
                 }
             } else if self.tsv {
-                for generation in profile.generations() {
+                let exclusive_sizes = (!self.no_size && self.unique_sizes).then(|| profile.exclusive_sizes());
+                for (i, generation) in profile.generations().iter().enumerate() {
                     let num = generation.number();
                     let path = generation.path().to_string_lossy();
                     let store_path = generation.store_path()
                         .map(|sp| sp.path().to_string_lossy().to_string())
                         .unwrap_or_default();
-                    if self.no_size {
-                        println!("{num}\t{path}\t{store_path}");
-                    } else  {
-                        let size = generation.store_path()
-                            .map(|sp| sp.closure_size().to_string())
-                            .unwrap_or_default();
-                        println!("{num}\t{path}\t{store_path}\t{size}");
-
+                    let mut line = format!("{num}\t{path}\t{store_path}");
+                    if !self.no_size {
+                        let size = match &exclusive_sizes {
+                            Some(sizes) => sizes[i].clone(),
+                            None => generation.store_path().and_then(|sp| sp.closure_size()),
+                        };
+                        let size = match size {
+                            Ok(size) => size.to_string(),
+                            Err(e) => {
+                                nfailed += 1;
+                                warn(&format!("Unable to calculate the closure size of {}: {}", path, e));
+                                String::from("n/a")
+                            },
+                        };
+                        line.push_str(&format!("\t{size}"));
+                    }
+                    line.push_str(&format!("\t{}", generation.age().as_secs()));
+                    if config.is_some() {
+                        line.push_str(if generation.marked() { "\tremove" } else { "\tkeep" });
                     }
+                    println!("{line}");
+                }
+            } else if let Some(format) = &self.format {
+                let exclusive_sizes = (!self.no_size && self.unique_sizes).then(|| profile.exclusive_sizes());
+                for (i, generation) in profile.generations().iter().enumerate() {
+                    let line = format.render(|field| match field {
+                        "number" => generation.number().to_string(),
+                        "path" => generation.path().to_string_lossy().to_string(),
+                        "store_path" => generation.store_path()
+                            .map(|sp| sp.path().to_string_lossy().to_string())
+                            .unwrap_or_default(),
+                        "name" => generation.name(),
+                        "revision" => generation.revision().unwrap_or_default(),
+                        "profile" => profile.path().to_string_lossy().to_string(),
+                        "size" => {
+                            let size = match &exclusive_sizes {
+                                Some(sizes) => sizes[i].clone(),
+                                None => generation.store_path().and_then(|sp| sp.closure_size()),
+                            };
+                            match size {
+                                Ok(size) => size.to_string(),
+                                Err(e) => {
+                                    nfailed += 1;
+                                    warn(&format!("Unable to calculate the closure size of {}: {}", generation.path().to_string_lossy(), e));
+                                    String::from("n/a")
+                                },
+                            }
+                        },
+                        "age" => generation.age().as_secs().to_string(),
+                        "created" => FmtDate::new(generation.created()).to_string(),
+                        "marker" => if generation.marked() { "remove" } else { "keep" }.to_owned(),
+                        _ => String::new(),
+                    });
+                    println!("{line}");
                 }
             } else {
-                profile.list_generations(!self.no_size, false);
+                nfailed += profile.list_generations(!self.no_size, self.unique_sizes, self.markers, self.show_changes);
                 println!();
             }
         }
 
+        if nfailed > 0 {
+            let msg = format!("Unable to calculate the size of {nfailed} generations");
+            match self.strict {
+                true => return Err(msg),
+                false => warn(&msg),
+            }
+        }
+
+        if nexceeding > 0 {
+            exit_threshold_exceeded(&format!("{nexceeding} generations are older than {}",
+                FmtAge::new(self.warn_older.unwrap_or_default())));
+        }
+
         Ok(())
     }
 }
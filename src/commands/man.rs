@@ -9,7 +9,7 @@ pub struct ManCommand {
 }
 
 impl super::Command for ManCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
         // export main
         let man = clap_mangen::Man::new(crate::Args::command());
         let mut buffer: Vec<u8> = Default::default();
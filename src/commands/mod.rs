@@ -2,14 +2,94 @@ pub mod add_root;
 pub mod analyze;
 pub mod cleanout;
 pub mod completions;
+pub mod config;
+pub mod delete_paths;
+pub mod dev;
+pub mod drvs;
+pub mod full_clean;
 pub mod gc;
 pub mod gc_roots;
 pub mod generations;
 pub mod man;
 pub mod path_info;
 pub mod tidyup_gc_roots;
+pub mod tidyup_profiles;
 pub mod presets;
+pub mod remove_root;
+pub mod report;
+pub mod resume;
+pub mod verify;
+pub mod watch;
+
+use std::sync::{Arc, Once};
+
+use crate::nix::backend::backend;
+use crate::nix::closures::{self, ClosureCache};
+use crate::nix::profiles::Profile;
+use crate::nix::roots::GCRoot;
+use crate::nix::store::StorePath;
+use crate::utils::interaction::{debug, info};
+
+
+static OFFLINE_SIZES_NOTICE: Once = Once::new();
+
 
 pub trait Command: clap::Args {
-    fn run(self) -> Result<(), String>;
+    fn run(self, ctx: &Context) -> Result<(), String>;
+}
+
+/// State shared by all commands that run as part of one invocation
+///
+/// Commands that call other commands pass it on. Closures are kept in the cache installed by
+/// [`Context::new`], which is shared by the whole process, so closures queried by one command are
+/// not queried again by the next.
+pub struct Context {
+    _private: (),
+}
+
+impl Context {
+    /// Context whose closure cache takes up about `cache_memory` bytes at most
+    pub fn new(cache_memory: u64) -> Result<Self, String> {
+        closures::set_cache(Arc::new(ClosureCache::new(cache_memory)))?;
+        Ok(Context { _private: () })
+    }
+
+    /// Query the closures of all generations of the given profiles at once
+    pub fn prefetch_profiles(&self, profiles: &[Profile]) {
+        let paths: Vec<_> = profiles.iter()
+            .flat_map(|p| p.generations())
+            .flat_map(|g| g.store_path())
+            .collect();
+        self.prefetch(&paths);
+    }
+
+    /// Query the closures of the given gc roots at once
+    pub fn prefetch_roots(&self, roots: &[GCRoot]) {
+        let paths: Vec<_> = roots.iter()
+            .flat_map(|r| r.store_path())
+            .cloned()
+            .collect();
+        self.prefetch(&paths);
+    }
+
+    /// Whether to leave out sizes, as they were not asked for or cannot be calculated offline
+    pub fn skip_sizes(&self, no_size: bool) -> bool {
+        if !no_size && backend().is_offline() {
+            OFFLINE_SIZES_NOTICE.call_once(|| info("Closure sizes are not available offline"));
+            return true;
+        }
+        no_size
+    }
+
+    /// Print hits, misses and evictions of the closure cache
+    pub fn report_cache_stats(&self) {
+        closures::cache().report();
+    }
+
+    fn prefetch(&self, paths: &[StorePath]) {
+        // closures are queried individually later on if this fails
+        if let Err(e) = closures::cache().prefetch(paths) {
+            debug(&format!("unable to prefetch closures: {e}"));
+        }
+    }
 }
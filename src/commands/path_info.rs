@@ -3,19 +3,27 @@ use std::path::PathBuf;
 
 use colored::Colorize;
 
+use crate::utils::files;
 use crate::utils::fmt::*;
 use crate::nix::store::StorePath;
 
 
 #[derive(clap::Args)]
 pub struct PathInfoCommand {
+    /// Also count the targets of symlinks (deduplicated by inode)
+    ///
+    /// This reports how much data is referenced through symlinks in addition to the regular size,
+    /// e.g. for environments built with buildEnv.
+    #[clap(long)]
+    count_symlinks: bool,
+
     /// Paths to get information about
     #[clap(required = true)]
     paths: Vec<PathBuf>,
 }
 
 impl super::Command for PathInfoCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
         for path in &self.paths {
             let metadata = fs::symlink_metadata(path)
                 .map_err(|e| e.to_string())?;
@@ -23,7 +31,7 @@ impl super::Command for PathInfoCommand {
             let closure = store_path.closure()?;
             let size = store_path.size();
             let naive_size = store_path.size_naive();
-            let closure_size = store_path.closure_size();
+            let closure_size = store_path.closure_size()?;
             let naive_closure_size = store_path.closure_size_naive();
 
             println!();
@@ -57,6 +65,16 @@ impl super::Command for PathInfoCommand {
             }
             println!();
 
+            if self.count_symlinks {
+                let symlinks_size = files::dir_size_following_symlinks_all(&[store_path.path().clone()])
+                    .saturating_sub(size);
+                let closure_paths: Vec<_> = closure.iter().map(|sp| sp.path().clone()).collect();
+                let closure_symlinks_size = files::dir_size_following_symlinks_all(&closure_paths)
+                    .saturating_sub(closure_size);
+                println!("  symlink targets: +{}", FmtSize::new(symlinks_size).left_pad().bright_yellow());
+                println!("  closure symlinks:+{}", FmtSize::new(closure_symlinks_size).left_pad().yellow());
+            }
+
             println!("  paths in closure: {:>align$}", closure.len().to_string().bright_blue(), align = FmtSize::MAX_WIDTH);
             println!();
         }
@@ -24,18 +24,21 @@ pub struct PresetsCommand {
 #[derive(clap::Args, Clone)]
 #[group(required = true, multiple = false)]
 pub struct Queries {
+    /// List available presets together with the config files (system, user, custom) defining them
     #[clap(short, long)]
     list: bool,
 
+    /// Show the merged settings of a single preset
     #[clap(short, long)]
     show: Option<String>,
 
+    /// Show the merged settings of all presets
     #[clap(short('a'), long)]
     show_all: bool,
 }
 
 impl super::Command for PresetsCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
 
         if self.queries.list {
             let mut presets: Vec<_> = ConfigPreset::available(self.config.as_ref())?.into_iter().collect();
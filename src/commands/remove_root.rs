@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::{self, Path, PathBuf};
+
+use crate::nix::roots::GCRoot;
+use crate::nix::sandbox;
+use crate::utils::interaction::conclusion;
+
+use super::Command;
+
+
+#[derive(clap::Args)]
+pub struct RemoveRootCommand {
+    /// Names of roots in the gc roots directory of the current user or paths of gc roots
+    ///
+    /// For indirect roots (see `add-root --indirect`) the registration in the auto gc roots directory is removed.
+    /// Only roots in the directories add-root writes to can be removed, other links in the gc roots directory
+    /// (e.g. the one keeping all profiles alive) are left alone.
+    #[clap(required = true)]
+    roots: Vec<String>,
+}
+
+impl Command for RemoveRootCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        let gc_roots_dir = GCRoot::dir();
+        // without a user name the user directory falls back to the gc roots directory itself
+        let removable_dirs: Vec<_> = [GCRoot::user_dir(), GCRoot::auto_dir()].into_iter()
+            .filter(|d| *d != gc_roots_dir)
+            .collect();
+
+        let mut links = Vec::with_capacity(self.roots.len());
+        for root in &self.roots {
+            let link = if root.contains('/') {
+                let path = path::absolute(root)
+                    .map_err(|e| e.to_string())?;
+                if path.starts_with(&gc_roots_dir) {
+                    path
+                } else {
+                    find_indirect_root(&path)
+                        .ok_or(format!("{root} is not a gc root"))?
+                }
+            } else {
+                GCRoot::user_dir().join(root)
+            };
+
+            if !link.is_symlink() {
+                return Err(format!("Could not find gc root {root}"));
+            }
+            if !link.parent().is_some_and(|p| removable_dirs.iter().any(|d| d == p)) {
+                return Err(format!("Refusing to remove {} - only roots of the current user and indirect roots can be removed",
+                    link.to_string_lossy()));
+            }
+            links.push(link);
+        }
+
+        for link in links {
+            fs::remove_file(&link)
+                .map_err(|e| format!("Unable to remove gc root {}: {}", link.to_string_lossy(), e))?;
+            conclusion(&format!("Removed gc root {}", link.to_string_lossy()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Registration of `path` in the auto gc roots directory
+fn find_indirect_root(path: &Path) -> Option<PathBuf> {
+    fs::read_dir(GCRoot::auto_dir()).ok()?
+        .flatten()
+        .map(|e| e.path())
+        .find(|p| fs::read_link(p).is_ok_and(|target| sandbox::remap(&target) == sandbox::remap(path)))
+}
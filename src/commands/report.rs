@@ -0,0 +1,153 @@
+use std::time::{Duration, SystemTime};
+
+use crate::state::State;
+use crate::utils::duration;
+use crate::utils::fmt::{FmtDate, FmtSize};
+
+
+#[derive(clap::Args)]
+pub struct ReportCommand {
+    /// Period to summarize
+    #[clap(long, default_value = "7d", value_parser = duration::parse)]
+    since: Duration,
+
+    /// Output format
+    #[clap(long, value_enum, default_value_t = ReportFormat::Text)]
+    format: ReportFormat,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum ReportFormat {
+    Text,
+    Html,
+}
+
+/// Titled section of the digest with labeled values
+struct Section {
+    title: &'static str,
+    rows: Vec<(&'static str, String)>,
+}
+
+
+impl super::Command for ReportCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        let state = State::load()?;
+        let now = SystemTime::now();
+        let since = now.checked_sub(self.since).unwrap_or(SystemTime::UNIX_EPOCH);
+
+        let title = format!("nix-sweep report {} to {}", FmtDate::new(since), FmtDate::new(now));
+        let sections = [
+            cleanout_section(&state, since),
+            gc_section(&state, since),
+            store_section(&state, since),
+        ];
+
+        match self.format {
+            ReportFormat::Text => print!("{}", render_text(&title, &sections)),
+            ReportFormat::Html => print!("{}", render_html(&title, &sections)),
+        }
+        Ok(())
+    }
+}
+
+fn cleanout_section(state: &State, since: SystemTime) -> Section {
+    let cleanouts = state.cleanouts_since(since);
+    let removed: usize = cleanouts.iter().map(|(_, removed)| removed).sum();
+
+    let mut rows = vec![
+        ("Runs", cleanouts.len().to_string()),
+        ("Generations removed", removed.to_string()),
+    ];
+    if let Some((time, _)) = cleanouts.last() {
+        rows.push(("Last run", FmtDate::new(*time).to_string()));
+    }
+    Section { title: "Clean outs", rows }
+}
+
+fn gc_section(state: &State, since: SystemTime) -> Section {
+    let gcs = state.gcs_since(since);
+    let deleted: usize = gcs.iter().map(|(_, deleted, _)| deleted).sum();
+    let freed: u64 = gcs.iter().map(|(_, _, freed)| freed).sum();
+
+    let mut rows = vec![
+        ("Runs", gcs.len().to_string()),
+        ("Paths deleted", deleted.to_string()),
+        ("Space freed", FmtSize::new(freed).to_string()),
+    ];
+    if let Some((time, _, _)) = gcs.last() {
+        rows.push(("Last run", FmtDate::new(*time).to_string()));
+    }
+    Section { title: "Garbage collections", rows }
+}
+
+fn store_section(state: &State, since: SystemTime) -> Section {
+    let since_secs = since.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let measurements: Vec<_> = state.store_sizes().iter()
+        .filter(|m| m.time >= since_secs)
+        .collect();
+
+    let rows = match (measurements.first(), measurements.last()) {
+        (Some(first), Some(last)) if measurements.len() > 1 => {
+            let growth = if last.size >= first.size {
+                format!("+{}", FmtSize::new(last.size - first.size))
+            } else {
+                format!("-{}", FmtSize::new(first.size - last.size))
+            };
+            vec![
+                ("Size", FmtSize::new(last.size).to_string()),
+                ("Available", FmtSize::new(last.available).to_string()),
+                ("Growth", growth),
+                ("Measurements", measurements.len().to_string()),
+            ]
+        },
+        (Some(last), _) => vec![
+            ("Size", FmtSize::new(last.size).to_string()),
+            ("Available", FmtSize::new(last.available).to_string()),
+            ("Growth", "unknown (only one measurement)".to_owned()),
+        ],
+        _ => vec![
+            ("Size", "unknown (no measurements, run `nix-sweep analyze` to record them)".to_owned()),
+        ],
+    };
+    Section { title: "Store", rows }
+}
+
+fn render_text(title: &str, sections: &[Section]) -> String {
+    let label_width = sections.iter()
+        .flat_map(|s| s.rows.iter())
+        .map(|(label, _)| label.len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut out = format!("{title}\n");
+    for section in sections {
+        out.push_str(&format!("\n{}\n", section.title));
+        for (label, value) in &section.rows {
+            out.push_str(&format!("  {:<label_width$} {}\n", format!("{label}:"), value));
+        }
+    }
+    out
+}
+
+fn render_html(title: &str, sections: &[Section]) -> String {
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", escape_html(title), escape_html(title)));
+    for section in sections {
+        out.push_str(&format!("<h2>{}</h2>\n<table>\n", escape_html(section.title)));
+        for (label, value) in &section.rows {
+            out.push_str(&format!("<tr><th align=\"left\">{}</th><td>{}</td></tr>\n", escape_html(label), escape_html(value)));
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,55 @@
+use std::time::SystemTime;
+
+use crate::job::{self, Job};
+use crate::utils::fmt::FmtAge;
+use crate::utils::interaction::conclusion;
+
+
+#[derive(clap::Args)]
+pub struct ResumeCommand {
+    /// Only show what would be resumed
+    #[clap(long, conflicts_with = "discard")]
+    show: bool,
+
+    /// Forget about the interrupted run instead of resuming it
+    #[clap(long)]
+    discard: bool,
+}
+
+impl ResumeCommand {
+    /// The job to continue, unless only asked to show or discard it
+    pub fn job(&self) -> Result<Option<Job>, String> {
+        if self.show || self.discard {
+            return Ok(None);
+        }
+
+        Job::load()?
+            .map(Some)
+            .ok_or("There is no interrupted run to resume".to_owned())
+    }
+}
+
+impl super::Command for ResumeCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        let job = match Job::load()? {
+            Some(job) => job,
+            None => {
+                conclusion("There is no interrupted run");
+                return Ok(());
+            },
+        };
+
+        if self.discard {
+            job::discard()?;
+            conclusion(&format!("Discarded interrupted run `nix-sweep {}`", job.args().join(" ")));
+            return Ok(());
+        }
+
+        let age = SystemTime::now().duration_since(job.started()).unwrap_or_default();
+        println!("Command:   nix-sweep {}", job.args().join(" "));
+        println!("Started:   {} ago", FmtAge::new(age));
+        println!("Done:      {} profiles or gc roots", job.ndone());
+        println!("Confirmed: {} removals not carried out yet", job.nconfirmed());
+        Ok(())
+    }
+}
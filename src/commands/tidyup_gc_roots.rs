@@ -6,9 +6,13 @@ use colored::Colorize;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use rayon::slice::ParallelSliceMut;
 
+use crate::job::Job;
+use crate::utils::duration;
 use crate::utils::interaction::*;
+use crate::utils::progress::{self, Event};
 use crate::utils::ordered_channel::OrderedChannel;
 use crate::nix::roots::GCRoot;
+use crate::HashMap;
 
 
 #[derive(clap::Args)]
@@ -34,20 +38,34 @@ pub struct TidyupGCRootsCommand {
     exclude_inaccessible: bool,
 
     /// Only show gc roots older than OLDER
-    #[clap(long, value_parser = |s: &str| duration_str::parse_std(s))]
+    #[clap(long, value_parser = duration::parse)]
     older: Option<Duration>,
 
     /// Only show gc roots newer than NEWER
-    #[clap(long, value_parser = |s: &str| duration_str::parse_std(s))]
+    #[clap(long, value_parser = duration::parse)]
     newer: Option<Duration>,
 
     /// Do not calculate the size of generations
     #[clap(long)]
     no_size: bool,
+
+    /// Warn about gc roots whose closure is used by running processes
+    ///
+    /// With --force, such gc roots are skipped instead of removed.
+    #[clap(long)]
+    check_in_use: bool,
+
+    /// Also offer to remove the gc roots of the dev shell nix-sweep is running in
+    ///
+    /// By default these are skipped, as removing them would allow the garbage collector to delete
+    /// the environment of the running shell (detected via DIRENV_DIR or IN_NIX_SHELL).
+    #[clap(long)]
+    include_shell: bool,
 }
 
 impl super::Command for TidyupGCRootsCommand {
-    fn run(self) -> Result<(), String> {
+    fn run(mut self, ctx: &super::Context) -> Result<(), String> {
+        self.no_size = ctx.skip_sizes(self.no_size);
         let mut roots = GCRoot::all(false, false, self.include_missing)?;
         let print_size = !(self.no_size || self.force);
 
@@ -57,7 +75,30 @@ impl super::Command for TidyupGCRootsCommand {
 
         roots = GCRoot::filter_roots(roots, self.include_profiles, self.include_current,
             !self.exclude_inaccessible, self.older, self.newer);
+        if !self.include_shell {
+            roots.retain(|r| {
+                let of_shell = r.is_current_shell();
+                if of_shell {
+                    warn(&format!("Skipping gc root of the current dev shell: {} (pass --include-shell to include it)",
+                        r.link().to_string_lossy()));
+                }
+                !of_shell
+            });
+        }
+
+        // continue an interrupted run without asking for what has been decided already
+        let mut job = Job::begin();
+        roots.retain(|r| !job.is_done(r.link()));
         let nroots_listed = roots.len();
+        let in_use = if self.check_in_use {
+            GCRoot::paths_in_use()?
+        } else {
+            HashMap::default()
+        };
+
+        if print_size {
+            ctx.prefetch_roots(&roots);
+        }
 
         let ordered_channel: OrderedChannel<_> = OrderedChannel::new();
         rayon::join( || {
@@ -70,21 +111,42 @@ impl super::Command for TidyupGCRootsCommand {
                 .for_each(|(i, tup)| ordered_channel.put(i, tup));
         }, || {
             for (root, closure_size) in ordered_channel.iter(nroots_listed) {
-                if !self.force {
+                let confirmed = job.confirmed(root.link()).is_some();
+                if !self.force && !confirmed {
                     root.print_fancy(closure_size, !self.no_size);
                 }
 
+                if root.is_current_shell() {
+                    warn(&format!("{} belongs to the dev shell you are running in", root.link().to_string_lossy()));
+                }
+
+                let used_by = root.used_by(&in_use);
+                let pids: Vec<_> = used_by.iter().map(|pid| pid.to_string()).collect();
+                if !used_by.is_empty() {
+                    warn(&format!("Store path of {} in use by PID {}", root.link().to_string_lossy(), pids.join(", ")));
+                }
+
                 if root.store_path().is_err() {
                     if self.force {
                         warn(&format!("Cannot remove as the path is inaccessible: {}", root.link().to_string_lossy()))
                     } else {
                         ack("Cannot remove as the path is inaccessible");
                     }
-                } else if self.force || ask("Remove gc root?", false) {
+                } else if self.force && !used_by.is_empty() {
+                    warn(&format!("Skipping gc root in use: {}", root.link().to_string_lossy()));
+                } else if self.force || confirmed || ask("Remove gc root?", false) {
+                    if let Err(e) = job.confirm(root.link(), Vec::new()) {
+                        warn(&e);
+                    }
                     if let Err(e) =  fs::remove_file(root.link()) {
                         println!("{}", format!("Error: {e}").red());
                     }
                     println!("-> Removed gc root '{}'", root.link().to_string_lossy());
+                    progress::emit(Event::Item("tidyup-gc-roots", &root.link().to_string_lossy()));
+                }
+
+                if let Err(e) = job.done(root.link()) {
+                    warn(&e);
                 }
             }
         });
@@ -92,6 +154,6 @@ impl super::Command for TidyupGCRootsCommand {
         if !self.force {
             println!();
         }
-        Ok(())
+        job.finish()
     }
 }
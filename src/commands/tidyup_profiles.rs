@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use colored::Colorize;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::nix::profiles::{OrphanedUserDir, Profile};
+use crate::nix::sandbox;
+use crate::utils::duration;
+use crate::utils::fmt::{Formattable, FmtAge, FmtOrNA, FmtSize};
+use crate::utils::interaction::{announce, ask, conclusion, warn};
+
+
+#[derive(clap::Args)]
+pub struct TidyupProfilesCommand {
+    /// Consider profiles abandoned if their newest generation is older than this
+    ///
+    /// Profiles with a single generation whose store path is gone are abandoned regardless of
+    /// their age.
+    #[clap(long, default_value = "1y", value_parser = duration::parse)]
+    older: Duration,
+
+    /// Remove the profile directories of users that no longer exist
+    ///
+    /// Without this, the directories are only listed. Each directory is confirmed separately.
+    #[clap(long)]
+    purge_orphaned_users: bool,
+
+    /// Do not calculate the size of the profiles
+    #[clap(long)]
+    no_size: bool,
+
+    /// Leave out profiles matching this name or path pattern (can be given multiple times)
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+}
+
+impl super::Command for TidyupProfilesCommand {
+    fn run(mut self, ctx: &super::Context) -> Result<(), String> {
+        self.no_size = ctx.skip_sizes(self.no_size);
+        if self.purge_orphaned_users && sandbox::root().is_some() {
+            return Err("--purge-orphaned-users cannot be used with --store-root, as the users of the store root are unknown".to_owned());
+        }
+        let orphaned = OrphanedUserDir::find()?;
+
+        // profiles of removed users are handled as a whole below
+        let abandoned: Vec<_> = Profile::discover(&self.exclude)?
+            .into_iter()
+            .filter(|p| !orphaned.iter().any(|o| p.starts_with(o.path())))
+            .flat_map(Profile::from_path)
+            .filter(|p| p.is_abandoned(self.older))
+            .collect();
+
+        if abandoned.is_empty() && orphaned.is_empty() {
+            conclusion("No abandoned profiles found");
+            return Ok(());
+        }
+
+        if !self.no_size {
+            ctx.prefetch_profiles(&abandoned);
+            for dir in &orphaned {
+                ctx.prefetch_profiles(dir.profiles());
+            }
+        }
+
+        if !abandoned.is_empty() {
+            self.list_abandoned(&abandoned);
+        }
+        if !orphaned.is_empty() {
+            if !abandoned.is_empty() {
+                println!();
+            }
+            self.list_orphaned(&orphaned);
+        }
+
+        let mut nremoved = 0;
+        if !abandoned.is_empty() {
+            println!();
+        }
+        for profile in abandoned {
+            let path = profile.path();
+            let question = format!("Delete profile {} with all of its {} generations?",
+                path.to_string_lossy(), profile.generations().len());
+            if !ask(&question, false) {
+                continue;
+            }
+            match profile.delete() {
+                Ok(()) => {
+                    println!("-> Deleted {}", path.to_string_lossy());
+                    nremoved += 1;
+                },
+                Err(e) => warn(&e),
+            }
+        }
+
+        if self.purge_orphaned_users {
+            println!();
+            for dir in &orphaned {
+                if !ask(&format!("Remove the profiles of removed user '{}'?", dir.user()), false) {
+                    continue;
+                }
+                match dir.remove() {
+                    Ok(()) => {
+                        println!("-> Removed {}", dir.path().to_string_lossy());
+                        nremoved += 1;
+                    },
+                    Err(e) => warn(&e),
+                }
+            }
+        } else if !orphaned.is_empty() {
+            println!("\n{}", "Pass --purge-orphaned-users to remove the profiles of removed users".bright_black());
+        }
+
+        if nremoved > 0 {
+            conclusion("The store paths of the removed profiles are freed by the next garbage collection");
+        }
+
+        Ok(())
+    }
+}
+
+impl TidyupProfilesCommand {
+    fn list_abandoned(&self, abandoned: &[Profile]) {
+        let sizes: Vec<_> = abandoned.par_iter()
+            .map(|p| (!self.no_size).then(|| p.full_closure_size().ok()).flatten())
+            .collect();
+
+        announce(&format!("{} abandoned profiles", abandoned.len()));
+        for (profile, size) in abandoned.iter().zip(&sizes) {
+            let newest = profile.generations().last();
+            print!("{}\t{} generations", profile.path().to_string_lossy(), profile.generations().len());
+            if let Some(newest) = newest {
+                print!("\tlast changed {} ago", FmtAge::new(newest.age()));
+            }
+            if !self.no_size {
+                print!("\t{}", FmtOrNA::mapped(*size, FmtSize::new).left_pad().yellow());
+            }
+            if newest.is_some_and(|g| g.store_path().is_err()) {
+                print!("\t{}", "(store path missing)".red());
+            }
+            println!();
+        }
+    }
+
+    fn list_orphaned(&self, orphaned: &[OrphanedUserDir]) {
+        let sizes: Vec<_> = orphaned.par_iter()
+            .map(|o| (!self.no_size).then(|| o.closure_size().ok()).flatten())
+            .collect();
+
+        announce(&format!("Profile directories of {} removed users", orphaned.len()));
+        for (dir, size) in orphaned.iter().zip(&sizes) {
+            print!("{}\t{} profiles", dir.path().to_string_lossy(), dir.profiles().len());
+            if !self.no_size {
+                print!("\t{}", FmtOrNA::mapped(*size, FmtSize::new).left_pad().yellow());
+            }
+            println!();
+        }
+    }
+}
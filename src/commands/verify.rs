@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::PathBuf;
+
+use colored::Colorize;
+
+use crate::nix::roots::GCRoot;
+use crate::nix::store::{Store, StorePath};
+use crate::utils::interaction::{announce, ask, conclusion, warn};
+
+
+#[derive(clap::Args)]
+pub struct VerifyCommand {
+    /// Only check the existence of paths, not their contents
+    #[clap(long)]
+    no_contents: bool,
+
+    /// Only report problems, do not offer to repair or delete affected paths
+    #[clap(short('n'), long)]
+    non_interactive: bool,
+}
+
+impl super::Command for VerifyCommand {
+    fn run(self, _ctx: &super::Context) -> Result<(), String> {
+        announce("Verifying store");
+        let (corrupted, missing) = Store::verify(!self.no_contents)?;
+
+        announce("Verification results:");
+        println!("Corrupted paths: {}", colored_count(corrupted.len()));
+        for path in &corrupted {
+            println!("  {}", path.to_string_lossy());
+        }
+        println!("Missing paths:   {}", colored_count(missing.len()));
+        for path in &missing {
+            println!("  {}", path.to_string_lossy());
+        }
+
+        if corrupted.is_empty() {
+            conclusion("No corrupted paths found\n");
+            return Ok(());
+        } else if self.non_interactive {
+            println!();
+            return Err(format!("Found {} corrupted store paths", corrupted.len()));
+        }
+
+        for path in corrupted {
+            handle_corrupted(path)?;
+        }
+
+        println!();
+        Ok(())
+    }
+}
+
+fn handle_corrupted(path: PathBuf) -> Result<(), String> {
+    let store_path = StorePath::new(path)?;
+    announce(&format!("Corrupted path {}", store_path.path().to_string_lossy()));
+
+    if ask("Try to repair this path?", true) {
+        match store_path.repair() {
+            Ok(()) => {
+                conclusion("Repaired path");
+                return Ok(());
+            },
+            Err(e) => warn(&e),
+        }
+    }
+
+    // profile generations and the running system have to be removed with cleanout
+    let mut roots = Vec::new();
+    for link in store_path.roots()? {
+        roots.push(GCRoot::new(link)?);
+    }
+    let (protected, removable): (Vec<_>, Vec<_>) = roots.into_iter()
+        .partition(|r| r.is_profile() || r.is_current());
+
+    if !removable.is_empty() {
+        println!("The path is referenced by the following gc roots:");
+        for root in &removable {
+            println!("  {}", root.link().to_string_lossy().bright_black());
+        }
+    }
+    if !protected.is_empty() {
+        println!("The path is kept alive by the following profile generations or system links, which will not be removed:");
+        for root in &protected {
+            println!("  {}", root.link().to_string_lossy().bright_black());
+        }
+        if removable.is_empty() {
+            conclusion("Not touching path (remove the generations with cleanout first)");
+            return Ok(());
+        }
+    }
+
+    let question = match protected.is_empty() {
+        true => "Delete the path and the gc roots referencing it?",
+        false => "Delete the other gc roots referencing the path?",
+    };
+    if !ask(question, false) {
+        conclusion("Not touching path");
+        return Ok(());
+    }
+
+    for root in &removable {
+        let link = root.link();
+        match fs::remove_file(link) {
+            Ok(()) => println!("-> Removed gc root '{}'", link.to_string_lossy()),
+            Err(e) => warn(&format!("Unable to remove gc root '{}': {}", link.to_string_lossy(), e)),
+        }
+    }
+    if !protected.is_empty() {
+        conclusion("Kept path, as it is still referenced by profile generations or system links");
+        return Ok(());
+    }
+    store_path.delete()?;
+    conclusion("Deleted path");
+
+    Ok(())
+}
+
+fn colored_count(n: usize) -> colored::ColoredString {
+    if n == 0 {
+        n.to_string().green()
+    } else {
+        n.to_string().red()
+    }
+}
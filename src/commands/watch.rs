@@ -0,0 +1,103 @@
+use std::path;
+use std::thread;
+use std::time::Duration;
+
+use colored::Colorize;
+
+use crate::config;
+use crate::nix::settings;
+use crate::nix::store::Store;
+use crate::utils::{duration, files};
+use crate::utils::interaction::{announce, warn};
+
+use super::Command;
+use super::cleanout::CleanoutCommand;
+use super::gc::GCCommand;
+
+
+#[derive(clap::Args)]
+pub struct WatchCommand {
+    /// Clean up once the device of the store is filled more than QUOTA%
+    #[clap(short, long, value_parser = parse_percentage)]
+    quota: u64,
+
+    /// Only clean up again after the usage dropped HYSTERESIS% below the quota
+    #[clap(long, default_value_t = 5, value_parser = parse_percentage)]
+    hysteresis: u64,
+
+    /// Time between checks
+    #[clap(long, default_value = "1h", value_parser = duration::parse)]
+    interval: Duration,
+
+    /// Settings for clean out criteria
+    #[clap(short, long, default_value_t = config::DEFAULT_PRESET.to_owned())]
+    preset: String,
+
+    /// Alternative config file
+    #[clap(short('C'), long)]
+    config: Option<path::PathBuf>,
+
+    /// Profiles to clean out; valid values: system, user, home, <path_to_profile>
+    #[clap(default_values_t = ["system".to_owned()])]
+    profiles: Vec<String>,
+}
+
+impl Command for WatchCommand {
+    fn run(self, ctx: &super::Context) -> Result<(), String> {
+        if self.interval.is_zero() {
+            return Err("Interval must not be zero".to_owned());
+        }
+
+        // validate preset before going resident
+        config::ConfigPreset::load(&self.preset, self.config.as_ref())?;
+
+        if let (Some(auto_gc), Ok((total, used, available))) = (settings::auto_gc(), files::fs_usage(Store::dir())) {
+            auto_gc.warn_conflicts(&[total * self.quota / 100], used, available);
+        }
+
+        let rearm_below = self.quota.saturating_sub(self.hysteresis);
+        let mut armed = true;
+
+        loop {
+            let usage = device_usage()?;
+
+            if armed && usage >= self.quota {
+                announce(&format!("Device usage at {usage}% exceeds quota of {}%", self.quota));
+                let cleanout = CleanoutCommand::new(self.preset.clone(), self.config.clone(), self.profiles.clone());
+                if let Err(e) = cleanout.run(ctx) {
+                    warn(&format!("Clean out failed: {e}"));
+                }
+                if let Err(e) = GCCommand::new(false, false, None, None, false, None).run(ctx) {
+                    warn(&format!("Garbage collection failed: {e}"));
+                }
+
+                let usage = device_usage()?;
+                eprintln!("{}", format!("Device usage is now at {usage}%").bright_black());
+                armed = usage < rearm_below;
+            } else if !armed && usage < rearm_below {
+                eprintln!("{}", format!("Device usage at {usage}%, watching for quota of {}% again", self.quota).bright_black());
+                armed = true;
+            }
+
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+fn device_usage() -> Result<u64, String> {
+    let (total, available) = files::fs_space(Store::dir())?;
+    if total == 0 {
+        return Err("Unable to determine size of the store device".to_owned());
+    }
+    Ok((total - available) * 100 / total)
+}
+
+fn parse_percentage(s: &str) -> Result<u64, String> {
+    let n: u64 = s.strip_suffix('%').unwrap_or(s)
+        .parse()
+        .map_err(|_| format!("'{s}' is not a valid percentage"))?;
+    if n >= 100 {
+        return Err(format!("'{s}' is not a valid percentage (must be below 100%)"));
+    }
+    Ok(n)
+}
@@ -1,23 +1,101 @@
 use std::fs;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 use std::time::Duration;
 
 use clap::Parser;
 use duration_str::HumanFormat;
 use serde::{Deserialize, Serialize};
 
+use crate::utils::calendar::LocalDate;
+use crate::utils::duration;
+use crate::utils::interaction::warn;
 use crate::HashMap;
 
 
-const SYSTEM_CONFIG: &str = "/etc/nix-sweep/presets.toml";
-const APP_PREFIX: &str = "nix-sweep";
-const CONFIG_FILENAME: &str = "presets.toml";
+const SYSTEM_CONFIG_DIR: &str = "/etc/nix-sweep";
+pub const APP_PREFIX: &str = "nix-sweep";
+/// Names of the preset files, in TOML or JSON (e.g. as generated with `builtins.toJSON`)
+const CONFIG_FILENAMES: &[&str] = &["presets.toml", "presets.json"];
 pub const DEFAULT_PRESET: &str = "default";
 
+/// Keys that have been renamed, as (old name, new name)
+///
+/// Old names are still accepted with a warning, both at the top level and in presets.
+/// `nix-sweep config migrate` rewrites them in a preset file.
+pub const RENAMED_KEYS: &[(&str, &str)] = &[];
+
+static GLOBAL_CONFIG: OnceLock<Result<GlobalConfig, String>> = OnceLock::new();
+static LEGACY_KEYS_REPORTED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
 
 #[derive(Debug, Deserialize, Default)]
-pub struct ConfigFile(HashMap<String, ConfigPreset>);
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Additional directories to search for profiles
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    profile_dirs: Vec<PathBuf>,
+
+    /// Number of worker threads
+    threads: Option<usize>,
+
+    /// Niceness to run with
+    nice: Option<i32>,
+
+    /// IO priority to run with
+    ionice: Option<IoPriority>,
+
+    /// Which part of long paths to cut off
+    truncate: Option<Truncation>,
+
+    /// Show sizes in decimal instead of binary units
+    si: Option<bool>,
+
+    /// Memory to use for caching closures at most
+    #[serde(default, deserialize_with = "deserialize_option_size")]
+    cache_memory: Option<u64>,
+
+    #[serde(flatten)]
+    presets: HashMap<String, ConfigPreset>,
+}
+
+/// Settings of the system and user config files, that are not specific to a preset
+#[derive(Debug, Default)]
+pub struct GlobalConfig {
+    pub profile_dirs: Vec<PathBuf>,
+    pub threads: Option<usize>,
+    pub nice: Option<i32>,
+    pub ionice: Option<IoPriority>,
+    pub truncate: Option<Truncation>,
+    pub si: Option<bool>,
+    pub cache_memory: Option<u64>,
+}
+
+/// IO scheduling priorities nix-sweep can lower itself to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoPriority {
+    /// Only access the disk when no other program needs it
+    Idle,
+
+    /// Lowest priority of the default (best-effort) scheduling class
+    Low,
+}
+
+/// Part of a string that is replaced by an ellipsis if it does not fit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Truncation {
+    /// Keep the end (e.g. "...er/home-manager/gcroots")
+    #[default]
+    Head,
+
+    /// Keep the beginning and the end
+    Middle,
+
+    /// Keep the beginning
+    Tail,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, Parser)]
 #[serde(rename_all = "kebab-case")]
@@ -37,17 +115,96 @@ pub struct ConfigPreset {
     /// Keep all generations newer than this many days
     ///
     /// Pass 0 to unset this option.
-    #[clap(long, value_parser = |s: &str| duration_str::parse_std(s))]
-    #[serde(default, deserialize_with = "duration_str::deserialize_option_duration", serialize_with = "serialize_option_duration")]
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
     pub keep_newer: Option<Duration>,
 
     /// Discard all generations older than this many days
     ///
+    /// Whole days (including months and years) are counted in local calendar days, so "7d" removes
+    /// generations created before midnight seven days ago.
     /// Pass 0 to unset this option.
-    #[clap(long, value_parser = |s: &str| duration_str::parse_std(s))]
-    #[serde(default, deserialize_with = "duration_str::deserialize_option_duration", serialize_with = "serialize_option_duration")]
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
     pub remove_older: Option<Duration>,
 
+    /// Always keep the PROTECT_PREVIOUS generations right before the active one
+    ///
+    /// These are the rollback targets after a bad deployment, which are kept regardless of all other
+    /// criteria. Pass 0 to unset this option.
+    #[clap(long)]
+    pub protect_previous: Option<usize>,
+
+    /// Keep all generations created on or after this date (e.g. "2024-01-01" or "2024-01-01 12:00")
+    ///
+    /// Pass 0 to unset this option.
+    #[clap(long, value_parser = LocalDate::parse)]
+    pub keep_since: Option<LocalDate>,
+
+    /// Discard all generations created before this date (e.g. "2023-06-01" or "2023-06-01 12:00")
+    ///
+    /// Without a time of day, generations are compared by local calendar day. Pass 0 to unset this option.
+    #[clap(long, value_parser = LocalDate::parse)]
+    pub remove_before: Option<LocalDate>,
+
+    /// Keep the newest generation of each of the last KEEP_DAILY days that have generations
+    ///
+    /// Days start at midnight in the local time zone. Pass 0 to unset this option.
+    #[clap(long)]
+    pub keep_daily: Option<usize>,
+
+    /// Keep the newest generation of each of the last KEEP_WEEKLY (ISO) weeks that have generations
+    ///
+    /// Pass 0 to unset this option.
+    #[clap(long)]
+    pub keep_weekly: Option<usize>,
+
+    /// Keep the newest generation of each of the last KEEP_MONTHLY months that have generations
+    ///
+    /// Pass 0 to unset this option.
+    #[clap(long)]
+    pub keep_monthly: Option<usize>,
+
+    /// Defer removal of generations until they have been marked for at least this long
+    ///
+    /// Marked generations are recorded as pending and only removed on a subsequent run once the
+    /// deferral period has passed and they still match the clean out criteria.
+    /// Pass 0 to unset this option.
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
+    pub defer: Option<Duration>,
+
+    /// Show ages younger than this in green
+    ///
+    /// Defaults to one week. Pass 0 to unset this option.
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
+    pub age_fresh: Option<Duration>,
+
+    /// Show ages older than this in red (ages in between are shown in yellow)
+    ///
+    /// Defaults to 30 days. Pass 0 to unset this option.
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
+    pub age_stale: Option<Duration>,
+
+    /// Remove generations whose closure is larger than this (e.g. "10GiB")
+    ///
+    /// This applies regardless of the age of a generation, but never removes the newest or the active one.
+    /// Pass 0 to unset this option.
+    #[clap(long, value_parser = parse_size, value_name = "SIZE")]
+    #[serde(default, deserialize_with = "deserialize_option_size")]
+    pub remove_larger_than: Option<u64>,
+
+    /// Only remove as many of the marked generations as needed to free at least this much (e.g. "20GiB")
+    ///
+    /// Generations holding the most store paths on their own are removed first, instead of the
+    /// oldest ones. The freed size is an estimate, as other gc roots are not taken into account.
+    /// Pass 0 to unset this option.
+    #[clap(long, value_parser = parse_size, value_name = "SIZE")]
+    #[serde(default, deserialize_with = "deserialize_option_size")]
+    pub free_at_least: Option<u64>,
+
     /// Remove these specific generations
     ///
     /// You can pass the option multiple times to remove multiple generations.
@@ -78,16 +235,139 @@ pub struct ConfigPreset {
 
     /// Collect just as much garbage as to match --gc-bigger or --gc-quota
     #[clap(long)]
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialize_null_default")]
     pub gc_modest: bool,
+
+    /// Do not start removing further generations or store paths once this time budget is used up
+    ///
+    /// The removal in progress is finished and what remains is reported.
+    /// The budget covers the whole run including garbage collection. Pass 0 to unset this option.
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
+    pub timeout: Option<Duration>,
+
+    /// Shell command to run before generations of a profile are removed
+    ///
+    /// The profile is passed in NIX_SWEEP_PROFILE and the generations to be removed in NIX_SWEEP_GENERATIONS.
+    #[clap(long)]
+    pub pre_cleanout: Option<String>,
+
+    /// Shell command to run after generations of a profile have been removed
+    ///
+    /// Receives the same environment variables as --pre-cleanout.
+    #[clap(long)]
+    pub post_cleanout: Option<String>,
+
+    /// Kill hooks and policy scripts that run longer than this
+    ///
+    /// Pass 0 to unset this option.
+    #[clap(long, value_parser = duration::parse)]
+    #[serde(default, deserialize_with = "duration::deserialize_option", serialize_with = "serialize_option_duration")]
+    pub hook_timeout: Option<Duration>,
+
+    /// What to do if a hook fails or times out [default: abort]
+    #[clap(long, value_enum)]
+    pub hook_failure: Option<HookFailure>,
+
+    /// Executable that decides which generations to remove
+    ///
+    /// The script receives the generations of a profile as JSON on stdin and prints a JSON array with
+    /// the indices of the generations to remove. The newest and the active generation are never removed.
+    #[clap(long)]
+    pub policy_script: Option<PathBuf>,
+
+    /// Snapshot the file system containing the Nix store before removing generations or collecting garbage
+    #[clap(long, value_enum)]
+    pub snapshot: Option<SnapshotKind>,
+
+    /// Subvolume (btrfs) or dataset (zfs) to snapshot instead of the one containing the Nix store
+    #[clap(long)]
+    pub snapshot_target: Option<String>,
+
+    /// Only retain this many snapshots created by nix-sweep
+    ///
+    /// Pass 0 to unset this option.
+    #[clap(long)]
+    pub snapshot_keep: Option<usize>,
+}
+
+/// Reaction to failing cleanout hooks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookFailure {
+    /// Stop the clean out (a failing pre-cleanout hook prevents the removal of generations)
+    Abort,
+
+    /// Print a warning and continue
+    Warn,
+
+    /// Continue silently
+    Ignore,
+}
+
+/// File systems nix-sweep can create snapshots on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotKind {
+    Btrfs,
+    Zfs,
+}
+
+/// Key of a preset file that is still used with its old name, see [RENAMED_KEYS]
+#[derive(Debug)]
+pub struct LegacyKey {
+    /// Preset containing the key, or `None` for top-level keys
+    pub preset: Option<String>,
+    pub old: &'static str,
+    pub new: &'static str,
 }
 
 impl ConfigFile {
-    fn from_str(s: &str) -> Result<Self, String> {
-        let config: Self = toml::from_str(s)
+    fn from_str(s: &str) -> Result<(Self, Vec<LegacyKey>), String> {
+        let mut table: toml::Table = toml::from_str(s)
             .map_err(|e| e.to_string())?;
+        let legacy = migrate_keys(&mut table, RENAMED_KEYS)?;
+
+        // deserializing the string itself gives error messages pointing at the location in the file
+        let config: Self = if legacy.is_empty() {
+            toml::from_str(s)
+        } else {
+            toml::Value::Table(table).try_into()
+        }.map_err(|e| e.to_string())?;
+        Ok((config.validate()?, legacy))
+    }
+
+    /// Parse a JSON preset file, in which `null` leaves an option unset
+    fn from_json(s: &str) -> Result<(Self, Vec<LegacyKey>), String> {
+        let mut value: serde_json::Value = serde_json::from_str(s)
+            .map_err(|e| e.to_string())?;
+        let legacy = match value.as_object_mut() {
+            Some(object) => migrate_json_keys(object, RENAMED_KEYS)?,
+            None => Vec::new(),
+        };
+
+        let config: Self = if legacy.is_empty() {
+            serde_json::from_str(s)
+        } else {
+            serde_json::from_value(value)
+        }.map_err(|e| e.to_string())?;
+        Ok((config.validate()?, legacy))
+    }
 
-        for (preset_name, preset_config) in &config.0 {
+    fn validate(self) -> Result<Self, String> {
+        if let Some(dir) = self.profile_dirs.iter().find(|d| !d.is_absolute()) {
+            return Err(format!("Invalid profile directory '{}' - must be an absolute path", dir.to_string_lossy()));
+        }
+
+        if self.threads == Some(0) {
+            return Err("Invalid number of threads - must be at least 1".to_owned());
+        }
+
+        if self.nice.is_some_and(|n| !(0..=19).contains(&n)) {
+            return Err("Invalid niceness - must be between 0 and 19".to_owned());
+        }
+
+        for (preset_name, preset_config) in &self.presets {
             if !preset_name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
                 return Err(format!("Invalid preset name '{preset_name}' - must only contain alphanumeric characters, dashes and underscores"));
             }
@@ -95,13 +375,62 @@ impl ConfigFile {
             preset_config.validate()?;
         }
 
-        Ok(config)
+        Ok(self)
     }
 
+    /// Read a preset file, which is parsed as JSON if it has a `.json` extension and as TOML otherwise
     pub fn read_config_file(path: &PathBuf) -> Result<ConfigFile, String> {
         let s = fs::read_to_string(path)
             .map_err(|e| e.to_string())?;
-        Self::from_str(&s)
+        let is_json = path.extension().is_some_and(|e| e == "json");
+        let (config, legacy) = match is_json {
+            true => Self::from_json(&s),
+            false => Self::from_str(&s),
+        }.map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+
+        // config files are read several times per run, but should only be complained about once
+        let reported = LEGACY_KEYS_REPORTED.lock()
+            .map(|mut r| r.contains(path) || { r.push(path.clone()); false })
+            .unwrap_or(true);
+        if !reported {
+            for key in &legacy {
+                warn(&format!("{}: {}", path.to_string_lossy(), key));
+            }
+            if !legacy.is_empty() && !is_json {
+                warn(&format!("Run `nix-sweep config migrate --config {}` to update the file", path.to_string_lossy()));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Path of the preset file of the user, if there is one
+    pub fn user_config_file() -> Result<Option<PathBuf>, String> {
+        let dir = xdg::BaseDirectories::with_prefix(APP_PREFIX)
+            .get_config_home()
+            .ok_or(String::from("Unable to open config file"))?;
+        Self::find_config_in(&dir)
+    }
+
+    /// Path of the preset file in `dir`, either presets.toml or presets.json
+    fn find_config_in(dir: &Path) -> Result<Option<PathBuf>, String> {
+        let existing: Vec<_> = CONFIG_FILENAMES.iter()
+            .map(|f| dir.join(f))
+            .filter(|p| p.exists())
+            .collect();
+        match existing.as_slice() {
+            [] => Ok(None),
+            [path] => Ok(Some(path.clone())),
+            _ => Err(format!("Found both presets.toml and presets.json in {} - please only use one of them",
+                dir.to_string_lossy())),
+        }
+    }
+
+    fn get_config_in(dir: &Path) -> Result<Option<ConfigFile>, String> {
+        match Self::find_config_in(dir)? {
+            Some(path) => Self::get_config(&path),
+            None => Ok(None),
+        }
     }
 
     fn get_config(path: &PathBuf) -> Result<Option<ConfigFile>, String> {
@@ -114,24 +443,62 @@ impl ConfigFile {
     }
 
     fn get_system_config() -> Result<Option<ConfigFile>, String> {
-        let path = PathBuf::from_str(SYSTEM_CONFIG)
-            .map_err(|e| e.to_string())?;
-        Self::get_config(&path)
+        Self::get_config_in(Path::new(SYSTEM_CONFIG_DIR))
     }
 
     fn get_user_config() -> Result<Option<ConfigFile>, String> {
-        xdg::BaseDirectories::with_prefix(APP_PREFIX)
-            .get_config_file(CONFIG_FILENAME)
-            .ok_or(String::from("Unable to open config file"))
-            .and_then(|d| Self::get_config(&d))
+        match Self::user_config_file()? {
+            Some(path) => Self::get_config(&path),
+            None => Ok(None),
+        }
     }
 
     fn get_preset(&self, s: &str) -> Option<&ConfigPreset> {
-        self.0.get(s)
+        self.presets.get(s)
     }
 
     fn presets(&self) -> &HashMap<String, ConfigPreset> {
-        &self.0
+        &self.presets
+    }
+}
+
+impl GlobalConfig {
+    pub fn get() -> Result<&'static GlobalConfig, String> {
+        GLOBAL_CONFIG.get_or_init(Self::load)
+            .as_ref()
+            .map_err(|e| e.clone())
+    }
+
+    fn load() -> Result<Self, String> {
+        let mut global = GlobalConfig::default();
+
+        for config in [ConfigFile::get_system_config()?, ConfigFile::get_user_config()?].into_iter().flatten() {
+            for dir in config.profile_dirs {
+                if !global.profile_dirs.contains(&dir) {
+                    global.profile_dirs.push(dir);
+                }
+            }
+            if config.threads.is_some() {
+                global.threads = config.threads;
+            }
+            if config.nice.is_some() {
+                global.nice = config.nice;
+            }
+            if config.ionice.is_some() {
+                global.ionice = config.ionice;
+            }
+            if config.truncate.is_some() {
+                global.truncate = config.truncate;
+            }
+            if config.si.is_some() {
+                global.si = config.si;
+            }
+            if config.cache_memory.is_some() {
+                global.cache_memory = config.cache_memory;
+            }
+        }
+
+        Ok(global)
     }
 }
 
@@ -213,7 +580,7 @@ impl ConfigPreset {
                 None => continue,
             };
 
-            for (preset_name, preset_config) in config.0 {
+            for (preset_name, preset_config) in config.presets {
                 if let Some(prev) = final_config.get_mut(&preset_name) {
                     *prev = prev.override_with(&preset_config);
                 } else {
@@ -240,6 +607,28 @@ impl ConfigPreset {
                 return Err("Invalid configuration - keep-newer is greater than remove-older".to_owned());
             }
 
+        if let Some(quota) = self.gc_quota
+            && quota >= 100 {
+                return Err("Invalid configuration - gc-quota must be below 100%".to_owned());
+            }
+
+        if let (Some(fresh), Some(stale)) = (self.age_fresh, self.age_stale)
+            && fresh > stale {
+                return Err("Invalid configuration - age-fresh is greater than age-stale".to_owned());
+            }
+
+        Ok(())
+    }
+
+    /// Check the gc settings of a merged preset
+    ///
+    /// This is separate from [validate](Self::validate) as the gc thresholds and --gc-modest may
+    /// come from different presets.
+    pub fn validate_gc(&self) -> Result<(), String> {
+        if self.gc == Some(true) && self.gc_modest && self.gc_bigger.is_none() && self.gc_quota.is_none() {
+            return Err("Invalid configuration - gc-modest requires gc-bigger or gc-quota".to_owned());
+        }
+
         Ok(())
     }
 
@@ -272,6 +661,111 @@ impl ConfigPreset {
             (Some(val), None) => Some(val),
         };
 
+        let protect_previous = match (self.protect_previous, other.protect_previous) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let keep_since = match (self.keep_since, other.keep_since) {
+            (None, None) => None,
+            (_, Some(date)) if date.is_unset() => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let remove_before = match (self.remove_before, other.remove_before) {
+            (None, None) => None,
+            (_, Some(date)) if date.is_unset() => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let keep_daily = match (self.keep_daily, other.keep_daily) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let keep_weekly = match (self.keep_weekly, other.keep_weekly) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let keep_monthly = match (self.keep_monthly, other.keep_monthly) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let defer = match (self.defer, other.defer) {
+            (None, None) => None,
+            (_, Some(Duration::ZERO)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let age_fresh = match (self.age_fresh, other.age_fresh) {
+            (None, None) => None,
+            (_, Some(Duration::ZERO)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let age_stale = match (self.age_stale, other.age_stale) {
+            (None, None) => None,
+            (_, Some(Duration::ZERO)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let remove_larger_than = match (self.remove_larger_than, other.remove_larger_than) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let free_at_least = match (self.free_at_least, other.free_at_least) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let hook_timeout = match (self.hook_timeout, other.hook_timeout) {
+            (None, None) => None,
+            (_, Some(Duration::ZERO)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let timeout = match (self.timeout, other.timeout) {
+            (None, None) => None,
+            (_, Some(Duration::ZERO)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
+        let pre_cleanout = other.pre_cleanout.clone().or(self.pre_cleanout.clone());
+        let post_cleanout = other.post_cleanout.clone().or(self.post_cleanout.clone());
+        let hook_failure = other.hook_failure.or(self.hook_failure);
+        let policy_script = other.policy_script.clone().or(self.policy_script.clone());
+
+        let snapshot = other.snapshot.or(self.snapshot);
+        let snapshot_target = other.snapshot_target.clone().or(self.snapshot_target.clone());
+        let snapshot_keep = match (self.snapshot_keep, other.snapshot_keep) {
+            (None, None) => None,
+            (_, Some(0)) => None,
+            (_, Some(val)) => Some(val),
+            (Some(val), None) => Some(val),
+        };
+
         let interactive = match (self.interactive, other.interactive) {
             (None, None) => None,
             (_, Some(val)) => Some(val),
@@ -321,9 +815,14 @@ impl ConfigPreset {
         let gc_modest = self.gc_modest || other.gc_modest;
 
         ConfigPreset {
-            keep_min, keep_max, keep_newer, remove_older,
+            keep_min, keep_max, keep_newer, remove_older, defer,
+            keep_since, remove_before, protect_previous,
+            keep_daily, keep_weekly, keep_monthly,
+            age_fresh, age_stale, remove_larger_than, free_at_least,
             interactive, _non_interactive: None,
-            gc, gc_bigger, gc_quota, gc_modest,
+            gc, gc_bigger, gc_quota, gc_modest, timeout,
+            pre_cleanout, post_cleanout, hook_timeout, hook_failure, policy_script,
+            snapshot, snapshot_target, snapshot_keep,
             generations: other.generations.clone(),
         }
     }
@@ -342,12 +841,32 @@ impl ConfigPreset {
             keep_max: if let Some(0) = self.keep_max { None } else { self.keep_max },
             keep_newer: if let Some(Duration::ZERO) = self.keep_newer { None } else { self.keep_newer },
             remove_older: if let Some(Duration::ZERO) = self.remove_older { None } else { self.remove_older },
+            keep_since: self.keep_since.filter(|d| !d.is_unset()),
+            remove_before: self.remove_before.filter(|d| !d.is_unset()),
+            protect_previous: if let Some(0) = self.protect_previous { None } else { self.protect_previous },
+            keep_daily: if let Some(0) = self.keep_daily { None } else { self.keep_daily },
+            keep_weekly: if let Some(0) = self.keep_weekly { None } else { self.keep_weekly },
+            keep_monthly: if let Some(0) = self.keep_monthly { None } else { self.keep_monthly },
+            defer: if let Some(Duration::ZERO) = self.defer { None } else { self.defer },
+            age_fresh: if let Some(Duration::ZERO) = self.age_fresh { None } else { self.age_fresh },
+            age_stale: if let Some(Duration::ZERO) = self.age_stale { None } else { self.age_stale },
+            remove_larger_than: if let Some(0) = self.remove_larger_than { None } else { self.remove_larger_than },
+            free_at_least: if let Some(0) = self.free_at_least { None } else { self.free_at_least },
             interactive: self.interactive,
             _non_interactive: None,
             gc: self.gc,
             gc_bigger: if let Some(0) = self.gc_bigger { None } else { self.gc_bigger },
             gc_quota: if let Some(0) = self.gc_quota { None } else { self.gc_quota },
             gc_modest: self.gc_modest,
+            timeout: if let Some(Duration::ZERO) = self.timeout { None } else { self.timeout },
+            pre_cleanout: self.pre_cleanout.clone(),
+            post_cleanout: self.post_cleanout.clone(),
+            hook_timeout: if let Some(Duration::ZERO) = self.hook_timeout { None } else { self.hook_timeout },
+            hook_failure: self.hook_failure,
+            policy_script: self.policy_script.clone(),
+            snapshot: self.snapshot,
+            snapshot_target: self.snapshot_target.clone(),
+            snapshot_keep: if let Some(0) = self.snapshot_keep { None } else { self.snapshot_keep },
             generations: self.generations.clone(),
         }
     }
@@ -360,18 +879,156 @@ impl Default for ConfigPreset {
             keep_max: None,
             keep_newer: None,
             remove_older: None,
+            keep_since: None,
+            remove_before: None,
+            protect_previous: None,
+            keep_daily: None,
+            keep_weekly: None,
+            keep_monthly: None,
+            defer: None,
+            age_fresh: None,
+            age_stale: None,
+            remove_larger_than: None,
+            free_at_least: None,
             interactive: None,
             _non_interactive: None,
             gc: None,
             gc_bigger: None,
             gc_quota: None,
             gc_modest: false,
+            timeout: None,
+            pre_cleanout: None,
+            post_cleanout: None,
+            hook_timeout: None,
+            hook_failure: None,
+            policy_script: None,
+            snapshot: None,
+            snapshot_target: None,
+            snapshot_keep: None,
             generations: Vec::default(),
         }
     }
 }
 
 
+impl std::fmt::Display for LegacyKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.preset {
+            Some(preset) => write!(f, "'{}.{}' has been renamed to '{}'", preset, self.old, self.new),
+            None => write!(f, "'{}' has been renamed to '{}'", self.old, self.new),
+        }
+    }
+}
+
+
+/// Keys that have been renamed, as (old name, new name)
+type Renames = &'static [(&'static str, &'static str)];
+
+/// Rename keys that still use their old name, at the top level and in the presets of `table`
+fn migrate_keys(table: &mut toml::Table, renames: Renames) -> Result<Vec<LegacyKey>, String> {
+    let mut legacy = rename_keys(table, None, renames)?;
+    for (name, value) in table.iter_mut() {
+        if let toml::Value::Table(preset) = value {
+            legacy.extend(rename_keys(preset, Some(name), renames)?);
+        }
+    }
+    Ok(legacy)
+}
+
+/// Same as [migrate_keys] for the top-level object of a JSON preset file
+fn migrate_json_keys(object: &mut serde_json::Map<String, serde_json::Value>, renames: Renames) -> Result<Vec<LegacyKey>, String> {
+    let mut legacy = rename_json_keys(object, None, renames)?;
+    for (name, value) in object.iter_mut() {
+        if let serde_json::Value::Object(preset) = value {
+            legacy.extend(rename_json_keys(preset, Some(name), renames)?);
+        }
+    }
+    Ok(legacy)
+}
+
+/// Rename keys that still use their old name in the text of a TOML preset file
+///
+/// Comments, formatting and the order of keys are preserved. Returns the new text together with
+/// the renamed keys.
+pub fn migrate_toml(s: &str, renames: Renames) -> Result<(String, Vec<LegacyKey>), String> {
+    let mut document: toml_edit::DocumentMut = s.parse()
+        .map_err(|e: toml_edit::TomlError| e.to_string())?;
+    let mut legacy = rename_document_keys(document.as_table_mut(), None, renames)?;
+    for (name, item) in document.as_table_mut().iter_mut() {
+        let name = name.get().to_owned();
+        if let Some(preset) = item.as_table_like_mut() {
+            legacy.extend(rename_document_keys(preset, Some(&name), renames)?);
+        }
+    }
+    Ok((document.to_string(), legacy))
+}
+
+fn rename_keys(table: &mut toml::Table, preset: Option<&str>, renames: Renames) -> Result<Vec<LegacyKey>, String> {
+    let legacy = legacy_keys(preset, renames, |key| table.get(key).map(|v| v.is_table()))?;
+    for key in &legacy {
+        if let Some(value) = table.remove(key.old) {
+            table.insert(key.new.to_owned(), value);
+        }
+    }
+    Ok(legacy)
+}
+
+fn rename_json_keys(object: &mut serde_json::Map<String, serde_json::Value>, preset: Option<&str>, renames: Renames) -> Result<Vec<LegacyKey>, String> {
+    let legacy = legacy_keys(preset, renames, |key| object.get(key).map(|v| v.is_object()))?;
+    for key in &legacy {
+        if let Some(value) = object.remove(key.old) {
+            object.insert(key.new.to_owned(), value);
+        }
+    }
+    Ok(legacy)
+}
+
+fn rename_document_keys(table: &mut dyn toml_edit::TableLike, preset: Option<&str>, renames: Renames) -> Result<Vec<LegacyKey>, String> {
+    let legacy = legacy_keys(preset, renames, |key| table.get(key).map(|i| i.is_table_like()))?;
+    if legacy.is_empty() {
+        return Ok(legacy);
+    }
+
+    // new keys are appended, so all keys are inserted again to keep their order
+    let keys: Vec<_> = table.iter().map(|(k, _)| k.to_owned()).collect();
+    for key in keys {
+        let name = legacy.iter().find(|l| l.old == key).map(|l| l.new).unwrap_or(&key);
+        let decor = table.key(&key).map(|k| (k.leaf_decor().clone(), k.dotted_decor().clone()));
+        let item = match table.remove(&key) {
+            Some(item) => item,
+            None => continue,
+        };
+        table.insert(name, item);
+        if let (Some(mut new_key), Some((leaf, dotted))) = (table.key_mut(name), decor) {
+            *new_key.leaf_decor_mut() = leaf;
+            *new_key.dotted_decor_mut() = dotted;
+        }
+    }
+    Ok(legacy)
+}
+
+/// Keys of a table that have to be renamed
+///
+/// `lookup` tells whether a key is set and whether its value is a table.
+fn legacy_keys(preset: Option<&str>, renames: Renames, lookup: impl Fn(&str) -> Option<bool>) -> Result<Vec<LegacyKey>, String> {
+    let mut legacy = Vec::new();
+    for (old, new) in renames {
+        match lookup(old) {
+            None => continue,
+            // at the top level, tables are presets, which may be named like an old key
+            Some(true) if preset.is_none() => continue,
+            Some(_) => (),
+        }
+
+        let key = LegacyKey { preset: preset.map(|p| p.to_owned()), old, new };
+        if lookup(new).is_some() {
+            return Err(format!("{key}, but both names are set - please remove '{old}'"));
+        }
+        legacy.push(key);
+    }
+    Ok(legacy)
+}
+
 fn serialize_option_duration<S>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -383,3 +1040,222 @@ where
 
 }
 
+/// Parse sizes like "10GiB", "500 MB" or "1024"
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse()
+        .map_err(|_| format!("Invalid size '{s}'"))?;
+
+    let factor: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "tb" => 1000 * 1000 * 1000 * 1000,
+        unit => return Err(format!("Unknown size unit '{unit}'")),
+    };
+
+    Ok((number * factor as f64) as u64)
+}
+
+fn deserialize_null_default<'de, D, T>(d: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(d).map(Option::unwrap_or_default)
+}
+
+fn deserialize_option_size<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum SizeValue {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<SizeValue>::deserialize(d)? {
+        Some(SizeValue::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(SizeValue::Text(s)) => parse_size(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_matches_toml() {
+        let (toml, _) = ConfigFile::from_str(r#"
+            threads = 2
+            cache-memory = "1GiB"
+            [weekly]
+            keep-min = 3
+            remove-older = "30d"
+            gc-bigger = 50
+        "#).unwrap();
+        let (json, _) = ConfigFile::from_json(r#"{
+            "threads": 2,
+            "cache-memory": "1GiB",
+            "weekly": { "keep-min": 3, "remove-older": "30d", "gc-bigger": 50 }
+        }"#).unwrap();
+
+        assert_eq!(json.threads, toml.threads);
+        assert_eq!(json.cache_memory, toml.cache_memory);
+        let (toml, json) = (&toml.presets["weekly"], &json.presets["weekly"]);
+        assert_eq!(json.keep_min, toml.keep_min);
+        assert_eq!(json.remove_older, toml.remove_older);
+        assert_eq!(json.gc_bigger, toml.gc_bigger);
+    }
+
+    #[test]
+    fn json_null_is_unset() {
+        let (config, _) = ConfigFile::from_json(r#"{
+            "profile-dirs": null,
+            "threads": null,
+            "cache-memory": null,
+            "weekly": { "keep-min": null, "remove-older": null, "gc-modest": null }
+        }"#).unwrap();
+
+        assert!(config.profile_dirs.is_empty());
+        assert_eq!(config.threads, None);
+        assert_eq!(config.cache_memory, None);
+        let preset = &config.presets["weekly"];
+        assert_eq!(preset.keep_min, None);
+        assert_eq!(preset.remove_older, None);
+        assert!(!preset.gc_modest);
+    }
+
+    #[test]
+    fn json_errors() {
+        assert!(ConfigFile::from_json("{").is_err());
+        assert!(ConfigFile::from_json(r#"{ "threads": "many" }"#).is_err());
+        assert!(ConfigFile::from_json(r#"{ "threads": 0 }"#).is_err());
+        assert!(ConfigFile::from_json(r#"{ "weekly": { "keep-min": -1 } }"#).is_err());
+    }
+
+    #[test]
+    fn gc_settings() {
+        let preset = |gc_bigger, gc_quota, gc_modest| ConfigPreset {
+            gc: Some(true), gc_bigger, gc_quota, gc_modest,
+            ..ConfigPreset::default()
+        };
+        assert!(preset(Some(10), Some(50), true).validate().is_ok());
+        assert!(preset(None, Some(100), false).validate().is_err());
+
+        assert!(preset(Some(10), Some(50), true).validate_gc().is_ok());
+        assert!(preset(None, Some(50), true).validate_gc().is_ok());
+        assert!(preset(None, None, true).validate_gc().is_err());
+        assert!(preset(None, None, false).validate_gc().is_ok());
+        assert!(ConfigPreset { gc: Some(false), ..preset(None, None, true) }.validate_gc().is_ok());
+
+        // the thresholds may come from a different preset than --gc-modest
+        let merged = preset(Some(10), None, false).override_with(&preset(None, None, true));
+        assert!(merged.validate_gc().is_ok());
+    }
+
+    #[test]
+    fn dates_can_be_unset() {
+        let (config, _) = ConfigFile::from_str(r#"
+            [base]
+            keep-since = 2024-01-01T12:30:00Z
+            remove-before = "2023-06-01 08:00"
+            [unset]
+            keep-since = 0
+            remove-before = "0"
+        "#).unwrap();
+
+        let base = &config.presets["base"];
+        assert_eq!(base.keep_since, Some(LocalDate::parse("2024-01-01").unwrap()));
+        assert_eq!(base.remove_before, Some(LocalDate::parse("2023-06-01 08:00").unwrap()));
+
+        let merged = base.override_with(&config.presets["unset"]);
+        assert_eq!(merged.keep_since, None);
+        assert_eq!(merged.remove_before, None);
+        assert_eq!(config.presets["unset"].finalize().keep_since, None);
+    }
+
+    const RENAMES: Renames = &[("keep-atleast", "keep-min"), ("gc-above", "gc-bigger")];
+
+    #[test]
+    fn migrate_toml_keeps_formatting() {
+        let (migrated, legacy) = migrate_toml(r#"# global settings
+threads = 2
+
+[weekly]
+# at least a few
+keep-atleast = 3   # rollback targets
+remove-older = "30d"
+gc-above = 50
+"#, RENAMES).unwrap();
+
+        assert_eq!(migrated, r#"# global settings
+threads = 2
+
+[weekly]
+# at least a few
+keep-min = 3   # rollback targets
+remove-older = "30d"
+gc-bigger = 50
+"#);
+        assert_eq!(legacy.len(), 2);
+        assert!(legacy.iter().all(|l| l.preset.as_deref() == Some("weekly")));
+    }
+
+    #[test]
+    fn migrate_toml_inline_and_dotted() {
+        let (migrated, legacy) = migrate_toml(r#"keep-atleast = 1
+daily = { keep-atleast = 2, gc-bigger = 10 }
+monthly.gc-above = 20
+"#, RENAMES).unwrap();
+
+        assert_eq!(migrated, r#"keep-min = 1
+daily = { keep-min = 2, gc-bigger = 10 }
+monthly.gc-bigger = 20
+"#);
+        assert_eq!(legacy.len(), 3);
+    }
+
+    #[test]
+    fn migrate_toml_ignores_presets_named_like_keys() {
+        let s = "gc-above = { keep-min = 2 }\n\n[keep-atleast]\nkeep-max = 5\n";
+        let (migrated, legacy) = migrate_toml(s, RENAMES).unwrap();
+        assert_eq!(migrated, s);
+        assert!(legacy.is_empty());
+    }
+
+    #[test]
+    fn migrate_both_names_set() {
+        let s = "[weekly]\nkeep-atleast = 3\nkeep-min = 2\n";
+        assert!(migrate_toml(s, RENAMES).is_err());
+
+        let mut table: toml::Table = toml::from_str(s).unwrap();
+        assert!(migrate_keys(&mut table, RENAMES).is_err());
+    }
+
+    #[test]
+    fn migrate_parsed_keys() {
+        let mut table: toml::Table = toml::from_str("keep-atleast = 1\n[weekly]\ngc-above = 50\n").unwrap();
+        let legacy = migrate_keys(&mut table, RENAMES).unwrap();
+        assert_eq!(legacy.len(), 2);
+        assert_eq!(table["keep-min"].as_integer(), Some(1));
+        assert_eq!(table["weekly"]["gc-bigger"].as_integer(), Some(50));
+
+        let mut value: serde_json::Value = serde_json::from_str(r#"{"keep-atleast": 1, "weekly": {"gc-above": 50}}"#).unwrap();
+        let legacy = migrate_json_keys(value.as_object_mut().unwrap(), RENAMES).unwrap();
+        assert_eq!(legacy.len(), 2);
+        assert_eq!(value["keep-min"], 1);
+        assert_eq!(value["weekly"]["gc-bigger"], 50);
+    }
+}
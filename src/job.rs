@@ -0,0 +1,167 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::APP_PREFIX;
+use crate::utils::interaction::warn;
+
+
+const JOB_FILENAME: &str = "job.toml";
+
+static RESUMED: Mutex<Option<Job>> = Mutex::new(None);
+
+
+/// Progress of a clean out or tidy up run, which can be continued by `nix-sweep resume`
+///
+/// The job file is written whenever a decision is made and removed once the run has finished, so
+/// it only survives runs that were interrupted or failed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Job {
+    /// Arguments of the run (without the program name)
+    args: Vec<String>,
+
+    started: u64,
+
+    /// Profiles or gc roots that have been dealt with completely
+    #[serde(default)]
+    done: Vec<PathBuf>,
+
+    /// Removals the user has confirmed, but which may not have been carried out yet
+    #[serde(default)]
+    confirmed: Vec<Confirmation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Confirmation {
+    /// Profile or gc root link
+    path: PathBuf,
+
+    /// Generations to remove from a profile
+    #[serde(default)]
+    generations: Vec<usize>,
+}
+
+
+impl Job {
+    /// Continue the resumed job or start a new one for the current invocation
+    pub fn begin() -> Self {
+        if let Some(job) = RESUMED.lock().ok().and_then(|mut r| r.take()) {
+            return job;
+        }
+
+        if Self::load().is_ok_and(|j| j.is_some()) {
+            warn("Discarding the progress of an interrupted run (use `nix-sweep resume` to continue it instead)");
+        }
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Job { args: env::args().skip(1).collect(), started, done: Vec::new(), confirmed: Vec::new() }
+    }
+
+    /// The job of an interrupted run, if there is one
+    pub fn load() -> Result<Option<Self>, String> {
+        let path = match xdg::BaseDirectories::with_prefix(APP_PREFIX).get_state_file(JOB_FILENAME) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        if !fs::exists(&path).map_err(|e| e.to_string())? {
+            return Ok(None);
+        }
+
+        let s = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read job file {}: {}", path.to_string_lossy(), e))?;
+        toml::from_str(&s)
+            .map(Some)
+            .map_err(|e| format!("Unable to parse job file {}: {}", path.to_string_lossy(), e))
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = xdg::BaseDirectories::with_prefix(APP_PREFIX)
+            .place_state_file(JOB_FILENAME)
+            .map_err(|e| format!("Unable to create job file: {e}"))?;
+        let s = toml::to_string_pretty(self)
+            .map_err(|e| e.to_string())?;
+        fs::write(&path, s)
+            .map_err(|e| format!("Unable to write job file {}: {}", path.to_string_lossy(), e))
+    }
+
+    /// Forget about the job, as the run has finished
+    pub fn finish(self) -> Result<(), String> {
+        discard()
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn started(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.started)
+    }
+
+    pub fn ndone(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn nconfirmed(&self) -> usize {
+        self.confirmed.len()
+    }
+
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done.iter().any(|p| p == path)
+    }
+
+    /// Generations confirmed for removal from a profile, or an empty list for a confirmed gc root
+    pub fn confirmed(&self, path: &Path) -> Option<&[usize]> {
+        self.confirmed.iter()
+            .find(|c| c.path == path)
+            .map(|c| c.generations.as_slice())
+    }
+
+    /// Record that the user confirmed a removal
+    pub fn confirm(&mut self, path: &Path, generations: Vec<usize>) -> Result<(), String> {
+        self.confirmed.retain(|c| c.path != path);
+        self.confirmed.push(Confirmation { path: path.to_path_buf(), generations });
+        self.save()
+    }
+
+    /// Record that a profile or gc root has been dealt with
+    pub fn done(&mut self, path: &Path) -> Result<(), String> {
+        self.confirmed.retain(|c| c.path != path);
+        if !self.is_done(path) {
+            self.done.push(path.to_path_buf());
+        }
+        self.save()
+    }
+}
+
+/// Whether the next run continues an interrupted one
+pub fn resuming() -> bool {
+    RESUMED.lock().is_ok_and(|r| r.is_some())
+}
+
+/// Continue `job` with the next run of a clean out or tidy up
+pub fn resume(job: Job) {
+    if let Ok(mut resumed) = RESUMED.lock() {
+        *resumed = Some(job);
+    }
+}
+
+/// Remove the job file of an interrupted run
+pub fn discard() -> Result<(), String> {
+    let path = match xdg::BaseDirectories::with_prefix(APP_PREFIX).get_state_file(JOB_FILENAME) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Unable to remove job file {}: {}", path.to_string_lossy(), e)),
+    }
+}
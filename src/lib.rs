@@ -0,0 +1,379 @@
+use std::cmp;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::{env, iter, thread};
+
+use clap::Parser;
+use rayon::ThreadPoolBuilder;
+
+use crate::commands::{Command, Context};
+use crate::config::{ConfigPreset, GlobalConfig, IoPriority};
+use crate::nix::closures;
+use crate::utils::fmt::{set_age_format, set_age_thresholds, set_si_units, AgeFormat};
+use crate::utils::interaction::{resolve, set_verbosity, warn};
+use crate::utils::priority::Priority;
+use crate::utils::privileges::{self, Escalation};
+use crate::utils::{progress, signals, terminal, timings};
+
+pub mod config;
+pub mod state;
+pub mod job;
+pub mod nix;
+pub mod utils;
+pub mod commands;
+
+
+const THREADS_ENV_VAR: &str = "NIX_SWEEP_NUM_THREADS";
+const MAX_THREADS: usize = 4;
+
+
+type HashMap<K, V> = rustc_hash::FxHashMap<K, V>;
+type HashSet<V> = rustc_hash::FxHashSet<V>;
+type Hasher = rustc_hash::FxHasher;
+
+/// Utility to clean up old Nix profile generations and left-over garbage collection roots
+///
+/// You can adjust the number of worker threads this program uses with the `--threads` option, the
+/// `NIX_SWEEP_NUM_THREADS` env variable or the `threads` key in the config file (in that order of
+/// precedence).
+#[derive(Parser)]
+#[command(version, about, long_about)]
+pub struct Args {
+    /// Number of worker threads
+    #[clap(long, global = true, value_parser = clap::value_parser!(u64).range(1..))]
+    threads: Option<u64>,
+
+    /// Operate on a copy of a Nix installation below this directory
+    ///
+    /// Paths like /nix/store and /nix/var/nix/gcroots are looked up below DIR and absolute symlinks
+    /// are resolved relative to it. The Nix daemon is never contacted in this mode; references are found by
+    /// scanning the store paths instead and garbage collection deletes dead paths below DIR directly.
+    #[clap(long, global = true, value_name = "DIR")]
+    store_root: Option<PathBuf>,
+
+    /// Never run Nix commands, only inspect the file system
+    ///
+    /// Profiles, generations and gc roots are still listed, but closure sizes and everything else
+    /// that requires querying Nix is unavailable. This is meant for recovery environments where
+    /// neither the daemon nor the Nix tools can be used.
+    #[clap(long, global = true)]
+    offline: bool,
+
+    /// Print more details (-v) or debugging information such as timings and cache hits (-vv)
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print errors, warnings and the requested information
+    #[clap(long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Lower the CPU priority of nix-sweep and the programs it runs to this niceness (0-19)
+    #[clap(long, global = true, value_parser = clap::value_parser!(i32).range(0..=19))]
+    nice: Option<i32>,
+
+    /// Lower the IO priority of nix-sweep and the programs it runs
+    #[clap(long, global = true, value_enum)]
+    ionice: Option<IoPriority>,
+
+    /// How to display ages
+    #[clap(long, global = true, value_enum, default_value_t)]
+    age_format: AgeFormat,
+
+    /// Show sizes in decimal units (kB, MB, GB) instead of binary ones (KiB, MiB, GiB)
+    ///
+    /// This can also be set with a top-level `si = true` entry in the preset file. Separators of
+    /// numbers follow the locale (LC_NUMERIC).
+    #[clap(long, global = true)]
+    si: bool,
+
+    /// Do not use colors or live updating progress output
+    #[clap(long, global = true, alias = "no-color")]
+    plain: bool,
+
+    /// Run operations that require root (e.g. removing system generations) via sudo or doas
+    ///
+    /// Everything else, like user profiles, is still handled without elevated privileges.
+    #[clap(long, global = true, value_enum, value_name = "PROGRAM", num_args = 0..=1, default_missing_value = "sudo")]
+    sudo: Option<Escalation>,
+
+    /// Report how long the phases of the run took
+    #[clap(long, global = true)]
+    timings: bool,
+
+    /// Memory to use for caching closures at most (e.g. "512MiB", defaults to 1GiB)
+    ///
+    /// Once the cache grows beyond this, the least recently used closures are dropped and queried
+    /// again when needed. This can also be set with a top-level `cache-memory` entry in the preset file.
+    #[clap(long, global = true, value_parser = config::parse_size, value_name = "SIZE")]
+    cache_memory: Option<u64>,
+
+    /// Report hits, misses and evictions of the closure cache
+    #[clap(long, global = true)]
+    cache_stats: bool,
+
+    /// Write newline-delimited json progress events to stderr or the given file descriptor
+    #[clap(long, global = true, value_name = "FD", num_args = 0..=1, default_missing_value = "stderr")]
+    progress_json: Option<String>,
+
+    #[clap(subcommand)]
+    subcommand: Subcommand,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Add a new garbage collection root
+    AddRoot(commands::add_root::AddRootCommand),
+
+    /// Analyze store usage
+    ///
+    /// This shows the current size and optimization state of the nix store.
+    /// It also displays the current full closure size of profiles and garbage collection roots, as well as
+    /// the percentage of total store space that is used by those closures.
+    Analyze(commands::analyze::AnalyzeCommand),
+
+    /// Clean out old profile generations
+    ///
+    /// Positive criteria (e.g. --keep-min, --keep-newer) are prioritized over negative ones
+    /// (e.g. --keep-max, --remove-older).
+    /// Passing 0 on any cleanout criterion will reset it to the default behavior.
+    ///
+    /// The latest generation, the currently active one and that of the running system
+    /// (/run/current-system) will not be removed, even if they match the specified criteria. If you want to delete those generations or the entire
+    /// profile, you will have to do so manually. Please beware of the risks of this operation and
+    /// the impact it may have on your system state..
+    Cleanout(Box<commands::cleanout::CleanoutCommand>),
+
+    /// Inspect and migrate preset files
+    Config(commands::config::ConfigCommand),
+
+    /// Delete specific store paths without running a full garbage collection
+    ///
+    /// Paths that are still reachable from a gc root are refused. Dead paths referencing the given
+    /// paths are deleted along with them.
+    DeletePaths(commands::delete_paths::DeletePathsCommand),
+
+    /// List derivation files (*.drv) in the store
+    ///
+    /// With --orphaned only derivations not referenced by any gc root are listed, which are usually
+    /// leftovers from builds. These can be deleted with --delete without running a full garbage
+    /// collection.
+    Drvs(commands::drvs::DrvsCommand),
+
+    /// Clean out all profiles and collect garbage with a single preset
+    ///
+    /// The home and user profiles are cleaned out as the invoking user (when running via sudo,
+    /// nix-sweep runs itself as that user for this part).
+    /// The system profile and garbage collection are only included if they can be done with the
+    /// available privileges (see --sudo).
+    FullClean(commands::full_clean::FullCleanCommand),
+
+    /// Run garbage collection (short for `nix-store --gc`)
+    GC(commands::gc::GCCommand),
+
+    /// List garbage collection roots
+    GCRoots(commands::gc_roots::GCRootsCommand),
+
+    /// List profile generations
+    Generations(commands::generations::GenerationsCommand),
+
+    /// Show information on a path or a symlink to a path
+    PathInfo(commands::path_info::PathInfoCommand),
+
+    /// Show information about available presets for `cleanout`
+    Presets(commands::presets::PresetsCommand),
+
+    /// Remove gc roots previously created with `add-root`
+    RemoveRoot(commands::remove_root::RemoveRootCommand),
+
+    /// Summarize recent clean ups and store growth
+    ///
+    /// The digest is built from the history nix-sweep keeps of its own runs (clean outs, garbage
+    /// collections and store sizes recorded by `analyze`), which makes it suitable for a periodic
+    /// email from cron.
+    Report(commands::report::ReportCommand),
+
+    /// Continue an interrupted clean out or tidy up run
+    ///
+    /// Removals that were confirmed before the interruption are carried out without asking again,
+    /// profiles and gc roots that have been dealt with are skipped. The run uses the options of the
+    /// interrupted invocation.
+    Resume(commands::resume::ResumeCommand),
+
+    /// Selectively remove gc roots
+    #[clap(aliases = &["tidyup"])]
+    TidyupGCRoots(commands::tidyup_gc_roots::TidyupGCRootsCommand),
+
+    /// Find profiles that are not needed anymore
+    ///
+    /// These are profiles that have not been changed for a long time or only point to a missing
+    /// store path, which are offered for deletion, and the per-user profile directories of
+    /// accounts that have been removed (/nix/var/nix/profiles/per-user/<user>). The default, system
+    /// and channels profiles and profiles with generations listed in .nix-sweep-keep are never
+    /// offered.
+    TidyupProfiles(commands::tidyup_profiles::TidyupProfilesCommand),
+
+    /// Verify the integrity of the store (wraps `nix-store --verify`)
+    ///
+    /// Corrupted paths are listed and you are offered to repair them or to delete them together
+    /// with the gc roots referencing them. Profile generations and the current or booted system are
+    /// never removed this way.
+    Verify(commands::verify::VerifyCommand),
+
+    /// Watch store device usage and clean up once a quota is exceeded
+    ///
+    /// This stays resident and periodically checks how full the device of the Nix store is.
+    /// Once the usage exceeds the quota, the profiles are cleaned out non-interactively according
+    /// to the given preset and garbage collection is run. To avoid thrashing, no further clean up
+    /// is triggered until the usage dropped below the quota minus the hysteresis.
+    Watch(commands::watch::WatchCommand),
+
+    /// Export shell completions
+    #[clap(hide(true))]
+    Completions(commands::completions::CompletionsCommand),
+
+    /// Export manpage
+    #[clap(hide(true))]
+    Man(commands::man::ManCommand),
+
+    /// Tools for developing nix-sweep and reproducing bugs
+    #[clap(hide(true))]
+    Dev(commands::dev::DevCommand),
+}
+
+fn init_priority(nice: Option<i32>, ionice: Option<IoPriority>) -> Result<Priority, String> {
+    let global = GlobalConfig::get()?;
+    let priority = Priority {
+        nice: nice.or(global.nice),
+        ionice: ionice.or(global.ionice),
+    };
+    if let Err(e) = priority.apply() {
+        warn(&e);
+    }
+    Ok(priority)
+}
+
+fn init_rayon(threads: Option<u64>, priority: Priority) -> Result<(), String> {
+    let nthreads: usize = match (threads, env::var(THREADS_ENV_VAR).ok()) {
+        (Some(n), _) => n as usize,
+        (None, Some(n)) => n.parse()
+            .map_err(|_| format!("Unable to parse {THREADS_ENV_VAR} environment variable"))?,
+        (None, None) => match GlobalConfig::get()?.threads {
+            Some(n) => n,
+            None => match thread::available_parallelism().ok() {
+                Some(avail) => cmp::min(avail.into(), MAX_THREADS),
+                None => MAX_THREADS,
+            },
+        },
+    };
+
+    // errors have already been reported for the main thread
+    ThreadPoolBuilder::new()
+        .num_threads(nthreads)
+        .start_handler(move |_| { let _ = priority.apply(); })
+        .build_global()
+        .map_err(|e| e.to_string())
+}
+
+fn init_context(cache_memory: Option<u64>) -> Result<Context, String> {
+    let cache_memory = match cache_memory {
+        Some(bytes) => bytes,
+        None => GlobalConfig::get()?.cache_memory.unwrap_or(closures::DEFAULT_MEMORY_LIMIT),
+    };
+    Context::new(cache_memory)
+}
+
+fn parse_args<I: IntoIterator<Item = T>, T: Into<OsString> + Clone>(args: I) -> Result<Args, String> {
+    match Args::try_parse_from(args) {
+        Ok(args) => Ok(args),
+        Err(e) => {
+            if e.render().to_string().starts_with("error: ") {
+                let msg = e.render().to_string().chars()
+                    .skip(7)
+                    .enumerate()
+                    .map(|(i, c)| if i == 0 { c.to_ascii_uppercase() } else { c })
+                    .collect();
+                Err(msg)
+            } else {
+                e.exit()
+            }
+        },
+    }
+}
+
+/// Entry point of the `nix-sweep` binary
+pub fn run() {
+    let mut config = resolve(parse_args(env::args_os()));
+
+    // a resumed run continues with the arguments of the interrupted one
+    if let Subcommand::Resume(cmd) = &config.subcommand
+            && let Some(job) = resolve(cmd.job()) {
+        let args = iter::once(env!("CARGO_PKG_NAME").to_owned()).chain(job.args().iter().cloned());
+        config = resolve(parse_args(args));
+        job::resume(job);
+    }
+    match config.quiet {
+        true => set_verbosity(-1),
+        false => set_verbosity(config.verbose.min(2) as i8),
+    }
+    set_age_format(config.age_format);
+    // commands working with a preset replace these with its own thresholds
+    if let Ok(preset) = ConfigPreset::load(config::DEFAULT_PRESET, None) {
+        set_age_thresholds(preset.age_fresh, preset.age_stale);
+    }
+    set_si_units(config.si || GlobalConfig::get().is_ok_and(|g| g.si == Some(true)));
+    if let Some(escalation) = config.sudo {
+        privileges::set_escalation(escalation);
+    }
+    if config.plain {
+        terminal::set_plain();
+    }
+    resolve(signals::install());
+    if config.timings {
+        timings::enable();
+    }
+    if config.offline {
+        nix::offline::enable();
+    }
+    if let Some(root) = &config.store_root {
+        resolve(nix::sandbox::init(root));
+    }
+    let priority = resolve(init_priority(config.nice, config.ionice));
+    resolve(init_rayon(config.threads, priority));
+    if let Some(target) = &config.progress_json {
+        resolve(progress::init(target));
+    }
+
+    let ctx = resolve(init_context(config.cache_memory));
+
+    use Subcommand::*;
+    let res = match config.subcommand {
+        AddRoot(cmd) => cmd.run(&ctx),
+        Analyze(cmd) => cmd.run(&ctx),
+        Cleanout(cmd) => cmd.run(&ctx),
+        Completions(cmd) => cmd.run(&ctx),
+        Config(cmd) => cmd.run(&ctx),
+        DeletePaths(cmd) => cmd.run(&ctx),
+        Dev(cmd) => cmd.run(&ctx),
+        Drvs(cmd) => cmd.run(&ctx),
+        FullClean(cmd) => cmd.run(&ctx),
+        GC(cmd) => cmd.run(&ctx),
+        GCRoots(cmd) => cmd.run(&ctx),
+        Generations(cmd) => cmd.run(&ctx),
+        Man(cmd) => cmd.run(&ctx),
+        PathInfo(cmd) => cmd.run(&ctx),
+        RemoveRoot(cmd) => cmd.run(&ctx),
+        Report(cmd) => cmd.run(&ctx),
+        Resume(cmd) => cmd.run(&ctx),
+        TidyupGCRoots(cmd) => cmd.run(&ctx),
+        TidyupProfiles(cmd) => cmd.run(&ctx),
+        Presets(cmd) => cmd.run(&ctx),
+        Verify(cmd) => cmd.run(&ctx),
+        Watch(cmd) => cmd.run(&ctx),
+    };
+    timings::report();
+    if config.cache_stats {
+        ctx.report_cache_stats();
+    }
+    signals::exit_if_interrupted(&res);
+    resolve(res);
+}
@@ -0,0 +1,455 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Once, OnceLock};
+use std::thread;
+use std::time::Instant;
+
+use crate::config;
+use crate::utils::interaction::{debug, info, warn};
+use crate::utils::{privileges, signals};
+
+use super::offline::{self, OfflineBackend};
+use super::store::{Store, NIX_STORE};
+
+
+const REGISTRATION_CHUNK_SIZE: usize = 1024;
+const DELETION_CHUNK_SIZE: usize = 4096;
+
+static BACKEND: OnceLock<Box<dyn NixBackend>> = OnceLock::new();
+static READ_ONLY_NOTICE: Once = Once::new();
+
+
+/// Interface to the Nix tooling
+///
+/// Every query and modification of the store or of profiles goes through this trait, so the
+/// rest of the program can be run against something other than the real Nix installation.
+pub trait NixBackend: Send + Sync {
+    /// All paths in the store
+    fn valid_paths(&self) -> Result<Vec<PathBuf>, String> {
+        let read_dir = fs::read_dir(Store::dir())
+            .map_err(|e| e.to_string())?;
+        let paths = read_dir.into_iter()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| Store::is_valid_path(p))
+            .collect();
+        Ok(paths)
+    }
+
+    /// Closure of the given store paths
+    fn requisites(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String>;
+
+    /// Store paths that (indirectly) reference the given store paths, including the paths themselves
+    fn referrers(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String>;
+
+    /// Direct references of every path in the closure of the given store paths
+    fn reference_graph(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String>;
+
+    /// All gc roots as pairs of link and store path, including those held by processes
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>, String>;
+
+    /// Gc roots that (indirectly) reference `path` as pairs of link and store path
+    fn roots_of(&self, path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, String>;
+
+    /// Store paths that would be deleted by a garbage collection
+    fn dead_paths(&self) -> Result<Vec<PathBuf>, String>;
+
+    /// Run the garbage collector, calling `on_delete` for every store path that is deleted
+    ///
+    /// If `on_delete` breaks or the deadline passes, the collection is stopped after the deletion
+    /// in progress.
+    /// Returns what the garbage collector reported to have done, if it did so.
+    fn collect_garbage(&self, max_freed: Option<u64>, deadline: Option<Instant>, on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String>;
+
+    /// Verify the store, returning the corrupted and the missing paths
+    fn verify(&self, check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String>;
+
+    /// Delete the given dead store paths, in whatever order their references require
+    fn delete_paths(&self, paths: &[PathBuf]) -> Result<(), String>;
+
+    fn repair_path(&self, path: &Path) -> Result<(), String>;
+
+    fn delete_generation(&self, profile: &Path, number: usize) -> Result<(), String>;
+
+    /// Build or substitute a derivation, returning its output paths
+    fn realise(&self, drv: &Path) -> Result<Vec<PathBuf>, String>;
+
+    /// Whether queries about the store cannot be answered at all (see [super::offline])
+    fn is_offline(&self) -> bool {
+        false
+    }
+}
+
+/// Backend calling `nix-store` and `nix-env`
+pub struct NixCli;
+
+/// Summary of a garbage collection run
+#[derive(Debug, Clone, Copy)]
+pub struct GCResult {
+    pub deleted: usize,
+    pub freed: u64,
+}
+
+
+/// The backend in use, defaulting to [NixCli] or, in offline mode, to [OfflineBackend]
+pub fn backend() -> &'static dyn NixBackend {
+    BACKEND.get_or_init(|| match offline::enabled() {
+        true => Box::new(OfflineBackend),
+        false => Box::new(NixCli),
+    }).as_ref()
+}
+
+/// Replace the default backend
+///
+/// This must happen before the backend is first used.
+pub fn set_backend(backend: Box<dyn NixBackend>) -> Result<(), String> {
+    BACKEND.set(backend)
+        .map_err(|_| "Nix backend already initialized".to_owned())
+}
+
+
+impl NixCli {
+    fn output<S: AsRef<OsStr>>(program: &str, args: &[S]) -> Result<String, String> {
+        debug(&format!("running `{program}` with {} arguments", args.len()));
+        let output = process::Command::new(program)
+            .args(args)
+            .stdin(process::Stdio::inherit())
+            .stderr(process::Stdio::inherit())
+            .output()
+            .map_err(|e| e.to_string())?;
+
+        if !output.status.success() {
+            match output.status.code() {
+                Some(code) => return Err(format!("`{program}` failed (exit code {code})")),
+                None => return Err(format!("`{program}` failed")),
+            }
+        }
+
+        String::from_utf8(output.stdout)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Run a program, via sudo or doas if `privileged` and requested by the user
+    fn status<S: AsRef<OsStr>>(program: &str, args: &[S], privileged: bool) -> Result<(), String> {
+        Self::wait(privileges::command(program, privileged), program, args)
+    }
+
+    /// Run a program modifying the store (see [NixCli::store_command])
+    fn store_status<S: AsRef<OsStr>>(program: &str, args: &[S]) -> Result<(), String> {
+        Self::wait(Self::store_command(program), program, args)
+    }
+
+    /// Command running a program that modifies the store
+    ///
+    /// A read-only store is modified through the Nix daemon, even when running as root, as opening
+    /// the store directly would fail. Otherwise the program is run via sudo or doas if necessary.
+    fn store_command(program: &str) -> process::Command {
+        if !Store::is_read_only() || !privileges::daemon_available() {
+            return privileges::command(program, privileges::store_needs_root());
+        }
+
+        READ_ONLY_NOTICE.call_once(|| info("Store is read-only; using daemon"));
+        let mut command = process::Command::new(program);
+        command.env("NIX_REMOTE", "daemon");
+        command
+    }
+
+    fn wait<S: AsRef<OsStr>>(mut command: process::Command, program: &str, args: &[S]) -> Result<(), String> {
+        debug(&format!("running `{program}` with {} arguments", args.len()));
+        let status = command
+            .args(args)
+            .stdin(process::Stdio::inherit())
+            .stdout(process::Stdio::inherit())
+            .stderr(process::Stdio::inherit())
+            .status()
+            .map_err(|e| e.to_string())?;
+
+        match status.code() {
+            _ if status.success() => Ok(()),
+            Some(code) => Err(format!("`{program}` failed (exit code {code})")),
+            None => Err(format!("`{program}` failed")),
+        }
+    }
+
+    fn parse_roots(output: &str) -> Vec<(PathBuf, PathBuf)> {
+        output.lines()
+            .filter_map(|l| l.split_once(" -> "))
+            .filter(|(link, _)| *link != "{censored}")
+            .map(|(link, store_path)| (PathBuf::from(link), PathBuf::from(store_path)))
+            .collect()
+    }
+
+    /// Parse the references out of the registration info printed by `nix-store --dump-db`
+    ///
+    /// Every path is described by its name, hash, size, deriver, the number of references and the
+    /// references themselves, each on a line of its own.
+    fn parse_registrations(output: &str) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String> {
+        let invalid = || "Unable to parse output of `nix-store --dump-db`".to_owned();
+        let mut lines = output.lines();
+        let mut registrations = Vec::new();
+        while let Some(path) = lines.next() {
+            let nrefs: usize = lines.nth(3)
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(invalid)?;
+            let references = lines.by_ref()
+                .take(nrefs)
+                .map(PathBuf::from)
+                .collect::<Vec<_>>();
+            if references.len() != nrefs {
+                return Err(invalid());
+            }
+            registrations.push((PathBuf::from(path), references));
+        }
+        Ok(registrations)
+    }
+
+    /// Ask a running Nix process to stop, warning if it cannot be signalled (e.g. when run via sudo)
+    fn interrupt(child: &process::Child) -> bool {
+        let pid = rustix::process::Pid::from_child(child);
+        match rustix::process::kill_process(pid, rustix::process::Signal::INT) {
+            Ok(()) => true,
+            Err(e) => {
+                warn(&format!("Unable to interrupt `nix-store` ({e}), waiting for it to finish"));
+                false
+            },
+        }
+    }
+
+    /// Parse the final "N store paths deleted, X MiB freed" line of `nix-store --gc`
+    fn parse_gc_summary(line: &str) -> Option<GCResult> {
+        let (deleted, freed) = line.trim().split_once(" store paths deleted, ")?;
+        let deleted = deleted.parse().ok()?;
+        let freed = config::parse_size(freed.strip_suffix(" freed")?).ok()?;
+        Some(GCResult { deleted, freed })
+    }
+}
+
+impl NixBackend for NixCli {
+    fn requisites(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let mut args = vec![OsStr::new("--query"), OsStr::new("--requisites")];
+        args.extend(paths.iter().map(|p| p.as_os_str()));
+        let output = Self::output("nix-store", &args)?;
+        Ok(output.lines().map(PathBuf::from).collect())
+    }
+
+    fn referrers(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let mut args = vec![OsStr::new("--query"), OsStr::new("--referrers-closure")];
+        args.extend(paths.iter().map(|p| p.as_os_str()));
+        let output = Self::output("nix-store", &args)?;
+        Ok(output.lines().map(PathBuf::from).collect())
+    }
+
+    fn reference_graph(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String> {
+        let closure = self.requisites(paths)?;
+        let mut graph = Vec::with_capacity(closure.len());
+        for chunk in closure.chunks(REGISTRATION_CHUNK_SIZE) {
+            let mut args = vec![OsStr::new("--dump-db")];
+            args.extend(chunk.iter().map(|p| p.as_os_str()));
+            let output = Self::output("nix-store", &args)?;
+            graph.extend(Self::parse_registrations(&output)?);
+        }
+        Ok(graph)
+    }
+
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        let output = Self::output("nix-store", &["--gc", "--print-roots"])?;
+        Ok(Self::parse_roots(&output))
+    }
+
+    fn roots_of(&self, path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        let output = Self::output("nix-store", &[OsStr::new("--query"), OsStr::new("--roots"), path.as_os_str()])?;
+        Ok(Self::parse_roots(&output))
+    }
+
+    fn dead_paths(&self) -> Result<Vec<PathBuf>, String> {
+        let output = process::Command::new("nix-store")
+            .arg("--gc")
+            .arg("--print-dead")
+            .output()
+            .map_err(|e| e.to_string())?;
+
+        if !output.status.success() {
+            match output.status.code() {
+                Some(code) => return Err(format!("`nix-store` failed (exit code {code})")),
+                None => return Err("`nix-store` failed".to_string()),
+            }
+        }
+
+        let paths = String::from_utf8(output.stdout)
+            .map_err(|e| e.to_string())?
+            .lines()
+            .map(PathBuf::from)
+            .collect();
+        Ok(paths)
+    }
+
+    fn collect_garbage(&self, max_freed: Option<u64>, deadline: Option<Instant>, on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String> {
+        let mut command = Self::store_command("nix-store");
+        command.arg("--gc");
+        if let Some(amount) = max_freed {
+            command.args(["--max-freed".to_owned(), format!("{amount}")]);
+        }
+        let mut child = command
+            .stdin(process::Stdio::inherit())
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+
+        // the summary is printed to stdout once the collection is done, which is read alongside
+        // stderr so neither of the pipes fills up
+        let stdout = child.stdout.take();
+        let summary = thread::spawn(move || {
+            let mut result = None;
+            for line in stdout.into_iter().flat_map(|s| io::BufReader::new(s).lines().map_while(Result::ok)) {
+                match Self::parse_gc_summary(&line) {
+                    Some(summary) => result = Some(summary),
+                    None => println!("{line}"),
+                }
+            }
+            result
+        });
+
+        // stderr is read on a thread of its own, so the deadline passing is noticed while nix is busy
+        let stderr = child.stderr.take();
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stderr.into_iter().flat_map(|s| io::BufReader::new(s).lines().map_while(Result::ok)) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // nix reports every deleted path as "deleting '/nix/store/...'" on stderr
+        let mut stop_requested = false;
+        let mut interrupted = false;
+        loop {
+            // no line means the deadline has passed
+            let line = match deadline {
+                Some(deadline) if !stop_requested => match lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(line) => Some(line),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                _ => match lines.recv() {
+                    Ok(line) => Some(line),
+                    Err(_) => break,
+                },
+            };
+
+            let stop = match &line {
+                Some(line) => match line.strip_prefix("deleting '").and_then(|l| l.strip_suffix('\'')) {
+                    Some(path) if path.starts_with(NIX_STORE) => on_delete(Path::new(path)).is_break(),
+                    _ if interrupted => false,
+                    _ => {
+                        eprintln!("{line}");
+                        false
+                    },
+                },
+                None => true,
+            };
+
+            // nix finishes the deletion in progress when interrupted
+            if stop && !stop_requested {
+                stop_requested = true;
+                interrupted = Self::interrupt(&child);
+            }
+        }
+
+        let result = summary.join()
+            .map_err(|_| "Unable to read the output of `nix-store`".to_owned())?;
+        let status = child.wait().map_err(|e| e.to_string())?;
+        match status.code() {
+            _ if status.success() || interrupted || signals::interrupted() => Ok(result),
+            Some(code) => Err(format!("`nix-store` failed (exit code {code})")),
+            None => Err("`nix-store` failed".to_string()),
+        }
+    }
+
+    fn verify(&self, check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        let mut command = process::Command::new("nix-store");
+        command.arg("--verify");
+        if check_contents {
+            command.arg("--check-contents");
+        }
+        let mut child = command
+            .stdin(process::Stdio::inherit())
+            .stdout(process::Stdio::inherit())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+
+        let mut corrupted = Vec::new();
+        let mut missing = Vec::new();
+        if let Some(stderr) = child.stderr.take() {
+            for line in io::BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("{line}");
+                let path = match line.split('\'').nth(1) {
+                    Some(path) if path.starts_with(NIX_STORE) => PathBuf::from(path),
+                    _ => continue,
+                };
+                if line.contains("was modified") {
+                    corrupted.push(path);
+                } else if line.contains("disappeared") {
+                    missing.push(path);
+                }
+            }
+        }
+
+        let status = child.wait().map_err(|e| e.to_string())?;
+        if !status.success() && corrupted.is_empty() && missing.is_empty() {
+            match status.code() {
+                Some(code) => return Err(format!("`nix-store` failed (exit code {code})")),
+                None => return Err("`nix-store` failed".to_string()),
+            }
+        }
+
+        Ok((corrupted, missing))
+    }
+
+    fn delete_paths(&self, paths: &[PathBuf]) -> Result<(), String> {
+        // nix only orders the paths within a single call, so a chunk fails while a later one still
+        // refers to it; failed chunks are retried for as long as the previous pass made progress
+        let mut pending: Vec<&PathBuf> = paths.iter().collect();
+        loop {
+            let mut failed = Vec::new();
+            let mut error = None;
+            for chunk in pending.chunks(DELETION_CHUNK_SIZE) {
+                let mut args = vec![OsStr::new("--delete")];
+                args.extend(chunk.iter().map(|p| p.as_os_str()));
+                if let Err(e) = Self::store_status("nix-store", &args) {
+                    failed.extend(chunk.iter().filter(|p| p.exists()));
+                    error = Some(e);
+                }
+            }
+
+            match error {
+                None => return Ok(()),
+                Some(e) if failed.len() >= pending.len() => return Err(e),
+                Some(_) => pending = failed,
+            }
+        }
+    }
+
+    fn repair_path(&self, path: &Path) -> Result<(), String> {
+        Self::store_status("nix-store", &[OsStr::new("--repair-path"), path.as_os_str()])
+    }
+
+    fn delete_generation(&self, profile: &Path, number: usize) -> Result<(), String> {
+        let number = number.to_string();
+        let privileged = !profile.parent().is_some_and(privileges::can_write);
+        Self::status("nix-env", &[OsStr::new("-p"), profile.as_os_str(),
+            OsStr::new("--delete-generations"), OsStr::new(&number)], privileged)
+    }
+
+    fn realise(&self, drv: &Path) -> Result<Vec<PathBuf>, String> {
+        let output = Self::output("nix-store", &[OsStr::new("--realise"), drv.as_os_str()])?;
+        Ok(output.lines().map(PathBuf::from).collect())
+    }
+}
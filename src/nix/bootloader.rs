@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::utils::glob;
+use crate::utils::interaction::debug;
+
+use super::sandbox;
+use super::store::StorePath;
+
+
+/// Mount points of the EFI system partition (and the XBOOTLDR partition) used by systemd-boot
+const ESP_DIRS: &[&str] = &["/boot", "/efi", "/boot/efi"];
+const EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
+const LOADER_VENDOR_GUID: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+const GRUB_DIRS: &[&str] = &["/boot/grub", "/boot/grub2"];
+
+
+/// Store path of the system the bootloader starts if nothing else is chosen in its menu
+///
+/// This is usually the active generation of the system profile, but may be an older one after
+/// `bootctl set-default` or `grub-set-default`. Both systemd-boot and GRUB are supported.
+pub fn default_system() -> Option<&'static StorePath> {
+    static DEFAULT_SYSTEM: OnceLock<Option<StorePath>> = OnceLock::new();
+    DEFAULT_SYSTEM.get_or_init(|| systemd_boot_default().or_else(grub_default))
+        .as_ref()
+}
+
+fn systemd_boot_default() -> Option<StorePath> {
+    let entries: Vec<_> = ESP_DIRS.iter()
+        .flat_map(|d| fs::read_dir(sandbox::path(d).join("loader/entries")))
+        .flat_map(|entries| entries.flatten())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "conf"))
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+
+    // the EFI variable set by `bootctl set-default` takes precedence over loader.conf
+    let mut default = efi_variable("LoaderEntryDefault")
+        .or_else(|| ESP_DIRS.iter().find_map(|d| loader_conf_default(&sandbox::path(d).join("loader/loader.conf"))))?;
+    if default == "@saved" {
+        default = efi_variable("LoaderEntryLastBooted")?;
+    }
+
+    // patterns select the entry with the highest version, i.e. the newest generation
+    let entry = entries.iter()
+        .filter(|e| e.file_name().and_then(|n| n.to_str())
+            .is_some_and(|n| glob::matches(&default, n) || glob::matches(&default, n.trim_end_matches(".conf"))))
+        .max_by_key(|e| version_key(&e.file_name().unwrap_or_default().to_string_lossy()))?;
+    debug(&format!("default boot entry is {}", entry.to_string_lossy()));
+
+    let content = fs::read_to_string(entry).ok()?;
+    let init = content.lines()
+        .filter_map(|l| l.trim().strip_prefix("options"))
+        .flat_map(|o| o.split_whitespace())
+        .find_map(|o| o.strip_prefix("init="))?;
+    system_of_init(init)
+}
+
+fn loader_conf_default(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()?
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.starts_with('#'))
+        .find_map(|l| l.strip_prefix("default"))
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+/// String variable of the boot loader interface, as set by systemd-boot and bootctl
+fn efi_variable(name: &str) -> Option<String> {
+    let path = sandbox::path(EFIVARS_DIR).join(format!("{name}-{LOADER_VENDOR_GUID}"));
+    let content = fs::read(path).ok()?;
+
+    // the first four bytes are the attributes, followed by a NUL terminated UTF-16LE string
+    let chars: Vec<_> = content.get(4..)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16(&chars).ok()
+        .filter(|s| !s.is_empty())
+}
+
+fn grub_default() -> Option<StorePath> {
+    let dir = GRUB_DIRS.iter()
+        .map(|d| sandbox::path(d))
+        .find(|d| d.join("grub.cfg").exists())?;
+    let env = fs::read_to_string(dir.join("grubenv")).ok()?;
+    let saved = env.lines()
+        .find_map(|l| l.strip_prefix("saved_entry="))
+        .filter(|v| !v.is_empty())?;
+
+    let config = fs::read_to_string(dir.join("grub.cfg")).ok()?;
+    let entry = grub_entries(&config).into_iter()
+        .find(|e| e.title == saved || e.id.as_deref() == Some(saved) || e.index == saved)?;
+    debug(&format!("default boot entry is '{}'", entry.title));
+    system_of_init(entry.init.as_deref()?)
+}
+
+/// Menu entry of grub.cfg
+struct GrubEntry {
+    /// Position in the menu, with submenus separated by '>' (e.g. "1>3")
+    index: String,
+    title: String,
+    id: Option<String>,
+    init: Option<String>,
+}
+
+/// Block of grub.cfg enclosed in braces
+enum GrubBlock {
+    Submenu,
+    Entry(usize),
+    Other,
+}
+
+fn grub_entries(config: &str) -> Vec<GrubEntry> {
+    let mut entries: Vec<GrubEntry> = Vec::new();
+    // position of the next entry, one level per open submenu
+    let mut position = vec![0];
+    let mut blocks = Vec::new();
+
+    for line in config.lines().map(|l| l.trim()) {
+        if line.starts_with("menuentry ") || line.starts_with("submenu ") {
+            let index = position.iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(">");
+
+            if line.starts_with("submenu ") {
+                position.push(0);
+                blocks.push(GrubBlock::Submenu);
+            } else {
+                let mut tokens = line.split_whitespace();
+                let id = tokens.find(|t| *t == "--id" || *t == "$menuentry_id_option")
+                    .and_then(|_| tokens.next())
+                    .map(|t| t.trim_matches(['\'', '"']).to_owned());
+                entries.push(GrubEntry {
+                    index,
+                    title: first_quoted(line).unwrap_or_default(),
+                    id,
+                    init: None,
+                });
+                blocks.push(GrubBlock::Entry(entries.len() - 1));
+                *position.last_mut().unwrap() += 1;
+            }
+        } else if line == "}" {
+            if let Some(GrubBlock::Submenu) = blocks.pop() {
+                position.pop();
+                *position.last_mut().unwrap() += 1;
+            }
+        } else if line.ends_with('{') {
+            // e.g. functions, which must not close the enclosing menu entry
+            blocks.push(GrubBlock::Other);
+        } else if line.starts_with("linux ")
+                && let Some(GrubBlock::Entry(i)) = blocks.last()
+                && let Some(entry) = entries.get_mut(*i) {
+            entry.init = line.split_whitespace()
+                .find_map(|t| t.strip_prefix("init="))
+                .map(|t| t.to_owned());
+        }
+    }
+
+    entries
+}
+
+fn first_quoted(line: &str) -> Option<String> {
+    let start = line.find(['\'', '"'])?;
+    let quote = line[start..].chars().next()?;
+    let rest = &line[start + 1..];
+    Some(rest[..rest.find(quote).unwrap_or(rest.len())].to_owned())
+}
+
+/// Toplevel of the system started with the given init (e.g. /nix/store/...-nixos-system-.../init)
+fn system_of_init(init: &str) -> Option<StorePath> {
+    let toplevel: PathBuf = Path::new(init).components().take(4).collect();
+    StorePath::new(sandbox::remap(&toplevel)).ok()
+}
+
+/// Numbers in an entry name for comparing versions (e.g. nixos-generation-10 > nixos-generation-9)
+fn version_key(name: &str) -> Vec<u64> {
+    name.split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
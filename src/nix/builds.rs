@@ -0,0 +1,93 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::utils::files;
+use crate::utils::users::User;
+
+use super::sandbox;
+
+
+const TEMP_DIR: &str = "/tmp";
+const BUILD_DIR_PREFIX: &str = "nix-build-";
+const BUILD_USER_PREFIXES: &[&str] = &["nixbld", "_nixbld"];
+const EMPTY_HOME: &str = "/var/empty";
+
+
+/// Directory left behind by a build, which the garbage collector does not know about
+///
+/// Failed builds (or those run with `--keep-failed`) leave their build directory in the temp
+/// directory, and some builders write to the home directories of the build users.
+pub struct Leftover {
+    path: PathBuf,
+    size: u64,
+    age: Duration,
+}
+
+
+impl Leftover {
+    /// Build directories in the temp directory and non-empty home directories of the build users
+    pub fn find() -> Vec<Self> {
+        let mut candidates = Vec::new();
+
+        if let Ok(entries) = fs::read_dir(sandbox::path(TEMP_DIR)) {
+            candidates.extend(entries.flatten()
+                .filter(|e| e.file_name().to_string_lossy().starts_with(BUILD_DIR_PREFIX))
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                .map(|e| e.path()));
+        }
+
+        for user in User::all() {
+            let is_build_user = BUILD_USER_PREFIXES.iter().any(|p| user.name().starts_with(p));
+            if !is_build_user || user.home() == Path::new(EMPTY_HOME) {
+                continue;
+            }
+            let home = sandbox::remap(user.home());
+            let nonempty = fs::read_dir(&home).is_ok_and(|mut entries| entries.next().is_some());
+            if nonempty {
+                candidates.push(home);
+            }
+        }
+
+        let mut leftovers: Vec<_> = candidates.into_par_iter()
+            .map(|path| {
+                // a running build may only write deep inside its build directory
+                let age = files::newest_mtime(&path)
+                    .and_then(|m| SystemTime::now().duration_since(m).ok())
+                    .unwrap_or_default();
+                let size = files::dir_size_naive(&path);
+                Leftover { path, size, age }
+            })
+            .collect();
+        leftovers.sort_by(|a, b| a.path.cmp(&b.path));
+        leftovers
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn age(&self) -> Duration {
+        self.age
+    }
+
+    /// Whether this is a build directory in the temp directory rather than a home directory
+    pub fn is_build_dir(&self) -> bool {
+        self.path.starts_with(sandbox::path(TEMP_DIR))
+    }
+
+    /// Delete a build directory (home directories are only reported, never touched)
+    pub fn remove(&self) -> Result<(), String> {
+        if !self.is_build_dir() {
+            return Err(format!("Not removing {}, as it is not a build directory", self.path.to_string_lossy()));
+        }
+        fs::remove_dir_all(&self.path)
+            .map_err(|e| format!("Unable to remove {}: {}", self.path.to_string_lossy(), e))
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
+
+use colored::Colorize;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::utils::files;
+use crate::utils::fmt::FmtSize;
+use crate::utils::interaction::debug;
+use crate::utils::timings::{self, Phase};
+use crate::{HashMap, HashSet};
+
+use super::backend::backend;
+use super::store::StorePath;
+
+
+const CLOSURE_LOOKUP_CHUNK_SIZE: usize = 1024;
+pub const DEFAULT_MEMORY_LIMIT: u64 = 1 << 30;
+
+static CACHE: OnceLock<Arc<ClosureCache>> = OnceLock::new();
+
+
+/// Closures and closure sizes of store paths, shared by everything running as part of one invocation
+///
+/// The closure of a store path never changes, so entries are kept until the closures take up more
+/// memory than allowed, in which case the least recently used ones are dropped. Only the size of the
+/// store itself is dropped again after a garbage collection.
+pub struct ClosureCache {
+    closures: Mutex<Lru>,
+    sizes: RwLock<HashMap<StorePath, u64>>,
+    store_size: RwLock<Option<u64>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Closures ordered by when they were last used
+struct Lru {
+    entries: HashMap<StorePath, LruEntry>,
+    by_use: BTreeMap<u64, StorePath>,
+    clock: u64,
+    memory: u64,
+    memory_limit: u64,
+    evictions: u64,
+}
+
+struct LruEntry {
+    closure: Arc<HashSet<StorePath>>,
+    memory: u64,
+    last_used: u64,
+}
+
+
+/// The cache of the current run
+pub fn cache() -> &'static ClosureCache {
+    CACHE.get_or_init(|| Arc::new(ClosureCache::new(DEFAULT_MEMORY_LIMIT))).as_ref()
+}
+
+/// Use `cache` for all closure queries of the current run
+///
+/// This must happen before the first closure is queried.
+pub fn set_cache(cache: Arc<ClosureCache>) -> Result<(), String> {
+    CACHE.set(cache)
+        .map_err(|_| "Closure cache already initialized".to_owned())
+}
+
+
+/// Combined closure of the closures yielded by a parallel iterator
+pub fn union(closures: impl ParallelIterator<Item = Arc<HashSet<StorePath>>>) -> HashSet<StorePath> {
+    closures
+        .fold(HashSet::default, |mut union, closure| {
+            union.extend(closure.iter().cloned());
+            union
+        })
+        .reduce(HashSet::default, |mut a, mut b| {
+            if a.len() < b.len() {
+                mem::swap(&mut a, &mut b);
+            }
+            a.extend(b);
+            a
+        })
+}
+
+
+impl ClosureCache {
+    /// Cache that keeps closures taking up about `memory_limit` bytes at most
+    pub fn new(memory_limit: u64) -> Self {
+        let lru = Lru {
+            entries: HashMap::default(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            memory: 0,
+            memory_limit,
+            evictions: 0,
+        };
+        ClosureCache {
+            closures: Mutex::new(lru),
+            sizes: RwLock::default(),
+            store_size: RwLock::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Closure of `store_path`, which is shared with the cache
+    pub fn closure(&self, store_path: &StorePath) -> Result<Arc<HashSet<StorePath>>, String> {
+        let cached = self.closures.lock().unwrap().get(store_path).map(Arc::clone);
+        timings::closure_cache_lookup(cached.is_some());
+        match cached {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        if let Some(closure) = cached {
+            debug(&format!("closure cache hit for {}", store_path.path().to_string_lossy()));
+            return Ok(closure);
+        }
+
+        let start = Instant::now();
+        let closure: Arc<HashSet<_>> = Arc::new(timings::measure(Phase::ClosureQueries, || backend().requisites(std::slice::from_ref(store_path.path())))?
+            .into_iter()
+            .flat_map(StorePath::new)
+            .collect());
+        debug(&format!("queried closure of {} ({} paths) in {:.2}s",
+            store_path.path().to_string_lossy(), closure.len(), start.elapsed().as_secs_f64()));
+
+        self.closures.lock().unwrap().insert(store_path.clone(), Arc::clone(&closure));
+        Ok(closure)
+    }
+
+    /// Size of the closure of `store_path` considering hardlinks
+    pub fn closure_size(&self, store_path: &StorePath) -> Result<u64, String> {
+        if let Some(size) = self.sizes.read().unwrap().get(store_path) {
+            return Ok(*size);
+        }
+
+        let closure: Vec<_> = self.closure(store_path)?
+            .iter()
+            .map(|sp| sp.path().clone())
+            .collect();
+        let size = files::dir_size_considering_hardlinks_all(&closure);
+        self.sizes.write().unwrap().insert(store_path.clone(), size);
+        Ok(size)
+    }
+
+    /// Combined closure of many store paths
+    ///
+    /// Closures that are not cached yet are queried together, but not added to the cache, as this
+    /// is mostly used for large numbers of paths that are not looked at individually.
+    pub fn full_closure(&self, paths: &[&StorePath]) -> HashSet<StorePath> {
+        let mut full_closure = HashSet::default();
+        let mut missing = Vec::new();
+        {
+            let mut closures = self.closures.lock().unwrap();
+            for store_path in paths {
+                match closures.get(store_path) {
+                    Some(closure) => full_closure.extend(closure.iter().cloned()),
+                    None => missing.push(store_path.path().clone()),
+                }
+            }
+        }
+
+        let chunks: Vec<_> = missing.chunks(CLOSURE_LOOKUP_CHUNK_SIZE).collect();
+        let queried: Vec<_> = chunks.par_iter()
+            .flat_map(|chunk| {
+                let start = Instant::now();
+                match timings::measure(Phase::ClosureQueries, || backend().requisites(chunk)) {
+                    Ok(closure) => {
+                        debug(&format!("queried closure of {} paths ({} paths) in {:.2}s",
+                            chunk.len(), closure.len(), start.elapsed().as_secs_f64()));
+                        Some(closure)
+                    },
+                    Err(e) => {
+                        debug(&format!("unable to query closure of {} paths: {}", chunk.len(), e));
+                        None
+                    },
+                }
+            })
+            .collect();
+        full_closure.extend(queried.into_iter().flatten().flat_map(StorePath::new));
+
+        full_closure
+    }
+
+    /// Query the closures of all given store paths at once
+    ///
+    /// Instead of asking Nix for every closure on its own, the references of the combined closure
+    /// are fetched in a single query and the individual closures are derived from them.
+    pub fn prefetch(&self, paths: &[StorePath]) -> Result<(), String> {
+        let missing: Vec<_> = {
+            let closures = self.closures.lock().unwrap();
+            let unique: HashSet<_> = paths.iter()
+                .filter(|sp| !closures.entries.contains_key(*sp))
+                .collect();
+            unique.into_iter().cloned().collect()
+        };
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let missing_paths: Vec<_> = missing.iter().map(|sp| sp.path().clone()).collect();
+        let references: HashMap<PathBuf, Vec<PathBuf>> = timings::measure(Phase::ClosureQueries, || {
+            backend().reference_graph(&missing_paths)
+        })?.into_iter().collect();
+
+        let mut new_closures = Vec::with_capacity(missing.len());
+        for store_path in missing {
+            let mut closure = HashSet::default();
+            let mut queue = vec![store_path.path()];
+            while let Some(path) = queue.pop() {
+                if closure.insert(path) {
+                    queue.extend(references.get(path).into_iter().flatten());
+                }
+            }
+            let closure: HashSet<_> = closure.into_iter()
+                .flat_map(|p| StorePath::new(p.clone()))
+                .collect();
+            new_closures.push((store_path, Arc::new(closure)));
+        }
+        debug(&format!("queried closures of {} paths ({} paths in total) in {:.2}s",
+            new_closures.len(), references.len(), start.elapsed().as_secs_f64()));
+
+        let mut closures = self.closures.lock().unwrap();
+        for (store_path, closure) in new_closures {
+            closures.insert(store_path, closure);
+        }
+        Ok(())
+    }
+
+    /// Size of the store considering hardlinks, measured by `measure` unless already known
+    pub fn store_size(&self, measure: impl FnOnce() -> u64) -> u64 {
+        if let Some(size) = *self.store_size.read().unwrap() {
+            return size;
+        }
+        let size = measure();
+        *self.store_size.write().unwrap() = Some(size);
+        size
+    }
+
+    /// Forget the size of the store, as it has changed
+    pub fn invalidate_store_size(&self) {
+        *self.store_size.write().unwrap() = None;
+    }
+
+    /// Print how well the cache worked out to stderr
+    pub fn report(&self) {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let rate = match hits + misses {
+            0 => 0,
+            lookups => hits * 100 / lookups,
+        };
+        let closures = self.closures.lock().unwrap();
+
+        eprintln!("\n{}", "=> Closure cache".green());
+        eprintln!("{:<12} {}", "Hits:", hits);
+        eprintln!("{:<12} {} ({}% hit rate)", "Misses:", misses, rate);
+        eprintln!("{:<12} {}", "Evictions:", closures.evictions);
+        eprintln!("{:<12} {} closures using about {} (limit {})", "Entries:", closures.entries.len(),
+            FmtSize::new(closures.memory), FmtSize::new(closures.memory_limit));
+    }
+}
+
+impl Lru {
+    fn get(&mut self, store_path: &StorePath) -> Option<&Arc<HashSet<StorePath>>> {
+        let entry = self.entries.get_mut(store_path)?;
+        self.clock += 1;
+        self.by_use.remove(&entry.last_used);
+        self.by_use.insert(self.clock, store_path.clone());
+        entry.last_used = self.clock;
+        Some(&entry.closure)
+    }
+
+    fn insert(&mut self, store_path: StorePath, closure: Arc<HashSet<StorePath>>) {
+        let memory = estimate_memory(&store_path, &closure);
+        if memory > self.memory_limit {
+            debug(&format!("not caching closure of {}, it exceeds the memory limit", store_path.path().to_string_lossy()));
+            return;
+        }
+
+        self.clock += 1;
+        self.by_use.insert(self.clock, store_path.clone());
+        let entry = LruEntry { closure, memory, last_used: self.clock };
+        if let Some(previous) = self.entries.insert(store_path, entry) {
+            self.by_use.remove(&previous.last_used);
+            self.memory -= previous.memory;
+        }
+        self.memory += memory;
+
+        while self.memory > self.memory_limit {
+            let (_, oldest) = match self.by_use.pop_first() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.memory -= entry.memory;
+                self.evictions += 1;
+            }
+        }
+    }
+}
+
+/// Rough number of bytes a closure takes up in the cache, including its key
+fn estimate_memory(store_path: &StorePath, closure: &HashSet<StorePath>) -> u64 {
+    // every bucket of the set holds a store path and a control byte
+    let buckets = closure.capacity() * (mem::size_of::<StorePath>() + 1);
+    let paths: usize = closure.iter()
+        .map(|sp| sp.path().capacity())
+        .sum();
+    let key = mem::size_of::<StorePath>() + store_path.path().capacity();
+    (buckets + paths + key + mem::size_of::<LruEntry>()) as u64
+}
@@ -0,0 +1,182 @@
+use std::ffi::OsStr;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+use crate::HashMap;
+
+use super::backend::{self, GCResult, NixBackend};
+use super::store::{Store, NIX_STORE};
+
+
+/// Backend reading path and reference information from a copy of the Nix database
+///
+/// The database is queried through the `sqlite3` command line tool and opened read-only.
+/// Store paths are looked up in a separate store directory, as the database always refers to them
+/// by their location below [NIX_STORE].
+pub struct DbBackend {
+    db: PathBuf,
+    store: PathBuf,
+}
+
+
+/// Use the database at `db` and the store directory `store` for all store queries
+pub fn init(db: &Path, store: &Path) -> Result<(), String> {
+    if !db.is_file() {
+        return Err(format!("Database {} does not exist", db.to_string_lossy()));
+    }
+    let store = store.canonicalize()
+        .map_err(|e| format!("Unable to resolve store {}: {}", store.to_string_lossy(), e))?;
+
+    Store::set_dir(store.clone())?;
+    backend::set_backend(Box::new(DbBackend { db: db.to_path_buf(), store }))
+}
+
+impl DbBackend {
+    fn query(&self, sql: &str) -> Result<Vec<String>, String> {
+        let output = process::Command::new("sqlite3")
+            .args([OsStr::new("-readonly"), OsStr::new("-batch"), OsStr::new("-noheader")])
+            .arg(&self.db)
+            .arg(sql)
+            .stdin(process::Stdio::null())
+            .stderr(process::Stdio::inherit())
+            .output()
+            .map_err(|e| format!("Unable to run `sqlite3`: {e}"))?;
+
+        if !output.status.success() {
+            match output.status.code() {
+                Some(code) => return Err(format!("`sqlite3` failed (exit code {code})")),
+                None => return Err("`sqlite3` failed".to_owned()),
+            }
+        }
+
+        let output = String::from_utf8(output.stdout)
+            .map_err(|e| e.to_string())?;
+        Ok(output.lines().map(str::to_owned).collect())
+    }
+
+    /// Location of a path from the database in the store directory
+    fn to_store(&self, path: &str) -> PathBuf {
+        match Path::new(path).strip_prefix(NIX_STORE) {
+            Ok(rest) => self.store.join(rest),
+            Err(_) => PathBuf::from(path),
+        }
+    }
+
+    /// Name of a path in the store directory as recorded in the database
+    fn to_db(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.store) {
+            Ok(rest) => Path::new(NIX_STORE).join(rest).to_string_lossy().to_string(),
+            Err(_) => path.to_string_lossy().to_string(),
+        }
+    }
+
+    fn unsupported<T>(what: &str) -> Result<T, String> {
+        Err(format!("{what} is not supported when working on a database copy"))
+    }
+}
+
+impl NixBackend for DbBackend {
+    fn valid_paths(&self) -> Result<Vec<PathBuf>, String> {
+        let paths = self.query("SELECT path FROM ValidPaths;")?
+            .iter()
+            .map(|p| self.to_store(p))
+            .collect();
+        Ok(paths)
+    }
+
+    fn requisites(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let paths: Vec<_> = paths.iter()
+            .map(|p| format!("'{}'", self.to_db(p).replace('\'', "''")))
+            .collect();
+        let sql = format!("WITH RECURSIVE closure(id) AS (\
+                SELECT id FROM ValidPaths WHERE path IN ({}) \
+                UNION SELECT reference FROM Refs JOIN closure ON referrer = closure.id\
+            ) SELECT path FROM ValidPaths JOIN closure ON ValidPaths.id = closure.id;",
+            paths.join(","));
+
+        let closure = self.query(&sql)?
+            .iter()
+            .map(|p| self.to_store(p))
+            .collect();
+        Ok(closure)
+    }
+
+    fn referrers(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let paths: Vec<_> = paths.iter()
+            .map(|p| format!("'{}'", self.to_db(p).replace('\'', "''")))
+            .collect();
+        let sql = format!("WITH RECURSIVE closure(id) AS (\
+                SELECT id FROM ValidPaths WHERE path IN ({}) \
+                UNION SELECT referrer FROM Refs JOIN closure ON reference = closure.id\
+            ) SELECT path FROM ValidPaths JOIN closure ON ValidPaths.id = closure.id;",
+            paths.join(","));
+
+        let closure = self.query(&sql)?
+            .iter()
+            .map(|p| self.to_store(p))
+            .collect();
+        Ok(closure)
+    }
+
+    fn reference_graph(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String> {
+        let paths: Vec<_> = paths.iter()
+            .map(|p| format!("'{}'", self.to_db(p).replace('\'', "''")))
+            .collect();
+        let sql = format!("WITH RECURSIVE closure(id) AS (\
+                SELECT id FROM ValidPaths WHERE path IN ({}) \
+                UNION SELECT reference FROM Refs JOIN closure ON referrer = closure.id\
+            ) SELECT referrer.path, reference.path FROM closure \
+                JOIN ValidPaths referrer ON referrer.id = closure.id \
+                LEFT JOIN Refs ON Refs.referrer = closure.id \
+                LEFT JOIN ValidPaths reference ON reference.id = Refs.reference;",
+            paths.join(","));
+
+        let mut graph: HashMap<PathBuf, Vec<PathBuf>> = HashMap::default();
+        for row in self.query(&sql)? {
+            let (referrer, reference) = row.split_once('|').unwrap_or((&row, ""));
+            let references = graph.entry(self.to_store(referrer)).or_default();
+            if !reference.is_empty() {
+                references.push(self.to_store(reference));
+            }
+        }
+        Ok(graph.into_iter().collect())
+    }
+
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        Self::unsupported("Looking up gc roots")
+    }
+
+    fn roots_of(&self, _path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        Self::unsupported("Looking up gc roots")
+    }
+
+    fn dead_paths(&self) -> Result<Vec<PathBuf>, String> {
+        Self::unsupported("Looking up dead paths")
+    }
+
+    fn collect_garbage(&self, _max_freed: Option<u64>, _deadline: Option<Instant>, _on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String> {
+        Self::unsupported("Garbage collection")
+    }
+
+    fn verify(&self, _check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        Self::unsupported("Verification")
+    }
+
+    fn delete_paths(&self, _paths: &[PathBuf]) -> Result<(), String> {
+        Self::unsupported("Deleting store paths")
+    }
+
+    fn repair_path(&self, _path: &Path) -> Result<(), String> {
+        Self::unsupported("Repairing store paths")
+    }
+
+    fn delete_generation(&self, _profile: &Path, _number: usize) -> Result<(), String> {
+        Self::unsupported("Removing generations")
+    }
+
+    fn realise(&self, _drv: &Path) -> Result<Vec<PathBuf>, String> {
+        Self::unsupported("Building derivations")
+    }
+}
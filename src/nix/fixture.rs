@@ -0,0 +1,162 @@
+use std::fs;
+use std::hash::{Hash, Hasher as _};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use rustix::fs::{AtFlags, Timespec, Timestamps, CWD};
+
+use crate::Hasher;
+
+use super::roots::GCRoot;
+use super::store::{HASH_ALPHABET, NIX_STORE};
+
+
+const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+const CURRENT_SYSTEM: &str = "/run/current-system";
+const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots";
+const HASH_LEN: usize = 32;
+
+
+/// Fake Nix installation below a directory, for use with `--store-root`
+///
+/// Store paths are plain directories whose files mention the paths they reference, which is how
+/// the sandbox backend finds references. All paths passed to and returned from the methods are
+/// absolute paths as seen from inside the fixture (e.g. `/nix/store/...`).
+pub struct Fixture {
+    root: PathBuf,
+    counter: u64,
+}
+
+
+impl Fixture {
+    /// Set up the directory structure of an empty installation in `root`
+    pub fn create(root: &Path) -> Result<Self, String> {
+        let fixture = Fixture { root: root.to_path_buf(), counter: 0 };
+        for dir in [NIX_STORE, PROFILES_DIR, &format!("{GC_ROOTS_DIR}/auto")] {
+            fixture.create_dir(Path::new(dir))?;
+        }
+
+        // profiles are registered as gc roots through the whole profile directory, as by Nix itself
+        fixture.link(Path::new(&format!("{GC_ROOTS_DIR}/profiles")), Path::new(PROFILES_DIR))?;
+        Ok(fixture)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Location of an absolute path of the fixture on the real file system
+    pub fn path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Add a store path with `size` bytes of content, referencing the given store paths
+    pub fn add_store_path(&mut self, name: &str, size: usize, references: &[PathBuf]) -> Result<PathBuf, String> {
+        let store_path = self.missing_store_path(name);
+        self.create_dir(&store_path)?;
+        self.write(&store_path.join("data"), &vec![b'x'; size])?;
+
+        let refs: String = references.iter()
+            .map(|r| format!("{}\n", r.to_string_lossy()))
+            .collect();
+        if !refs.is_empty() {
+            self.write(&store_path.join("references"), refs.as_bytes())?;
+        }
+        Ok(store_path)
+    }
+
+    /// Name of a store path that does not exist, e.g. to simulate a broken generation
+    pub fn missing_store_path(&mut self, name: &str) -> PathBuf {
+        self.counter += 1;
+        let mut hash = String::with_capacity(HASH_LEN);
+        let mut round = 0;
+        while hash.len() < HASH_LEN {
+            let mut hasher = Hasher::default();
+            (name, self.counter, round).hash(&mut hasher);
+            let mut bits = hasher.finish();
+            for _ in 0..(64 / 5) {
+                hash.push(HASH_ALPHABET[(bits % 32) as usize] as char);
+                bits /= 32;
+            }
+            round += 1;
+        }
+        hash.truncate(HASH_LEN);
+        Path::new(NIX_STORE).join(format!("{hash}-{name}"))
+    }
+
+    /// Add generation `number` of the profile at `profile` (e.g. `/nix/var/nix/profiles/system`)
+    ///
+    /// The generation link is backdated by `age`. The profile itself is pointed to the generation,
+    /// so the last one added is the active one.
+    pub fn add_generation(&self, profile: &Path, number: usize, target: &Path, age: Duration) -> Result<(), String> {
+        let name = profile.file_name()
+            .ok_or(format!("Invalid profile path {}", profile.to_string_lossy()))?
+            .to_string_lossy();
+        let parent = profile.parent().unwrap_or(Path::new("/"));
+        let link_name = format!("{name}-{number}-link");
+
+        self.create_dir(parent)?;
+        self.link(&parent.join(&link_name), target)?;
+        self.set_age(&parent.join(&link_name), age)?;
+        self.replace_link(profile, Path::new(&link_name))?;
+        self.set_age(profile, age)
+    }
+
+    /// Add a gc root at `link` (e.g. a `result` link in a home directory), registered in the auto
+    /// directory like Nix does for indirect roots
+    pub fn add_gc_root(&self, link: &Path, target: &Path, age: Duration) -> Result<(), String> {
+        if let Some(parent) = link.parent() {
+            self.create_dir(parent)?;
+        }
+        self.link(link, target)?;
+        self.set_age(link, age)?;
+        self.add_auto_root(link)
+    }
+
+    /// Point `/run/current-system` to `target`, as on a booted NixOS or nix-darwin system
+    pub fn set_running_system(&self, target: &Path) -> Result<(), String> {
+        self.create_dir(Path::new("/run"))?;
+        self.replace_link(Path::new(CURRENT_SYSTEM), target)
+    }
+
+    /// Register `link` in the auto directory, whether it exists or not
+    pub fn add_auto_root(&self, link: &Path) -> Result<(), String> {
+        let auto = Path::new(GC_ROOTS_DIR).join("auto").join(GCRoot::auto_name(link));
+        self.link(&auto, link)
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), String> {
+        fs::create_dir_all(self.path(path))
+            .map_err(|e| format!("Unable to create {}: {}", self.path(path).to_string_lossy(), e))
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), String> {
+        fs::write(self.path(path), content)
+            .map_err(|e| format!("Unable to write {}: {}", self.path(path).to_string_lossy(), e))
+    }
+
+    fn link(&self, link: &Path, target: &Path) -> Result<(), String> {
+        symlink(target, self.path(link))
+            .map_err(|e| format!("Unable to create link {}: {}", self.path(link).to_string_lossy(), e))
+    }
+
+    fn replace_link(&self, link: &Path, target: &Path) -> Result<(), String> {
+        if fs::symlink_metadata(self.path(link)).is_ok() {
+            fs::remove_file(self.path(link))
+                .map_err(|e| format!("Unable to replace link {}: {}", self.path(link).to_string_lossy(), e))?;
+        }
+        self.link(link, target)
+    }
+
+    /// Backdate the modification time of a link, which is what generation ages are based on
+    fn set_age(&self, link: &Path, age: Duration) -> Result<(), String> {
+        let time = SystemTime::now().checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let timespec = Timespec { tv_sec: time.as_secs() as _, tv_nsec: time.subsec_nanos() as _ };
+        let timestamps = Timestamps { last_access: timespec, last_modification: timespec };
+        rustix::fs::utimensat(CWD, self.path(link), &timestamps, AtFlags::SYMLINK_NOFOLLOW)
+            .map_err(|e| format!("Unable to set the age of {}: {}", self.path(link).to_string_lossy(), e))
+    }
+}
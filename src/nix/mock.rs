@@ -0,0 +1,137 @@
+use std::fs;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+use std::time::Instant;
+
+use super::backend::{self, GCResult, NixBackend};
+
+
+static STATE: Mutex<MockState> = Mutex::new(MockState { dead: Vec::new(), calls: Vec::new() });
+static EXCLUSIVE: Mutex<()> = Mutex::new(());
+
+
+/// Backend for tests, recording the modifications requested instead of running Nix
+///
+/// Deleting a generation removes its link like `nix-env` does, and a garbage collection deletes the
+/// paths registered with [Session::add_dead]. Store paths have no references.
+pub struct MockBackend;
+
+/// Exclusive use of the [MockBackend] by a test
+///
+/// The backend is shared by the whole process, so tests using it are run one after another.
+pub struct Session {
+    _exclusive: MutexGuard<'static, ()>,
+}
+
+/// Modification requested from the [MockBackend]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    CollectGarbage(Option<u64>),
+    DeletePaths(Vec<PathBuf>),
+    DeleteGeneration(PathBuf, usize),
+}
+
+#[derive(Default)]
+struct MockState {
+    dead: Vec<PathBuf>,
+    calls: Vec<Call>,
+}
+
+
+/// Set up the mock as backend of this process and start with a clean slate
+///
+/// No other backend may have been used before.
+pub fn install() -> Session {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| backend::set_backend(Box::new(MockBackend)).unwrap());
+
+    let exclusive = EXCLUSIVE.lock().unwrap_or_else(PoisonError::into_inner);
+    *state() = MockState::default();
+    Session { _exclusive: exclusive }
+}
+
+fn state() -> MutexGuard<'static, MockState> {
+    STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+
+impl Session {
+    /// Add paths to be deleted by the next garbage collection
+    pub fn add_dead(&self, paths: &[PathBuf]) {
+        state().dead.extend_from_slice(paths);
+    }
+
+    /// Modifications requested so far, in order
+    pub fn calls(&self) -> Vec<Call> {
+        state().calls.clone()
+    }
+}
+
+impl NixBackend for MockBackend {
+    fn requisites(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        Ok(paths.to_vec())
+    }
+
+    fn referrers(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        Ok(paths.to_vec())
+    }
+
+    fn reference_graph(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String> {
+        Ok(paths.iter().map(|p| (p.clone(), Vec::new())).collect())
+    }
+
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        Ok(Vec::new())
+    }
+
+    fn roots_of(&self, _path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        Ok(Vec::new())
+    }
+
+    fn dead_paths(&self) -> Result<Vec<PathBuf>, String> {
+        Ok(state().dead.clone())
+    }
+
+    fn collect_garbage(&self, max_freed: Option<u64>, _deadline: Option<Instant>, on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String> {
+        state().calls.push(Call::CollectGarbage(max_freed));
+        let mut deleted = 0;
+        // the state is not locked while calling back, as the callback may query the backend
+        while let Some(path) = state().dead.pop() {
+            deleted += 1;
+            if on_delete(&path).is_break() {
+                break;
+            }
+        }
+        Ok(Some(GCResult { deleted, freed: 0 }))
+    }
+
+    fn verify(&self, _check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        Ok((Vec::new(), Vec::new()))
+    }
+
+    fn delete_paths(&self, paths: &[PathBuf]) -> Result<(), String> {
+        let mut state = state();
+        state.calls.push(Call::DeletePaths(paths.to_vec()));
+        state.dead.retain(|p| !paths.contains(p));
+        Ok(())
+    }
+
+    fn repair_path(&self, _path: &Path) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn delete_generation(&self, profile: &Path, number: usize) -> Result<(), String> {
+        state().calls.push(Call::DeleteGeneration(profile.to_path_buf(), number));
+        let name = profile.file_name()
+            .ok_or(format!("Invalid profile path {}", profile.to_string_lossy()))?
+            .to_string_lossy();
+        let link = profile.with_file_name(format!("{name}-{number}-link"));
+        fs::remove_file(&link)
+            .map_err(|e| format!("Unable to remove {}: {}", link.to_string_lossy(), e))
+    }
+
+    fn realise(&self, drv: &Path) -> Result<Vec<PathBuf>, String> {
+        Err(format!("Unable to realise {} without Nix", drv.to_string_lossy()))
+    }
+}
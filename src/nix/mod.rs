@@ -1,3 +1,15 @@
+pub mod backend;
+pub mod bootloader;
+pub mod builds;
+pub mod closures;
+pub mod database;
+pub mod fixture;
+#[cfg(test)]
+pub mod mock;
+pub mod offline;
+pub mod planner;
 pub mod profiles;
 pub mod roots;
+pub mod sandbox;
+pub mod settings;
 pub mod store;
@@ -0,0 +1,86 @@
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use super::backend::{GCResult, NixBackend};
+
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+
+/// Backend that never runs Nix, for systems where neither the daemon nor the Nix tools can be used
+///
+/// Only what can be found out by looking at the file system is available. Everything that would
+/// require a query (closures, references, process roots) or a modification of the store fails.
+pub struct OfflineBackend;
+
+
+/// Use [OfflineBackend] instead of [super::backend::NixCli] unless another backend is set up
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+impl OfflineBackend {
+    fn unavailable<T>(what: &str) -> Result<T, String> {
+        Err(format!("{what} is not available offline"))
+    }
+}
+
+impl NixBackend for OfflineBackend {
+    fn requisites(&self, _paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        Self::unavailable("Querying closures")
+    }
+
+    fn referrers(&self, _paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        Self::unavailable("Querying referrers")
+    }
+
+    fn reference_graph(&self, _paths: &[PathBuf]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String> {
+        Self::unavailable("Querying references")
+    }
+
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        Self::unavailable("Querying gc roots from Nix")
+    }
+
+    fn roots_of(&self, _path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        Self::unavailable("Querying gc roots from Nix")
+    }
+
+    fn dead_paths(&self) -> Result<Vec<PathBuf>, String> {
+        Self::unavailable("Looking up dead paths")
+    }
+
+    fn collect_garbage(&self, _max_freed: Option<u64>, _deadline: Option<Instant>, _on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String> {
+        Self::unavailable("Garbage collection")
+    }
+
+    fn verify(&self, _check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        Self::unavailable("Verification")
+    }
+
+    fn delete_paths(&self, _paths: &[PathBuf]) -> Result<(), String> {
+        Self::unavailable("Deleting store paths")
+    }
+
+    fn repair_path(&self, _path: &Path) -> Result<(), String> {
+        Self::unavailable("Repairing store paths")
+    }
+
+    fn delete_generation(&self, _profile: &Path, _number: usize) -> Result<(), String> {
+        Self::unavailable("Removing generations with nix-env (use --removal-backend unlink instead)")
+    }
+
+    fn realise(&self, _drv: &Path) -> Result<Vec<PathBuf>, String> {
+        Self::unavailable("Building derivations")
+    }
+
+    fn is_offline(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,103 @@
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::HashMap;
+use crate::HashSet;
+use crate::utils::interaction::debug;
+use crate::nix::backend::backend;
+use crate::nix::closures;
+use crate::nix::profiles::Profile;
+use crate::nix::store::StorePath;
+
+
+/// Outcome of [free_at_least]
+pub struct Plan {
+    /// Number of generations that remain marked
+    pub selected: usize,
+
+    /// Number of generations that were marked before planning
+    pub candidates: usize,
+
+    /// Estimated size of the store paths freed by removing the selected generations
+    pub expected: u64,
+}
+
+
+/// Narrow down the marked generations to those freeing at least `target` bytes
+///
+/// Generations are picked by the size of the store paths only they still hold on to, so few large
+/// generations are removed rather than many small ones. Whenever no single generation frees anything
+/// on its own (e.g. two generations sharing a large closure), the oldest one is picked, as removing
+/// it lets the others free their shared paths. Planning stops as soon as the target is reached; all
+/// other generations are unmarked.
+///
+/// Paths held by generations that are not marked, in any of the `profiles`, are never counted.
+/// Other gc roots are not considered, so the result is an estimate.
+pub fn free_at_least(profiles: &mut [Profile], target: u64) -> Result<Plan, String> {
+    if backend().is_offline() {
+        return Err("Removing generations by freed size (free-at-least) is not possible offline".to_owned());
+    }
+
+    let candidates: Vec<(usize, usize)> = profiles.iter()
+        .enumerate()
+        .flat_map(|(i, p)| p.generations().iter().filter(|g| g.marked()).map(move |g| (i, g.number())))
+        .collect();
+    let generation = |(profile, number): (usize, usize)| profiles[profile].generations().iter()
+        .find(|g| g.number() == number)
+        .expect("candidate generation vanished");
+
+    let kept: HashSet<StorePath> = closures::union(profiles.par_iter()
+        .flat_map(|p| p.generations().par_iter().filter(|g| !g.marked()))
+        .flat_map(|g| g.closure()));
+
+    // store paths each candidate would free, once no other candidate holds them anymore
+    let closures: Vec<Vec<StorePath>> = candidates.par_iter()
+        .map(|c| generation(*c).closure()
+            .map(|closure| closure.iter().filter(|sp| !kept.contains(*sp)).cloned().collect())
+            .unwrap_or_default())
+        .collect();
+    let mut holders: HashMap<&StorePath, usize> = HashMap::default();
+    for store_path in closures.iter().flatten() {
+        *holders.entry(store_path).or_default() += 1;
+    }
+    let sizes: HashMap<&StorePath, u64> = holders.keys()
+        .copied()
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|sp| (*sp, sp.size()))
+        .collect();
+
+    let mut remaining: Vec<_> = (0..candidates.len()).collect();
+    let mut selected = Vec::new();
+    let mut expected = 0;
+    while expected < target && !remaining.is_empty() {
+        let gain = |i: &usize| closures[*i].iter()
+            .filter(|sp| holders[sp] == 1)
+            .map(|sp| sizes[sp])
+            .sum::<u64>();
+        let created = |i: &usize| generation(candidates[*i]).created();
+
+        let (pos, best_gain) = remaining.iter()
+            .map(gain)
+            .enumerate()
+            .max_by_key(|(pos, gain)| (*gain, std::cmp::Reverse(created(&remaining[*pos]))))
+            .expect("no candidates left");
+        let best = remaining.swap_remove(pos);
+        debug(&format!("planning removal of generation {} of {} (frees {best_gain} bytes)",
+            candidates[best].1, profiles[candidates[best].0].path().to_string_lossy()));
+
+        for store_path in &closures[best] {
+            if let Some(count) = holders.get_mut(store_path) {
+                *count -= 1;
+            }
+        }
+        expected += best_gain;
+        selected.push(best);
+    }
+
+    for i in remaining {
+        let (profile, number) = candidates[i];
+        profiles[profile].toggle_marker(number)?;
+    }
+
+    Ok(Plan { selected: selected.len(), candidates: candidates.len(), expected })
+}
@@ -1,11 +1,10 @@
-use std::env;
 use std::fs;
 use std::path;
 use std::path::Component;
-use std::process;
 use std::str;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use std::time::SystemTime;
 
@@ -14,17 +13,47 @@ use rayon::iter::IndexedParallelIterator;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 
-use crate::config;
+use crate::config::{self, GlobalConfig};
+use crate::utils::calendar::Date;
 use crate::utils::files::dir_size_considering_hardlinks_all;
-use crate::utils::fmt::FmtAge;
+use crate::utils::fmt::{age_colored, size_overlap_note, FmtAge};
 use crate::utils::fmt::FmtSize;
-use crate::utils::fmt::Formattable;
-use crate::utils::interaction::announce;
+use crate::utils::fmt::{Formattable, FmtWithEllipsis};
+use crate::utils::glob;
+use crate::utils::interaction::{announce, debug, warn};
 use crate::utils::ordered_channel::OrderedChannel;
+use crate::utils::policy;
+use crate::utils::privileges;
+use crate::utils::timings::{self, Phase};
+use crate::utils::users::User;
+use crate::nix::backend::backend;
+use crate::nix::bootloader;
+use crate::nix::closures;
+use crate::nix::sandbox;
+use crate::nix::roots::GCRoot;
 use crate::nix::store::StorePath;
+use crate::state::State;
 use crate::HashSet;
 
 
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const SYSTEM_PROFILES_DIR: &str = "/nix/var/nix/profiles/";
+const CONFIGURATION_REVISION_FILE: &str = "configuration-revision";
+const OS_RELEASE_FILE: &str = "etc/os-release";
+const OS_RELEASE_REVISION_KEY: &str = "CONFIGURATION_REVISION=";
+const SHORT_REVISION_LEN: usize = 7;
+const KEEP_FILE: &str = ".nix-sweep-keep";
+
+const PER_USER_PROFILES_DIR: &str = "/nix/var/nix/profiles/per-user";
+
+/// Profiles that must never be deleted as a whole, as they contain Nix itself, its channels or
+/// the system (e.g. in containers, where the running system cannot be detected)
+const ESSENTIAL_PROFILES: &[&str] = &["default", "system", "channels"];
+
+/// Link to the running system on NixOS and nix-darwin (on macOS /run is a link to /private/var/run)
+pub const CURRENT_SYSTEM: &str = "/run/current-system";
+
+
 #[derive(Debug)]
 pub struct Profile {
     parent: PathBuf,
@@ -32,20 +61,35 @@ pub struct Profile {
     generations: Vec<Generation>,
 }
 
+/// Method used to remove generations
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RemovalBackend {
+    /// Remove generations with `nix-env --delete-generations`
+    #[default]
+    NixEnv,
+
+    /// Directly delete the generation symlinks
+    Unlink,
+}
+
 #[derive(Eq, Debug)]
 pub struct Generation {
     number: usize,
     path: PathBuf,
     profile_path: PathBuf,
     age: Duration,
+    created: SystemTime,
     marker: bool,
+    pending_since: Option<SystemTime>,
 }
 
 
 impl Profile {
     pub fn new(parent: PathBuf, name: String) -> Result<Self, String> {
         let full_path = parent.clone().join(&name);
-        if !fs::exists(&full_path)
+        // the link itself is enough, as the store path of a profile may have been deleted
+        let link_exists = fs::symlink_metadata(&full_path).is_ok();
+        if !link_exists && !sandbox::exists(&full_path)
             .map_err(|e| format!("Unable to check path {}: {}", full_path.to_string_lossy(), e))? {
             return Err(format!("Could not find profile '{}'", full_path.to_string_lossy()));
         }
@@ -76,50 +120,169 @@ impl Profile {
             _ => return Err(format!("Unable to retrieve profile name for profile '{}'", path.to_string_lossy())),
         };
 
+        // users sometimes pass a generation link instead of the profile itself
+        if let Some(profile_name) = profile_name_of_generation_link(&name) {
+            if !fs::exists(parent.join(profile_name)).unwrap_or(false) {
+                return Err(format!("'{}' looks like a generation link, but its profile '{}' does not exist",
+                    path.to_string_lossy(), parent.join(profile_name).to_string_lossy()));
+            }
+            warn(&format!("'{}' is a generation link, using profile '{}' instead",
+                path.to_string_lossy(), parent.join(profile_name).to_string_lossy()));
+            return Profile::new(parent, profile_name.to_owned());
+        }
+
         Profile::new(parent, name)
     }
 
-    pub fn new_user_profile(name: String) -> Result<Self, String> {
-        let check_path = |path: &str| fs::exists(format!("{path}/{name}"))
-                .map_err(|e| format!("Unable to check path {path}: {e}"));
-        let user = env::var("USER")
-            .map_err(|_| String::from("Unable to read $USER"))?;
+    pub fn new_user_profile(name: String, user: &User) -> Result<Self, String> {
+        let check_path = |path: &Path| sandbox::exists(&path.join(&name))
+                .map_err(|e| format!("Unable to check path {}: {e}", path.to_string_lossy()));
 
-        let path = format!("/nix/var/nix/profiles/per-user/{user}");
+        let path = sandbox::path(PER_USER_PROFILES_DIR).join(user.name());
         if check_path(&path)? {
-            return Self::new(PathBuf::from(path), name);
+            return Self::new(path, name);
         }
 
-        let home = env::var("HOME")
-            .map_err(|_| String::from("Unable to read $USER"))?;
-
-        let path = format!("{home}/.local/state/nix/profiles");
+        let path = user.state_profiles_dir();
         if check_path(&path)? {
-            return Self::new(PathBuf::from(path), name);
+            return Self::new(path, name);
+        }
+
+        if let Some(profile) = Self::from_profile_dirs(&name)? {
+            return Ok(profile);
         }
 
         Err("Could not find profile".to_owned())
     }
 
+    fn from_profile_dirs(name: &str) -> Result<Option<Self>, String> {
+        for dir in &GlobalConfig::get()?.profile_dirs {
+            let exists = sandbox::exists(&dir.join(name))
+                .map_err(|e| format!("Unable to check path {}: {}", dir.to_string_lossy(), e))?;
+            if exists {
+                return Self::new(dir.clone(), name.to_owned()).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Find all profiles registered as gc roots or located in the configured profile directories
+    ///
+    /// Profiles matching one of the `exclude` patterns are left out (see [is_excluded]).
+    pub fn discover(exclude: &[String]) -> Result<Vec<PathBuf>, String> {
+        let mut paths = GCRoot::profile_paths()?;
+
+        for dir in &GlobalConfig::get()?.profile_dirs {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if is_profile_link(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+
+        paths.retain(|p| !is_excluded(p, exclude));
+        paths.sort();
+        paths.dedup();
+
+        Ok(paths)
+    }
+
+    /// System profile of NixOS or nix-darwin
     pub fn system() -> Result<Self, String> {
-        Self::new(PathBuf::from("/nix/var/nix/profiles/"), String::from("system"))
+        let profile = Self::new(sandbox::path(SYSTEM_PROFILES_DIR), String::from("system"));
+        match profile {
+            Err(_) if cfg!(target_os = "macos") =>
+                Err(format!("Could not find the nix-darwin system profile in {SYSTEM_PROFILES_DIR} (is nix-darwin installed?)")),
+            profile => profile,
+        }
     }
 
     pub fn home() -> Result<Self, String> {
-        Self::new_user_profile(String::from("home-manager"))
+        Self::new_user_profile(String::from("home-manager"), &User::current()?)
     }
 
     pub fn user() -> Result<Self, String> {
-        Self::new_user_profile(String::from("profile"))
+        Self::new_user_profile(String::from("profile"), &User::current()?)
+    }
+
+    /// Like [Profile::from_str], but resolves the `user` and `home` shorthands for the given user
+    pub fn from_str_for_user(s: &str, user: Option<&User>) -> Result<Self, String> {
+        match (s, user) {
+            ("user", Some(user)) => Self::new_user_profile(String::from("profile"), user),
+            ("home", Some(user)) => Self::new_user_profile(String::from("home-manager"), user),
+            _ => Self::from_str(s),
+        }
     }
 
-    pub fn apply_markers(&mut self, config: &config::ConfigPreset) {
+    /// Resolve profile arguments (including patterns), reporting all that cannot be resolved
+    ///
+    /// With `skip_missing` unresolvable arguments only cause a warning, as long as at least one
+    /// profile could be resolved. Profiles matching one of the `exclude` patterns are dropped.
+    pub fn resolve_args(args: &[String], user: Option<&User>, skip_missing: bool, exclude: &[String]) -> Result<Vec<Self>, String> {
+        let mut profiles = Vec::with_capacity(args.len());
+        let mut errors = Vec::new();
+
+        for arg in args {
+            let resolved = glob::expand_args(std::slice::from_ref(arg))
+                .and_then(|strs| strs.iter().map(|s| Self::from_str_for_user(s, user)).collect::<Result<Vec<_>, _>>());
+            match resolved {
+                Ok(resolved) => profiles.extend(resolved),
+                Err(e) => errors.push(format!("{arg}: {e}")),
+            }
+        }
+
+        profiles.retain(|p| {
+            let excluded = is_excluded(&p.path(), exclude);
+            if excluded {
+                debug(&format!("excluding profile {}", p.path().to_string_lossy()));
+            }
+            !excluded
+        });
+
+        if errors.is_empty() {
+            Ok(profiles)
+        } else if skip_missing && !profiles.is_empty() {
+            for error in errors {
+                warn(&format!("Skipping profile {error}"));
+            }
+            Ok(profiles)
+        } else {
+            Err(format!("Unable to resolve {} of {} profiles:\n  {}", errors.len(), args.len(), errors.join("\n  ")))
+        }
+    }
+
+    pub fn apply_markers(&mut self, config: &config::ConfigPreset) -> Result<(), String> {
         // negative criteria are applied first
 
         // mark older generations
         if let Some(older) = config.remove_older {
+            // whole days are compared by local calendar day
+            let cutoff = match older.as_secs() % SECS_PER_DAY {
+                0 => Some(Date::local(SystemTime::now()).days_before(older.as_secs() / SECS_PER_DAY)),
+                _ => None,
+            };
             for generation in self.generations.iter_mut() {
-                if generation.age() >= older {
+                let older = match cutoff {
+                    Some(cutoff) => Date::local(generation.created()) < cutoff,
+                    None => generation.age() >= older,
+                };
+                if older {
+                    generation.mark();
+                }
+            }
+        }
+
+        // mark generations created before a fixed date
+        if let Some(before) = config.remove_before {
+            for generation in self.generations.iter_mut() {
+                if before.is_after(generation.created()) {
                     generation.mark();
                 }
             }
@@ -143,6 +306,15 @@ impl Profile {
             }
         }
 
+        // unmark generations created since a fixed date
+        if let Some(since) = config.keep_since {
+            for generation in self.generations.iter_mut() {
+                if !since.is_after(generation.created()) {
+                    generation.unmark();
+                }
+            }
+        }
+
         // unmark kept generations
         if let Some(min) = config.keep_min {
             for (i, generation) in self.generations.iter_mut().rev().enumerate() {
@@ -152,6 +324,32 @@ impl Profile {
             }
         }
 
+        // unmark the newest generation of each of the last calendar days, weeks and months
+        if let Some(count) = config.keep_daily {
+            self.keep_per_period(count, |d| d);
+        }
+        if let Some(count) = config.keep_weekly {
+            self.keep_per_period(count, Date::week_start);
+        }
+        if let Some(count) = config.keep_monthly {
+            self.keep_per_period(count, Date::month_start);
+        }
+
+        // mark generations with oversized closures
+        if let Some(max_size) = config.remove_larger_than {
+            if backend().is_offline() {
+                return Err("Removing generations by size (remove-larger-than) is not possible offline".to_owned());
+            }
+            let sizes: Vec<_> = self.generations.par_iter()
+                .map(|g| g.store_path().and_then(|sp| sp.closure_size()).ok())
+                .collect();
+            for (generation, size) in self.generations.iter_mut().zip(sizes) {
+                if size.is_some_and(|s| s > max_size) {
+                    generation.mark();
+                }
+            }
+        }
+
         // mark explicitly removed generations
         for num in &config.generations {
             let generation = self.generations.iter_mut()
@@ -161,6 +359,31 @@ impl Profile {
             }
         }
 
+        // let an external policy script decide
+        if let Some(script) = &config.policy_script {
+            let marked = policy::evaluate(script, config.hook_timeout, self)?;
+            for (i, generation) in self.generations.iter_mut().enumerate() {
+                generation.marker = marked.contains(&i);
+            }
+        }
+
+        // rollback targets right before the active generation
+        if let Some(count) = config.protect_previous
+            && let Some(active) = self.generations.iter().position(|g| self.is_active_generation(g)) {
+            for generation in self.generations[..active].iter_mut().rev().take(count) {
+                generation.unmark();
+            }
+        }
+
+        // generations protected by external tools (e.g. rollback targets of deployments)
+        let protected = self.protected_patterns();
+        for generation in self.generations.iter_mut() {
+            if generation.matches_any(&protected) {
+                debug(&format!("keeping generation {} as listed in {}", generation.number(), KEEP_FILE));
+                generation.unmark();
+            }
+        }
+
         // always unmark newest generation
         if let Some(newest) = self.generations.last_mut() {
             newest.unmark()
@@ -170,6 +393,140 @@ impl Profile {
         if let Ok(active) = self.active_generation_mut() {
             active.unmark()
         }
+
+        // the running system may still be an older generation (e.g. after `nixos-rebuild boot`)
+        let path = self.path();
+        if let Some(running) = self.running_generation_mut()
+                && running.marked() {
+            warn(&format!("Keeping generation {} of {}, which is the running system ({}) but not the active one",
+                running.number(), path.to_string_lossy(), CURRENT_SYSTEM));
+            running.unmark();
+        }
+
+        // the bootloader may default to yet another one (e.g. after `bootctl set-default`)
+        if let Some(default) = self.boot_default_generation_mut()
+                && default.marked() {
+            warn(&format!("Keeping generation {} of {}, which is the default boot entry",
+                default.number(), path.to_string_lossy()));
+            default.unmark();
+        }
+
+        Ok(())
+    }
+
+    /// Generations listed in the keep file of the profile directory, one number or pattern per line
+    ///
+    /// Patterns are matched against the generation number and the name of the generation link
+    /// (e.g. `system-42-link`), so a file shared by several profiles can refer to a single one.
+    fn protected_patterns(&self) -> Vec<String> {
+        let path = self.parent.join(KEEP_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                warn(&format!("Unable to read {}: {}", path.to_string_lossy(), e));
+                return Vec::new();
+            },
+        };
+
+        content.lines()
+            .map(|l| l.split('#').next().unwrap_or_default().trim())
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_owned())
+            .collect()
+    }
+
+    /// Unmark the newest generation of each of the last `count` periods containing generations
+    fn keep_per_period(&mut self, count: usize, period_of: fn(Date) -> Date) {
+        let mut last_period = None;
+        let mut kept = 0;
+        for generation in self.generations.iter_mut().rev() {
+            if kept >= count {
+                break;
+            }
+
+            let period = period_of(Date::local(generation.created()));
+            if last_period != Some(period) {
+                generation.unmark();
+                last_period = Some(period);
+                kept += 1;
+            }
+        }
+    }
+
+    pub fn apply_pending(&mut self, state: &State) {
+        for generation in self.generations.iter_mut() {
+            generation.pending_since = state.pending_since(generation.path());
+        }
+    }
+
+    pub fn apply_deferral(&mut self, defer: Duration, state: &mut State) {
+        let now = SystemTime::now();
+
+        for generation in self.generations.iter_mut() {
+            if generation.marked() {
+                let since = *generation.pending_since.get_or_insert(now);
+                state.defer(generation.path(), since);
+
+                // only remove generations, whose deferral period has passed
+                if now.duration_since(since).unwrap_or_default() < defer {
+                    generation.unmark();
+                }
+            } else {
+                state.undefer(generation.path());
+                generation.pending_since = None;
+            }
+        }
+    }
+
+    pub fn clear_markers(&mut self) {
+        for generation in self.generations.iter_mut() {
+            generation.unmark();
+        }
+    }
+
+    /// Toggle the marker of a generation, refusing to mark the newest, the active, the running generation
+    /// or those listed in the keep file
+    pub fn toggle_marker(&mut self, number: usize) -> Result<(), String> {
+        let marked = self.generations.iter()
+            .find(|g| g.number() == number)
+            .ok_or(format!("Generation {number} does not exist"))?
+            .marked();
+        self.set_marker(number, !marked)
+    }
+
+    /// Mark or unmark a generation, with the same restrictions as [toggle_marker](Self::toggle_marker)
+    pub fn set_marker(&mut self, number: usize, marked: bool) -> Result<(), String> {
+        let protected = self.protected_patterns();
+        let newest = self.generations.last().map(|g| g.number());
+        let active = self.active_generation().ok().map(|g| g.number());
+        let running = self.running_generation().map(|g| g.number());
+        let boot_default = self.boot_default_generation().map(|g| g.number());
+        let generation = self.generations.iter_mut()
+            .find(|g| g.number() == number)
+            .ok_or(format!("Generation {number} does not exist"))?;
+
+        if !marked {
+            generation.unmark();
+        } else if Some(number) == newest || Some(number) == active {
+            return Err(format!("Generation {number} is the newest or the active one and cannot be removed"));
+        } else if Some(number) == running {
+            return Err(format!("Generation {number} is the running system and cannot be removed"));
+        } else if Some(number) == boot_default {
+            return Err(format!("Generation {number} is the default boot entry and cannot be removed"));
+        } else if generation.matches_any(&protected) {
+            return Err(format!("Generation {number} is listed in {} and cannot be removed", KEEP_FILE));
+        } else {
+            generation.mark();
+        }
+        Ok(())
+    }
+
+    pub fn marked_numbers(&self) -> Vec<usize> {
+        self.generations.iter()
+            .filter(|g| g.marked())
+            .map(|g| g.number())
+            .collect()
     }
 
     pub fn count_marked(&self) -> usize {
@@ -197,6 +554,34 @@ impl Profile {
             .ok_or("Cannot find current generation".to_owned())
     }
 
+    /// Generation of the running system, as linked from [CURRENT_SYSTEM]
+    ///
+    /// This is only ever found in the system profile of NixOS or nix-darwin.
+    pub fn running_generation(&self) -> Option<&Generation> {
+        let running = running_system()?;
+        self.generations.iter()
+            .find(|g| g.store_path().is_ok_and(|sp| sp == *running))
+    }
+
+    fn running_generation_mut(&mut self) -> Option<&mut Generation> {
+        let running = running_system()?;
+        self.generations.iter_mut()
+            .find(|g| g.store_path().is_ok_and(|sp| sp == *running))
+    }
+
+    /// Generation started by the bootloader by default, see [bootloader::default_system]
+    pub fn boot_default_generation(&self) -> Option<&Generation> {
+        let default = bootloader::default_system()?;
+        self.generations.iter()
+            .find(|g| g.store_path().is_ok_and(|sp| sp == *default))
+    }
+
+    fn boot_default_generation_mut(&mut self) -> Option<&mut Generation> {
+        let default = bootloader::default_system()?;
+        self.generations.iter_mut()
+            .find(|g| g.store_path().is_ok_and(|sp| sp == *default))
+    }
+
     pub fn active_generation_mut(&mut self) -> Result<&mut Generation, String> {
         let gen_name = fs::read_link(self.path())
             .map(|p| p.to_path_buf())
@@ -209,49 +594,72 @@ impl Profile {
     }
 
 
-    pub fn list_generations(&self, print_size: bool, print_markers: bool) {
+    /// List the generations, returning the number of generations whose size could not be calculated
+    pub fn list_generations(&self, print_size: bool, unique_sizes: bool, print_markers: bool, print_changes: bool) -> usize {
         announce(&format!("Listing generations for profile {}", self.path().to_string_lossy()));
 
         let store_paths: Vec<_> = self.generations().iter()
             .flat_map(|g| g.store_path())
             .collect();
+        let exclusive_sizes = (print_size && unique_sizes).then(|| self.exclusive_sizes());
 
         let ordered_channel: OrderedChannel<_> = OrderedChannel::new();
         let gens = self.generations();
         let ngens = gens.len();
+        let descriptions: Vec<_> = gens.par_iter()
+            .map(|g| g.description())
+            .collect();
+        let description_width = descriptions.iter()
+            .map(|d| d.len())
+            .max()
+            .unwrap_or(0);
+        let mut rows_size = 0;
+        let mut nfailed = 0;
 
         rayon::join( || {
             gens.par_iter()
                 .enumerate()
                 .map(|(i, g)| {
                     let active = self.is_active_generation(g);
-                    let size = if print_size {
-                        Some(
-                            g.store_path()
-                                .map(|sp| sp.closure_size())
-                                .unwrap_or_default()
-                        )
-                    } else { None };
-                    (i, active, size)
+                    let size = match (print_size, &exclusive_sizes) {
+                        (true, Some(sizes)) => Some(sizes[i].clone()),
+                        (true, None) => Some(g.store_path().and_then(|sp| sp.closure_size())),
+                        (false, _) => None,
+                    };
+                    let changes = match (print_changes, i.checked_sub(1)) {
+                        (true, Some(prev)) => g.store_path()
+                            .and_then(|sp| gens[prev].store_path().and_then(|prev_sp| sp.diff_closures(&prev_sp)))
+                            .ok(),
+                        _ => None,
+                    };
+                    (i, active, size, changes)
                 })
                 .for_each(|tup| ordered_channel.put(tup.0, tup));
         }, || {
-                for (i, active, size) in ordered_channel.iter(ngens) {
-                    gens[i].print_fancy(active, print_markers, size);
+                for (i, active, size, changes) in ordered_channel.iter(ngens) {
+                    let size = size.transpose();
+                    gens[i].print_fancy(active, print_markers, size.as_ref().ok().copied().flatten(), &descriptions[i], description_width);
+                    match size {
+                        Ok(size) => rows_size += size.unwrap_or(0),
+                        Err(e) => {
+                            nfailed += 1;
+                            println!("{}", format!("  -> unable to calculate closure size: {e}").red());
+                        },
+                    }
+                    if let Some(changes) = changes {
+                        println!("{}", format!("  -> {} added, {} removed, {} changed",
+                            changes.added, changes.removed, changes.changed).bright_black());
+                    }
                 }
         });
 
         if print_size {
-            let paths: HashSet<_> = store_paths.par_iter()
-                .flat_map(|sp| sp.closure())
-                .flatten()
-                .collect();
-            let kept_paths: HashSet<_> = self.generations().par_iter()
+            let paths = closures::union(store_paths.par_iter()
+                .flat_map(|sp| sp.closure()));
+            let kept_paths = closures::union(self.generations().par_iter()
                 .filter(|g| !g.marked())
                 .flat_map(|g| g.store_path())
-                .flat_map(|sp| sp.closure())
-                .flatten()
-                .collect();
+                .flat_map(|sp| sp.closure()));
 
             let dirs: Vec<_> = paths.iter().map(|sp| sp.path())
                 .cloned()
@@ -266,14 +674,58 @@ impl Profile {
             println!();
             println!("Estimated total size: {} ({} store paths)",
                 FmtSize::new(size).to_string().yellow(), paths.len());
+            if let Some(note) = size_overlap_note(rows_size, size, unique_sizes) {
+                println!("{}", note.bright_black());
+            }
             if print_markers {
                 println!("  -> after removal:   {} ({} store paths)",
                     FmtSize::new(kept_size).to_string().green(), kept_paths.len());
             }
         }
+
+        nfailed
     }
 
 
+    /// Whether the newest generation is older than `age` or the profile only consists of a single
+    /// generation whose store path is gone
+    ///
+    /// Essential profiles (see [ESSENTIAL_PROFILES]), profiles containing the running system and
+    /// profiles with generations listed in the keep file are never considered abandoned.
+    pub fn is_abandoned(&self, age: Duration) -> bool {
+        let newest = match self.generations.last() {
+            Some(newest) => newest,
+            None => return false,
+        };
+        if ESSENTIAL_PROFILES.contains(&self.name.as_str()) || self.has_protected_generations() {
+            return false;
+        }
+        let broken = self.generations.len() == 1 && newest.store_path().is_err();
+        self.running_generation().is_none() && (newest.age() > age || broken)
+    }
+
+    fn has_protected_generations(&self) -> bool {
+        let protected = self.protected_patterns();
+        self.generations.iter().any(|g| g.matches_any(&protected))
+    }
+
+    /// Delete the whole profile, i.e. the profile link and all of its generations
+    ///
+    /// Profiles with generations listed in the keep file are not deleted.
+    pub fn delete(self) -> Result<(), String> {
+        if self.has_protected_generations() {
+            return Err(format!("Not deleting profile {}, as some of its generations are listed in {}",
+                self.path().to_string_lossy(), self.parent.join(KEEP_FILE).to_string_lossy()));
+        }
+
+        let mut paths: Vec<_> = self.generations.iter()
+            .map(|g| g.path().to_path_buf())
+            .collect();
+        paths.push(self.path());
+        remove_paths(&self.parent, &paths, false)
+            .map_err(|e| format!("Unable to delete profile {}: {}", self.path().to_string_lossy(), e))
+    }
+
     pub fn is_active_generation(&self, generation: &Generation) -> bool {
         let active = match self.active_generation() {
             Ok(g) => g,
@@ -287,13 +739,46 @@ impl Profile {
             .map(|g| g.closure())
             .collect();
         let full_closure: HashSet<_> = closures?
-            .into_iter()
-            .flatten()
+            .iter()
+            .flat_map(|c| c.iter().cloned())
             .collect();
 
         Ok(full_closure)
     }
 
+    /// Estimated size of the store paths that are only referenced by marked generations
+    pub fn marked_only_size(&self) -> u64 {
+        let closure_of = |marked: bool| -> HashSet<StorePath> {
+            closures::union(self.generations.par_iter()
+                .filter(|g| g.marked() == marked)
+                .flat_map(|g| g.closure()))
+        };
+        let kept = closure_of(false);
+        let dirs: Vec<_> = closure_of(true).into_iter()
+            .filter(|sp| !kept.contains(sp))
+            .map(|sp| sp.path().clone())
+            .collect();
+        dir_size_considering_hardlinks_all(&dirs)
+    }
+
+    /// Size of the store paths only referenced by each generation, in the order of [Self::generations]
+    pub fn exclusive_sizes(&self) -> Vec<Result<u64, String>> {
+        let store_paths: Vec<_> = self.generations.iter()
+            .map(|g| g.store_path())
+            .collect();
+        let existing: Vec<_> = store_paths.iter()
+            .map(|sp| sp.as_ref().ok())
+            .collect();
+        StorePath::exclusive_closure_sizes(&existing)
+            .into_iter()
+            .zip(store_paths)
+            .map(|(size, sp)| match (size, sp) {
+                (Some(size), _) => size,
+                (None, sp) => sp.map(|_| 0),
+            })
+            .collect()
+    }
+
     pub fn full_closure_size(&self) -> Result<u64, String> {
         let full_closure: Vec<_> = self.full_closure()?
             .iter()
@@ -331,9 +816,11 @@ impl Generation {
 
         Ok(Generation {
             number, age,
+            created: last_modified,
             path: dirent.path(),
             profile_path,
             marker: false,
+            pending_since: None,
         })
     }
 
@@ -359,6 +846,10 @@ impl Generation {
         self.age
     }
 
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
     pub fn mark(&mut self) {
         self.marker = true;
     }
@@ -367,44 +858,136 @@ impl Generation {
         self.marker = false;
     }
 
+    /// Whether the number or link name of the generation matches one of `patterns`
+    fn matches_any(&self, patterns: &[String]) -> bool {
+        let number = self.number.to_string();
+        let link_name = self.path.file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        patterns.iter().any(|p| glob::matches(p, &number) || glob::matches(p, &link_name))
+    }
+
     pub fn marked(&self) -> bool{
         self.marker
     }
 
-    pub fn closure(&self) -> Result<HashSet<StorePath>, String> {
+    pub fn pending(&self) -> bool {
+        self.pending_since.is_some()
+    }
+
+    pub fn closure(&self) -> Result<Arc<HashSet<StorePath>>, String> {
         self.store_path().and_then(|sp| sp.closure())
     }
 
-    pub fn remove(&self) -> Result<(), String> {
-        let result = process::Command::new("nix-env")
-            .args(["-p", self.profile_path().to_str().unwrap()])
-            .args(["--delete-generations", &self.number().to_string()])
-            .stdin(process::Stdio::inherit())
-            .stdout(process::Stdio::inherit())
-            .stderr(process::Stdio::inherit())
-            .status();
-
-        match result {
-            Ok(status) => if status.success() {
-                Ok(())
-            } else {
-                Err(format!("Removal of generation {} failed", self.number()))
-            },
-            Err(e) => Err(format!("Removal of generation {} failed: {}", self.number(), e)),
+    pub fn remove(&self, backend: RemovalBackend) -> Result<(), String> {
+        timings::measure(Phase::Deletion, || match backend {
+            RemovalBackend::NixEnv => self.remove_nix_env(),
+            RemovalBackend::Unlink => self.remove_unlink(),
+        })
+    }
+
+    fn remove_unlink(&self) -> Result<(), String> {
+        // never remove the generation the profile currently points to
+        let active = fs::read_link(self.profile_path())
+            .map_err(|e| format!("Unable to read profile link {}: {}", self.profile_path().to_string_lossy(), e))?;
+        if active.file_name() == self.path.file_name() {
+            return Err(format!("Refusing to remove generation {} as it is currently active", self.number()));
+        }
+
+        let parent = self.path.parent()
+            .ok_or(format!("Unable to get parent for generation {}", self.number()))?;
+        if !privileges::can_write(parent) {
+            if !privileges::can_escalate() {
+                return Err(format!("Unable to remove generation {}: {} is not writable", self.number(), parent.to_string_lossy()));
+            }
+
+            let status = privileges::command("rm", true)
+                .arg("--")
+                .arg(&self.path)
+                .status()
+                .map_err(|e| format!("Removal of generation {} failed: {}", self.number(), e))?;
+            if !status.success() {
+                return Err(format!("Removal of generation {} failed", self.number()));
+            }
+            return Ok(());
+        }
+
+        fs::remove_file(&self.path)
+            .map_err(|e| format!("Removal of generation {} failed: {}", self.number(), e))
+    }
+
+    fn remove_nix_env(&self) -> Result<(), String> {
+        backend().delete_generation(self.profile_path(), self.number())
+            .map_err(|e| format!("Removal of generation {} failed: {}", self.number(), e))
+    }
+
+    /// Name of the store path of the generation without the hash (e.g. `nixos-system-host-24.05`)
+    pub fn name(&self) -> String {
+        self.store_path()
+            .map(|sp| sp.name())
+            .unwrap_or_default()
+    }
+
+    /// Short revision of the configuration the generation was built from, if it has been recorded
+    ///
+    /// NixOS records `system.configurationRevision` (e.g. the git revision of a flake) in the
+    /// `configuration-revision` file of the system, otherwise it is looked up in `etc/os-release`.
+    pub fn revision(&self) -> Option<String> {
+        let store_path = self.store_path().ok()?;
+        let revision = fs::read_to_string(store_path.path().join(CONFIGURATION_REVISION_FILE)).ok()
+            .or_else(|| {
+                let os_release = sandbox::canonicalize(&store_path.path().join(OS_RELEASE_FILE)).ok()?;
+                fs::read_to_string(os_release).ok()?
+                    .lines()
+                    .find_map(|l| l.strip_prefix(OS_RELEASE_REVISION_KEY))
+                    .map(|v| v.trim_matches('"').to_owned())
+            })?;
+
+        let revision = revision.trim();
+        let (revision, dirty) = match revision.strip_suffix("-dirty") {
+            Some(revision) => (revision, "-dirty"),
+            None => (revision, ""),
+        };
+        let short = match revision.chars().all(|c| c.is_ascii_hexdigit()) {
+            true => &revision[..revision.len().min(SHORT_REVISION_LEN)],
+            false => revision,
+        };
+        (!short.is_empty()).then(|| format!("{short}{dirty}"))
+    }
+
+    /// Name of the generation together with its configuration revision, if known
+    pub fn description(&self) -> String {
+        match self.revision() {
+            Some(revision) => format!("{} ({})", self.name(), revision),
+            None => self.name(),
         }
     }
 
-    pub fn print_fancy(&self, active: bool, print_marker: bool, size: Option<u64>) {
-        let marker = if self.marked() { "would remove".red() } else { "would keep".green() };
+    pub fn print_fancy(&self, active: bool, print_marker: bool, size: Option<u64>, description: &str, description_width: usize) {
+        let marker = if self.marked() {
+            "would remove".red()
+        } else if self.pending() {
+            "pending removal".yellow()
+        } else {
+            "would keep".green()
+        };
         let id_str = format!("[{}]", self.number()).bright_blue();
 
-        print!("{}\t{}", id_str,
-            FmtAge::new(self.age())
-                .with_suffix::<4>(" old".to_owned())
-                .left_pad());
+        let age_str = FmtAge::new(self.age())
+            .with_suffix::<4>(" old".to_owned())
+            .left_pad();
+        if description_width > 0 {
+            let description_str = FmtWithEllipsis::fitting_terminal(description.to_owned(), description_width, 64)
+                .right_pad();
+            print!("{} {}\t{}", id_str, description_str, age_colored(self.age(), &age_str));
+        } else {
+            print!("{}\t{}", id_str, age_colored(self.age(), &age_str));
+        }
 
         if print_marker {
             print!(", {marker}");
+        } else if self.pending() {
+            print!(", {}", "pending removal".yellow());
         }
 
         if let Some(size) = size {
@@ -452,8 +1035,172 @@ impl FromStr for Profile {
             other => {
                 let path = path::PathBuf::from_str(other)
                     .map_err(|e| e.to_string())?;
+
+                // plain names may refer to profiles in the configured profile directories
+                if !fs::exists(&path).unwrap_or(false) && path.components().count() == 1
+                        && let Some(profile) = Profile::from_profile_dirs(other)? {
+                    return Ok(profile);
+                }
+
                 Profile::from_path(path)
             },
         }
     }
 }
+
+/// Per-user profile directory of an account that does not exist anymore
+///
+/// The profiles in it still keep their closures alive, although nobody is going to use them again.
+#[derive(Debug)]
+pub struct OrphanedUserDir {
+    path: PathBuf,
+    profiles: Vec<Profile>,
+}
+
+impl OrphanedUserDir {
+    /// Per-user profile directories whose user does not exist
+    ///
+    /// Users whose existence cannot be checked are skipped with a warning. With `--store-root`
+    /// nothing is found, as the users of the host say nothing about those of the other root.
+    pub fn find() -> Result<Vec<Self>, String> {
+        if sandbox::root().is_some() {
+            return Ok(Vec::new());
+        }
+
+        let dir = sandbox::path(PER_USER_PROFILES_DIR);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Unable to read {}: {}", dir.to_string_lossy(), e)),
+        };
+
+        let mut orphaned: Vec<_> = entries.flatten()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter(|e| match User::exists(&e.file_name().to_string_lossy()) {
+                Ok(exists) => !exists,
+                Err(e) => { warn(&e); false },
+            })
+            .map(|e| {
+                let path = e.path();
+                let profiles = fs::read_dir(&path).into_iter()
+                    .flatten()
+                    .flatten()
+                    .map(|p| p.path())
+                    .filter(|p| is_profile_link(p))
+                    .flat_map(Profile::from_path)
+                    .collect();
+                OrphanedUserDir { path, profiles }
+            })
+            .collect();
+        orphaned.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(orphaned)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name of the removed user
+    pub fn user(&self) -> String {
+        self.path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    /// Size of the combined closure of all profiles in the directory
+    pub fn closure_size(&self) -> Result<u64, String> {
+        let mut full_closure = HashSet::default();
+        for profile in &self.profiles {
+            full_closure.extend(profile.full_closure()?);
+        }
+        let paths: Vec<_> = full_closure.into_iter()
+            .map(|sp| sp.path().clone())
+            .collect();
+        Ok(dir_size_considering_hardlinks_all(&paths))
+    }
+
+    /// Delete the directory together with all profiles and generation links in it
+    pub fn remove(&self) -> Result<(), String> {
+        let parent = self.path.parent().unwrap_or(Path::new("/"));
+        remove_paths(parent, std::slice::from_ref(&self.path), true)
+            .map_err(|e| format!("Unable to remove {}: {}", self.path.to_string_lossy(), e))
+    }
+}
+
+/// Remove `paths` in `dir`, escalating privileges if the directory is not writable
+fn remove_paths(dir: &Path, paths: &[PathBuf], recursive: bool) -> Result<(), String> {
+    if !privileges::can_write(dir) {
+        if !privileges::can_escalate() {
+            return Err(format!("{} is not writable", dir.to_string_lossy()));
+        }
+        let status = privileges::command("rm", true)
+            .args(if recursive { &["-r", "--"][..] } else { &["--"][..] })
+            .args(paths)
+            .status()
+            .map_err(|e| e.to_string())?;
+        return match status.success() {
+            true => Ok(()),
+            false => Err("rm failed".to_owned()),
+        };
+    }
+
+    for path in paths {
+        match recursive {
+            true => fs::remove_dir_all(path),
+            false => fs::remove_file(path),
+        }.map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    }
+    Ok(())
+}
+
+/// Profile name for names of the form `<profile>-<number>-link`
+fn profile_name_of_generation_link(name: &str) -> Option<&str> {
+    let (profile, number) = name.strip_suffix("-link")?.rsplit_once('-')?;
+    if !profile.is_empty() && !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+        Some(profile)
+    } else {
+        None
+    }
+}
+
+/// Whether the profile at `path` matches one of the `--exclude` patterns
+///
+/// Patterns containing a slash are matched against the full path, all others against the name of
+/// the profile (e.g. `channels*`).
+pub fn is_excluded(path: &Path, patterns: &[String]) -> bool {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    patterns.iter().any(|pattern| match pattern.contains('/') {
+        true => glob::matches(pattern, &path.to_string_lossy()),
+        false => glob::matches(pattern, &name),
+    })
+}
+
+fn is_profile_link(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let target = match fs::read_link(path) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+
+    target.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.starts_with(&format!("{name}-")) && n.ends_with("-link"))
+        .unwrap_or(false)
+}
+
+/// Store path of the running system, if there is one
+fn running_system() -> Option<&'static StorePath> {
+    static RUNNING_SYSTEM: OnceLock<Option<StorePath>> = OnceLock::new();
+    RUNNING_SYSTEM.get_or_init(|| StorePath::from_symlink(&sandbox::path(CURRENT_SYSTEM)).ok())
+        .as_ref()
+}
+
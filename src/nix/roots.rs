@@ -1,24 +1,39 @@
-use std::process;
 use std::time::Duration;
 use std::time::SystemTime;
+use std::env;
 use std::fs;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use colored::Colorize;
+use rayon::iter::IntoParallelIterator;
 use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 use rayon::slice::ParallelSliceMut;
+use sha1::{Digest, Sha1};
 
+use crate::config::GlobalConfig;
 use crate::utils::files::dir_size_considering_hardlinks_all;
 use crate::utils::fmt::*;
-use crate::nix::store::StorePath;
-use crate::HashSet;
+use crate::utils::interaction::warn;
+use crate::utils::timings::{self, Phase};
+use crate::utils::users::User;
+use crate::nix::store::{Store, StorePath};
+use crate::{HashMap, HashSet};
 
-use super::store::NIX_STORE;
+use super::backend::backend;
+use super::sandbox;
+use super::store::{self, NIX_STORE};
 
 
 const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots";
+const GC_ROOTS_AUTO_DIR: &str = "/nix/var/nix/gcroots/auto";
+const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+const MAX_CHAIN_LEN: usize = 40;
+const MAX_SEARCH_DEPTH: usize = 32;
+const DIRENV_LAYOUT_DIR: &str = ".direnv";
 
 
 #[derive(Clone)]
@@ -29,7 +44,7 @@ pub struct GCRoot {
 }
 
 impl GCRoot {
-    fn new(link: PathBuf) -> Result<Self, String> {
+    pub fn new(link: PathBuf) -> Result<Self, String> {
         let store_path = StorePath::from_symlink(&link);
         Self::new_with_store_path(link, store_path)
     }
@@ -49,18 +64,37 @@ impl GCRoot {
     }
 
     pub fn all_search_directory(include_missing: bool) -> Result<Vec<Self>, String> {
-        let gc_roots_dir = PathBuf::from_str(GC_ROOTS_DIR)
-            .map_err(|e| e.to_string())?;
+        let gc_roots_dir = Self::dir();
 
         let mut roots = Vec::new();
-        for location in find_links(&gc_roots_dir, Vec::new())? {
+        let mut seen = HashSet::default();
+        // like Nix, the profiles are searched in addition to the gc roots
+        let mut dirs = vec![gc_roots_dir];
+        let profiles_dir = sandbox::path(PROFILES_DIR);
+        if profiles_dir.is_dir() {
+            dirs.push(profiles_dir);
+        }
+        let search = timings::measure(Phase::RootDiscovery, || find_links(&dirs))?;
+        for (path, reason) in &search.skipped {
+            warn(&format!("Skipping {} while searching for gc roots: {}", path.to_string_lossy(), reason));
+        }
+        for location in search.links {
             let mut link = fs::read_link(&location)
                 .map_err(|e| e.to_string())?;
             if link.starts_with(NIX_STORE) {
                 link = location;
+            } else if link.is_relative() {
+                link = location.parent().unwrap_or(Path::new("/")).join(link);
+            } else {
+                link = sandbox::remap(&link);
             }
 
-            if include_missing || fs::exists(&link).unwrap_or(true) {
+            // several links may lead to the same root (e.g. a profile and its current generation)
+            if !seen.insert(link.clone()) {
+                continue;
+            }
+
+            if include_missing || sandbox::exists(&link).unwrap_or(true) {
                 roots.push(GCRoot::new(link)?);
             }
 
@@ -69,6 +103,53 @@ impl GCRoot {
         Ok(roots)
     }
 
+    /// Links into the store found below `dirs`, whether they are registered with Nix or not
+    ///
+    /// The directories are searched in parallel, descending at most `depth` levels. Unlike the gc
+    /// roots directory, links to other directories are not followed.
+    pub fn scan(dirs: &[PathBuf], depth: usize) -> Result<Vec<Self>, String> {
+        let dirs = dirs.iter()
+            .map(|d| fs::canonicalize(d).map_err(|e| format!("Unable to scan {}: {}", d.to_string_lossy(), e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (links, skipped) = timings::measure(Phase::RootDiscovery, || {
+            dirs.par_iter()
+                .map(|d| scan_links(d, depth))
+                .reduce(Default::default, merge_scans)
+        });
+        for (path, reason) in &skipped {
+            warn(&format!("Skipping {} while scanning for gc roots: {}", path.to_string_lossy(), reason));
+        }
+
+        links.into_iter()
+            .map(GCRoot::new)
+            .collect()
+    }
+
+    pub fn dir() -> PathBuf {
+        sandbox::path(GC_ROOTS_DIR)
+    }
+
+    /// Directory for gc roots of the current user
+    pub fn user_dir() -> PathBuf {
+        match User::current() {
+            Ok(user) => sandbox::path(&format!("{GC_ROOTS_DIR}/per-user/{}", user.name())),
+            Err(_) => sandbox::path(GC_ROOTS_DIR),
+        }
+    }
+
+    /// Directory for indirect gc roots
+    pub fn auto_dir() -> PathBuf {
+        sandbox::path(GC_ROOTS_AUTO_DIR)
+    }
+
+    /// Name of the entry in the [auto directory](Self::auto_dir) for an indirect root at `link`
+    ///
+    /// This is the base-32 SHA-1 hash of the absolute link path, under which Nix registers it too.
+    pub fn auto_name(link: &Path) -> String {
+        store::base32(&Sha1::digest(link.as_os_str().as_bytes()))
+    }
+
     pub fn all(query_nix: bool, include_proc: bool, include_missing: bool) -> Result<Vec<Self>, String> {
         if include_proc {
             Self::all_with_proc()
@@ -83,31 +164,52 @@ impl GCRoot {
     }
 
     pub fn all_with_proc() -> Result<Vec<Self>, String> {
-        let output = process::Command::new("nix-store")
-            .arg("--gc")
-            .arg("--print-roots")
-            .stdin(process::Stdio::inherit())
-            .stderr(process::Stdio::inherit())
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !output.status.success() {
-            match output.status.code() {
-                Some(code) => return Err(format!("`nix-store` failed (exit code {code})")),
-                None => return Err("`nix-store` failed".to_string()),
+        let roots: Vec<_> = timings::measure(Phase::RootDiscovery, || backend().roots())?
+            .into_iter()
+            .map(|(link, store_path)| GCRoot::new_with_store_path(link, StorePath::new(store_path)))
+            .collect::<Result<Vec<Self>, String>>()?;
+
+        Ok(roots)
+    }
+
+    /// Store paths held by running processes, with the ids of the processes
+    pub fn paths_in_use() -> Result<HashMap<StorePath, Vec<u32>>, String> {
+        let mut in_use: HashMap<StorePath, Vec<u32>> = HashMap::default();
+        for root in Self::all_with_proc()?.into_iter().filter(|r| r.is_proc()) {
+            let pid = root.link().components()
+                .nth(2)
+                .and_then(|c| c.as_os_str().to_str())
+                .and_then(|s| s.parse().ok());
+            if let (Ok(store_path), Some(pid)) = (root.store_path, pid) {
+                let pids = in_use.entry(store_path).or_default();
+                if !pids.contains(&pid) {
+                    pids.push(pid);
+                }
             }
         }
+        Ok(in_use)
+    }
 
-        let roots: Vec<_> = String::from_utf8(output.stdout)
-            .map_err(|e| e.to_string())?
-            .lines()
-            .filter_map(|l| l.split_once(" -> "))
-            .filter(|(link, _)| *link != "{censored}")
-            .map(|(link, store_path)| (link, StorePath::new(store_path.into())))
-            .map(|(link, store_path)| GCRoot::new_with_store_path(link.into(), store_path))
-            .collect::<Result<Vec<Self>, String>>()?;
+    /// Processes using a path in the closure of this root
+    pub fn used_by(&self, in_use: &HashMap<StorePath, Vec<u32>>) -> Vec<u32> {
+        // avoid querying the closure if no process could be using it anyway
+        if in_use.is_empty() {
+            return Vec::new();
+        }
 
-        Ok(roots)
+        let closure = match self.store_path() {
+            Ok(store_path) => store_path.closure().unwrap_or_default(),
+            Err(_) => return Vec::new(),
+        };
+
+        let mut pids: Vec<_> = closure.iter()
+            .filter_map(|sp| in_use.get(sp))
+            .flatten()
+            .copied()
+            .collect();
+        pids.sort();
+        pids.dedup();
+        pids
     }
 
     pub fn link(&self) -> &PathBuf {
@@ -124,12 +226,15 @@ impl GCRoot {
 
     pub fn is_profile(&self) -> bool {
         let parent = self.link.parent().unwrap();
-        parent.starts_with("/nix/var/nix/profiles")
+        parent.starts_with(sandbox::path(PROFILES_DIR))
         || parent.ends_with(".local/state/nix/profiles")
+        || GlobalConfig::get().map(|c| c.profile_dirs.iter().any(|d| parent.starts_with(d))).unwrap_or(false)
     }
 
     pub fn is_current(&self) -> bool {
+        // /run is a link to /private/var/run on macOS
         self.link.starts_with("/run/current-system")
+        || self.link.starts_with("/private/var/run/current-system")
         || self.link.starts_with("/run/booted-system")
         || self.link.ends_with("home-manager/gcroots/current-home")
         || self.link.ends_with("nix/flake-registry.json")
@@ -139,6 +244,28 @@ impl GCRoot {
         self.link().starts_with("/proc")
     }
 
+    /// Classification of the root as used in machine readable output
+    pub fn kind(&self) -> &'static str {
+        if self.is_profile() {
+            "profile"
+        } else if self.is_current() {
+            "current"
+        } else if self.is_proc() {
+            "proc"
+        } else {
+            "other"
+        }
+    }
+
+    /// Whether the root keeps the dev shell alive that nix-sweep is running in
+    ///
+    /// This is detected via direnv (`DIRENV_DIR`) or, for `nix-shell` and `nix develop`
+    /// (`IN_NIX_SHELL`), the working directory: roots below the `.direnv` directory of the project
+    /// belong to the shell.
+    pub fn is_current_shell(&self) -> bool {
+        current_shell_dir().is_some_and(|dir| self.link.starts_with(dir))
+    }
+
     pub fn is_independent(&self) -> bool {
         !self.is_profile() && !self.is_current() && !self.is_proc()
     }
@@ -147,8 +274,9 @@ impl GCRoot {
         self.age.as_ref()
     }
 
+    /// Profiles registered as gc roots, including those whose store path has gone missing
     pub fn profile_paths() -> Result<Vec<PathBuf>, String> {
-        let links: Option<Vec<_>> = Self::all(false, false, false)?.into_iter()
+        let links: Option<Vec<_>> = Self::all(false, false, true)?.into_iter()
             .filter(|r| r.is_profile())
             .map(|r| r.link().to_str().map(|s| s.to_owned()))
             .collect();
@@ -177,8 +305,36 @@ impl GCRoot {
         Ok(paths)
     }
 
+    /// Intermediate symlinks between the gc root and its store path
+    ///
+    /// The flag indicates that the chain ends in a broken link outside of the store.
+    pub fn symlink_chain(&self) -> (Vec<PathBuf>, bool) {
+        let mut hops = Vec::new();
+        let mut current = self.link.clone();
+
+        for _ in 0..MAX_CHAIN_LEN {
+            let target = match fs::read_link(&current) {
+                Ok(target) if target.is_absolute() => sandbox::remap(&target),
+                Ok(target) => current.parent().unwrap_or(Path::new("/")).join(target),
+                Err(_) => break,
+            };
+
+            if target.starts_with(Store::dir()) {
+                break;
+            } else if target.symlink_metadata().is_err() {
+                hops.push(target);
+                return (hops, true);
+            }
+
+            hops.push(target.clone());
+            current = target;
+        }
+
+        (hops, false)
+    }
+
     pub fn closure_size(&self) -> Result<u64, String> {
-        self.store_path.clone().map(|sp| sp.closure_size())
+        self.store_path.clone().and_then(|sp| sp.closure_size())
     }
 
     pub fn full_closure(roots: &[Self]) -> HashSet<StorePath> {
@@ -240,10 +396,15 @@ impl GCRoot {
         let link_str = FmtWithEllipsis::fitting_terminal(link, max_col_len, 32)
             .right_pad();
 
+        let age_str = match self.age() {
+            Ok(age) => age_colored(*age, &age_str),
+            Err(_) => age_str.bright_blue(),
+        };
+
         println!("{}  {}    {}",
             link_str,
             size_str.yellow(),
-            age_str.bright_blue());
+            age_str);
     }
 
     pub fn print_fancy(&self, closure_size: Option<u64>, show_size: bool) {
@@ -261,7 +422,7 @@ impl GCRoot {
 
         let age_str = self.age()
             .ok()
-            .map(|a| FmtAge::new(*a).to_string());
+            .map(|a| age_colored(*a, &FmtAge::new(*a).to_string()));
 
         let (store_path, size) = if let Ok(store_path) = self.store_path() {
             let store_path_str = store_path.path().to_string_lossy().into();
@@ -274,11 +435,22 @@ impl GCRoot {
             (String::from("<not accessible>"), None)
         };
 
+        let (hops, broken) = self.symlink_chain();
+
         println!("\n{}", self.link().to_string_lossy());
-        println!("{}", format!("  -> {store_path}").bright_black());
+        for (i, hop) in hops.iter().enumerate() {
+            if broken && i == hops.len() - 1 {
+                println!("{} {}", format!("  -> {}", hop.to_string_lossy()).bright_black(), "(broken link)".red());
+            } else {
+                println!("{}", format!("  -> {}", hop.to_string_lossy()).bright_black());
+            }
+        }
+        if !broken {
+            println!("{}", format!("  -> {store_path}").bright_black());
+        }
         print!("  ");
         match age_str {
-            Some(age) => print!("age: {}, ", age.bright_blue()),
+            Some(age) => print!("age: {}, ", age),
             None => print!("age: {}, ", "n/a".bright_blue()),
         }
         if show_size {
@@ -291,21 +463,177 @@ impl GCRoot {
     }
 }
 
-fn find_links(path: &PathBuf, mut links: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
-    let metadata = path.symlink_metadata()
-        .map_err(|e| e.to_string())?;
-    let ft = metadata.file_type();
-
-    if ft.is_dir() {
-        for entry in fs::read_dir(path).map_err(|e| e.to_string())? {
-            let child_path = entry
-                .map_err(|e| e.to_string())?
-                .path();
-            links = find_links(&child_path, links)?;
+/// Symlinks below the given directories
+///
+/// Like Nix, links to other directories are not followed. Paths that could not be searched are
+/// recorded together with the reason instead of failing.
+fn find_links(dirs: &[PathBuf]) -> Result<LinkSearch, String> {
+    let mut search = LinkSearch::default();
+    for dir in dirs {
+        dir.symlink_metadata()
+            .map_err(|e| format!("Unable to read {}: {}", dir.to_string_lossy(), e))?;
+        search.walk(dir, 0);
+    }
+    Ok(search)
+}
+
+/// Directory holding the gc roots of the dev shell nix-sweep is running in
+fn current_shell_dir() -> Option<&'static Path> {
+    static SHELL_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+    SHELL_DIR.get_or_init(|| {
+        // direnv prefixes the directory with a dash
+        let project = match env::var("DIRENV_DIR") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir.trim_start_matches('-')),
+            _ if env::var_os("IN_NIX_SHELL").is_some() => env::current_dir().ok()?,
+            _ => return None,
+        };
+        let dir = project.join(DIRENV_LAYOUT_DIR);
+        Some(fs::canonicalize(&dir).unwrap_or(dir))
+    }).as_deref()
+}
+
+/// Links into the store below `path` (up to `depth` levels) and the paths that could not be searched
+fn scan_links(path: &Path, depth: usize) -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+    let read_dir = match fs::read_dir(path) {
+        Ok(read_dir) => read_dir,
+        Err(e) => return (Vec::new(), vec![(path.to_path_buf(), e.to_string())]),
+    };
+    let entries: Vec<_> = read_dir.collect();
+
+    entries.into_par_iter()
+        .map(|entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return (Vec::new(), vec![(path.to_path_buf(), e.to_string())]),
+            };
+            let entry_path = entry.path();
+            match entry.file_type() {
+                Ok(t) if t.is_symlink() => {
+                    let into_store = fs::read_link(&entry_path)
+                        .is_ok_and(|target| target.starts_with(NIX_STORE) || target.starts_with(Store::dir()));
+                    match into_store {
+                        true => (vec![entry_path], Vec::new()),
+                        false => Default::default(),
+                    }
+                },
+                Ok(t) if t.is_dir() && depth > 1 => scan_links(&entry_path, depth - 1),
+                Ok(_) => Default::default(),
+                Err(e) => (Vec::new(), vec![(entry_path, e.to_string())]),
+            }
+        })
+        .reduce(Default::default, merge_scans)
+}
+
+fn merge_scans(mut a: (Vec<PathBuf>, Vec<(PathBuf, String)>), b: (Vec<PathBuf>, Vec<(PathBuf, String)>))
+        -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+    a.0.extend(b.0);
+    a.1.extend(b.1);
+    a
+}
+
+#[derive(Default)]
+struct LinkSearch {
+    links: Vec<PathBuf>,
+    /// Paths that were not searched and the reason why
+    skipped: Vec<(PathBuf, String)>,
+    visited: HashSet<(u64, u64)>,
+}
+
+impl LinkSearch {
+    fn walk(&mut self, path: &Path, depth: usize) {
+        if depth > MAX_SEARCH_DEPTH {
+            self.skipped.push((path.to_path_buf(), format!("nested deeper than {MAX_SEARCH_DEPTH} levels")));
+            return;
+        }
+
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => return self.skipped.push((path.to_path_buf(), e.to_string())),
+        };
+
+        if metadata.is_dir() {
+            if !self.visited.insert((metadata.dev(), metadata.ino())) {
+                return self.skipped.push((path.to_path_buf(), "directory already visited".to_owned()));
+            }
+
+            let read_dir = match fs::read_dir(path) {
+                Ok(read_dir) => read_dir,
+                Err(e) => return self.skipped.push((path.to_path_buf(), e.to_string())),
+            };
+            for entry in read_dir {
+                match entry {
+                    Ok(entry) => self.walk(&entry.path(), depth + 1),
+                    Err(e) => self.skipped.push((path.to_path_buf(), e.to_string())),
+                }
+            }
+        } else if metadata.is_symlink() {
+            // links to directories outside of the store are neither roots nor searched
+            match sandbox::canonicalize(path) {
+                Ok(target) if !target.starts_with(Store::dir()) && target.is_dir() => (),
+                _ => self.links.push(path.to_path_buf()),
+            }
         }
-    } else if ft.is_symlink() {
-        links.push(path.clone());
     }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
 
-    Ok(links)
+    const STORE_PATH: &str = "/nix/store/00000000000000000000000000000000-result";
+
+    #[test]
+    fn auto_name_matches_nix() {
+        assert_eq!(GCRoot::auto_name(Path::new("abc")), "kpcd173cq987hw957sx6m0868wv3x6d9");
+    }
+
+    #[test]
+    fn find_links_survives_symlink_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = dir.path().join("roots");
+        fs::create_dir(&roots).unwrap();
+        symlink(&roots, roots.join("loop")).unwrap();
+        symlink(dir.path(), roots.join("parent")).unwrap();
+        symlink(STORE_PATH, roots.join("result")).unwrap();
+
+        let search = find_links(std::slice::from_ref(&roots)).unwrap();
+        assert_eq!(search.links, vec![roots.join("result")]);
+        assert!(search.skipped.is_empty());
+    }
+
+    #[test]
+    fn find_links_does_not_follow_directory_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let (roots, other) = (dir.path().join("roots"), dir.path().join("other"));
+        fs::create_dir_all(roots.join("nested")).unwrap();
+        fs::create_dir(&other).unwrap();
+        symlink(STORE_PATH, roots.join("nested/result")).unwrap();
+        symlink(STORE_PATH, other.join("result")).unwrap();
+        symlink(&other, roots.join("other")).unwrap();
+
+        let search = find_links(std::slice::from_ref(&roots)).unwrap();
+        assert_eq!(search.links, vec![roots.join("nested/result")]);
+
+        let search = find_links(&[roots.clone(), other.clone()]).unwrap();
+        assert_eq!(search.links.len(), 2);
+        assert!(search.links.contains(&other.join("result")));
+    }
+
+    #[test]
+    fn find_links_limits_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let deepest = (0..=MAX_SEARCH_DEPTH).fold(dir.path().to_path_buf(), |p, i| p.join(i.to_string()));
+        fs::create_dir_all(&deepest).unwrap();
+        symlink(STORE_PATH, deepest.join("result")).unwrap();
+
+        let search = find_links(&[dir.path().to_path_buf()]).unwrap();
+        assert!(search.links.is_empty());
+        assert_eq!(search.skipped.len(), 1);
+        assert!(search.skipped[0].1.contains("nested deeper"));
+
+        assert!(find_links(&[dir.path().join("missing")]).is_err());
+    }
 }
@@ -0,0 +1,359 @@
+use std::fs;
+use std::ops::ControlFlow;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::utils::files;
+use crate::{HashMap, HashSet};
+
+use super::backend::{self, GCResult, NixBackend};
+use super::store::{Store, NIX_STORE};
+
+
+const GC_ROOTS_DIR: &str = "/nix/var/nix/gcroots";
+const MAX_SYMLINK_DEPTH: usize = 40;
+const HASH_LEN: usize = 32;
+
+static STORE_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+
+/// Backend operating on a copy of a Nix installation below a directory
+///
+/// References between store paths are found by scanning the files of a path for the names of other
+/// store paths, which is the same approach Nix itself takes.
+/// The Nix daemon and database are never touched.
+pub struct SandboxBackend {
+    index: OnceLock<HashMap<String, PathBuf>>,
+}
+
+
+/// Treat `root` as the root directory for all paths below `/nix`
+pub fn init(root: &Path) -> Result<(), String> {
+    let root = fs::canonicalize(root)
+        .map_err(|e| format!("Unable to resolve store root {}: {}", root.to_string_lossy(), e))?;
+    if !root.join(NIX_STORE.trim_start_matches('/')).is_dir() {
+        return Err(format!("{} does not contain a Nix store", root.to_string_lossy()));
+    }
+
+    STORE_ROOT.set(root)
+        .map_err(|_| "Store root already set".to_owned())?;
+    backend::set_backend(Box::new(SandboxBackend { index: OnceLock::new() }))
+}
+
+pub fn root() -> Option<&'static Path> {
+    STORE_ROOT.get().map(|r| r.as_path())
+}
+
+/// Location of an absolute path on the file system, taking the store root into account
+pub fn path(path: &str) -> PathBuf {
+    remap(Path::new(path))
+}
+
+/// Translate an absolute path (e.g. the target of a symlink) to a location below the store root
+pub fn remap(path: &Path) -> PathBuf {
+    match root() {
+        Some(root) if path.is_absolute() && !path.starts_with(root) => {
+            root.join(path.strip_prefix("/").unwrap_or(path))
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Like [fs::canonicalize], but resolves absolute symlinks relative to the store root
+pub fn canonicalize(path: &Path) -> Result<PathBuf, String> {
+    if root().is_none() {
+        return fs::canonicalize(path).map_err(|e| e.to_string());
+    }
+
+    let resolved = resolve(path, 0)?;
+    resolved.symlink_metadata()
+        .map_err(|e| format!("{}: {}", resolved.to_string_lossy(), e))?;
+    Ok(resolved)
+}
+
+/// Like [fs::exists], but resolves absolute symlinks relative to the store root
+pub fn exists(path: &Path) -> Result<bool, String> {
+    match root() {
+        Some(_) => Ok(canonicalize(path).is_ok()),
+        None => fs::exists(path).map_err(|e| e.to_string()),
+    }
+}
+
+fn resolve(path: &Path, depth: usize) -> Result<PathBuf, String> {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir => resolved.push("/"),
+            Component::ParentDir => { resolved.pop(); },
+            Component::CurDir | Component::Prefix(_) => (),
+            Component::Normal(name) => {
+                resolved.push(name);
+                if !resolved.is_symlink() {
+                    continue;
+                }
+                if depth >= MAX_SYMLINK_DEPTH {
+                    return Err(format!("Too many levels of symbolic links at {}", resolved.to_string_lossy()));
+                }
+
+                let target = fs::read_link(&resolved)
+                    .map_err(|e| e.to_string())?;
+                resolved.pop();
+                let target = if target.is_absolute() {
+                    remap(&target)
+                } else {
+                    resolved.join(target)
+                };
+                resolved = resolve(&target, depth + 1)?;
+            },
+        }
+    }
+    Ok(resolved)
+}
+
+impl SandboxBackend {
+    /// Store paths by their hash
+    fn index(&self) -> &HashMap<String, PathBuf> {
+        self.index.get_or_init(|| {
+            Store::all_paths().unwrap_or_default()
+                .into_iter()
+                .flat_map(|sp| {
+                    let name = sp.path().file_name()?.to_str()?.to_owned();
+                    Some((name[..HASH_LEN].to_owned(), sp.path().clone()))
+                })
+                .collect()
+        })
+    }
+
+    /// Store paths referenced from within `path`
+    fn references(&self, path: &Path) -> HashSet<PathBuf> {
+        let mut references = HashSet::default();
+        self.scan(path, &mut references);
+        references.remove(path);
+        references
+    }
+
+    fn scan(&self, path: &Path, references: &mut HashSet<PathBuf>) {
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+
+        if metadata.is_dir() {
+            for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+                self.scan(&entry.path(), references);
+            }
+        } else if metadata.is_symlink() {
+            if let Ok(target) = fs::read_link(path) {
+                self.find_hashes(target.as_os_str().as_encoded_bytes(), references);
+            }
+        } else if let Ok(content) = fs::read(path) {
+            self.find_hashes(&content, references);
+        }
+    }
+
+    fn find_hashes(&self, content: &[u8], references: &mut HashSet<PathBuf>) {
+        let prefix = format!("{NIX_STORE}/");
+        let prefix = prefix.as_bytes();
+        let mut rest = content;
+        while let Some(pos) = rest.windows(prefix.len()).position(|w| w == prefix) {
+            rest = &rest[pos + prefix.len()..];
+            let hash = match rest.get(..HASH_LEN).and_then(|h| str::from_utf8(h).ok()) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if let Some(path) = self.index().get(hash) {
+                references.insert(path.clone());
+            }
+        }
+    }
+
+    fn find_roots(&self, path: &Path, visited: &mut HashSet<PathBuf>, roots: &mut Vec<(PathBuf, PathBuf)>) {
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+
+        if metadata.is_dir() {
+            for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+                self.find_roots(&entry.path(), visited, roots);
+            }
+        } else if metadata.is_symlink() {
+            let target = match fs::read_link(path) {
+                Ok(target) => target,
+                Err(_) => return,
+            };
+
+            if target.starts_with(NIX_STORE) {
+                let store_path: PathBuf = target.components().take(4).collect();
+                roots.push((path.to_path_buf(), remap(&store_path)));
+            } else {
+                let target = match target.is_absolute() {
+                    true => remap(&target),
+                    false => path.parent().unwrap_or(Path::new("/")).join(target),
+                };
+                if visited.insert(target.clone()) {
+                    self.find_roots(&target, visited, roots);
+                }
+            }
+        }
+    }
+
+    fn dead(&self) -> Result<Vec<PathBuf>, String> {
+        let live: HashSet<_> = self.requisites(&self.roots()?.into_iter().map(|(_, sp)| sp).collect::<Vec<_>>())?
+            .into_iter()
+            .collect();
+        let mut dead: Vec<_> = self.index().values()
+            .filter(|p| !live.contains(*p))
+            .cloned()
+            .collect();
+        dead.sort();
+        Ok(dead)
+    }
+}
+
+impl NixBackend for SandboxBackend {
+    fn requisites(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let mut closure = HashSet::default();
+        let mut queue: Vec<_> = paths.to_vec();
+        while let Some(path) = queue.pop() {
+            if closure.insert(path.clone()) {
+                queue.extend(self.references(&path));
+            }
+        }
+        Ok(closure.into_iter().collect())
+    }
+
+    fn referrers(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+        let mut referrers: HashMap<PathBuf, Vec<PathBuf>> = HashMap::default();
+        for path in self.index().values() {
+            for reference in self.references(path) {
+                referrers.entry(reference).or_default().push(path.clone());
+            }
+        }
+
+        let mut closure = HashSet::default();
+        let mut queue: Vec<_> = paths.to_vec();
+        while let Some(path) = queue.pop() {
+            if closure.insert(path.clone()) {
+                queue.extend(referrers.get(&path).into_iter().flatten().cloned());
+            }
+        }
+        Ok(closure.into_iter().collect())
+    }
+
+    fn reference_graph(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, Vec<PathBuf>)>, String> {
+        let graph = self.requisites(paths)?
+            .into_iter()
+            .map(|path| {
+                let references = self.references(&path).into_iter().collect();
+                (path, references)
+            })
+            .collect();
+        Ok(graph)
+    }
+
+    fn roots(&self) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        let mut roots = Vec::new();
+        self.find_roots(&path(GC_ROOTS_DIR), &mut HashSet::default(), &mut roots);
+        Ok(roots)
+    }
+
+    fn roots_of(&self, path: &Path) -> Result<Vec<(PathBuf, PathBuf)>, String> {
+        let mut roots = self.roots()?;
+        roots.retain(|(_, store_path)| self.requisites(std::slice::from_ref(store_path))
+            .map(|closure| closure.iter().any(|p| p == path))
+            .unwrap_or(false));
+        Ok(roots)
+    }
+
+    fn dead_paths(&self) -> Result<Vec<PathBuf>, String> {
+        self.dead()
+    }
+
+    fn collect_garbage(&self, max_freed: Option<u64>, _deadline: Option<Instant>, on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String> {
+        check_writable()?;
+        let mut result = GCResult { deleted: 0, freed: 0 };
+        for path in self.dead()? {
+            if max_freed.is_some_and(|max| result.freed >= max) {
+                break;
+            }
+            result.freed += files::dir_size_naive(&path);
+            let flow = on_delete(&path);
+            remove_store_path(&path)?;
+            result.deleted += 1;
+            if flow.is_break() {
+                break;
+            }
+        }
+        Ok(Some(result))
+    }
+
+    fn verify(&self, _check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        Err("Verification is not supported with a custom store root".to_owned())
+    }
+
+    fn delete_paths(&self, paths: &[PathBuf]) -> Result<(), String> {
+        check_writable()?;
+        let dead: HashSet<_> = self.dead()?.into_iter().collect();
+        if let Some(alive) = paths.iter().find(|p| !dead.contains(*p)) {
+            return Err(format!("{} is still alive", alive.to_string_lossy()));
+        }
+        paths.iter().try_for_each(|p| remove_store_path(p))
+    }
+
+    fn repair_path(&self, _path: &Path) -> Result<(), String> {
+        Err("Repairing is not supported with a custom store root".to_owned())
+    }
+
+    fn realise(&self, _drv: &Path) -> Result<Vec<PathBuf>, String> {
+        Err("Building derivations is not supported with a custom store root".to_owned())
+    }
+
+    fn delete_generation(&self, profile: &Path, number: usize) -> Result<(), String> {
+        let name = profile.file_name()
+            .ok_or("Invalid profile path".to_owned())?
+            .to_string_lossy();
+        let link_name = format!("{name}-{number}-link");
+
+        let active = fs::read_link(profile)
+            .map_err(|e| e.to_string())?;
+        if active.file_name().is_some_and(|n| n == link_name.as_str()) {
+            return Err("Generation is currently active".to_owned());
+        }
+
+        fs::remove_file(profile.with_file_name(link_name))
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Fail on a read-only store, as there is no daemon to modify it through
+fn check_writable() -> Result<(), String> {
+    match Store::is_read_only() {
+        true => Err("Store is read-only; the Nix daemon is not used with a custom store root".to_owned()),
+        false => Ok(()),
+    }
+}
+
+/// Remove a store path, making read-only directories writable first
+fn remove_store_path(path: &Path) -> Result<(), String> {
+    let metadata = path.symlink_metadata()
+        .map_err(|e| e.to_string())?;
+    if !metadata.is_dir() {
+        return fs::remove_file(path).map_err(|e| e.to_string());
+    }
+
+    make_writable(path);
+    fs::remove_dir_all(path)
+        .map_err(|e| format!("Unable to delete {}: {}", path.to_string_lossy(), e))
+}
+
+fn make_writable(dir: &Path) {
+    let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o755));
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        if entry.file_type().is_ok_and(|ft| ft.is_dir()) {
+            make_writable(&entry.path());
+        }
+    }
+}
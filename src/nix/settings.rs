@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+
+use crate::utils::fmt::FmtSize;
+use crate::utils::interaction::{debug, warn};
+use crate::HashMap;
+
+use super::backend::backend;
+use super::sandbox;
+
+
+const NIX_CONF: &str = "/etc/nix/nix.conf";
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+static AUTO_GC: OnceLock<Option<AutoGC>> = OnceLock::new();
+
+
+/// Automatic garbage collection of the Nix daemon
+///
+/// Once less than `min_free` bytes are free on the store device during a build, the daemon
+/// collects garbage until `max_free` bytes are free.
+#[derive(Debug, Clone, Copy)]
+pub struct AutoGC {
+    pub min_free: u64,
+    pub max_free: u64,
+}
+
+
+impl AutoGC {
+    pub fn enabled(&self) -> bool {
+        self.min_free > 0
+    }
+
+    /// Whether the daemon collects garbage before `used` bytes grow to `threshold` bytes
+    pub fn preempts(&self, threshold: u64, used: u64, available: u64) -> bool {
+        let free_at_threshold = (available + used).saturating_sub(threshold);
+        self.enabled() && free_at_threshold < self.min_free
+    }
+
+    /// Warn if the daemon is going to collect garbage before any of the given store size thresholds is reached
+    pub fn warn_conflicts(&self, thresholds: &[u64], used: u64, available: u64) {
+        // nix-sweep collects as soon as the smallest threshold is exceeded
+        let threshold = match thresholds.iter().min() {
+            Some(threshold) if *threshold > used => *threshold,
+            _ => return,
+        };
+
+        if self.preempts(threshold, used, available) {
+            warn(&format!("The Nix daemon collects garbage once less than {} are free (min-free in nix.conf), \
+                which happens before the gc threshold of {} is reached",
+                FmtSize::new(self.min_free), FmtSize::new(threshold)));
+        }
+    }
+}
+
+/// Auto gc settings of the Nix daemon, if they can be determined
+pub fn auto_gc() -> Option<AutoGC> {
+    *AUTO_GC.get_or_init(|| {
+        let settings = match sandbox::root() {
+            Some(_) => read_conf(&sandbox::path(NIX_CONF), 0),
+            None if backend().is_offline() => read_conf(Path::new(NIX_CONF), 0),
+            None => show_config().or_else(|e| {
+                debug(&format!("unable to query nix config ({e}), reading {NIX_CONF} instead"));
+                read_conf(Path::new(NIX_CONF), 0)
+            }),
+        };
+        let settings = match settings {
+            Ok(settings) => settings,
+            Err(e) => {
+                debug(&format!("unable to read nix settings: {e}"));
+                return None;
+            },
+        };
+
+        let min_free = settings.get("min-free").and_then(|v| parse_size(v)).unwrap_or(0);
+        let max_free = settings.get("max-free").and_then(|v| parse_size(v)).unwrap_or(u64::MAX);
+        Some(AutoGC { min_free, max_free })
+    })
+}
+
+/// Settings as reported by Nix itself, which takes all configuration sources into account
+fn show_config() -> Result<HashMap<String, String>, String> {
+    let output = process::Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command", "show-config"])
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err("`nix show-config` failed".to_owned());
+    }
+
+    let output = String::from_utf8(output.stdout)
+        .map_err(|e| e.to_string())?;
+    Ok(parse_settings(&output))
+}
+
+fn read_conf(path: &Path, depth: usize) -> Result<HashMap<String, String>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+    let mut settings = HashMap::default();
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (optional, include) = match line.split_once(char::is_whitespace) {
+            Some(("include", file)) => (false, file.trim()),
+            Some(("!include", file)) => (true, file.trim()),
+            _ => {
+                settings.extend(parse_settings(line));
+                continue;
+            },
+        };
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(format!("Too many nested includes in {}", path.to_string_lossy()));
+        }
+        let included = match Path::new(include).is_absolute() {
+            true => sandbox::remap(Path::new(include)),
+            false => path.parent().map(|p| p.join(include)).unwrap_or(PathBuf::from(include)),
+        };
+        match read_conf(&included, depth + 1) {
+            Ok(included) => settings.extend(included),
+            Err(_) if optional => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(settings)
+}
+
+fn parse_settings(s: &str) -> HashMap<String, String> {
+    s.lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+        .collect()
+}
+
+/// Parse a size as accepted by Nix (e.g. `1073741824` or `1G`)
+fn parse_size(s: &str) -> Option<u64> {
+    let (number, factor) = match s.chars().last()? {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        'T' => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    number.parse::<u64>().ok()
+        .map(|n| n.saturating_mul(factor))
+}
@@ -1,66 +1,96 @@
-use std::hash::{Hash, Hasher};
-use std::str::FromStr;
-use std::{fs, process};
+use std::collections::BTreeSet;
+use std::ops::ControlFlow;
+use std::sync::{Arc, OnceLock};
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-use crate::utils::caching::Cache;
 use crate::utils::files;
-use crate::HashSet;
+use crate::utils::timings::{self, Phase};
+use crate::{HashMap, HashSet};
+
+use super::backend::{backend, GCResult};
+use super::closures;
+use super::sandbox;
 
 
 pub const NIX_STORE: &str = "/nix/store";
-const CLOSURE_LOOKUP_CHUNK_SIZE: usize = 1024;
-static CLOSURE_CACHE: Cache<u64, HashSet<StorePath>> = Cache::new();
+/// Characters of the base-32 encoding Nix uses for hashes
+pub const HASH_ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+static STORE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static STORE_READ_ONLY: OnceLock<bool> = OnceLock::new();
 
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub struct StorePath(PathBuf);
 
+
+/// Encode a hash in base 32 the way Nix does (last byte first)
+pub fn base32(hash: &[u8]) -> String {
+    let len = (hash.len() * 8).div_ceil(5);
+    (0..len).rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let high = hash.get(i + 1).map(|b| (*b as u16) << (8 - j)).unwrap_or(0);
+            HASH_ALPHABET[(((hash[i] >> j) as u16 | high) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// Package level differences between two closures
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClosureDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+/// Versions of a package before and after, either of which is empty if the package was added or removed
+#[derive(Debug, Clone)]
+pub struct PackageChange {
+    pub name: String,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+
 pub struct Store();
 
 
 impl Store {
-    pub fn all_paths() -> Result<HashSet<StorePath>, String> {
-        let read_dir = match fs::read_dir(NIX_STORE) {
-            Ok(rd) => rd,
-            Err(e) => return Err(e.to_string()),
-        };
+    /// Location of the store on the file system
+    ///
+    /// This is [NIX_STORE], unless a different store root (see [sandbox]) or directory is set.
+    pub fn dir() -> &'static Path {
+        STORE_DIR.get_or_init(|| sandbox::path(NIX_STORE))
+    }
+
+    /// Use a different store directory
+    ///
+    /// This must happen before the location of the store is first used.
+    pub fn set_dir(dir: PathBuf) -> Result<(), String> {
+        STORE_DIR.set(dir)
+            .map_err(|_| "Store directory already set".to_owned())
+    }
 
-        let paths = read_dir.into_iter()
-            .flatten()
-            .map(|e| e.path())
-            .filter(|p| Self::is_valid_path(p))
+    /// Whether the store is mounted read-only, so it can only be modified through the Nix daemon
+    pub fn is_read_only() -> bool {
+        *STORE_READ_ONLY.get_or_init(|| files::is_read_only(Self::dir()).unwrap_or(false))
+    }
+
+    pub fn all_paths() -> Result<HashSet<StorePath>, String> {
+        let paths = backend().valid_paths()?
+            .into_iter()
             .flat_map(StorePath::new)
             .collect();
         Ok(paths)
     }
 
     pub fn paths_dead() -> Result<HashSet<StorePath>, String> {
-        Self::paths_with_flag("--print-dead")
-    }
-
-    fn paths_with_flag(flag: &str) -> Result<HashSet<StorePath>, String> {
-        let output = process::Command::new("nix-store")
-            .arg("--gc")
-            .arg(flag)
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !output.status.success() {
-            match output.status.code() {
-                Some(code) => return Err(format!("`nix-store` failed (exit code {code})")),
-                None => return Err("`nix-store` failed".to_string()),
-            }
-        }
-
-        let paths: HashSet<_> = String::from_utf8(output.stdout)
-            .map_err(|e| e.to_string())?
-            .lines()
-            .flat_map(|p| StorePath::new(p.into()))
+        let paths = backend().dead_paths()?
+            .into_iter()
+            .flat_map(StorePath::new)
             .collect();
-
         Ok(paths)
     }
 
@@ -70,7 +100,7 @@ impl Store {
             None => return false,
         };
 
-        let is_in_store = path.starts_with(NIX_STORE);
+        let is_in_store = path.starts_with(Self::dir());
         let has_sufficient_length = file_name.len() > 32;
         let starts_with_hash = file_name.chars()
             .take(32)
@@ -87,36 +117,61 @@ impl Store {
         Ok(total_size)
     }
 
+    /// Size of the store considering hardlinks
+    ///
+    /// The measurement is reused for subsequent calls until the next garbage collection.
     pub fn size() -> Result<u64, String> {
-        let store_path = std::path::PathBuf::from(NIX_STORE);
-        let size = files::dir_size_considering_hardlinks(&store_path);
-        Ok(size)
+        Ok(closures::cache().store_size(|| files::dir_size_considering_hardlinks_uncached(Self::dir())))
     }
 
     pub fn blkdev() -> Result<String, String> {
-        files::blkdev_of_path(Path::new(NIX_STORE))
+        files::blkdev_of_path(Self::dir())
     }
 
-    pub fn gc(max_freed: Option<u64>) -> Result<(), String> {
-        let mut command = process::Command::new("nix-store");
-        command.arg("--gc");
-        if let Some(amount) = max_freed {
-            command.args(["--max-freed".to_owned(), format!("{amount}")]);
-        }
-        let result = command
-            .stdin(process::Stdio::inherit())
-            .stdout(process::Stdio::inherit())
-            .stderr(process::Stdio::inherit())
-            .status();
-
-        match result {
-            Ok(status) => if status.success() {
-                Ok(())
-            } else {
-                Err("Garbage collection failed".to_string())
-            },
-            Err(e) => Err(format!("Garbage collection failed: {e}")),
+    /// Description and capacity of the device holding the store
+    ///
+    /// Falls back to the capacity reported for the file system if the block device cannot be
+    /// determined (e.g. for btrfs subvolumes, LVM thin pools or bind mounts).
+    pub fn device() -> Result<(String, u64), String> {
+        let mount = files::mount_of_path(Self::dir()).ok();
+
+        if let Ok(dev) = Self::blkdev()
+                && let Ok(size) = files::get_blkdev_size(&dev) {
+            let desc = match mount {
+                Some(m) => format!("{dev}, {}", m.fs_type),
+                None => dev,
+            };
+            return Ok((desc, size));
         }
+
+        let (total, _) = files::fs_space(Self::dir())?;
+        let desc = match mount {
+            Some(m) => format!("{}, {}", m.device, m.fs_type),
+            None => "unknown device".to_owned(),
+        };
+        Ok((desc, total))
+    }
+
+    /// Delete the given dead store paths, regardless of the order they are given in
+    pub fn delete(paths: &[StorePath]) -> Result<(), String> {
+        let paths: Vec<_> = paths.iter().map(|sp| sp.path().clone()).collect();
+        let result = timings::measure(Phase::Deletion, || backend().delete_paths(&paths));
+        closures::cache().invalidate_store_size();
+        result.map_err(|e| format!("Deletion of {} store paths failed: {}", paths.len(), e))
+    }
+
+    /// Collect garbage until `max_freed` bytes are freed, the deadline passes or `on_delete` breaks
+    pub fn gc(max_freed: Option<u64>, deadline: Option<Instant>, on_delete: &mut dyn FnMut(&Path) -> ControlFlow<()>) -> Result<Option<GCResult>, String> {
+        let result = timings::measure(Phase::Deletion, || backend().collect_garbage(max_freed, deadline, on_delete));
+        closures::cache().invalidate_store_size();
+        result.map_err(|e| format!("Garbage collection failed: {e}"))
+    }
+}
+
+impl Store {
+    /// Verify the store, returning the corrupted and the missing paths
+    pub fn verify(check_contents: bool) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        backend().verify(check_contents)
     }
 }
 
@@ -129,9 +184,8 @@ impl StorePath {
         }
     }
 
-    pub fn from_symlink(link: &PathBuf) -> Result<Self, String> {
-        let path = fs::canonicalize(link)
-            .map_err(|e| e.to_string())?;
+    pub fn from_symlink(link: &Path) -> Result<Self, String> {
+        let path = sandbox::canonicalize(link)?;
         Self::new(path)
     }
 
@@ -147,76 +201,151 @@ impl StorePath {
         files::dir_size_naive(&self.0)
     }
 
+    /// Name of the store path without the hash
+    pub fn name(&self) -> String {
+        let file_name = self.0.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match file_name.split_once('-') {
+            Some((_, name)) => name.to_owned(),
+            None => file_name,
+        }
+    }
+
+    /// Package name and version, split like Nix does at the first dash followed by a digit
+    pub fn package(&self) -> (String, String) {
+        let name = self.name();
+        let split = name.char_indices()
+            .zip(name.chars().skip(1))
+            .find(|((_, c), next)| *c == '-' && next.is_ascii_digit())
+            .map(|((i, _), _)| i);
+        match split {
+            Some(i) => (name[..i].to_owned(), name[i + 1..].to_owned()),
+            None => (name, String::new()),
+        }
+    }
+
     pub fn is_drv(&self) -> bool {
         self.0.to_string_lossy().ends_with("drv")
     }
 
-    pub fn closure(&self) -> Result<HashSet<StorePath>, String> {
-        Self::closure_helper(&[self])
+    pub fn closure(&self) -> Result<Arc<HashSet<StorePath>>, String> {
+        closures::cache().closure(self)
     }
 
-    pub fn closure_size(&self) -> u64 {
-        let closure: Vec<_> = self.closure().unwrap_or_default()
-            .iter()
-            .map(|sp| sp.path())
-            .cloned()
-            .collect();
-        files::dir_size_considering_hardlinks_all(&closure)
+    pub fn closure_size(&self) -> Result<u64, String> {
+        closures::cache().closure_size(self)
     }
 
     pub fn closure_size_naive(&self) -> u64 {
        self.closure().unwrap_or_default()
             .iter()
-            .map(|sp| sp.path())
-            .map(files::dir_size_naive)
+            .map(|sp| files::dir_size_naive(sp.path()))
             .sum()
     }
 
-    fn closure_helper(paths: &[&Self]) -> Result<HashSet<StorePath>, String> {
-        let key_hash = {
-            let mut hasher = crate::Hasher::default();
-            paths.hash(&mut hasher);
-            hasher.finish()
-        };
-        if let Some(closure) = CLOSURE_CACHE.lookup(&key_hash) {
-            return Ok(closure);
-        }
+    /// Store paths that (indirectly) reference this path, including the path itself
+    pub fn referrers(&self) -> Result<HashSet<StorePath>, String> {
+        let referrers = backend().referrers(std::slice::from_ref(self.path()))?
+            .into_iter()
+            .map(StorePath)
+            .collect();
+        Ok(referrers)
+    }
 
-        let paths: Vec<_> = paths.iter().map(|sp| sp.path().clone()).collect();
-        let output = process::Command::new("nix-store")
-            .arg("--query")
-            .arg("--requisites")
-            .args(&paths)
-            .stdin(process::Stdio::inherit())
-            .stderr(process::Stdio::inherit())
-            .output()
-            .map_err(|e| e.to_string())?;
-
-        if !output.status.success() {
-            match output.status.code() {
-                Some(code) => return Err(format!("`nix-store` failed (exit code {code})")),
-                None => return Err("`nix-store` failed".to_string()),
+    /// Garbage collection roots that (indirectly) reference this path
+    pub fn roots(&self) -> Result<Vec<PathBuf>, String> {
+        let roots = timings::measure(Phase::RootDiscovery, || backend().roots_of(self.path()))?
+            .into_iter()
+            .map(|(link, _)| link)
+            .filter(|link| !link.starts_with("/proc"))
+            .collect();
+        Ok(roots)
+    }
+
+    pub fn repair(&self) -> Result<(), String> {
+        backend().repair_path(self.path())
+            .map_err(|e| format!("Repair of {} failed: {}", self.path().to_string_lossy(), e))
+    }
+
+    pub fn delete(&self) -> Result<(), String> {
+        timings::measure(Phase::Deletion, || backend().delete_paths(std::slice::from_ref(self.path())))
+            .map_err(|e| format!("Deletion of {} failed: {}", self.path().to_string_lossy(), e))
+    }
+
+    /// Compare the packages in the closure of `self` to those in the closure of `previous`
+    pub fn diff_closures(&self, previous: &Self) -> Result<ClosureDiff, String> {
+        let mut diff = ClosureDiff::default();
+        for change in self.package_changes(previous)? {
+            match (change.old.is_empty(), change.new.is_empty()) {
+                (true, _) => diff.added += 1,
+                (_, true) => diff.removed += 1,
+                _ => diff.changed += 1,
             }
         }
+        Ok(diff)
+    }
 
-        let closure: HashSet<_> = String::from_utf8(output.stdout)
-            .map_err(|e| e.to_string())?
-            .lines()
-            .map(PathBuf::from_str)
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| e.to_string())
-            .map(|i| i.into_iter().map(StorePath).collect())?;
+    /// Packages whose versions differ between the closure of `previous` and that of `self`, sorted by name
+    pub fn package_changes(&self, previous: &Self) -> Result<Vec<PackageChange>, String> {
+        let packages = |sp: &Self| -> Result<HashMap<String, HashSet<String>>, String> {
+            let mut packages: HashMap<_, HashSet<_>> = HashMap::default();
+            for path in sp.closure()?.iter() {
+                let (name, version) = path.package();
+                packages.entry(name).or_default().insert(version);
+            }
+            Ok(packages)
+        };
+        let old = packages(previous)?;
+        let new = packages(self)?;
 
-        CLOSURE_CACHE.insert(key_hash, closure.clone());
+        let sorted = |versions: Option<&HashSet<String>>| {
+            let mut versions: Vec<_> = versions.into_iter().flatten().cloned().collect();
+            versions.sort();
+            versions
+        };
+        let names: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+        let changes = names.into_iter()
+            .filter(|name| old.get(*name) != new.get(*name))
+            .map(|name| PackageChange {
+                name: name.clone(),
+                old: sorted(old.get(name)),
+                new: sorted(new.get(name)),
+            })
+            .collect();
 
-        Ok(closure)
+        Ok(changes)
     }
 
     pub fn full_closure(paths: &[&Self]) -> HashSet<StorePath> {
-        let chunks: Vec<_> = paths.chunks(CLOSURE_LOOKUP_CHUNK_SIZE).collect();
-        chunks.par_iter()
-            .flat_map(|c| Self::closure_helper(c))
-            .flatten()
+        closures::cache().full_closure(paths)
+    }
+
+    /// Size of the part of each closure that is not shared with any of the other closures
+    ///
+    /// This is roughly what would be freed by removing only that one path. A path that is listed
+    /// more than once has no exclusive size, and neither has a missing one (`None`).
+    pub fn exclusive_closure_sizes(paths: &[Option<&Self>]) -> Vec<Option<Result<u64, String>>> {
+        let closures: Vec<_> = paths.par_iter()
+            .map(|sp| sp.map(|sp| sp.closure()))
+            .collect();
+
+        let mut counts: HashMap<&StorePath, usize> = HashMap::default();
+        for store_path in closures.iter().flatten().flatten().flat_map(|c| c.iter()) {
+            *counts.entry(store_path).or_default() += 1;
+        }
+
+        closures.par_iter()
+            .map(|closure| closure.as_ref().map(|closure| match closure {
+                Ok(closure) => {
+                    let dirs: Vec<_> = closure.iter()
+                        .filter(|sp| counts.get(sp) == Some(&1))
+                        .map(|sp| sp.path().clone())
+                        .collect();
+                    Ok(files::dir_size_considering_hardlinks_all(&dirs))
+                },
+                Err(e) => Err(e.clone()),
+            }))
             .collect()
     }
 
@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::APP_PREFIX;
+
+
+const STATE_FILENAME: &str = "state.toml";
+const MAX_STORE_SIZE_MEASUREMENTS: usize = 1000;
+const MAX_GC_RECORDS: usize = 100;
+const MAX_CLEANOUT_RECORDS: usize = 100;
+
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct State {
+    #[serde(default)]
+    deferred: Vec<DeferredRemoval>,
+
+    #[serde(default)]
+    store_sizes: Vec<StoreSizeMeasurement>,
+
+    #[serde(default)]
+    gc_history: Vec<GCRecord>,
+
+    #[serde(default)]
+    cleanout_history: Vec<CleanoutRecord>,
+
+    last_cleanout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DeferredRemoval {
+    path: PathBuf,
+    since: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct GCRecord {
+    time: u64,
+    deleted: usize,
+    freed: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CleanoutRecord {
+    time: u64,
+    removed: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StoreSizeMeasurement {
+    pub time: u64,
+    pub size: u64,
+    pub available: u64,
+}
+
+
+impl State {
+    pub fn load() -> Result<Self, String> {
+        let path = match xdg::BaseDirectories::with_prefix(APP_PREFIX).get_state_file(STATE_FILENAME) {
+            Some(path) => path,
+            None => return Ok(State::default()),
+        };
+
+        if !fs::exists(&path).map_err(|e| e.to_string())? {
+            return Ok(State::default());
+        }
+
+        let s = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read state file {}: {}", path.to_string_lossy(), e))?;
+        let mut state: Self = toml::from_str(&s)
+            .map_err(|e| format!("Unable to parse state file {}: {}", path.to_string_lossy(), e))?;
+
+        // forget about generations that have been removed in the meantime
+        state.deferred.retain(|d| d.path.symlink_metadata().is_ok());
+
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = xdg::BaseDirectories::with_prefix(APP_PREFIX)
+            .place_state_file(STATE_FILENAME)
+            .map_err(|e| format!("Unable to create state file: {e}"))?;
+        let s = toml::to_string_pretty(self)
+            .map_err(|e| e.to_string())?;
+        fs::write(&path, s)
+            .map_err(|e| format!("Unable to write state file {}: {}", path.to_string_lossy(), e))
+    }
+
+    pub fn pending_since(&self, path: &Path) -> Option<SystemTime> {
+        self.deferred.iter()
+            .find(|d| d.path == path)
+            .map(|d| from_secs(d.since))
+    }
+
+    pub fn defer(&mut self, path: &Path, since: SystemTime) {
+        if self.deferred.iter().any(|d| d.path == path) {
+            return;
+        }
+
+        let since = since.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.deferred.push(DeferredRemoval { path: path.to_path_buf(), since });
+    }
+
+    pub fn undefer(&mut self, path: &Path) {
+        self.deferred.retain(|d| d.path != path);
+    }
+
+    pub fn record_store_size(&mut self, size: u64, available: u64) {
+        let time = now_secs();
+        self.store_sizes.push(StoreSizeMeasurement { time, size, available });
+
+        let excess = self.store_sizes.len().saturating_sub(MAX_STORE_SIZE_MEASUREMENTS);
+        self.store_sizes.drain(..excess);
+    }
+
+    pub fn store_sizes(&self) -> &[StoreSizeMeasurement] {
+        &self.store_sizes
+    }
+
+    pub fn record_gc(&mut self, deleted: usize, freed: u64) {
+        self.gc_history.push(GCRecord { time: now_secs(), deleted, freed });
+
+        let excess = self.gc_history.len().saturating_sub(MAX_GC_RECORDS);
+        self.gc_history.drain(..excess);
+    }
+
+    /// Record a clean out run that removed `removed` generations
+    pub fn record_cleanout(&mut self, removed: usize) {
+        let time = now_secs();
+        self.last_cleanout = Some(time);
+        self.cleanout_history.push(CleanoutRecord { time, removed });
+
+        let excess = self.cleanout_history.len().saturating_sub(MAX_CLEANOUT_RECORDS);
+        self.cleanout_history.drain(..excess);
+    }
+
+    /// Time of the last garbage collection with the number of deleted paths and the amount of space it freed
+    pub fn last_gc(&self) -> Option<(SystemTime, usize, u64)> {
+        self.gc_history.last().map(|r| (from_secs(r.time), r.deleted, r.freed))
+    }
+
+    pub fn last_cleanout(&self) -> Option<SystemTime> {
+        self.last_cleanout.map(from_secs)
+    }
+
+    /// Garbage collections since `since` with the number of deleted paths and the amount of space they freed
+    pub fn gcs_since(&self, since: SystemTime) -> Vec<(SystemTime, usize, u64)> {
+        self.gc_history.iter()
+            .map(|r| (from_secs(r.time), r.deleted, r.freed))
+            .filter(|(time, _, _)| *time >= since)
+            .collect()
+    }
+
+    /// Clean outs since `since` with the number of generations they removed
+    pub fn cleanouts_since(&self, since: SystemTime) -> Vec<(SystemTime, usize)> {
+        self.cleanout_history.iter()
+            .map(|r| (from_secs(r.time), r.removed))
+            .filter(|(time, _)| *time >= since)
+            .collect()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_secs(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
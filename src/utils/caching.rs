@@ -34,3 +34,9 @@ impl<K: Hash + Eq, V: Clone> Cache<K, V> {
         value
     }
 }
+
+impl<K: Hash + Eq, V: Clone> Default for Cache<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt::Display;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+
+/// Date in the proleptic Gregorian calendar
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Date of a day counted from the unix epoch
+    ///
+    /// See <http://howardhinnant.github.io/date_algorithms.html> for the algorithm.
+    pub fn from_days(days: i64) -> Self {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Date { year, month, day }
+    }
+
+    /// Days since the unix epoch (inverse of [from_days](Self::from_days))
+    pub fn to_days(self) -> i64 {
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// UTC date of a point in time
+    pub fn from_time(time: SystemTime) -> Self {
+        Date::from_days(epoch_secs(time).div_euclid(SECS_PER_DAY))
+    }
+
+    /// Date of a point in time in the local time zone (see `TZ`), falling back to UTC
+    pub fn local(time: SystemTime) -> Self {
+        let secs = epoch_secs(time) as libc::time_t;
+        // SAFETY: tm is plain data and localtime_r only writes to it
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        let result = unsafe { libc::localtime_r(&secs, &mut tm) };
+        if result.is_null() {
+            return Date::from_time(time);
+        }
+
+        Date {
+            year: tm.tm_year as i64 + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+        }
+    }
+
+    /// Monday of the (ISO) week containing this date
+    pub fn week_start(self) -> Self {
+        let days = self.to_days();
+        // the epoch was a Thursday
+        let weekday = (days + 3).rem_euclid(7);
+        Date::from_days(days - weekday)
+    }
+
+    pub fn month_start(self) -> Self {
+        Date { day: 1, ..self }
+    }
+
+    pub fn days_before(self, days: u64) -> Self {
+        Date::from_days(self.to_days() - days as i64)
+    }
+
+    /// The same day `months` months earlier, clamped to the end of shorter months
+    pub fn months_before(self, months: u32) -> Self {
+        let total = self.year * 12 + self.month as i64 - 1 - months as i64;
+        let year = total.div_euclid(12);
+        let month = total.rem_euclid(12) as u32 + 1;
+        Date { year, month, day: self.day.min(days_in_month(year, month)) }
+    }
+}
+
+/// Date in the local time zone, optionally with a time of day (e.g. "2024-01-01" or "2024-01-01 12:30")
+///
+/// Without a time of day, whole calendar days are compared. "0" stands for no date at all, which
+/// unsets a date given by another preset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalDate {
+    date: Date,
+    /// Hours, minutes and seconds
+    time: Option<(u32, u32, u32)>,
+}
+
+impl LocalDate {
+    /// Placeholder parsed from "0", no valid date has a month 0
+    const UNSET: LocalDate = LocalDate { date: Date { year: 0, month: 0, day: 0 }, time: None };
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if s == "0" {
+            return Ok(Self::UNSET);
+        }
+
+        let invalid = || format!("Invalid date '{s}' (expected YYYY-MM-DD or YYYY-MM-DD HH:MM[:SS])");
+        let (date, time) = match s.split_once([' ', 'T', 't']) {
+            Some((date, time)) => (date, Some(time.trim())),
+            None => (s, None),
+        };
+        // datetimes with an offset (e.g. "2024-01-01T00:00:00Z" in TOML) are not in the local time
+        // zone, so only their date is used
+        let time = time.filter(|t| !t.ends_with(['Z', 'z']) && !t.contains(['+', '-']))
+            .map(|t| t.split_once('.').map_or(t, |(t, _fraction)| t));
+
+        let mut fields = date.splitn(3, '-');
+        let (year, month, day) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(y), Some(m), Some(d)) => (
+                y.parse::<i64>().map_err(|_| invalid())?,
+                m.parse::<u32>().map_err(|_| invalid())?,
+                d.parse::<u32>().map_err(|_| invalid())?,
+            ),
+            _ => return Err(invalid()),
+        };
+        if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+            return Err(invalid());
+        }
+
+        let time = match time {
+            Some(time) => {
+                let fields: Vec<_> = time.split(':')
+                    .map(|f| f.parse::<u32>().map_err(|_| invalid()))
+                    .collect::<Result<_, _>>()?;
+                let (hours, minutes, seconds) = match fields.as_slice() {
+                    [h, m] => (*h, *m, 0),
+                    [h, m, s] => (*h, *m, *s),
+                    _ => return Err(invalid()),
+                };
+                if hours > 23 || minutes > 59 || seconds > 59 {
+                    return Err(invalid());
+                }
+                Some((hours, minutes, seconds))
+            },
+            None => None,
+        };
+
+        Ok(LocalDate { date: Date { year, month, day }, time })
+    }
+
+    /// Whether this is the "0" placeholder, which unsets the date
+    pub fn is_unset(&self) -> bool {
+        *self == Self::UNSET
+    }
+
+    /// Whether `time` lies before this date
+    pub fn is_after(&self, time: SystemTime) -> bool {
+        match self.time {
+            Some(_) => time < self.to_time(),
+            None => Date::local(time) < self.date,
+        }
+    }
+
+    /// Point in time of the date and time of day in the local time zone
+    fn to_time(self) -> SystemTime {
+        let (hours, minutes, seconds) = self.time.unwrap_or_default();
+        // SAFETY: tm is plain data and mktime only reads and normalizes it
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = (self.date.year - 1900) as libc::c_int;
+        tm.tm_mon = self.date.month as libc::c_int - 1;
+        tm.tm_mday = self.date.day as libc::c_int;
+        tm.tm_hour = hours as libc::c_int;
+        tm.tm_min = minutes as libc::c_int;
+        tm.tm_sec = seconds as libc::c_int;
+        tm.tm_isdst = -1;
+        let secs = unsafe { libc::mktime(&mut tm) };
+
+        // fall back to UTC if the local time cannot be determined
+        let secs: i64 = match secs {
+            -1 => self.date.to_days() * SECS_PER_DAY + (hours * 3600 + minutes * 60 + seconds) as i64,
+            // time_t is not 64 bit everywhere
+            secs => secs as _,
+        };
+        match u64::try_from(secs) {
+            Ok(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            Err(_) => SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+        }
+    }
+}
+
+impl Display for LocalDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_unset() {
+            return write!(f, "0");
+        }
+
+        let Date { year, month, day } = self.date;
+        write!(f, "{year:04}-{month:02}-{day:02}")?;
+        match self.time {
+            Some((hours, minutes, 0)) => write!(f, " {hours:02}:{minutes:02}"),
+            Some((hours, minutes, seconds)) => write!(f, " {hours:02}:{minutes:02}:{seconds:02}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Serialize for LocalDate {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for LocalDate {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        // dates may be given as strings or as bare TOML dates, and unset with a bare 0
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Toml(toml::value::Datetime),
+            Str(String),
+            Int(i64),
+        }
+
+        let s = match Repr::deserialize(d)? {
+            Repr::Toml(datetime) => datetime.to_string(),
+            Repr::Str(s) => s,
+            Repr::Int(i) => i.to_string(),
+        };
+        LocalDate::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Time since the same point in time `months` calendar months ago
+pub fn months_ago(months: u32) -> Duration {
+    let now = epoch_secs(SystemTime::now());
+    let today = now.div_euclid(SECS_PER_DAY);
+    let then = Date::from_days(today).months_before(months).to_days();
+    Duration::from_secs(((today - then) * SECS_PER_DAY) as u64)
+}
+
+fn epoch_secs(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i64, month: u32, day: u32) -> Date {
+        Date { year, month, day }
+    }
+
+    #[test]
+    fn months_before_clamps_to_month_end() {
+        assert_eq!(date(2024, 3, 31).months_before(1), date(2024, 2, 29));
+        assert_eq!(date(2023, 3, 31).months_before(1), date(2023, 2, 28));
+        assert_eq!(date(2024, 5, 31).months_before(1), date(2024, 4, 30));
+        assert_eq!(date(2024, 3, 15).months_before(0), date(2024, 3, 15));
+    }
+
+    #[test]
+    fn months_before_wraps_years() {
+        assert_eq!(date(2024, 1, 15).months_before(1), date(2023, 12, 15));
+        assert_eq!(date(2024, 3, 31).months_before(13), date(2023, 2, 28));
+        assert_eq!(date(2024, 2, 29).months_before(12), date(2023, 2, 28));
+        assert_eq!(date(2024, 2, 29).months_before(48), date(2020, 2, 29));
+        assert_eq!(date(1970, 1, 31).months_before(2), date(1969, 11, 30));
+    }
+
+    #[test]
+    fn leap_years() {
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(1900, 2), 28);
+        assert_eq!(days_in_month(2000, 2), 29);
+    }
+
+    #[test]
+    fn days_round_trip() {
+        assert_eq!(Date::from_days(0), date(1970, 1, 1));
+        assert_eq!(Date::from_days(-1), date(1969, 12, 31));
+        assert_eq!(Date::from_days(59), date(1970, 3, 1));
+        assert_eq!(Date::from_days(-306), date(1969, 3, 1));
+        assert_eq!(Date::from_days(-719468), date(0, 3, 1));
+        assert_eq!(Date::from_days(19782), date(2024, 2, 29));
+
+        let mut previous = Date::from_days(-800_000);
+        for days in -799_999..800_000 {
+            let date = Date::from_days(days);
+            assert_eq!(date.to_days(), days);
+            assert!(date > previous, "{date:?} after {previous:?}");
+            assert!(date.day <= days_in_month(date.year, date.month));
+            previous = date;
+        }
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        assert_eq!(date(1970, 1, 1).week_start(), date(1969, 12, 29));
+        assert_eq!(date(2024, 1, 1).week_start(), date(2024, 1, 1));
+        assert_eq!(date(2024, 1, 7).week_start(), date(2024, 1, 1));
+        assert_eq!(date(2024, 3, 2).week_start(), date(2024, 2, 26));
+        assert_eq!(date(2021, 1, 3).week_start(), date(2020, 12, 28));
+    }
+
+    #[test]
+    fn offset_datetimes_lose_their_time() {
+        let day = LocalDate { date: date(2024, 1, 1), time: None };
+        assert_eq!(LocalDate::parse("2024-01-01T12:30:00Z"), Ok(day));
+        assert_eq!(LocalDate::parse("2024-01-01T12:30:00+01:00"), Ok(day));
+        assert_eq!(LocalDate::parse("2024-01-01 12:30:00-05:00"), Ok(day));
+        assert_eq!(LocalDate::parse("2024-01-01T12:30:00.25z"), Ok(day));
+
+        let time = |time| LocalDate { date: date(2024, 1, 1), time: Some(time) };
+        assert_eq!(LocalDate::parse("2024-01-01T12:30:00"), Ok(time((12, 30, 0))));
+        assert_eq!(LocalDate::parse("2024-01-01 12:30"), Ok(time((12, 30, 0))));
+        assert_eq!(LocalDate::parse("2024-01-01T12:30:15.5"), Ok(time((12, 30, 15))));
+    }
+
+    #[test]
+    fn invalid_dates() {
+        assert!(LocalDate::parse("2023-02-29").is_err());
+        assert!(LocalDate::parse("2024-13-01").is_err());
+        assert!(LocalDate::parse("2024-01-01 24:00").is_err());
+        assert!(LocalDate::parse("2024-01").is_err());
+        assert!(LocalDate::parse("0").unwrap().is_unset());
+    }
+}
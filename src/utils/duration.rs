@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::calendar;
+
+
+/// Parse a duration like "30d", "4w", "6M" or "1y"
+///
+/// Months ("M", "mo", "month") and years ("y", "year") are calendar based: "6M" is the time since the
+/// same day six months ago rather than 180 days, while "6m" are six minutes. Everything else is
+/// handled by duration_str. Calendar units cannot be combined with other units (e.g. "1y 2M").
+pub fn parse(s: &str) -> Result<Duration, String> {
+    if let Some(months) = parse_calendar(s.trim()) {
+        return Ok(calendar::months_ago(months));
+    }
+
+    let mut units = s.split(|c: char| c.is_ascii_digit() || c.is_whitespace() || c == '+');
+    if units.any(|u| calendar_months(u).is_some()) {
+        return Err(format!("Months and years cannot be combined with other units in '{}'", s.trim()));
+    }
+    duration_str::parse_std(s)
+}
+
+/// Number of months of a calendar duration
+fn parse_calendar(s: &str) -> Option<u32> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = s.split_at(split);
+    let number: u32 = number.parse().ok()?;
+    number.checked_mul(calendar_months(unit.trim())?)
+}
+
+/// Number of months in a calendar unit
+fn calendar_months(unit: &str) -> Option<u32> {
+    match unit {
+        "M" | "mo" | "mon" | "month" | "months" => Some(1),
+        "y" | "Y" | "year" | "years" => Some(12),
+        _ => None,
+    }
+}
+
+pub fn deserialize_option<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Seconds(u64),
+        Expression(String),
+    }
+
+    match Option::<Raw>::deserialize(d)? {
+        Some(Raw::Seconds(secs)) => Ok(Some(Duration::from_secs(secs))),
+        Some(Raw::Expression(s)) => parse(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calendar_units() {
+        assert_eq!(parse_calendar("6M"), Some(6));
+        assert_eq!(parse_calendar("6 months"), Some(6));
+        assert_eq!(parse_calendar("1y"), Some(12));
+        assert_eq!(parse_calendar("2 years"), Some(24));
+        assert_eq!(parse_calendar("6m"), None);
+        assert_eq!(parse_calendar("30d"), None);
+        assert_eq!(parse_calendar("M"), None);
+        assert_eq!(parse_calendar("400000000y"), None);
+    }
+
+    #[test]
+    fn minutes_are_not_months() {
+        assert_eq!(parse("6m"), Ok(Duration::from_secs(6 * 60)));
+        assert_eq!(parse("30d"), Ok(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert!(parse("6M").unwrap() >= Duration::from_secs(181 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn calendar_units_are_not_combined() {
+        assert!(parse("1y 2M").is_err());
+        assert!(parse("1y2M").is_err());
+        assert!(parse("2M 3d").is_err());
+        assert!(parse("1 year + 3d").is_err());
+    }
+}
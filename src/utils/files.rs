@@ -1,21 +1,91 @@
 use std::fs;
-use std::num;
-use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 use rayon::iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
 
 use crate::utils::caching::Cache;
-use crate::HashMap;
+use crate::utils::timings::{self, Phase};
+use crate::{HashMap, HashSet};
 
 
 static INODE_CACHE: Cache<PathBuf, HashMap<InoKey, u64>> = Cache::new();
+const INODE_SHARDS: usize = 64;
 
 type Ino = u64;
 type DevId = u64;
 type InoKey = (DevId, Ino);
 
-pub fn dir_size_naive(path: &PathBuf) -> u64 {
+#[cfg(target_os = "linux")]
+const MOUNTS_PATH: &str = "/proc/self/mounts";
+#[cfg(target_os = "linux")]
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// Entry of the mount table
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub device: String,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+}
+
+/// Entry of the per-process mount info table, which also records the root of each mount
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone)]
+struct MountInfoEntry {
+    dev: String,
+    root: PathBuf,
+    mount_point: PathBuf,
+    read_only: bool,
+    fs_type: String,
+    options: Vec<String>,
+}
+
+/// Inode map split into multiple shards to reduce lock contention between threads
+struct ShardedInodes(Vec<Mutex<HashMap<InoKey, u64>>>);
+
+impl ShardedInodes {
+    fn new() -> Self {
+        ShardedInodes((0..INODE_SHARDS).map(|_| Mutex::default()).collect())
+    }
+
+    fn shard(&self, key: &InoKey) -> usize {
+        (key.1 as usize) % self.0.len()
+    }
+
+    fn insert(&self, key: InoKey, size: u64) {
+        self.0[self.shard(&key)].lock().unwrap().insert(key, size);
+    }
+
+    fn insert_all(&self, items: Vec<(InoKey, u64)>) {
+        let mut by_shard: Vec<Vec<_>> = (0..self.0.len()).map(|_| Vec::new()).collect();
+        for (key, size) in items {
+            by_shard[self.shard(&key)].push((key, size));
+        }
+
+        for (shard, items) in self.0.iter().zip(by_shard) {
+            if !items.is_empty() {
+                shard.lock().unwrap().extend(items);
+            }
+        }
+    }
+
+    fn into_map(self) -> HashMap<InoKey, u64> {
+        let mut map = HashMap::default();
+        for shard in self.0 {
+            map.extend(shard.into_inner().unwrap());
+        }
+        map
+    }
+}
+
+pub fn dir_size_naive(path: &Path) -> u64 {
+    timings::measure(Phase::SizeComputation, || dir_size_naive_helper(path))
+}
+
+fn dir_size_naive_helper(path: &Path) -> u64 {
     let metadata = match path.symlink_metadata() {
         Ok(meta) => meta,
         Err(_) => return 0,
@@ -32,7 +102,7 @@ pub fn dir_size_naive(path: &PathBuf) -> u64 {
         read_dir.into_iter()
             .flatten()
             .par_bridge()
-            .map(|entry| dir_size_naive(&entry.path()))
+            .map(|entry| dir_size_naive_helper(&entry.path()))
             .sum()
     } else if ft.is_file() {
         metadata.len()
@@ -41,6 +111,23 @@ pub fn dir_size_naive(path: &PathBuf) -> u64 {
     }
 }
 
+/// Most recent modification time of `path` or anything below it, without following symlinks
+pub fn newest_mtime(path: &Path) -> Option<SystemTime> {
+    let metadata = path.symlink_metadata().ok()?;
+    let modified = metadata.modified().ok();
+    if !metadata.is_dir() {
+        return modified;
+    }
+
+    let newest_entry = fs::read_dir(path).into_iter()
+        .flatten()
+        .flatten()
+        .par_bridge()
+        .filter_map(|entry| newest_mtime(&entry.path()))
+        .max();
+    modified.max(newest_entry)
+}
+
 pub fn dir_size_considering_hardlinks_all(paths: &[PathBuf]) -> u64 {
     let inodes = paths.par_iter()
         .map(|p| (p, INODE_CACHE.lookup(p)))
@@ -52,6 +139,11 @@ pub fn dir_size_considering_hardlinks_all(paths: &[PathBuf]) -> u64 {
     inodes.values().sum()
 }
 
+/// Like [dir_size_considering_hardlinks], but bypasses the inode cache
+pub fn dir_size_considering_hardlinks_uncached(path: &Path) -> u64 {
+    dir_size_hl_helper(path).values().sum()
+}
+
 pub fn dir_size_considering_hardlinks(path: &PathBuf) -> u64 {
     let inodes = match INODE_CACHE.lookup(path) {
         Some(inodes) => inodes,
@@ -60,6 +152,187 @@ pub fn dir_size_considering_hardlinks(path: &PathBuf) -> u64 {
     inodes.values().sum()
 }
 
+/// Like [dir_size_considering_hardlinks_all], but also counts the targets of symlinks
+pub fn dir_size_following_symlinks_all(paths: &[PathBuf]) -> u64 {
+    let mut inodes = HashMap::default();
+    let mut visited_dirs = HashSet::default();
+    timings::measure(Phase::SizeComputation, || {
+        for path in paths {
+            dir_size_follow_helper(path, &mut inodes, &mut visited_dirs);
+        }
+    });
+    inodes.values().sum()
+}
+
+/// Total and available space of the file system containing `path`
+pub fn fs_space(path: &Path) -> Result<(u64, u64), String> {
+    fs_usage(path).map(|(total, _, available)| (total, available))
+}
+
+/// Total, used and available space of the file system containing `path`
+pub fn fs_usage(path: &Path) -> Result<(u64, u64, u64), String> {
+    let stat = rustix::fs::statvfs(path)
+        .map_err(|e| format!("Unable to query file system of {}: {}", path.to_string_lossy(), e))?;
+    let total = stat.f_blocks * stat.f_frsize;
+    let used = stat.f_blocks.saturating_sub(stat.f_bfree) * stat.f_frsize;
+    Ok((total, used, stat.f_bavail * stat.f_frsize))
+}
+
+/// Whether the file system containing `path` is mounted read-only
+pub fn is_read_only(path: &Path) -> Result<bool, String> {
+    let stat = rustix::fs::statvfs(path)
+        .map_err(|e| format!("Unable to query file system of {}: {}", path.to_string_lossy(), e))?;
+    Ok(stat.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY))
+}
+
+/// Mount table entry of the file system containing `path`
+#[cfg(target_os = "linux")]
+pub fn mount_of_path(path: &Path) -> Result<MountInfo, String> {
+    let path = fs::canonicalize(path)
+        .map_err(|e| format!("Unable to resolve {}: {}", path.to_string_lossy(), e))?;
+    let mounts = fs::read_to_string(MOUNTS_PATH)
+        .map_err(|e| format!("Unable to read {MOUNTS_PATH}: {e}"))?;
+
+    // the last matching entry wins, as later mounts shadow earlier ones
+    mounts.lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return None;
+            }
+            Some(MountInfo {
+                device: unescape_mount_field(fields[0]),
+                mount_point: PathBuf::from(unescape_mount_field(fields[1])),
+                fs_type: fields[2].to_owned(),
+            })
+        })
+        .filter(|m| path.starts_with(&m.mount_point))
+        .fold(None, |best: Option<MountInfo>, m| match best {
+            Some(b) if b.mount_point.as_os_str().len() > m.mount_point.as_os_str().len() => Some(b),
+            _ => Some(m),
+        })
+        .ok_or(format!("Unable to find mount point of {}", path.to_string_lossy()))
+}
+
+/// Mount table entry of the file system containing `path`
+///
+/// There is no mount table to read on macOS, but statfs reports the mount of a path directly.
+#[cfg(target_os = "macos")]
+pub fn mount_of_path(path: &Path) -> Result<MountInfo, String> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| e.to_string())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!("Unable to query file system of {}: {}", path.to_string_lossy(), std::io::Error::last_os_error()));
+    }
+
+    // SAFETY: statfs returns nul-terminated strings
+    let field = |chars: &[libc::c_char]| unsafe { CStr::from_ptr(chars.as_ptr()) }.to_string_lossy().to_string();
+    Ok(MountInfo {
+        device: field(&stat.f_mntfromname),
+        mount_point: PathBuf::from(field(&stat.f_mntonname)),
+        fs_type: field(&stat.f_fstypename),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn mount_of_path(path: &Path) -> Result<MountInfo, String> {
+    Err(format!("Unable to find mount point of {} on this platform", path.to_string_lossy()))
+}
+
+/// Writable path of the btrfs subvolume containing `path`
+///
+/// On NixOS the store is a read-only bind mount, so the subvolume is determined by the `subvol`
+/// option of the store's file system and reached through a writable mount of the same device.
+#[cfg(target_os = "linux")]
+pub fn btrfs_subvolume_of_path(path: &Path) -> Result<PathBuf, String> {
+    let path = fs::canonicalize(path)
+        .map_err(|e| format!("Unable to resolve {}: {}", path.to_string_lossy(), e))?;
+    let table = fs::read_to_string(MOUNTINFO_PATH)
+        .map_err(|e| format!("Unable to read {MOUNTINFO_PATH}: {e}"))?;
+    find_btrfs_subvolume(&parse_mountinfo(&table), &path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn btrfs_subvolume_of_path(path: &Path) -> Result<PathBuf, String> {
+    Err(format!("Unable to determine the btrfs subvolume of {} on this platform", path.to_string_lossy()))
+}
+
+#[cfg(target_os = "linux")]
+fn parse_mountinfo(table: &str) -> Vec<MountInfoEntry> {
+    table.lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            // optional fields are terminated by a single dash
+            let sep = fields.iter().skip(6).position(|f| *f == "-")? + 6;
+            Some(MountInfoEntry {
+                dev: fields[2].to_owned(),
+                root: PathBuf::from(unescape_mount_field(fields[3])),
+                mount_point: PathBuf::from(unescape_mount_field(fields[4])),
+                read_only: fields[5].split(',').any(|o| o == "ro"),
+                fs_type: fields.get(sep + 1)?.to_string(),
+                options: fields.get(sep + 3)
+                    .map(|o| o.split(',').map(unescape_mount_field).collect())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn find_btrfs_subvolume(mounts: &[MountInfoEntry], path: &Path) -> Result<PathBuf, String> {
+    // the last matching entry wins, as later mounts shadow earlier ones
+    let mount = mounts.iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .fold(None, |best: Option<&MountInfoEntry>, m| match best {
+            Some(b) if b.mount_point.as_os_str().len() > m.mount_point.as_os_str().len() => Some(b),
+            _ => Some(m),
+        })
+        .ok_or(format!("Unable to find mount point of {}", path.to_string_lossy()))?;
+    if mount.fs_type != "btrfs" {
+        return Err(format!("{} is on {} rather than btrfs", path.to_string_lossy(), mount.fs_type));
+    }
+
+    let subvol = mount.options.iter()
+        .find_map(|o| o.strip_prefix("subvol="))
+        .map(PathBuf::from)
+        .unwrap_or(PathBuf::from("/"));
+    mounts.iter()
+        .filter(|m| m.dev == mount.dev && !m.read_only)
+        .filter_map(|m| Some((m, subvol.strip_prefix(&m.root).ok()?)))
+        .max_by_key(|(m, _)| m.root.as_os_str().len())
+        .map(|(m, rel)| match rel.as_os_str().is_empty() {
+            true => m.mount_point.clone(),
+            false => m.mount_point.join(rel),
+        })
+        .ok_or(format!("No writable mount of btrfs subvolume {} (containing {})",
+            subvol.to_string_lossy(), path.to_string_lossy()))
+}
+
+/// Decode octal escapes (e.g. `\040` for spaces) used in the mount table
+#[cfg(target_os = "linux")]
+fn unescape_mount_field(field: &str) -> String {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            let code: String = (0..3).filter_map(|_| chars.next_if(|c| c.is_digit(8))).collect();
+            match u8::from_str_radix(&code, 8) {
+                Ok(byte) if code.len() == 3 => result.push(byte as char),
+                _ => { result.push(c); result.push_str(&code); },
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
 pub fn blkdev_of_path(path: &Path) -> Result<String, String> {
     let dev = path.symlink_metadata()
         .map_err(|e| e.to_string())?
@@ -67,9 +340,26 @@ pub fn blkdev_of_path(path: &Path) -> Result<String, String> {
     find_blkdev(dev)
 }
 
+/// Name of the disk (e.g. `disk3s5`) holding `path`
+#[cfg(target_os = "macos")]
+pub fn blkdev_of_path(path: &Path) -> Result<String, String> {
+    let mount = mount_of_path(path)?;
+    mount.device.strip_prefix("/dev/")
+        .map(str::to_owned)
+        .ok_or(format!("{} is not on a disk", path.to_string_lossy()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn blkdev_of_path(path: &Path) -> Result<String, String> {
+    Err(format!("Unable to find block device of {} on this platform", path.to_string_lossy()))
+}
+
+#[cfg(target_os = "linux")]
 pub fn find_blkdev(id: u64) -> Result<String, String> {
+    use std::os::unix::fs::FileTypeExt;
+
     fs::read_dir("/dev")
-        .unwrap()
+        .map_err(|e| format!("Unable to read /dev: {e}"))?
         .flatten()
         .flat_map(|e| e.path().file_name().map(|n| (e, n.to_string_lossy().to_string())))
         .flat_map(|(e, n)| e.metadata().map(|m| (n, m)))
@@ -79,6 +369,7 @@ pub fn find_blkdev(id: u64) -> Result<String, String> {
         .ok_or(format!("Could not find device for id {id}"))
 }
 
+#[cfg(target_os = "linux")]
 pub fn get_blkdev_size(name: &str) -> Result<u64, String> {
     let size_file_path = PathBuf::from(&format!("/sys/class/block/{name}/size"));
     fs::read_to_string(size_file_path)
@@ -87,34 +378,133 @@ pub fn get_blkdev_size(name: &str) -> Result<u64, String> {
         .next()
         .ok_or(String::from("Size file empty"))?
         .parse()
-        .map_err(|e: num::ParseIntError| e.to_string())
+        .map_err(|e: std::num::ParseIntError| e.to_string())
         .map(|n: u64| n * 512)
 }
 
-fn dir_size_hl_helper(path: &PathBuf) -> HashMap<InoKey, u64> {
+/// The size of disks is not exposed without IOKit on macOS, so the file system size is used instead
+#[cfg(target_os = "macos")]
+pub fn get_blkdev_size(name: &str) -> Result<u64, String> {
+    Err(format!("Unable to determine the size of {name}"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn get_blkdev_size(name: &str) -> Result<u64, String> {
+    Err(format!("Unable to determine the size of {name} on this platform"))
+}
+
+fn dir_size_hl_helper(path: &Path) -> HashMap<InoKey, u64> {
+    timings::measure(Phase::SizeComputation, || {
+        let inodes = ShardedInodes::new();
+        rayon::scope(|s| dir_size_hl_walk(s, path.to_path_buf(), &inodes));
+        inodes.into_map()
+    })
+}
+
+fn dir_size_hl_walk<'s>(scope: &rayon::Scope<'s>, path: PathBuf, inodes: &'s ShardedInodes) {
     let metadata = match path.symlink_metadata() {
         Ok(meta) => meta,
-        Err(_) => return HashMap::default(),
+        Err(_) => return,
     };
     let ft = metadata.file_type();
 
+    if ft.is_file() {
+        inodes.insert((metadata.dev(), metadata.ino()), metadata.len());
+        return;
+    } else if !ft.is_dir() {
+        return;
+    }
+
+    let read_dir = match fs::read_dir(&path) {
+        Ok(rd) => rd,
+        Err(_) => return,
+    };
+
+    // files are handled in chunks per directory, only subdirectories are spawned as new tasks
+    let mut chunk = Vec::new();
+    for entry in read_dir.flatten() {
+        match entry.file_type() {
+            Ok(ft) if ft.is_dir() => {
+                let child = entry.path();
+                scope.spawn(move |s| dir_size_hl_walk(s, child, inodes));
+            },
+            Ok(ft) if ft.is_file() => if let Ok(meta) = entry.metadata() {
+                chunk.push(((meta.dev(), meta.ino()), meta.len()));
+            },
+            _ => (),
+        }
+    }
+    inodes.insert_all(chunk);
+}
+
+fn dir_size_follow_helper(path: &Path, inodes: &mut HashMap<InoKey, u64>, visited_dirs: &mut HashSet<InoKey>) {
+    let metadata = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return,
+    };
+    let ft = metadata.file_type();
+    let key = (metadata.dev(), metadata.ino());
+
     if ft.is_dir() {
+        // symlinks may introduce cycles
+        if !visited_dirs.insert(key) {
+            return;
+        }
+
         let read_dir = match fs::read_dir(path) {
             Ok(rd) => rd,
-            Err(_) => return HashMap::default(),
+            Err(_) => return,
         };
-
-        read_dir.into_iter()
-            .par_bridge()
-            .flatten()
-            .map(|e| dir_size_hl_helper(&e.path()))
-            .reduce(HashMap::default, |mut last, next| { last.extend(next); last })
+        for entry in read_dir.flatten() {
+            dir_size_follow_helper(&entry.path(), inodes, visited_dirs);
+        }
     } else if ft.is_file() {
-        let mut new = HashMap::default();
-        new.insert((metadata.dev(), metadata.ino()), metadata.len());
-        new
-    } else {
-        HashMap::default()
+        inodes.insert(key, metadata.len());
     }
 }
 
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    const NIXOS_MOUNTINFO: &str = "\
+22 1 0:26 /@ / rw,relatime shared:1 - btrfs /dev/sda2 rw,space_cache=v2,subvolid=256,subvol=/@
+23 22 0:26 /@nix /nix rw,relatime shared:2 - btrfs /dev/sda2 rw,space_cache=v2,subvolid=257,subvol=/@nix
+24 23 0:26 /@nix/store /nix/store ro,relatime shared:3 - btrfs /dev/sda2 rw,space_cache=v2,subvolid=257,subvol=/@nix
+25 22 0:5 / /dev rw,nosuid shared:4 - devtmpfs devtmpfs rw,size=4096k
+";
+
+    #[test]
+    fn subvolume_behind_read_only_bind_mount() {
+        let mounts = parse_mountinfo(NIXOS_MOUNTINFO);
+        assert_eq!(mounts.len(), 4);
+        assert!(mounts[2].read_only);
+        assert_eq!(find_btrfs_subvolume(&mounts, Path::new("/nix/store")), Ok(PathBuf::from("/nix")));
+        assert_eq!(find_btrfs_subvolume(&mounts, Path::new("/home")), Ok(PathBuf::from("/")));
+        assert!(find_btrfs_subvolume(&mounts, Path::new("/dev/null")).is_err());
+    }
+
+    #[test]
+    fn newest_mtime_looks_inside() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("a/b");
+        fs::create_dir_all(&nested).unwrap();
+        let file = fs::File::create(nested.join("output")).unwrap();
+
+        let day_ago = SystemTime::now() - std::time::Duration::from_secs(24 * 60 * 60);
+        for path in [dir.path(), &dir.path().join("a"), &nested] {
+            fs::File::open(path).unwrap().set_modified(day_ago).unwrap();
+        }
+        assert!(newest_mtime(dir.path()).unwrap() > day_ago);
+
+        file.set_modified(day_ago).unwrap();
+        assert_eq!(newest_mtime(dir.path()), Some(day_ago));
+        assert_eq!(newest_mtime(&dir.path().join("missing")), None);
+    }
+
+    #[test]
+    fn subvolume_without_writable_mount() {
+        let mounts = parse_mountinfo(&NIXOS_MOUNTINFO.replace("/@nix /nix rw", "/@nix /nix ro"));
+        assert!(find_btrfs_subvolume(&mounts, Path::new("/nix/store")).is_err());
+    }
+}
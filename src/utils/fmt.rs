@@ -1,18 +1,80 @@
-use std::{cmp, io};
+use std::{cmp, env, io};
+use std::sync::{OnceLock, RwLock};
+use std::time::SystemTime;
 use std::{fmt::Display, time::Duration};
 
-use size::Size;
+use colored::{ColoredString, Colorize};
 
+use crate::config::{GlobalConfig, Truncation};
+use super::calendar::Date;
+use super::interaction::warn;
 use super::terminal::terminal_width;
 
 
+const DEFAULT_AGE_FRESH: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const DEFAULT_AGE_STALE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+const SECS_PER_MINUTE: u64 = 60;
+const SECS_PER_HOUR: u64 = 60 * SECS_PER_MINUTE;
+const SECS_PER_DAY: u64 = 24 * SECS_PER_HOUR;
+const SECS_PER_MONTH: u64 = 30 * SECS_PER_DAY;
+const SECS_PER_YEAR: u64 = 365 * SECS_PER_DAY;
+
+/// Units used for compact and ISO ages, largest first
+const AGE_UNITS: [(u64, &str, &str); 6] = [
+    (SECS_PER_YEAR, "y", "Y"),
+    (SECS_PER_MONTH, "mo", "M"),
+    (SECS_PER_DAY, "d", "D"),
+    (SECS_PER_HOUR, "h", "H"),
+    (SECS_PER_MINUTE, "m", "M"),
+    (1, "s", "S"),
+];
+
+const BINARY_UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+const DECIMAL_UNITS: [&str; 6] = ["B", "kB", "MB", "GB", "TB", "PB"];
+
+/// Languages writing a decimal comma, with the separator they use for thousands
+const DECIMAL_COMMA_LANGUAGES: [(&str, char); 31] = [
+    ("da", '.'), ("de", '.'), ("el", '.'), ("es", '.'), ("hr", '.'), ("id", '.'), ("it", '.'),
+    ("nl", '.'), ("pt", '.'), ("ro", '.'), ("sl", '.'), ("sr", '.'), ("tr", '.'), ("vi", '.'),
+    ("bg", '\u{a0}'), ("cs", '\u{a0}'), ("et", '\u{a0}'), ("fi", '\u{a0}'), ("fr", '\u{202f}'),
+    ("hu", '\u{a0}'), ("lt", '\u{a0}'), ("lv", '\u{a0}'), ("nb", '\u{a0}'), ("nn", '\u{a0}'),
+    ("no", '\u{a0}'), ("pl", '\u{a0}'), ("ru", '\u{a0}'), ("sk", '\u{a0}'), ("sv", '\u{a0}'),
+    ("uk", '\u{a0}'), ("be", '\u{a0}'),
+];
+
+static AGE_THRESHOLDS: RwLock<(Duration, Duration)> = RwLock::new((DEFAULT_AGE_FRESH, DEFAULT_AGE_STALE));
+static AGE_FORMAT: OnceLock<AgeFormat> = OnceLock::new();
+static SI_UNITS: OnceLock<bool> = OnceLock::new();
+static NUMBER_FORMAT: OnceLock<NumberFormat> = OnceLock::new();
+
+
+/// How ages and other durations are displayed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AgeFormat {
+    /// Largest unit only (e.g. "3 days"), in weeks between one and three years
+    #[default]
+    Relative,
+
+    /// Two largest units, abbreviated (e.g. "1y 3mo")
+    Compact,
+
+    /// ISO 8601 duration of the two largest units (e.g. "P1Y3M")
+    Iso,
+}
+
+
 pub trait Formattable: Display {
     const MAX_WIDTH: usize;
 
+    /// Pad to [MAX_WIDTH](Self::MAX_WIDTH), aligned right
+    ///
+    /// Pad before coloring the result, as escape sequences would be counted towards the width.
     fn left_pad(&self) -> String {
         format!("{:>width$}", self.to_string(), width = Self::MAX_WIDTH)
     }
 
+    /// Pad to [MAX_WIDTH](Self::MAX_WIDTH), aligned left (see [left_pad](Self::left_pad))
     fn right_pad(&self) -> String {
         format!("{:<width$}", self.to_string(), width = Self::MAX_WIDTH)
     }
@@ -32,25 +94,149 @@ pub trait Formattable: Display {
 
 
 
-pub struct FmtSize(Size);
-pub struct FmtPercentage(u64);
+/// Set the thresholds for coloring ages (see [age_colored]), replacing those set before
+pub fn set_age_thresholds(fresh: Option<Duration>, stale: Option<Duration>) {
+    *AGE_THRESHOLDS.write().unwrap() = (fresh.unwrap_or(DEFAULT_AGE_FRESH), stale.unwrap_or(DEFAULT_AGE_STALE));
+}
+
+/// Set the format used by [FmtAge]
+pub fn set_age_format(format: AgeFormat) {
+    let _ = AGE_FORMAT.set(format);
+}
+
+/// Let [FmtSize] use decimal units (kB, MB, GB) instead of binary ones (KiB, MiB, GiB)
+pub fn set_si_units(si: bool) {
+    let _ = SI_UNITS.set(si);
+}
+
+/// Separators for displaying numbers
+struct NumberFormat {
+    decimal: char,
+    thousands: Option<char>,
+}
+
+impl NumberFormat {
+    /// Separators of the locale set for numbers (LC_ALL, LC_NUMERIC or LANG)
+    ///
+    /// Without a locale (or with C and POSIX) numbers are not grouped, as before.
+    fn from_env() -> Self {
+        let locale = ["LC_ALL", "LC_NUMERIC", "LANG"].iter()
+            .filter_map(|v| env::var(v).ok())
+            .find(|v| !v.is_empty())
+            .unwrap_or_default();
+        let locale = locale.split(['.', '@']).next().unwrap_or_default();
+        let (language, territory) = locale.split_once('_').unwrap_or((locale, ""));
+
+        match (language, territory) {
+            ("" | "C" | "POSIX", _) => NumberFormat { decimal: '.', thousands: None },
+            ("de" | "it" | "fr", "CH") => NumberFormat { decimal: '.', thousands: Some('\'') },
+            (language, _) => match DECIMAL_COMMA_LANGUAGES.iter().find(|(l, _)| *l == language) {
+                Some((_, thousands)) => NumberFormat { decimal: ',', thousands: Some(*thousands) },
+                None => NumberFormat { decimal: '.', thousands: Some(',') },
+            },
+        }
+    }
+
+    fn get() -> &'static Self {
+        NUMBER_FORMAT.get_or_init(Self::from_env)
+    }
+
+    /// Format `value` with the given number of decimal places
+    fn format(&self, value: f64, decimals: usize) -> String {
+        let formatted = format!("{value:.decimals$}");
+        let (integer, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+
+        let mut out = String::with_capacity(formatted.len() + integer.len() / 3);
+        for (i, c) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0
+                    && let Some(sep) = self.thousands {
+                out.push(sep);
+            }
+            out.push(c);
+        }
+        if !fraction.is_empty() {
+            out.push(self.decimal);
+            out.push_str(fraction);
+        }
+        out
+    }
+}
+
+/// Color a string representing `age` according to the configured age thresholds
+pub fn age_colored(age: Duration, s: &str) -> ColoredString {
+    let (fresh, stale) = *AGE_THRESHOLDS.read().unwrap();
+
+    if age < fresh {
+        s.green()
+    } else if age < stale {
+        s.yellow()
+    } else {
+        s.red()
+    }
+}
+
+/// Note on how the sizes of individual rows relate to the estimated total size
+///
+/// Closure sizes of rows overlap, so they add up to more than the total. Exclusive sizes
+/// (`unique_sizes`) leave out everything shared, so they add up to less.
+pub fn size_overlap_note(rows_size: u64, total_size: u64, unique_sizes: bool) -> Option<String> {
+    if total_size == 0 {
+        return None;
+    }
+
+    if unique_sizes {
+        let shared = total_size.saturating_sub(rows_size);
+        let percentage = shared * 100 / total_size;
+        (shared > 0).then(|| format!("  -> {} ({percentage}%) of the total is shared and not part of any row",
+            FmtSize::new(shared)))
+    } else {
+        let overlap = rows_size.saturating_sub(total_size);
+        let percentage = overlap * 100 / total_size;
+        (overlap > 0).then(|| format!("  -> rows add up to {}, as closures overlap by {percentage}% (see --unique-sizes)",
+            FmtSize::new(rows_size)))
+    }
+}
+
+
+
+pub struct FmtSize(u64);
+pub struct FmtNumber(u64);
+pub struct FmtPercentage(Option<f64>);
 pub struct FmtBracketed<T: Formattable>(Box<T>, [char; 2]);
 pub struct FmtOrNA<T: Formattable>(Option<T>, bool);
 pub struct FmtAge(Duration);
-pub struct FmtWithEllipsis(String, usize, bool);
+pub struct FmtDate(SystemTime);
+pub struct FmtWithEllipsis(String, usize, bool, Truncation);
 pub struct FmtPrefix<const ADD: usize, T: Formattable>(Box<T>, String);
 pub struct FmtSuffix<const ADD: usize, T: Formattable>(Box<T>, String);
 
 
 impl FmtSize {
     pub fn new(bytes: u64) -> Self {
-        FmtSize(Size::from_bytes(bytes))
+        FmtSize(bytes)
+    }
+}
+
+impl FmtNumber {
+    pub fn new(number: usize) -> Self {
+        FmtNumber(number as u64)
     }
 }
 
 impl FmtPercentage {
+    /// Percentage of `amount` in `total`, clamped to 100%
+    ///
+    /// A zero total is displayed as n/a.
     pub fn new(amount: u64, total: u64) -> Self {
-        FmtPercentage(amount * 100 / total)
+        if total == 0 {
+            return FmtPercentage(None);
+        }
+
+        let percentage = amount as f64 * 100.0 / total as f64;
+        if percentage > 100.0 {
+            warn(&format!("Clamping inconsistent percentage ({} of {})", FmtSize::new(amount), FmtSize::new(total)));
+        }
+        FmtPercentage(Some(percentage.min(100.0)))
     }
 }
 
@@ -60,7 +246,10 @@ impl FmtWithEllipsis {
             Some(tw) => cmp::min(tw.saturating_sub(leave_space), preferred_width),
             None => preferred_width,
         };
-        FmtWithEllipsis(s, actual_width, true)
+        let truncation = GlobalConfig::get().ok()
+            .and_then(|g| g.truncate)
+            .unwrap_or_default();
+        FmtWithEllipsis(s, actual_width, true, truncation)
     }
 
     pub fn truncate_if(mut self, trunc: bool) -> Self {
@@ -112,6 +301,12 @@ impl FmtAge {
     }
 }
 
+impl FmtDate {
+    pub fn new(time: SystemTime) -> Self {
+        FmtDate(time)
+    }
+}
+
 impl<const ADD: usize, T: Formattable> FmtPrefix<ADD, T> {
     pub fn new(obj: T, prefix: String) -> Self {
         FmtPrefix(Box::new(obj), prefix)
@@ -131,7 +326,7 @@ impl Formattable for FmtSize {
 }
 
 impl Formattable for FmtPercentage {
-    const MAX_WIDTH: usize = 3;
+    const MAX_WIDTH: usize = 4;
 }
 
 impl<T: Formattable> Formattable for FmtBracketed<T> {
@@ -146,6 +341,10 @@ impl Formattable for FmtAge {
     const MAX_WIDTH: usize = 9;
 }
 
+impl Formattable for FmtDate {
+    const MAX_WIDTH: usize = 10;
+}
+
 impl<const ADD: usize, T: Formattable> Formattable for FmtPrefix<ADD, T> {
     const MAX_WIDTH: usize = T::MAX_WIDTH + ADD;
 }
@@ -158,13 +357,43 @@ impl<const ADD: usize, T: Formattable> Formattable for FmtSuffix<ADD, T> {
 
 impl Display for FmtSize {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
+        let (base, units) = match SI_UNITS.get().copied().unwrap_or(false) {
+            true => (1000.0, DECIMAL_UNITS),
+            false => (1024.0, BINARY_UNITS),
+        };
+
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= base && unit < units.len() - 1 {
+            value /= base;
+            unit += 1;
+        }
+
+        // three significant digits, but whole bytes
+        let decimals = match (unit, value) {
+            (0, _) => 0,
+            (_, v) if v < 10.0 => 2,
+            (_, v) if v < 100.0 => 1,
+            _ => 0,
+        };
+        write!(f, "{} {}", NumberFormat::get().format(value, decimals), units[unit])
+    }
+}
+
+impl Display for FmtNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", NumberFormat::get().format(self.0 as f64, 0))
     }
 }
 
 impl Display for FmtPercentage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}%", self.0)
+        match self.0 {
+            // one decimal place, so small shares do not all show up as 0%
+            Some(p) if p < 9.95 => write!(f, "{}%", NumberFormat::get().format(p, 1)),
+            Some(p) => write!(f, "{}%", NumberFormat::get().format(p, 0)),
+            None => write!(f, "n/a"),
+        }
     }
 }
 
@@ -183,14 +412,22 @@ impl<T: Formattable> Display for FmtOrNA<T> {
     }
 }
 
-impl Display for FmtAge {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl FmtAge {
+    fn fmt_as(&self, format: AgeFormat, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match format {
+            AgeFormat::Relative => self.fmt_relative(f),
+            AgeFormat::Compact => self.fmt_compact(f),
+            AgeFormat::Iso => self.fmt_iso(f),
+        }
+    }
+
+    fn fmt_relative(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let seconds = self.0.as_secs();
-        let minutes = seconds / 60;
-        let hours = minutes / 60;
-        let days = hours / 24;
+        let minutes = seconds / SECS_PER_MINUTE;
+        let hours = seconds / SECS_PER_HOUR;
+        let days = seconds / SECS_PER_DAY;
         let weeks = days / 7;
-        let years = days / 365;
+        let years = seconds / SECS_PER_YEAR;
 
         if minutes < 1 {
             write!(f, "{seconds} sec")
@@ -209,17 +446,65 @@ impl Display for FmtAge {
                 write!(f, "{days} days")
             }
         } else if years < 3 {
-            if weeks == 1 {
-                write!(f, "1 week")
-            } else {
-                write!(f, "{weeks} weeks")
-            }
-        } else if years == 1 {
-            write!(f, "1 year")
+            write!(f, "{weeks} weeks")
         } else {
             write!(f, "{years} years")
         }
+    }
+
+    /// Values of the two largest units needed, as indices into [AGE_UNITS]
+    fn largest_units(&self) -> Vec<(usize, u64)> {
+        let mut rest = self.0.as_secs();
+        let largest = AGE_UNITS.iter()
+            .position(|(secs, _, _)| rest >= *secs)
+            .unwrap_or(AGE_UNITS.len() - 1);
+
+        let mut parts = Vec::with_capacity(2);
+        for (i, (secs, _, _)) in AGE_UNITS.iter().enumerate().take(largest + 2).skip(largest) {
+            let value = rest / secs;
+            rest %= secs;
+            if value > 0 || parts.is_empty() {
+                parts.push((i, value));
+            }
+        }
+        parts
+    }
+
+    fn fmt_compact(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<_> = self.largest_units().into_iter()
+            .map(|(i, value)| format!("{value}{}", AGE_UNITS[i].1))
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+
+    fn fmt_iso(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut date = String::new();
+        let mut time = String::new();
+        for (i, value) in self.largest_units() {
+            if value > 0 {
+                let part = if i < 3 { &mut date } else { &mut time };
+                part.push_str(&format!("{value}{}", AGE_UNITS[i].2));
+            }
+        }
+
+        match (date.is_empty(), time.is_empty()) {
+            (true, true) => write!(f, "PT0S"),
+            (_, true) => write!(f, "P{date}"),
+            _ => write!(f, "P{date}T{time}"),
+        }
+    }
+}
+
+impl Display for FmtAge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_as(AGE_FORMAT.get().copied().unwrap_or_default(), f)
+    }
+}
 
+impl Display for FmtDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Date { year, month, day } = Date::from_time(self.0);
+        write!(f, "{year:04}-{month:02}-{day:02}")
     }
 }
 
@@ -237,13 +522,168 @@ impl<const ADD: usize, T: Formattable> Display for FmtSuffix<ADD, T> {
 
 impl Display for FmtWithEllipsis {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let FmtWithEllipsis(s, width, trunc) = self;
-        let s = if *trunc && s.len() > *width {
-            format!("{}...", &s[..width.saturating_sub(3)])
-        } else {
-            s.to_owned()
+        let FmtWithEllipsis(s, width, trunc, truncation) = self;
+        let len = s.chars().count();
+        if !*trunc || len <= *width {
+            return write!(f, "{s}");
+        }
+
+        // widths are counted in chars, so multi-byte characters are never split
+        let dots = &"..."[..cmp::min(*width, 3)];
+        let keep = width - dots.len();
+        let head = |n: usize| s.chars().take(n).collect::<String>();
+        let tail = |n: usize| s.chars().skip(len - n).collect::<String>();
+        match truncation {
+            Truncation::Head => write!(f, "{dots}{}", tail(keep)),
+            Truncation::Middle => write!(f, "{}{dots}{}", head(keep / 2), tail(keep - keep / 2)),
+            Truncation::Tail => write!(f, "{}{dots}", head(keep)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const FORMATS: [AgeFormat; 3] = [AgeFormat::Relative, AgeFormat::Compact, AgeFormat::Iso];
+
+    struct AgeAs(FmtAge, AgeFormat);
+
+    impl Display for AgeAs {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            self.0.fmt_as(self.1, f)
+        }
+    }
+
+    fn age(secs: u64, format: AgeFormat) -> String {
+        AgeAs(FmtAge::new(Duration::from_secs(secs)), format).to_string()
+    }
+
+    /// Number of seconds a formatted age stands for
+    fn seconds(s: &str, format: AgeFormat) -> u64 {
+        let unit_secs = |unit: &str| match unit.trim_end_matches('s') {
+            "y" | "Y" | "year" => SECS_PER_YEAR,
+            "mo" | "month" => SECS_PER_MONTH,
+            "week" => 7 * SECS_PER_DAY,
+            "d" | "D" | "day" => SECS_PER_DAY,
+            "h" | "H" | "hour" => SECS_PER_HOUR,
+            "m" | "min" => SECS_PER_MINUTE,
+            "" | "S" | "sec" => 1,
+            _ => panic!("unknown unit in {s:?}"),
+        };
+        let value = |part: &str| {
+            let split = part.find(|c: char| !c.is_ascii_digit()).unwrap_or(part.len());
+            let (value, unit) = part.split_at(split);
+            value.parse::<u64>().unwrap() * unit_secs(unit.trim())
         };
 
-        write!(f, "{s}")
+        match format {
+            AgeFormat::Relative => value(s),
+            AgeFormat::Compact => s.split(' ').map(value).sum(),
+            AgeFormat::Iso => {
+                let (date, time) = s.strip_prefix('P').unwrap().split_once('T').unwrap_or((&s[1..], ""));
+                let parts = |s: &str| s.split_inclusive(char::is_alphabetic).map(str::to_owned).collect::<Vec<_>>();
+                let date: u64 = parts(date).iter().map(|p| value(&p.replace('M', "mo"))).sum();
+                let time: u64 = parts(time).iter().map(|p| value(&p.replace('M', "m"))).sum();
+                date + time
+            },
+        }
+    }
+
+    #[test]
+    fn relative_years() {
+        assert_eq!(age(SECS_PER_YEAR - 1, AgeFormat::Relative), "364 days");
+        assert_eq!(age(SECS_PER_YEAR, AgeFormat::Relative), "52 weeks");
+        assert_eq!(age(2 * SECS_PER_YEAR, AgeFormat::Relative), "104 weeks");
+        assert_eq!(age(3 * SECS_PER_YEAR - 1, AgeFormat::Relative), "156 weeks");
+        assert_eq!(age(3 * SECS_PER_YEAR, AgeFormat::Relative), "3 years");
+    }
+
+    #[test]
+    fn multiple_units() {
+        let secs = SECS_PER_YEAR + 3 * SECS_PER_MONTH + 2 * SECS_PER_DAY;
+        assert_eq!(age(secs, AgeFormat::Compact), "1y 3mo");
+        assert_eq!(age(secs, AgeFormat::Iso), "P1Y3M");
+        assert_eq!(age(SECS_PER_DAY + 59, AgeFormat::Iso), "P1D");
+        assert_eq!(age(SECS_PER_DAY + SECS_PER_HOUR, AgeFormat::Iso), "P1DT1H");
+        assert_eq!(age(61, AgeFormat::Iso), "PT1M1S");
+        assert_eq!(age(0, AgeFormat::Compact), "0s");
+        assert_eq!(age(0, AgeFormat::Iso), "PT0S");
+    }
+
+    fn percentage(amount: u64, total: u64) -> String {
+        FmtPercentage::new(amount, total).to_string().replace(NumberFormat::get().decimal, ".")
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(percentage(0, 0), "n/a");
+        assert_eq!(percentage(5, 0), "n/a");
+        assert_eq!(percentage(0, 1000), "0.0%");
+        assert_eq!(percentage(1, 1000), "0.1%");
+        assert_eq!(percentage(99, 1000), "9.9%");
+        assert_eq!(percentage(996, 10000), "10%");
+        assert_eq!(percentage(100, 1000), "10%");
+        assert_eq!(percentage(1000, 1000), "100%");
+        assert_eq!(percentage(1500, 1000), "100%");
+    }
+
+    #[test]
+    fn ellipsis_on_multi_byte_chars() {
+        let cut = |s: &str, width, truncation| FmtWithEllipsis(s.to_owned(), width, true, truncation).to_string();
+        assert_eq!(cut("/home/jürgen/.nix-profile", 12, Truncation::Head), "...x-profile");
+        assert_eq!(cut("/home/jürgen/.nix-profile", 12, Truncation::Tail), "/home/jür...");
+        assert_eq!(cut("/home/🦀🦀/profile", 12, Truncation::Head), "...🦀/profile");
+        assert_eq!(cut("/home/🦀🦀/profile", 12, Truncation::Middle), "/hom...ofile");
+        assert_eq!(cut("/home/🦀🦀/profile", 2, Truncation::Middle), "..");
+
+        for s in ["/home/jürgen/.local/state/nix/profiles/home-manager", "/home/🦀/🦀🦀/profile-🦀-link", "äöü"] {
+            let len = s.chars().count();
+            for width in 0..=len + 1 {
+                for truncation in [Truncation::Head, Truncation::Middle, Truncation::Tail] {
+                    let formatted = cut(s, width, truncation);
+                    assert!(formatted.chars().count() <= width, "{formatted:?} wider than {width}");
+                    if width >= len {
+                        assert_eq!(formatted, s);
+                    }
+                }
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn ages_are_monotonic(a in 0..100 * SECS_PER_YEAR, b in 0..100 * SECS_PER_YEAR) {
+            let (older, newer) = (cmp::max(a, b), cmp::min(a, b));
+            for format in FORMATS {
+                let (older, newer) = (age(older, format), age(newer, format));
+                prop_assert!(seconds(&newer, format) <= seconds(&older, format), "{newer} > {older}");
+            }
+        }
+
+        #[test]
+        fn ages_round_down(secs in 0..100 * SECS_PER_YEAR) {
+            for format in FORMATS {
+                let formatted = age(secs, format);
+                prop_assert!(seconds(&formatted, format) <= secs, "{formatted} for {secs}s");
+            }
+        }
+
+        #[test]
+        fn percentages_fit_width((amount, total) in (0..=u64::MAX).prop_flat_map(|t| (0..=t, Just(t)))) {
+            let formatted = percentage(amount, total);
+            prop_assert!(formatted.chars().count() <= FmtPercentage::MAX_WIDTH, "{formatted} too wide");
+        }
+
+        #[test]
+        fn ages_fit_width(secs in 0..100 * SECS_PER_YEAR) {
+            for format in FORMATS {
+                let formatted = age(secs, format);
+                prop_assert!(formatted.chars().count() <= FmtAge::MAX_WIDTH, "{formatted} too wide");
+            }
+        }
     }
 }
@@ -0,0 +1,112 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+
+pub fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Match a string against a shell-style wildcard pattern (`*`, `?` and `[...]`)
+pub fn matches(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let s: Vec<_> = s.chars().collect();
+    matches_helper(&pattern, &s)
+}
+
+fn matches_helper(pattern: &[char], s: &[char]) -> bool {
+    match pattern.first() {
+        None => s.is_empty(),
+        Some('*') => (0..=s.len()).any(|i| matches_helper(&pattern[1..], &s[i..])),
+        Some('?') => !s.is_empty() && matches_helper(&pattern[1..], &s[1..]),
+        Some('[') => match pattern.iter().position(|c| *c == ']') {
+            Some(end) if end > 1 => {
+                let (negated, set) = match pattern[1] {
+                    '!' | '^' => (true, &pattern[2..end]),
+                    _ => (false, &pattern[1..end]),
+                };
+                match s.first() {
+                    Some(c) => class_contains(set, *c) != negated && matches_helper(&pattern[end + 1..], &s[1..]),
+                    None => false,
+                }
+            },
+            _ => s.first() == Some(&'[') && matches_helper(&pattern[1..], &s[1..]),
+        },
+        Some(p) => s.first() == Some(p) && matches_helper(&pattern[1..], &s[1..]),
+    }
+}
+
+fn class_contains(set: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if set[i] <= c && c <= set[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Expand a path pattern to all existing paths matching it
+pub fn expand(pattern: &str) -> Vec<PathBuf> {
+    let mut candidates = vec!(PathBuf::new());
+
+    for component in Path::new(pattern).components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy(),
+            other => {
+                candidates.iter_mut().for_each(|c| c.push(other));
+                continue;
+            },
+        };
+
+        if !is_pattern(&name) {
+            candidates.iter_mut().for_each(|c| c.push(name.as_ref()));
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for candidate in &candidates {
+            let dir = if candidate.as_os_str().is_empty() { Path::new(".") } else { candidate.as_path() };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let entry_name = entry.file_name().to_string_lossy().to_string();
+                let hidden = entry_name.starts_with('.') && !name.starts_with('.');
+                if !hidden && matches(&name, &entry_name) {
+                    next.push(candidate.join(entry_name));
+                }
+            }
+        }
+        candidates = next;
+    }
+
+    candidates.retain(|c| c.symlink_metadata().is_ok());
+    candidates.sort();
+    candidates
+}
+
+/// Expand all arguments containing wildcards, leaving other arguments untouched
+pub fn expand_args(args: &[String]) -> Result<Vec<String>, String> {
+    let mut expanded = Vec::with_capacity(args.len());
+    for arg in args {
+        if is_pattern(arg) {
+            let matches = expand(arg);
+            if matches.is_empty() {
+                return Err(format!("No paths match pattern '{arg}'"));
+            }
+            expanded.extend(matches.into_iter().map(|p| p.to_string_lossy().to_string()));
+        } else {
+            expanded.push(arg.clone());
+        }
+    }
+    Ok(expanded)
+}
@@ -0,0 +1,73 @@
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::time::Duration;
+
+use crate::config::{ConfigPreset, HookFailure};
+use crate::nix::profiles::Profile;
+use crate::utils::interaction::warn;
+use crate::utils::subprocess;
+
+
+#[derive(Clone, Copy)]
+pub enum Hook {
+    PreCleanout,
+    PostCleanout,
+}
+
+impl Hook {
+    fn name(&self) -> &'static str {
+        match self {
+            Hook::PreCleanout => "pre-cleanout",
+            Hook::PostCleanout => "post-cleanout",
+        }
+    }
+
+    fn command<'a>(&self, config: &'a ConfigPreset) -> Option<&'a str> {
+        match self {
+            Hook::PreCleanout => config.pre_cleanout.as_deref(),
+            Hook::PostCleanout => config.post_cleanout.as_deref(),
+        }
+    }
+}
+
+
+/// Run a hook for the marked generations of `profile`, applying the configured failure policy
+///
+/// Only returns an error if the hook failed and the policy is to abort.
+pub fn run(hook: Hook, config: &ConfigPreset, profile: &Profile) -> Result<(), String> {
+    let command = match hook.command(config) {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    let generations: Vec<_> = profile.generations().iter()
+        .filter(|g| g.marked())
+        .map(|g| g.number().to_string())
+        .collect();
+
+    let result = execute(command, config.hook_timeout, &[
+        ("NIX_SWEEP_HOOK", hook.name()),
+        ("NIX_SWEEP_PROFILE", &profile.path().to_string_lossy()),
+        ("NIX_SWEEP_GENERATIONS", &generations.join(" ")),
+    ]).map_err(|e| format!("Hook {} failed: {}", hook.name(), e));
+
+    match (result, config.hook_failure.unwrap_or(HookFailure::Abort)) {
+        (Ok(()), _) => Ok(()),
+        (Err(e), HookFailure::Abort) => Err(e),
+        (Err(e), HookFailure::Warn) => { warn(&e); Ok(()) },
+        (Err(_), HookFailure::Ignore) => Ok(()),
+    }
+}
+
+fn execute(command: &str, timeout: Option<Duration>, env: &[(&str, &str)]) -> Result<(), String> {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().copied())
+        .stdin(process::Stdio::null())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    subprocess::wait(&mut child, timeout)
+}
@@ -1,9 +1,17 @@
 use std::fmt::Display;
 use std::io::Write;
 use std::process;
+use std::sync::atomic::{AtomicI8, Ordering};
 
 use colored::Colorize;
 
+
+/// Exit code used when a monitoring threshold (e.g. `--warn-older`) is exceeded
+pub const THRESHOLD_EXIT_CODE: i32 = 2;
+
+static VERBOSITY: AtomicI8 = AtomicI8::new(0);
+
+
 pub fn resolve<T, E: Display>(result: Result<T, E>) -> T {
     match result {
         Ok(t) => t,
@@ -14,6 +22,12 @@ pub fn resolve<T, E: Display>(result: Result<T, E>) -> T {
     }
 }
 
+/// Report an exceeded threshold and exit with [THRESHOLD_EXIT_CODE]
+pub fn exit_threshold_exceeded(msg: &str) -> ! {
+    eprintln!("{} {}", "Threshold exceeded:".red(), msg);
+    process::exit(THRESHOLD_EXIT_CODE)
+}
+
 pub fn warn(warning: &str) {
     eprintln!("{} {}", "Warning:".yellow(), warning);
 }
@@ -41,6 +55,18 @@ pub fn ask(question: &str, default: bool) -> bool {
     }
 }
 
+/// Ask for a line of input, returning an empty string at the end of input
+pub fn prompt(question: &str) -> String {
+    print!("{question} ");
+    let _ = std::io::stdout().flush();
+
+    let mut input = String::new();
+    match std::io::stdin().read_line(&mut input) {
+        Ok(_) => input.trim().to_owned(),
+        Err(_) => String::new(),
+    }
+}
+
 pub fn ack(question: &str) {
     loop {
         print!("{question} [enter] ");
@@ -55,10 +81,37 @@ pub fn ack(question: &str) {
     }
 }
 
+/// Set the verbosity level (-1: quiet, 0: default, 1: verbose, 2: debug)
+pub fn set_verbosity(level: i8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> i8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
 pub fn announce(s: &str) {
-    println!("\n{}", format!("=> {s}").green());
+    if verbosity() >= 0 {
+        println!("\n{}", format!("=> {s}").green());
+    }
 }
 
 pub fn conclusion(s: &str) {
-    println!("\n-> {}", s);
+    if verbosity() >= 0 {
+        println!("\n-> {}", s);
+    }
+}
+
+/// Print a status message to stderr, unless --quiet is given
+pub fn info(s: &str) {
+    if verbosity() >= 0 {
+        eprintln!("{s}");
+    }
+}
+
+/// Print debugging information to stderr (-vv)
+pub fn debug(s: &str) {
+    if verbosity() >= 2 {
+        eprintln!("{}", format!("debug: {s}").bright_black());
+    }
 }
@@ -6,8 +6,9 @@ use crate::utils::files;
 pub const JOURNAL_PATH: &str = "/var/log/journal";
 
 
+/// Whether there is a systemd journal, which only exists on Linux
 pub fn journal_exists() -> bool {
-    fs::exists(Path::new(JOURNAL_PATH))
+    cfg!(target_os = "linux") && fs::exists(Path::new(JOURNAL_PATH))
         .unwrap_or(false)
 }
 
@@ -1,7 +1,21 @@
 pub mod caching;
+pub mod calendar;
+pub mod duration;
 pub mod files;
 pub mod fmt;
+pub mod glob;
+pub mod hooks;
 pub mod interaction;
 pub mod journal;
 pub mod ordered_channel;
+pub mod policy;
+pub mod priority;
+pub mod privileges;
+pub mod progress;
+pub mod signals;
+pub mod snapshots;
+pub mod subprocess;
+pub mod template;
 pub mod terminal;
+pub mod timings;
+pub mod users;
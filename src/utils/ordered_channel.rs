@@ -55,3 +55,9 @@ impl<T> Iterator for OrderedChannelIterator<'_, T> {
         Some(self.channel.get(self.iter_counter - 1))
     }
 }
+
+impl<T> Default for OrderedChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::nix::profiles::Profile;
+use crate::utils::subprocess;
+
+
+/// Version of the JSON document passed to policy scripts
+///
+/// Incremented whenever fields are removed or change their meaning.
+pub const SCHEMA_VERSION: u32 = 1;
+
+
+#[derive(Serialize)]
+struct PolicyInput {
+    version: u32,
+    profile: String,
+    generations: Vec<PolicyGeneration>,
+}
+
+#[derive(Serialize)]
+struct PolicyGeneration {
+    index: usize,
+    number: usize,
+    path: String,
+    store_path: Option<String>,
+    age: u64,
+    active: bool,
+    marked: bool,
+}
+
+
+/// Ask an external policy script which generations of `profile` should be removed
+///
+/// The script receives a JSON document on stdin:
+/// ```json
+/// {"version":1,"profile":"/nix/var/nix/profiles/system","generations":[
+///   {"index":0,"number":41,"path":"/nix/var/nix/profiles/system-41-link",
+///    "store_path":"/nix/store/...-nixos-system","age":1209600,"active":false,"marked":true}]}
+/// ```
+/// `age` is given in seconds and `marked` reflects the other clean out criteria of the preset.
+/// The script has to print a JSON array with the indices of the generations to remove (e.g. `[0, 2]`),
+/// which replaces the markers set by the other criteria.
+///
+/// The script runs with an empty environment (except for `PATH`), in `/` and in its own process group,
+/// which is killed if it exceeds `timeout`. Apart from that it is not confined in any way and runs with
+/// the same privileges as nix-sweep itself, which usually means as root for system profiles.
+pub fn evaluate(script: &Path, timeout: Option<Duration>, profile: &Profile) -> Result<Vec<usize>, String> {
+    let input = to_json(profile)?;
+    let output = execute(script, timeout, input)
+        .map_err(|e| format!("Policy script {} failed: {}", script.to_string_lossy(), e))?;
+    let indices = parse_indices(&output)
+        .map_err(|e| format!("Invalid output of policy script {}: {}", script.to_string_lossy(), e))?;
+
+    if let Some(index) = indices.iter().find(|i| **i >= profile.generations().len()) {
+        return Err(format!("Policy script {} returned index {} for a profile with {} generations",
+            script.to_string_lossy(), index, profile.generations().len()));
+    }
+
+    Ok(indices)
+}
+
+fn to_json(profile: &Profile) -> Result<String, String> {
+    let generations = profile.generations().iter()
+        .enumerate()
+        .map(|(i, g)| PolicyGeneration {
+            index: i,
+            number: g.number(),
+            path: g.path().to_string_lossy().to_string(),
+            store_path: g.store_path().ok().map(|sp| sp.path().to_string_lossy().to_string()),
+            age: g.age().as_secs(),
+            active: profile.is_active_generation(g),
+            marked: g.marked(),
+        })
+        .collect();
+
+    let input = PolicyInput {
+        version: SCHEMA_VERSION,
+        profile: profile.path().to_string_lossy().to_string(),
+        generations,
+    };
+    serde_json::to_string(&input).map_err(|e| e.to_string())
+}
+
+fn parse_indices(output: &str) -> Result<Vec<usize>, String> {
+    serde_json::from_str(output.trim())
+        .map_err(|e| format!("expected a JSON array of indices ({e})"))
+}
+
+fn execute(script: &Path, timeout: Option<Duration>, input: String) -> Result<String, String> {
+    let mut command = process::Command::new(script);
+    command.env_clear()
+        .current_dir("/")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .process_group(0);
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+
+    let mut child = command.spawn()
+        .map_err(|e| e.to_string())?;
+
+    // feed stdin and drain stdout concurrently, so neither pipe can block the script
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        // the script may legitimately exit without reading its input
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).map(|_| output)
+    });
+
+    subprocess::wait(&mut child, timeout)?;
+
+    // processes the script left running in the background would keep the pipes open
+    subprocess::kill_group(&child)?;
+    let _ = writer.join();
+    reader.join()
+        .map_err(|_| "Unable to read output".to_owned())?
+        .map_err(|e| format!("Unable to read output: {e}"))
+}
@@ -0,0 +1,58 @@
+use rustix::process;
+
+use crate::config::IoPriority;
+
+
+/// CPU and IO priority to run store walks and subprocesses with
+///
+/// On Linux both priorities are attributes of a single thread, so they have to be applied to every
+/// worker thread individually.
+/// Subprocesses inherit the priorities of the thread spawning them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Priority {
+    pub nice: Option<i32>,
+    pub ionice: Option<IoPriority>,
+}
+
+impl Priority {
+    /// Lower the priority of the calling thread
+    pub fn apply(&self) -> Result<(), String> {
+        if let Some(nice) = self.nice {
+            process::setpriority_process(None, nice)
+                .map_err(|e| format!("Unable to set niceness: {e}"))?;
+        }
+
+        if let Some(ionice) = self.ionice {
+            set_io_priority(ionice)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_io_priority(ionice: IoPriority) -> Result<(), String> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_BE_LOWEST: libc::c_int = 7;
+
+    let ioprio = match ionice {
+        IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        IoPriority::Low => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | IOPRIO_BE_LOWEST,
+    };
+
+    // SAFETY: ioprio_set only takes integer arguments and does not touch our memory
+    let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+    if ret != 0 {
+        return Err(format!("Unable to set IO priority: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// IO priorities of threads are specific to Linux
+#[cfg(not(target_os = "linux"))]
+fn set_io_priority(_ionice: IoPriority) -> Result<(), String> {
+    Err("Setting the IO priority is only supported on Linux".to_owned())
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+use std::process;
+use std::sync::OnceLock;
+
+use rustix::fs::{access, Access};
+
+use crate::nix::sandbox;
+use crate::nix::store::Store;
+
+
+const DAEMON_SOCKET: &str = "/nix/var/nix/daemon-socket/socket";
+
+static ESCALATION: OnceLock<Escalation> = OnceLock::new();
+
+
+/// Program used to run privileged operations as root
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Escalation {
+    Sudo,
+    Doas,
+}
+
+impl Escalation {
+    fn program(&self) -> &'static str {
+        match self {
+            Escalation::Sudo => "sudo",
+            Escalation::Doas => "doas",
+        }
+    }
+}
+
+
+/// Run privileged operations through `escalation` instead of failing
+pub fn set_escalation(escalation: Escalation) {
+    let _ = ESCALATION.set(escalation);
+}
+
+fn is_root() -> bool {
+    rustix::process::geteuid().is_root()
+}
+
+/// Whether entries in `dir` can be created and removed by this process
+pub fn can_write(dir: &Path) -> bool {
+    is_root() || access(dir, Access::WRITE_OK).is_ok()
+}
+
+/// Whether modifying the store requires root
+///
+/// Without a writable store, operations are still possible through the Nix daemon.
+pub fn store_needs_root() -> bool {
+    !daemon_available() && !can_write(Store::dir())
+}
+
+/// Whether the store can be modified through the Nix daemon
+///
+/// The daemon is never used when operating on a custom store root.
+pub fn daemon_available() -> bool {
+    sandbox::root().is_none() && Path::new(DAEMON_SOCKET).exists()
+}
+
+/// Fail before doing anything, if some of the planned operations require root and cannot be escalated
+pub fn check(privileged: &[String]) -> Result<(), String> {
+    if privileged.is_empty() || ESCALATION.get().is_some() {
+        return Ok(());
+    }
+
+    Err(format!("The following operations require root privileges:\n  {}\n\
+        Run nix-sweep as root or pass --sudo to run them via sudo (or --sudo doas for doas)",
+        privileged.join("\n  ")))
+}
+
+/// Command running `program`, escalated to root if `privileged` and requested by the user
+pub fn command(program: &str, privileged: bool) -> process::Command {
+    match ESCALATION.get() {
+        Some(escalation) if privileged && !is_root() => {
+            let mut command = process::Command::new(escalation.program());
+            command.arg(program);
+            command
+        },
+        _ => process::Command::new(program),
+    }
+}
+
+/// Command running `program` as `user` via sudo, to hand work back to the user who invoked nix-sweep
+pub fn command_as_user(user: &str, program: &Path) -> process::Command {
+    let mut command = process::Command::new("sudo");
+    command.args(["--non-interactive", "--set-home", "--user", user, "--"])
+        .arg(program);
+    command
+}
+
+pub fn can_escalate() -> bool {
+    ESCALATION.get().is_some()
+}
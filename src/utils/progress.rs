@@ -0,0 +1,71 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+
+/// Machine-readable progress event
+pub enum Event<'a> {
+    PhaseStart(&'a str),
+    PhaseEnd(&'a str),
+    Item(&'a str, &'a str),
+    BytesFreed(&'a str, u64),
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    event: &'static str,
+    phase: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+    time: u64,
+}
+
+/// Enable progress events on stderr or the given file descriptor
+pub fn init(target: &str) -> Result<(), String> {
+    let sink: Box<dyn Write + Send> = match target {
+        "stderr" => Box::new(io::stderr()),
+        fd => {
+            let fd: u32 = fd.parse()
+                .map_err(|_| format!("Invalid progress target '{fd}' (expected 'stderr' or a file descriptor)"))?;
+            let file = OpenOptions::new()
+                .write(true)
+                .open(format!("/dev/fd/{fd}"))
+                .map_err(|e| format!("Unable to open file descriptor {fd}: {e}"))?;
+            Box::new(file)
+        },
+    };
+
+    *SINK.lock().unwrap() = Some(sink);
+    Ok(())
+}
+
+pub fn emit(event: Event) {
+    let mut sink = SINK.lock().unwrap();
+    let sink = match sink.as_mut() {
+        Some(sink) => sink,
+        None => return,
+    };
+
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let line = match event {
+        Event::PhaseStart(phase) => Line { event: "phase-start", phase, item: None, bytes: None, time },
+        Event::PhaseEnd(phase) => Line { event: "phase-end", phase, item: None, bytes: None, time },
+        Event::Item(phase, item) => Line { event: "item", phase, item: Some(item), bytes: None, time },
+        Event::BytesFreed(phase, bytes) => Line { event: "bytes-freed", phase, item: None, bytes: Some(bytes), time },
+    };
+
+    if let Ok(line) = serde_json::to_string(&line) {
+        let _ = writeln!(sink, "{line}");
+    }
+    let _ = sink.flush();
+}
@@ -0,0 +1,94 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use super::interaction::warn;
+use super::terminal;
+
+
+/// Resets colors and ends the current (possibly incomplete) line
+const TERMINAL_RESET: &[u8] = b"\x1b[0m\n";
+const FINISHING_MESSAGE: &[u8] = b"\nFinishing the current deletion (interrupt again to exit right away)\n";
+
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+static RESET_TERMINAL: AtomicBool = AtomicBool::new(false);
+
+
+/// Handle SIGINT and SIGTERM
+///
+/// Outside of [deferred] sections the program exits right away, as there is nothing to finish.
+/// Within them, the first signal is only recorded, so the current deletion can be completed and a
+/// partial summary printed before exiting with [exit_code].
+///
+/// Child processes are in the same process group and receive a SIGINT from the terminal as well, so
+/// a deletion delegated to a child may fail once [interrupted] is set. Such failures have to be
+/// treated as deletions that were not done rather than as errors.
+pub fn install() -> Result<(), String> {
+    RESET_TERMINAL.store(terminal::is_fancy(io::stderr()), Ordering::Relaxed);
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handle as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        let ret = unsafe {
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if ret != 0 {
+            return Err(format!("Unable to install signal handler: {}", io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handle(signal: libc::c_int) {
+    // only async-signal-safe functions may be used here
+    let previous = SIGNAL.swap(signal, Ordering::SeqCst);
+    if DEFERRED.load(Ordering::SeqCst) && previous == 0 {
+        unsafe {
+            libc::write(libc::STDERR_FILENO, FINISHING_MESSAGE.as_ptr().cast(), FINISHING_MESSAGE.len());
+        }
+        return;
+    }
+
+    unsafe {
+        if RESET_TERMINAL.load(Ordering::Relaxed) {
+            libc::write(libc::STDERR_FILENO, TERMINAL_RESET.as_ptr().cast(), TERMINAL_RESET.len());
+        }
+        libc::_exit(128 + signal);
+    }
+}
+
+/// Run `f`, postponing the cancellation of the program until it has returned
+///
+/// `f` is expected to check [interrupted] and stop early.
+pub fn deferred<T>(f: impl FnOnce() -> T) -> T {
+    let previous = DEFERRED.swap(true, Ordering::SeqCst);
+    let result = f();
+    DEFERRED.store(previous, Ordering::SeqCst);
+    result
+}
+
+/// Whether SIGINT or SIGTERM has been received during a [deferred] section
+pub fn interrupted() -> bool {
+    SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+/// Exit code for a cancelled run (128 + signal number, as for a shell)
+pub fn exit_code() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(128 + signal),
+    }
+}
+
+/// Exit with [exit_code] if the run has been cancelled, reporting `result` if it is an error
+pub fn exit_if_interrupted<T>(result: &Result<T, String>) {
+    if let Some(code) = exit_code() {
+        if let Err(e) = result {
+            warn(e);
+        }
+        warn("Cancelled");
+        std::process::exit(code);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::SnapshotKind;
+use crate::nix::store::Store;
+use crate::utils::files;
+
+
+const SNAPSHOT_PREFIX: &str = "nix-sweep-";
+const BTRFS_SNAPSHOT_DIR: &str = ".nix-sweep-snapshots";
+
+
+/// Snapshot the file system containing the Nix store (or `target`), returning the name of the snapshot
+///
+/// If `keep` is given, older snapshots created by nix-sweep are deleted afterwards, so that only
+/// the `keep` most recent ones remain.
+pub fn create(kind: SnapshotKind, target: Option<&str>, keep: Option<usize>) -> Result<String, String> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?
+        .as_secs();
+    let name = format!("{SNAPSHOT_PREFIX}{secs}");
+
+    match kind {
+        SnapshotKind::Btrfs => {
+            let subvolume = match target {
+                Some(target) => PathBuf::from(target),
+                None => files::btrfs_subvolume_of_path(Store::dir())?,
+            };
+            let dir = subvolume.join(BTRFS_SNAPSHOT_DIR);
+            fs::create_dir_all(&dir)
+                .map_err(|e| format!("Unable to create {}: {}", dir.to_string_lossy(), e))?;
+            let snapshot = dir.join(&name);
+            run("btrfs", &["subvolume", "snapshot", "-r", &subvolume.to_string_lossy(), &snapshot.to_string_lossy()])?;
+
+            if let Some(keep) = keep {
+                let existing = fs::read_dir(&dir)
+                    .map_err(|e| e.to_string())?
+                    .flatten()
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect();
+                for old in outdated(existing, keep) {
+                    run("btrfs", &["subvolume", "delete", &dir.join(old).to_string_lossy()])?;
+                }
+            }
+
+            Ok(snapshot.to_string_lossy().to_string())
+        },
+        SnapshotKind::Zfs => {
+            let dataset = match target {
+                Some(target) => target.to_owned(),
+                None => store_mount("zfs")?.device,
+            };
+            let snapshot = format!("{dataset}@{name}");
+            run("zfs", &["snapshot", &snapshot])?;
+
+            if let Some(keep) = keep {
+                let existing = run("zfs", &["list", "-H", "-o", "name", "-t", "snapshot", &dataset])?
+                    .lines()
+                    .filter_map(|l| l.split_once('@'))
+                    .filter(|(ds, _)| *ds == dataset)
+                    .map(|(_, snap)| snap.to_owned())
+                    .collect();
+                for old in outdated(existing, keep) {
+                    run("zfs", &["destroy", &format!("{dataset}@{old}")])?;
+                }
+            }
+
+            Ok(snapshot)
+        },
+    }
+}
+
+/// Mount containing the Nix store, making sure it has the expected file system
+fn store_mount(fs_type: &str) -> Result<files::MountInfo, String> {
+    let mount = files::mount_of_path(Store::dir())?;
+    if mount.fs_type != fs_type {
+        return Err(format!("The Nix store is on {} rather than {} - please specify a snapshot target",
+            mount.fs_type, fs_type));
+    }
+    Ok(mount)
+}
+
+/// Snapshots created by nix-sweep that exceed the number of snapshots to keep, oldest first
+fn outdated(names: Vec<String>, keep: usize) -> Vec<String> {
+    let mut snapshots: Vec<(u64, String)> = names.into_iter()
+        .filter_map(|n| Some((n.strip_prefix(SNAPSHOT_PREFIX)?.parse().ok()?, n)))
+        .collect();
+    snapshots.sort();
+    let excess = snapshots.len().saturating_sub(keep);
+    snapshots.into_iter()
+        .take(excess)
+        .map(|(_, n)| n)
+        .collect()
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = process::Command::new(program)
+        .args(args)
+        .stdin(process::Stdio::null())
+        .stderr(process::Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Unable to run `{program}`: {e}"))?;
+
+    if !output.status.success() {
+        match output.status.code() {
+            Some(code) => return Err(format!("`{program} {}` failed (exit code {code})", args.join(" "))),
+            None => return Err(format!("`{program} {}` failed", args.join(" "))),
+        }
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,47 @@
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/// Wait for `child` to exit successfully, killing its process group if it exceeds `timeout`
+///
+/// The child has to be spawned in a process group of its own (see
+/// [process_group](std::os::unix::process::CommandExt::process_group)), so processes it started
+/// are killed along with it instead of keeping its pipes open.
+pub fn wait(child: &mut process::Child, timeout: Option<Duration>) -> Result<(), String> {
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+
+        if timeout.is_some_and(|t| start.elapsed() >= t) {
+            let elapsed = start.elapsed().as_secs_f64();
+            kill_group(child)
+                .map_err(|e| format!("timed out after {elapsed:.1}s and could not be killed ({e})"))?;
+            let _ = child.wait();
+            return Err(format!("timed out after {elapsed:.1}s"));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    match status.code() {
+        _ if status.success() => Ok(()),
+        Some(code) => Err(format!("exit code {code}")),
+        None => Err("terminated by signal".to_owned()),
+    }
+}
+
+/// Kill the process group of `child`, which has to be spawned in a group of its own
+///
+/// Processes left behind in the group are killed even after `child` itself has exited.
+pub fn kill_group(child: &process::Child) -> Result<(), String> {
+    match rustix::process::kill_process_group(rustix::process::Pid::from_child(child), rustix::process::Signal::KILL) {
+        Ok(()) | Err(rustix::io::Errno::SRCH) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
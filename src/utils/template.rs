@@ -0,0 +1,92 @@
+/// Output format given by the user, e.g. `{number}\t{path}`
+///
+/// Fields are written in braces, literal braces as `{{` and `}}`. The escapes `\t`, `\n` and `\\`
+/// are resolved, so tabs and newlines can be passed without relying on the quoting of the shell.
+#[derive(Clone, Debug)]
+pub struct Template(Vec<Part>);
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+
+impl Template {
+    /// Parse a template, failing on unbalanced braces or unknown escapes
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => field.push(c),
+                            None => return Err(format!("Unclosed field '{{{field}' in format")),
+                        }
+                    }
+                    if field.is_empty() {
+                        return Err("Empty field '{}' in format".to_owned());
+                    }
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    parts.push(Part::Field(field));
+                },
+                '}' => return Err("Unmatched '}' in format (use '}}' for a literal brace)".to_owned()),
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('\\') => literal.push('\\'),
+                    Some(c) => return Err(format!("Unknown escape '\\{c}' in format")),
+                    None => literal.push('\\'),
+                },
+                c => literal.push(c),
+            }
+        }
+        parts.push(Part::Literal(literal));
+        parts.retain(|p| !matches!(p, Part::Literal(l) if l.is_empty()));
+
+        Ok(Template(parts))
+    }
+
+    /// Make sure all fields of the template are among `fields`
+    pub fn check_fields(&self, fields: &[&str]) -> Result<(), String> {
+        for part in &self.0 {
+            if let Part::Field(field) = part
+                && !fields.contains(&field.as_str()) {
+                return Err(format!("Unknown field '{{{field}}}' in format (available: {})",
+                    fields.iter().map(|f| format!("{{{f}}}")).collect::<Vec<_>>().join(", ")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `field` occurs in the template, to skip calculating values that are not needed
+    pub fn uses(&self, field: &str) -> bool {
+        self.0.iter().any(|p| matches!(p, Part::Field(f) if f == field))
+    }
+
+    /// Fill in the fields with the values returned by `value`
+    pub fn render(&self, mut value: impl FnMut(&str) -> String) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Field(field) => out.push_str(&value(field)),
+            }
+        }
+        out
+    }
+}
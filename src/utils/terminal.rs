@@ -1,7 +1,23 @@
 use std::os::fd;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use rustix::termios;
 
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+
+/// Disable colors and live updating output
+pub fn set_plain() {
+    PLAIN.store(true, Ordering::Relaxed);
+    colored::control::set_override(false);
+}
+
+/// Whether `fd` is a terminal that output with escape sequences (e.g. progress lines) can be written to
+pub fn is_fancy(fd: impl fd::AsFd) -> bool {
+    !PLAIN.load(Ordering::Relaxed) && termios::isatty(fd)
+}
+
 pub fn terminal_width(fd: impl fd::AsFd) -> Result<usize, String> {
     if !termios::isatty(&fd) {
         Err("Unable to get terminal width: Stream is not a tty".to_owned())
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static START: OnceLock<Instant> = OnceLock::new();
+static NANOS: [AtomicU64; PHASES.len()] = [const { AtomicU64::new(0) }; PHASES.len()];
+static CALLS: [AtomicU64; PHASES.len()] = [const { AtomicU64::new(0) }; PHASES.len()];
+static CLOSURE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CLOSURE_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+const PHASES: [Phase; 4] = [Phase::RootDiscovery, Phase::ClosureQueries, Phase::SizeComputation, Phase::Deletion];
+
+
+/// Parts of a run that are timed with `--timings`
+#[derive(Clone, Copy)]
+pub enum Phase {
+    RootDiscovery,
+    ClosureQueries,
+    SizeComputation,
+    Deletion,
+}
+
+impl Phase {
+    fn name(&self) -> &'static str {
+        match self {
+            Phase::RootDiscovery => "Root discovery",
+            Phase::ClosureQueries => "Closure queries",
+            Phase::SizeComputation => "Size computation",
+            Phase::Deletion => "Deletion",
+        }
+    }
+}
+
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+    let _ = START.set(Instant::now());
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Run `f`, accounting the time it takes to `phase`
+pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !enabled() {
+        return f();
+    }
+
+    let start = Instant::now();
+    let result = f();
+    let nanos = start.elapsed().as_nanos() as u64;
+    NANOS[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+    CALLS[phase as usize].fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+pub fn closure_cache_lookup(hit: bool) {
+    match hit {
+        true => CLOSURE_CACHE_HITS.fetch_add(1, Ordering::Relaxed),
+        false => CLOSURE_CACHE_MISSES.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+/// Print the collected timings to stderr, if `--timings` was given
+///
+/// Phases running on multiple threads at once are summed up, so they may exceed the total time.
+pub fn report() {
+    if !enabled() {
+        return;
+    }
+
+    eprintln!("\n{}", "=> Timings".green());
+    for phase in PHASES {
+        let time = Duration::from_nanos(NANOS[phase as usize].load(Ordering::Relaxed));
+        let calls = CALLS[phase as usize].load(Ordering::Relaxed);
+        eprintln!("{:<20} {:>8.2}s ({} calls)", format!("{}:", phase.name()), time.as_secs_f64(), calls);
+    }
+
+    let hits = CLOSURE_CACHE_HITS.load(Ordering::Relaxed);
+    let misses = CLOSURE_CACHE_MISSES.load(Ordering::Relaxed);
+    let rate = match hits + misses {
+        0 => 0,
+        lookups => hits * 100 / lookups,
+    };
+    eprintln!("{:<20} {} hits, {} misses ({}% hit rate)", "Closure cache:", hits, misses, rate);
+
+    if let Some(start) = START.get() {
+        eprintln!("{:<20} {:>8.2}s", "Total:", start.elapsed().as_secs_f64());
+    }
+}
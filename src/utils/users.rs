@@ -0,0 +1,146 @@
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rustix::process;
+
+
+const PASSWD_PATH: &str = "/etc/passwd";
+const PASSWD_BUF_SIZE: usize = 1024;
+const PASSWD_BUF_MAX: usize = 1024 * 1024;
+
+
+#[derive(Debug, Clone)]
+pub struct User {
+    name: String,
+    uid: u32,
+    home: PathBuf,
+}
+
+impl User {
+    /// The user this process is running as (respecting the effective user id)
+    pub fn current() -> Result<Self, String> {
+        let uid = process::geteuid().as_raw();
+        if let Some(user) = Self::from_passwd(|u| u.uid == uid)? {
+            return Ok(user);
+        }
+
+        // user is not listed in the passwd file (e.g. provided by NSS), fall back to the environment
+        let name = env::var("USER")
+            .map_err(|_| String::from("Unable to read $USER"))?;
+        let home = env::var("HOME")
+            .map_err(|_| String::from("Unable to read $HOME"))?;
+        Ok(User { name, uid, home: PathBuf::from(home) })
+    }
+
+    pub fn by_name(name: &str) -> Result<Self, String> {
+        Self::from_passwd(|u| u.name == name)?
+            .ok_or(format!("Unable to find user '{name}'"))
+    }
+
+    /// Look up a user to operate on
+    ///
+    /// Only root is allowed to operate on other users.
+    pub fn select(name: &str) -> Result<Self, String> {
+        let current = Self::current()?;
+        if current.name == name {
+            return Ok(current);
+        } else if !current.is_root() {
+            return Err(format!("Only root may operate on the profiles of other users (requested '{name}')"));
+        }
+
+        Self::by_name(name)
+    }
+
+    /// Whether an account of this name exists, also considering other sources than the passwd file (NSS)
+    ///
+    /// Fails if the user database cannot be queried (e.g. an unreachable LDAP server), which must not
+    /// be mistaken for a removed account.
+    pub fn exists(name: &str) -> Result<bool, String> {
+        let c_name = CString::new(name)
+            .map_err(|_| format!("Invalid user name '{name}'"))?;
+        let mut buf: Vec<libc::c_char> = vec![0; PASSWD_BUF_SIZE];
+        loop {
+            // SAFETY: passwd only consists of integers and pointers, for which zero is valid
+            let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut result = std::ptr::null_mut();
+            // SAFETY: all pointers are valid during the call and buf.len() is the size of buf
+            let ret = unsafe {
+                libc::getpwnam_r(c_name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result)
+            };
+            match ret {
+                0 if !result.is_null() => return Ok(true),
+                // some implementations report a missing entry as an error
+                0 | libc::ENOENT | libc::ESRCH => break,
+                libc::ERANGE if buf.len() < PASSWD_BUF_MAX => buf.resize(buf.len() * 2, 0),
+                errno => return Err(format!("Unable to look up user '{name}': {}", io::Error::from_raw_os_error(errno))),
+            }
+        }
+
+        Ok(Self::from_passwd(|u| u.name == name)?.is_some())
+    }
+
+    /// All users listed in the passwd file
+    pub fn all() -> Vec<Self> {
+        let passwd = match fs::read_to_string(PASSWD_PATH) {
+            Ok(passwd) => passwd,
+            Err(_) => return Vec::new(),
+        };
+
+        passwd.lines()
+            .filter_map(|line| {
+                let fields: Vec<_> = line.split(':').collect();
+                if fields.len() < 7 {
+                    return None;
+                }
+                let uid = fields[2].parse().ok()?;
+                Some(User { name: fields[0].to_owned(), uid, home: PathBuf::from(fields[5]) })
+            })
+            .collect()
+    }
+
+    fn from_passwd(pred: impl Fn(&User) -> bool) -> Result<Option<Self>, String> {
+        Ok(Self::all().into_iter().find(pred))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Directory containing the user's profiles inside their home directory
+    ///
+    /// `$XDG_STATE_HOME` is only respected if the environment actually belongs to this user, as
+    /// it might still point to the invoking user when running via sudo.
+    pub fn state_profiles_dir(&self) -> PathBuf {
+        let env_home = env::var("HOME").ok().map(PathBuf::from);
+        if env_home.as_ref() == Some(&self.home)
+                && let Some(state_home) = env::var("XDG_STATE_HOME").ok().filter(|s| !s.is_empty()) {
+            return PathBuf::from(state_home).join("nix/profiles");
+        }
+
+        self.home.join(".local/state/nix/profiles")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exists() {
+        assert_eq!(User::exists("root"), Ok(true));
+        assert_eq!(User::exists("nix-sweep-no-such-user"), Ok(false));
+        assert!(User::exists("nul\0byte").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use std::fs;
+use std::time::Duration;
+
+use common::{Env, DAY};
+
+const PROFILE: &str = "/nix/var/nix/profiles/per-user/test/profile";
+
+
+#[test]
+fn removes_generations_beyond_keep_max() {
+    let mut env = Env::new();
+    env.add_generations(PROFILE, 5, Duration::ZERO, 7 * DAY);
+
+    let run = env.run(&["cleanout", "-n", "--keep-max", "2", &env.path(PROFILE).to_string_lossy()]);
+    assert!(run.success, "{}", run.output());
+    assert_eq!(env.generations(PROFILE), vec![4, 5]);
+    assert!(run.stdout.contains("3 generations removed"), "{}", run.output());
+}
+
+#[test]
+fn dry_run_removes_nothing() {
+    let mut env = Env::new();
+    env.add_generations(PROFILE, 3, Duration::ZERO, 7 * DAY);
+
+    let run = env.run(&["cleanout", "--dry-run", "--keep-max", "1", &env.path(PROFILE).to_string_lossy()]);
+    assert!(run.success, "{}", run.output());
+    assert_eq!(env.generations(PROFILE), vec![1, 2, 3]);
+    assert!(run.stdout.contains("would remove"), "{}", run.output());
+}
+
+#[test]
+fn keeps_newest_and_listed_generations() {
+    let mut env = Env::new();
+    env.add_generations(PROFILE, 4, 30 * DAY, DAY);
+    fs::write(env.path("/nix/var/nix/profiles/per-user/test/.nix-sweep-keep"), "2\n").unwrap();
+
+    let run = env.run(&["cleanout", "-n", "--remove-older", "1d", &env.path(PROFILE).to_string_lossy()]);
+    assert!(run.success, "{}", run.output());
+    assert_eq!(env.generations(PROFILE), vec![2, 4]);
+}
+
+#[test]
+fn gc_deletes_unreachable_paths() {
+    let mut env = Env::new();
+    let targets = env.add_generations(PROFILE, 3, Duration::ZERO, 7 * DAY);
+    let garbage = env.fixture.add_store_path("garbage", 1024, &[]).unwrap();
+
+    let run = env.run(&["cleanout", "-n", "--keep-max", "1", "--gc", &env.path(PROFILE).to_string_lossy()]);
+    assert!(run.success, "{}", run.output());
+    assert!(!env.fixture.path(&garbage).exists());
+    assert!(!env.fixture.path(&targets[0]).exists());
+    assert!(env.fixture.path(&targets[2]).exists());
+}
+
+#[test]
+fn removal_while_browsing_is_a_real_run() {
+    let mut env = Env::new();
+    env.add_generations(PROFILE, 3, Duration::ZERO, 7 * DAY);
+    let garbage = env.fixture.add_store_path("garbage", 1024, &[]).unwrap();
+
+    // unmark generation 2, which raising keep-max would unmark anyway
+    let run = env.run_with_input(&["cleanout", "-n", "--dry-run", "--browse", "--keep-max", "1", "--gc",
+        &env.path(PROFILE).to_string_lossy()], "t 2\n+\nr\ny\n");
+    assert!(run.success, "{}", run.output());
+    assert_eq!(env.generations(PROFILE), vec![2, 3]);
+    assert!(!env.fixture.path(&garbage).exists(), "{}", run.output());
+}
@@ -0,0 +1,111 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use nix_sweep::nix::fixture::Fixture;
+use tempfile::TempDir;
+
+
+pub const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+
+/// Fake Nix installation with its own state and config directories to run nix-sweep against
+pub struct Env {
+    dir: TempDir,
+    pub fixture: Fixture,
+}
+
+/// Result of a nix-sweep run
+pub struct Run {
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+
+impl Env {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = Fixture::create(&dir.path().join("root")).unwrap();
+        Env { dir, fixture }
+    }
+
+    /// Location of an absolute path of the fixture on the real file system
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.fixture.path(Path::new(path))
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        fs::symlink_metadata(self.path(path)).is_ok()
+    }
+
+    /// Add `count` generations to `profile`, one every `interval` with the newest being `age` old
+    ///
+    /// Returns the store paths of the generations, oldest first.
+    pub fn add_generations(&mut self, profile: &str, count: usize, age: Duration, interval: Duration) -> Vec<PathBuf> {
+        let name = Path::new(profile).file_name().unwrap().to_string_lossy().to_string();
+        (1..=count)
+            .map(|number| {
+                let target = self.fixture.add_store_path(&format!("{name}-{number}"), 1024, &[]).unwrap();
+                let age = age + interval * (count - number) as u32;
+                self.fixture.add_generation(Path::new(profile), number, &target, age).unwrap();
+                target
+            })
+            .collect()
+    }
+
+    /// Numbers of the generations of `profile` that still exist
+    pub fn generations(&self, profile: &str) -> Vec<usize> {
+        let profile = self.path(profile);
+        let prefix = format!("{}-", profile.file_name().unwrap().to_string_lossy());
+        let mut numbers: Vec<usize> = fs::read_dir(profile.parent().unwrap()).unwrap()
+            .flatten()
+            .filter_map(|e| e.file_name().to_str()?
+                .strip_prefix(&prefix)?
+                .strip_suffix("-link")?
+                .parse().ok())
+            .collect();
+        numbers.sort();
+        numbers
+    }
+
+    /// Run nix-sweep with `--store-root` pointing to the fixture
+    pub fn run(&self, args: &[&str]) -> Run {
+        self.run_with_input(args, "")
+    }
+
+    /// Like [Env::run], answering prompts with `input`
+    pub fn run_with_input(&self, args: &[&str], input: &str) -> Run {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_nix-sweep"))
+            .arg("--plain")
+            .arg("--store-root")
+            .arg(self.fixture.root())
+            .args(args)
+            .env("XDG_STATE_HOME", self.dir.path().join("state"))
+            .env("XDG_CONFIG_HOME", self.dir.path().join("config"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        Run {
+            success: output.status.success(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        }
+    }
+}
+
+impl Run {
+    /// Stdout and stderr of the run
+    pub fn output(&self) -> String {
+        format!("{}{}", self.stdout, self.stderr)
+    }
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use std::path::Path;
+
+use common::{Env, DAY};
+
+
+#[test]
+fn lists_roots() {
+    let mut env = Env::new();
+    let target = env.fixture.add_store_path("result", 1024, &[]).unwrap();
+    env.fixture.add_gc_root(Path::new("/home/test/result"), &target, 3 * DAY).unwrap();
+    env.fixture.add_auto_root(Path::new("/home/test/removed")).unwrap();
+
+    let run = env.run(&["gc-roots", "--tsv"]);
+    assert!(run.success, "{}", run.output());
+    let line = format!("{}\t{}", env.path("/home/test/result").to_string_lossy(), env.fixture.path(&target).to_string_lossy());
+    assert!(run.stdout.contains(&line), "{}", run.output());
+    assert!(!run.stdout.contains("removed"), "{}", run.output());
+
+    let run = env.run(&["gc-roots", "--tsv", "--include-missing"]);
+    assert!(run.success, "{}", run.output());
+    assert!(run.stdout.contains(&*env.path("/home/test/removed").to_string_lossy()), "{}", run.output());
+}
+
+#[test]
+fn tidyup_removes_old_roots() {
+    let mut env = Env::new();
+    let old = env.fixture.add_store_path("old", 1024, &[]).unwrap();
+    let new = env.fixture.add_store_path("new", 1024, &[]).unwrap();
+    env.fixture.add_gc_root(Path::new("/home/test/old"), &old, 30 * DAY).unwrap();
+    env.fixture.add_gc_root(Path::new("/home/test/new"), &new, DAY).unwrap();
+
+    let run = env.run(&["tidyup-gc-roots", "--force", "--older", "10d"]);
+    assert!(run.success, "{}", run.output());
+    assert!(!env.exists("/home/test/old"));
+    assert!(env.exists("/home/test/new"));
+    assert!(env.exists("/nix/var/nix/gcroots/profiles"));
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{Env, DAY};
+
+const SYSTEM: &str = "/nix/var/nix/profiles/system";
+
+
+#[test]
+fn running_system_is_kept() {
+    let mut env = Env::new();
+    let targets = env.add_generations(SYSTEM, 3, Duration::ZERO, DAY);
+    env.fixture.set_running_system(&targets[0]).unwrap();
+
+    let run = env.run(&["cleanout", "--dry-run", "--keep-max", "1", "system"]);
+    assert!(run.success, "{}", run.output());
+    assert!(run.output().contains("Keeping generation 1 of"), "{}", run.output());
+    assert!(run.output().contains("which is the running system"), "{}", run.output());
+}
+
+#[test]
+fn no_warning_if_running_system_is_not_marked() {
+    let mut env = Env::new();
+    let targets = env.add_generations(SYSTEM, 3, Duration::ZERO, DAY);
+
+    env.fixture.set_running_system(&targets[2]).unwrap();
+    let run = env.run(&["cleanout", "--dry-run", "--keep-max", "1", "system"]);
+    assert!(run.success, "{}", run.output());
+    assert!(!run.output().contains("running system"), "{}", run.output());
+
+    env.fixture.set_running_system(&targets[0]).unwrap();
+    let run = env.run(&["cleanout", "--dry-run", "--keep-max", "3", "system"]);
+    assert!(run.success, "{}", run.output());
+    assert!(!run.output().contains("running system"), "{}", run.output());
+}
+
+#[test]
+fn system_shorthand_requires_profile() {
+    let env = Env::new();
+    let run = env.run(&["cleanout", "--dry-run", "system"]);
+    assert!(!run.success);
+    assert!(run.stderr.contains("system"), "{}", run.output());
+}
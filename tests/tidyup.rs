@@ -0,0 +1,39 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{Env, DAY};
+
+const ABANDONED: &str = "/nix/var/nix/profiles/per-user/old/profile";
+const DEFAULT: &str = "/nix/var/nix/profiles/default";
+
+
+#[test]
+fn asks_before_deleting_abandoned_profiles() {
+    let mut env = Env::new();
+    env.add_generations(ABANDONED, 2, 700 * DAY, DAY);
+    env.add_generations(DEFAULT, 2, 700 * DAY, DAY);
+
+    let run = env.run(&["tidyup-profiles"]);
+    assert!(run.success, "{}", run.output());
+    assert!(run.stdout.contains(&*env.path(ABANDONED).to_string_lossy()), "{}", run.output());
+    assert!(!run.stdout.contains(&*env.path(DEFAULT).to_string_lossy()), "{}", run.output());
+    assert!(env.exists(ABANDONED));
+
+    let run = env.run_with_input(&["tidyup-profiles"], "y\n");
+    assert!(run.success, "{}", run.output());
+    assert!(!env.exists(ABANDONED));
+    assert!(env.generations(ABANDONED).is_empty());
+    assert_eq!(env.generations(DEFAULT), vec![1, 2]);
+}
+
+#[test]
+fn keeps_profiles_in_use() {
+    let mut env = Env::new();
+    env.add_generations(ABANDONED, 2, Duration::ZERO, 700 * DAY);
+
+    let run = env.run(&["tidyup-profiles"]);
+    assert!(run.success, "{}", run.output());
+    assert!(run.output().contains("No abandoned profiles found"), "{}", run.output());
+    assert_eq!(env.generations(ABANDONED), vec![1, 2]);
+}